@@ -0,0 +1,165 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Derive macro for the `ToDatapoints` trait of the `kairosdb` crate,
+//! used through its `derive` feature
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Attribute, Data, DataStruct, DeriveInput, Error, Fields, LitStr};
+
+/// Implements `ToDatapoints` mapping every field of a struct to a metric
+/// with the field name. Fields marked with `#[kairos(tag)]` become tags
+/// of all metrics instead. The attributes
+///
+/// * `#[kairos(prefix = "...")]` on the struct prefixes all metric names,
+/// * `#[kairos(name = "...")]` sets the metric name of a field,
+/// * `#[kairos(tag = "...")]` sets the tag name of a field,
+/// * `#[kairos(skip)]` ignores a field.
+///
+/// Metric fields need a type convertible into a `DataPointValue`, tag
+/// fields a type implementing `ToString`.
+///
+/// # Example
+/// ```
+/// use kairosdb::KairosMetrics;
+/// use kairosdb::datapoints::{Datapoints, ToDatapoints};
+///
+/// #[derive(KairosMetrics)]
+/// #[kairos(prefix = "http.")]
+/// struct HttpStats {
+///     #[kairos(tag)]
+///     host: String,
+///     #[kairos(tag = "dc")]
+///     datacenter: &'static str,
+///     requests: i64,
+///     #[kairos(name = "latency_ms")]
+///     latency: f64,
+///     #[kairos(skip)]
+///     #[allow(dead_code)]
+///     note: String,
+/// }
+///
+/// let stats = HttpStats {
+///     host: "a".to_string(),
+///     datacenter: "eu",
+///     requests: 12,
+///     latency: 3.5,
+///     note: String::new(),
+/// };
+/// let datapoints = stats.to_datapoints(1000);
+/// assert_eq!(datapoints[0], Datapoints::builder("http.requests")
+///     .tag("host", "a").tag("dc", "eu").point(1000, 12).build());
+/// assert_eq!(datapoints[1], Datapoints::builder("http.latency_ms")
+///     .tag("host", "a").tag("dc", "eu").point(1000, 3.5).build());
+/// ```
+#[proc_macro_derive(KairosMetrics, attributes(kairos))]
+pub fn derive_kairos_metrics(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+// What a field maps to
+enum Field {
+    Metric(String),
+    Tag(String),
+    Skip,
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match input.data {
+        Data::Struct(DataStruct { fields: Fields::Named(ref fields), .. }) => &fields.named,
+        _ => return Err(Error::new_spanned(
+            &input.ident, "KairosMetrics can only be derived for structs with named fields")),
+    };
+
+    let mut prefix = String::new();
+    for attr in kairos_attrs(&input.attrs) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("prefix") {
+                prefix = meta.value()?.parse::<LitStr>()?.value();
+                Ok(())
+            } else {
+                Err(meta.error("unknown kairos attribute, expected `prefix`"))
+            }
+        })?;
+    }
+
+    let mut metrics = Vec::new();
+    let mut tags = Vec::new();
+    for field in fields {
+        let ident = field.ident.as_ref().expect("named field");
+        let mut kind = Field::Metric(format!("{}{}", prefix, ident));
+        for attr in kairos_attrs(&field.attrs) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("tag") {
+                    let name = match meta.value() {
+                        Ok(value) => value.parse::<LitStr>()?.value(),
+                        Err(_) => ident.to_string(),
+                    };
+                    kind = Field::Tag(name);
+                } else if meta.path.is_ident("name") {
+                    kind = Field::Metric(format!("{}{}", prefix,
+                                                 meta.value()?.parse::<LitStr>()?.value()));
+                } else if meta.path.is_ident("skip") {
+                    kind = Field::Skip;
+                } else {
+                    return Err(meta.error("unknown kairos attribute, expected `tag`, `name` \
+                                           or `skip`"));
+                }
+                Ok(())
+            })?;
+        }
+        match kind {
+            Field::Metric(name) => metrics.push((name, ident)),
+            Field::Tag(name) => tags.push((name, ident)),
+            Field::Skip => (),
+        }
+    }
+
+    let add_tags: Vec<TokenStream2> = tags.iter()
+        .map(|(name, ident)| quote! {
+            datapoints.add_tag(#name, &::std::string::ToString::to_string(&self.#ident));
+        })
+        .collect();
+    let datapoints = metrics.iter().map(|(name, ident)| quote! {
+        {
+            let mut datapoints = ::kairosdb::datapoints::Datapoints::new(#name, 0);
+            #(#add_tags)*
+            datapoints.add_value(ms, ::std::clone::Clone::clone(&self.#ident));
+            datapoints
+        }
+    });
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::kairosdb::datapoints::ToDatapoints for #name #ty_generics
+            #where_clause
+        {
+            fn to_datapoints(&self, ms: i64)
+                             -> ::std::vec::Vec<::kairosdb::datapoints::Datapoints> {
+                ::std::vec![#(#datapoints),*]
+            }
+        }
+    })
+}
+
+fn kairos_attrs(attrs: &[Attribute]) -> impl Iterator<Item = &Attribute> {
+    attrs.iter().filter(|attr| attr.path().is_ident("kairos"))
+}
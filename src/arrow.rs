@@ -0,0 +1,116 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Conversion of query results into Apache Arrow record batches
+//!
+//! Enabled with the `arrow` feature. The record batch has one row per
+//! datapoint with the columns
+//!
+//! * `metric`, the metric name,
+//! * one column per tag name of any series, with the values of the tag
+//!   in the series joined by `,` and null for series without the tag.
+//!   Results with tags named `metric`, `time` or `value` can't be
+//!   converted,
+//! * `time`, a UTC timestamp in milliseconds,
+//! * `value`, the value as double. Longs beyond 2^53 lose precision,
+//!   values which are not numeric are null.
+//!
+//! The rows are sorted by metric name, the series of a metric keep the
+//! order of the result.
+
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray, TimestampMillisecondArray};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+
+use crate::error::KairoError;
+use crate::result::ResultMap;
+
+/// Columns of every record batch besides the tags
+const FIXED_COLUMNS: [&str; 3] = ["metric", "time", "value"];
+
+/// Converts a query result into a record batch, e.g. to write it to
+/// Parquet or send it with Arrow Flight. Fails for tags named like one
+/// of the other columns.
+///
+/// # Example
+/// ```
+/// use kairosdb::arrow::to_record_batch;
+/// use kairosdb::result::QueryResult;
+///
+/// let body = r#"{"queries":[{"sample_size":3,"results":[
+///     {"name":"load","tags":{"host":["a"]},"values":[[1000,0.5],[2000,0.7]]},
+///     {"name":"load","tags":{"host":["b"]},"values":[[1000,3]]}]}]}"#;
+/// let result = QueryResult::new().parse_result(body).unwrap();
+///
+/// let batch = to_record_batch(&result).unwrap();
+/// assert_eq!(batch.num_rows(), 3);
+/// let names: Vec<_> = batch.schema().fields().iter().map(|f| f.name().clone()).collect();
+/// assert_eq!(names, vec!["metric", "host", "time", "value"]);
+///
+/// let body = r#"{"queries":[{"sample_size":1,"results":[
+///     {"name":"load","tags":{"time":["utc"]},"values":[[1000,0.5]]}]}]}"#;
+/// let result = QueryResult::new().parse_result(body).unwrap();
+/// assert!(to_record_batch(&result).is_err());
+/// ```
+pub fn to_record_batch(result: &ResultMap) -> Result<RecordBatch, KairoError> {
+    let mut metrics: Vec<_> = result.iter().collect();
+    metrics.sort_by(|a, b| a.0.cmp(b.0));
+    let tag_names: BTreeSet<&String> = metrics.iter()
+        .flat_map(|(_, series)| series.iter())
+        .flat_map(|series| series.tags.keys())
+        .collect();
+    if let Some(tag) = tag_names.iter().find(|tag| FIXED_COLUMNS.contains(&tag.as_str())) {
+        return Err(KairoError::Kairo(
+            format!("Tag '{}' collides with a column of the record batch", tag)));
+    }
+
+    let mut names = Vec::new();
+    let mut tags: Vec<Vec<Option<String>>> = vec![Vec::new(); tag_names.len()];
+    let mut times = Vec::new();
+    let mut values = Vec::new();
+    for (name, series) in metrics {
+        for series in series {
+            let series_tags: Vec<_> = tag_names.iter()
+                .map(|tag| series.tags.get(*tag).map(|values| values.join(",")))
+                .collect();
+            for value in &series.values {
+                names.push(name.as_str());
+                for (column, tag) in tags.iter_mut().zip(&series_tags) {
+                    column.push(tag.clone());
+                }
+                times.push(value.time as i64);
+                values.push(value.value.as_f64());
+            }
+        }
+    }
+
+    let mut fields = vec![Field::new("metric", DataType::Utf8, false)];
+    let mut columns: Vec<ArrayRef> = vec![Arc::new(StringArray::from(names))];
+    for (name, column) in tag_names.into_iter().zip(tags) {
+        fields.push(Field::new(name.as_str(), DataType::Utf8, true));
+        columns.push(Arc::new(StringArray::from(column)));
+    }
+    fields.push(Field::new("time",
+                           DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+                           false));
+    columns.push(Arc::new(TimestampMillisecondArray::from(times).with_timezone("UTC")));
+    fields.push(Field::new("value", DataType::Float64, true));
+    columns.push(Arc::new(Float64Array::from(values)));
+
+    RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)
+        .map_err(|err| KairoError::Kairo(format!("Creating record batch failed: {}", err)))
+}
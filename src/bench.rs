@@ -0,0 +1,220 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Load tests writing and querying synthetic series to measure the
+//! throughput of a server
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+
+use crate::KairosClient;
+use crate::datapoints::Datapoints;
+use crate::error::KairoError;
+use crate::query::{Metric, Query, Tags, Time};
+use crate::stats::LatencyHistogram;
+
+/// Load test writing synthetic datapoints to `series` series of a metric
+/// and querying them back. `concurrency` threads send `requests`
+/// requests in total. A write request holds `batch_size` datapoints
+/// spread over the series, one millisecond apart per series starting at
+/// `start`, an hour ago by default.
+///
+/// # Example
+/// ```
+/// use kairosdb::bench::Bench;
+/// use kairosdb::mock::MockClient;
+///
+/// let client = MockClient::new();
+/// let bench = Bench::new("bench.load")
+///     .series(10)
+///     .batch_size(100)
+///     .concurrency(4)
+///     .requests(20);
+///
+/// let report = bench.write(&client);
+/// assert_eq!((report.requests, report.errors, report.datapoints), (20, 0, 2000));
+/// assert_eq!(report.latency.count(), 20);
+/// println!("{:.0} datapoints/s", report.datapoints_per_second());
+///
+/// let report = bench.query(&client);
+/// assert_eq!((report.requests, report.errors, report.datapoints), (20, 0, 4000));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Bench {
+    metric: String,
+    series: usize,
+    batch_size: usize,
+    concurrency: usize,
+    requests: usize,
+    start: i64,
+}
+
+/// Result of a `Bench` run
+#[derive(Debug, Clone, Default)]
+pub struct BenchReport {
+    /// Number of sent requests
+    pub requests: u64,
+    /// Number of failed requests
+    pub errors: u64,
+    /// Number of written or queried datapoints of successful requests
+    pub datapoints: u64,
+    /// Duration of the whole run
+    pub elapsed: Duration,
+    /// Latency of the successful requests
+    pub latency: LatencyHistogram,
+}
+
+impl BenchReport {
+    /// Returns the number of requests per second
+    pub fn requests_per_second(&self) -> f64 {
+        self.requests as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+
+    /// Returns the number of written or queried datapoints per second
+    pub fn datapoints_per_second(&self) -> f64 {
+        self.datapoints as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+}
+
+impl Bench {
+    /// Creates a new `Bench` for the metric with 100 series, batches of
+    /// 1000 datapoints and 4 threads sending 100 requests
+    pub fn new(metric: &str) -> Bench {
+        Bench {
+            metric: metric.to_string(),
+            series: 100,
+            batch_size: 1000,
+            concurrency: 4,
+            requests: 100,
+            start: Utc::now().timestamp_millis() - 3_600_000,
+        }
+    }
+
+    /// Sets the number of series, told apart by their `series` tag
+    pub fn series(mut self, series: usize) -> Bench {
+        self.series = series.max(1);
+        self
+    }
+
+    /// Sets the number of datapoints of a write request
+    pub fn batch_size(mut self, batch_size: usize) -> Bench {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Sets the number of threads sending requests
+    pub fn concurrency(mut self, concurrency: usize) -> Bench {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Sets the number of requests sent by all threads together
+    pub fn requests(mut self, requests: usize) -> Bench {
+        self.requests = requests;
+        self
+    }
+
+    /// Sets the timestamp in milliseconds of the first datapoint
+    pub fn start(mut self, start: i64) -> Bench {
+        self.start = start;
+        self
+    }
+
+    /// Writes `requests` batches of synthetic datapoints
+    pub fn write<C: KairosClient + Sync>(&self, client: &C) -> BenchReport {
+        self.run(|request| {
+            let batch = self.batch(request);
+            client.add_batch(&batch).map(|_| self.batch_size)
+        })
+    }
+
+    /// Sends `requests` queries, each reading all datapoints of one of
+    /// the series written by `write`
+    pub fn query<C: KairosClient + Sync>(&self, client: &C) -> BenchReport {
+        let end = self.start + (self.requests * self.batch_size / self.series) as i64;
+        self.run(|request| {
+            let mut query = Query::new(Time::Nanoseconds(self.start), Time::Nanoseconds(end));
+            let mut tags = Tags::new();
+            tags.insert("series".to_string(), vec![(request % self.series).to_string()]);
+            query.add(Metric::new(&self.metric, tags, vec![]));
+            let result = client.query(&query)?;
+            Ok(result.values().flatten().map(|series| series.values.len()).sum())
+        })
+    }
+
+    // Sends the requests from `concurrency` threads. A request returns the
+    // number of its datapoints.
+    fn run<F>(&self, request: F) -> BenchReport
+        where F: Fn(usize) -> Result<usize, KairoError> + Sync
+    {
+        let next = AtomicUsize::new(0);
+        let report = Mutex::new(BenchReport::default());
+        let started = Instant::now();
+        thread::scope(|scope| {
+            for _ in 0..self.concurrency {
+                scope.spawn(|| loop {
+                    let number = next.fetch_add(1, Ordering::Relaxed);
+                    if number >= self.requests {
+                        break;
+                    }
+                    let sent = Instant::now();
+                    let result = request(number);
+                    let latency = sent.elapsed();
+
+                    let mut report = report.lock().unwrap();
+                    report.requests += 1;
+                    match result {
+                        Ok(datapoints) => {
+                            report.datapoints += datapoints as u64;
+                            report.latency.record(latency);
+                        }
+                        Err(err) => {
+                            debug!("bench request {} failed: {}", number, err);
+                            report.errors += 1;
+                        }
+                    }
+                });
+            }
+        });
+
+        let mut report = report.into_inner().unwrap();
+        report.elapsed = started.elapsed();
+        report
+    }
+
+    // Builds the batch of the request with the given number. The datapoint
+    // `n` of the run belongs to series `n % series`.
+    fn batch(&self, request: usize) -> Vec<Datapoints> {
+        let mut batch: BTreeMap<usize, Datapoints> = BTreeMap::new();
+        for n in request * self.batch_size..(request + 1) * self.batch_size {
+            let series = n % self.series;
+            let time = self.start + (n / self.series) as i64;
+            let value = ((n / self.series) as f64 / 60.0).sin() * 50.0 + 50.0;
+            batch.entry(series)
+                .or_insert_with(|| {
+                    let mut datapoints = Datapoints::new(&self.metric, 0);
+                    datapoints.add_tag("series", &series.to_string());
+                    datapoints
+                })
+                .add_ms(time, value);
+        }
+        batch.into_values().collect()
+    }
+}
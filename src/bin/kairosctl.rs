@@ -0,0 +1,305 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Command line client for KairosDB, built with the `cli` feature
+//!
+//! ```text
+//! kairosctl [--host HOST] [--port PORT] [--format json|csv] COMMAND
+//! kairosctl -h | --help
+//!
+//! query METRIC [--start TIME] [--end TIME] [--tag NAME=VALUE]...
+//! put METRIC VALUE [--time MS] [--tag NAME=VALUE]...
+//! put                     reads JSON lines from stdin
+//! metrics list [--prefix PREFIX] [--containing TEXT]
+//! metric delete NAME
+//! transfer METRIC... --target-host HOST [--target-port PORT]
+//!     [--start TIME] [--end TIME] [--chunk DURATION]
+//! ```
+//!
+//! Times are milliseconds since the epoch, RFC 3339 dates, `now` or
+//! relative times like `15m-ago` or `2d-ago`. Durations are like `1h` or
+//! `7d`.
+
+use std::env;
+use std::io::{self, Write};
+use std::process;
+
+use chrono::Utc;
+use kairosdb::{Client, KairoError};
+use kairosdb::datapoints::{DataPointValue, Datapoints};
+use kairosdb::import::{export_json_lines, import_json_lines};
+use kairosdb::options::MetricFilter;
+use kairosdb::query::{Metric, Query, RelativeTime, Tags, Time};
+use kairosdb::transfer::Transfer;
+
+const USAGE: &str = "\
+usage: kairosctl [--host HOST] [--port PORT] [--format json|csv] COMMAND
+       kairosctl -h | --help
+
+commands:
+    query METRIC [--start TIME] [--end TIME] [--tag NAME=VALUE]...
+    put METRIC VALUE [--time MS] [--tag NAME=VALUE]...
+    put                   reads JSON lines from stdin
+    metrics list [--prefix PREFIX] [--containing TEXT]
+    metric delete NAME
+    transfer METRIC... --target-host HOST [--target-port PORT]
+        [--start TIME] [--end TIME] [--chunk DURATION]";
+
+/// Options taking a value, all others are rejected
+const OPTIONS: &[&str] = &[
+    "host", "port", "format", "start", "end", "tag", "time", "prefix", "containing",
+    "target-host", "target-port", "chunk",
+];
+
+/// Datapoints written per request by `put` reading from stdin
+const BATCH_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Json,
+    Csv,
+}
+
+/// Positional arguments and `--name value` options of the command line
+#[derive(Debug, Default)]
+struct Args {
+    positional: Vec<String>,
+    options: Vec<(String, String)>,
+    help: bool,
+}
+
+impl Args {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Args, KairoError> {
+        let mut parsed = Args::default();
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                parsed.help = true;
+            } else if let Some(name) = arg.strip_prefix("--") {
+                if !OPTIONS.contains(&name) {
+                    return Err(usage(&format!("unknown option --{}", name)));
+                }
+                let value = args.next().ok_or_else(|| usage(&format!("--{} needs a value", name)))?;
+                parsed.options.push((name.to_string(), value));
+            } else if arg.starts_with('-') && arg.len() > 1 && arg.parse::<f64>().is_err() {
+                return Err(usage(&format!("unknown option {}", arg)));
+            } else {
+                parsed.positional.push(arg);
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// Returns the last value of the option
+    fn option<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+        self.options(name).last()
+    }
+
+    /// Returns all values of a repeated option
+    fn options<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.options.iter()
+            .filter(move |(option, _)| option == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn command(&self) -> Vec<&str> {
+        self.positional.iter().map(String::as_str).collect()
+    }
+}
+
+fn usage(message: &str) -> KairoError {
+    KairoError::Kairo(format!("{}\n\n{}", message, USAGE))
+}
+
+fn main() {
+    env_logger::init();
+    let result = Args::parse(env::args().skip(1)).and_then(|args| {
+        if args.help {
+            println!("{}", USAGE);
+            Ok(())
+        } else {
+            run(&args)
+        }
+    });
+    if let Err(err) = result {
+        eprintln!("kairosctl: {}", err);
+        process::exit(1);
+    }
+}
+
+fn run(args: &Args) -> Result<(), KairoError> {
+    let host = args.option("host").unwrap_or("localhost");
+    let port = parse_port(args.option("port"))?;
+    let format = match args.option("format").unwrap_or("json") {
+        "json" => Format::Json,
+        "csv" => Format::Csv,
+        other => return Err(usage(&format!("unknown format '{}'", other))),
+    };
+    let client = Client::new(host, port);
+
+    match args.command().as_slice() {
+        ["query", metric] => query(&client, args, metric, format),
+        ["put", metric, value] => put(&client, args, metric, value),
+        ["put"] => {
+            let stdin = io::stdin();
+            let written = import_json_lines(&client, stdin.lock(), BATCH_SIZE)?;
+            eprintln!("{} datapoints written", written);
+            Ok(())
+        }
+        ["metrics", "list"] => list_metrics(&client, args, format),
+        ["metric", "delete", metric] => client.delete_metric(metric),
+        ["transfer", metrics @ ..] if !metrics.is_empty() => transfer(&client, args, metrics),
+        [] => Err(usage("missing command")),
+        command => Err(usage(&format!("unknown command '{}'", command.join(" ")))),
+    }
+}
+
+fn query(client: &Client, args: &Args, metric: &str, format: Format) -> Result<(), KairoError> {
+    let start = parse_time(args.option("start").unwrap_or("1h-ago"))?;
+    let end = parse_time(args.option("end").unwrap_or("now"))?;
+    let mut query = Query::new(start, end);
+    let metric_query = Metric::try_new(metric, parse_tags(args)?, vec![])
+        .map_err(|err| KairoError::from(vec![err]))?;
+    query.add(metric_query);
+
+    let stdout = io::stdout();
+    match format {
+        Format::Json => {
+            export_json_lines(client, &query, stdout.lock())?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(stdout.lock());
+            writer.write_record(["metric", "timestamp", "value", "tags"])?;
+            for series in client.query(&query)?.remove(metric).unwrap_or_default() {
+                let mut tags: Vec<String> = series.tags.iter()
+                    .filter(|(_, values)| values.len() == 1)
+                    .map(|(name, values)| format!("{}={}", name, values[0]))
+                    .collect();
+                tags.sort();
+                let tags = tags.join(" ");
+                for value in &series.values {
+                    writer.write_record([metric, &value.time.to_string(),
+                                         &value.value.to_string(), &tags])?;
+                }
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+fn put(client: &Client, args: &Args, metric: &str, value: &str) -> Result<(), KairoError> {
+    let time = match args.option("time") {
+        Some(time) => time.parse().map_err(|_| usage(&format!("invalid time '{}'", time)))?,
+        None => Utc::now().timestamp_millis(),
+    };
+    let value = value.parse::<i64>().map(DataPointValue::from)
+        .or_else(|_| value.parse::<f64>().map(DataPointValue::from))
+        .unwrap_or_else(|_| DataPointValue::from(value.to_string()));
+
+    let mut datapoints = Datapoints::new(metric, 0);
+    datapoints.add_value(time, value);
+    for (name, values) in parse_tags(args)? {
+        for value in values {
+            datapoints.try_add_tag(&name, &value).map_err(|err| KairoError::from(vec![err]))?;
+        }
+    }
+    client.add(&datapoints)
+}
+
+fn list_metrics(client: &Client, args: &Args, format: Format) -> Result<(), KairoError> {
+    let filter = MetricFilter {
+        prefix: args.option("prefix").map(str::to_string),
+        containing: args.option("containing").map(str::to_string),
+    };
+    let names = client.list_metrics_filtered(&filter)?;
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    match format {
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut out, &names)?;
+            writeln!(out)?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            writer.write_record(["metric"])?;
+            for name in &names {
+                writer.write_record([name])?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+fn transfer(source: &Client, args: &Args, metrics: &[&str]) -> Result<(), KairoError> {
+    let host = args.option("target-host").ok_or_else(|| usage("missing --target-host"))?;
+    let port = parse_port(args.option("target-port"))?;
+    let target = Client::new(host, port);
+
+    let mut transfer = Transfer::new(parse_time(args.option("start").unwrap_or("1d-ago"))?,
+                                     parse_time(args.option("end").unwrap_or("now"))?);
+    if let Some(chunk) = args.option("chunk") {
+        transfer = transfer.chunk(parse_duration(chunk)?);
+    }
+    for metric in metrics {
+        transfer = transfer.metric(metric);
+    }
+    let copied = transfer
+        .on_progress(|progress| eprintln!("{:5.1}% {} datapoints",
+                                          progress.fraction() * 100.0, progress.datapoints))
+        .run(source, &target)?;
+    eprintln!("{} datapoints copied", copied);
+    Ok(())
+}
+
+/// Parses the repeated `--tag NAME=VALUE` options
+fn parse_tags(args: &Args) -> Result<Tags, KairoError> {
+    let mut tags = Tags::new();
+    for tag in args.options("tag") {
+        let mut parts = tag.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) if !name.is_empty() => {
+                tags.entry(name.to_string()).or_default().push(value.to_string());
+            }
+            _ => return Err(usage(&format!("invalid tag '{}', expected NAME=VALUE", tag))),
+        }
+    }
+    Ok(tags)
+}
+
+/// Parses milliseconds since the epoch or a time accepted by `Time::parse`
+fn parse_time(time: &str) -> Result<Time, KairoError> {
+    match time.parse() {
+        Ok(ms) => Ok(Time::Nanoseconds(ms)),
+        Err(_) => Time::parse(time),
+    }
+}
+
+/// Parses a duration like `1h` or `7d`
+fn parse_duration(duration: &str) -> Result<RelativeTime, KairoError> {
+    match Time::parse(&format!("{}-ago", duration)) {
+        Ok(Time::Relative { value, unit }) => Ok(RelativeTime::new(value, unit)),
+        _ => Err(usage(&format!("invalid duration '{}'", duration))),
+    }
+}
+
+/// Parses a port, 8080 when not set
+fn parse_port(port: Option<&str>) -> Result<u32, KairoError> {
+    match port {
+        Some(port) => port.parse().map_err(|_| usage(&format!("invalid port '{}'", port))),
+        None => Ok(8080),
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Circuit breaker to fail fast while the server is unavailable
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The states of a `CircuitBreaker`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CircuitState {
+    /// Requests pass through
+    Closed,
+    /// Requests are rejected with `KairoError::CircuitOpen`
+    Open,
+    /// The cool-down elapsed and a single trial request is let through
+    HalfOpen,
+}
+
+/// Opens after a number of consecutive failures and rejects all requests
+/// until the cool-down elapsed. Afterwards a single trial request decides
+/// whether the circuit closes again or stays open for another cool-down.
+///
+/// Transport errors and 5xx responses count as failures.
+///
+/// # Example
+/// ```
+/// use std::time::Duration;
+/// use kairosdb::Client;
+/// use kairosdb::circuitbreaker::CircuitBreaker;
+///
+/// let mut client = Client::new("localhost", 8080);
+/// client.set_circuit_breaker(CircuitBreaker::new(5, Duration::from_secs(30)));
+/// ```
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cool_down: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    failures: u32,
+    opened_at: Option<Instant>,
+    trial: bool,
+}
+
+impl CircuitBreaker {
+    /// Creates a new `CircuitBreaker` which opens after `failure_threshold`
+    /// consecutive failures and half-opens after `cool_down`.
+    pub fn new(failure_threshold: u32, cool_down: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            failure_threshold: failure_threshold.max(1),
+            cool_down,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    /// Returns the current state of the circuit
+    pub fn state(&self) -> CircuitState {
+        let state = self.state.lock().unwrap();
+        match state.opened_at {
+            None => CircuitState::Closed,
+            Some(_) if state.trial => CircuitState::HalfOpen,
+            Some(at) if at.elapsed() >= self.cool_down => CircuitState::HalfOpen,
+            Some(_) => CircuitState::Open,
+        }
+    }
+
+    pub(crate) fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.opened_at {
+            None => true,
+            Some(at) if at.elapsed() >= self.cool_down && !state.trial => {
+                info!("circuit half-open, sending trial request");
+                state.trial = true;
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    pub(crate) fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.opened_at.is_some() {
+            info!("circuit closed");
+        }
+        *state = BreakerState::default();
+    }
+
+    pub(crate) fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures += 1;
+        if state.trial || state.failures >= self.failure_threshold {
+            warn!("circuit opened after {} consecutive failures", state.failures);
+            state.opened_at = Some(Instant::now());
+            state.trial = false;
+        }
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Limit for the number of requests in flight
+
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Semaphore allowing at most a fixed number of requests in flight.
+/// Further requests block until a running one finished.
+///
+/// # Example
+/// ```
+/// use kairosdb::concurrency::ConcurrencyLimit;
+///
+/// let limit = ConcurrencyLimit::new(2);
+/// let first = limit.acquire();
+/// let second = limit.acquire();
+/// assert_eq!(limit.in_flight(), 2);
+/// drop(first);
+/// assert_eq!(limit.in_flight(), 1);
+/// ```
+#[derive(Debug)]
+pub struct ConcurrencyLimit {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    max: usize,
+    in_flight: Mutex<usize>,
+    released: Condvar,
+}
+
+/// Permit of a `ConcurrencyLimit`, released when dropped. A permit can
+/// be moved to another thread, e.g. one finishing an aborted request.
+#[derive(Debug)]
+pub struct Permit {
+    shared: Arc<Shared>,
+}
+
+impl ConcurrencyLimit {
+    /// Creates a new `ConcurrencyLimit` allowing `max` requests in flight
+    pub fn new(max: usize) -> ConcurrencyLimit {
+        ConcurrencyLimit {
+            shared: Arc::new(Shared {
+                max: max.max(1),
+                in_flight: Mutex::new(0),
+                released: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Blocks until a request may be sent and returns its permit
+    pub fn acquire(&self) -> Permit {
+        let mut in_flight = self.shared.in_flight.lock().unwrap();
+        while *in_flight >= self.shared.max {
+            in_flight = self.shared.released.wait(in_flight).unwrap();
+        }
+        *in_flight += 1;
+        Permit { shared: self.shared.clone() }
+    }
+
+    /// Returns the number of requests currently in flight
+    pub fn in_flight(&self) -> usize {
+        *self.shared.in_flight.lock().unwrap()
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        *self.shared.in_flight.lock().unwrap() -= 1;
+        self.shared.released.notify_one();
+    }
+}
@@ -13,41 +13,622 @@
 // limitations under the License.
 //
 use std::collections::HashMap;
-use chrono::{DateTime, TimeZone};
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+
+use crate::validation::{check_tag, ValidationError};
+
+/// Value of a datapoint, written with `Datapoints` and returned in query
+/// results. KairosDB stores long and double values with their own
+/// storage type, longs are serialized as JSON integers. Values of other
+/// storage types are text or arbitrary JSON.
+///
+/// # Example
+/// ```
+/// use kairosdb::datapoints::DataPointValue;
+///
+/// assert_eq!(DataPointValue::from(42), DataPointValue::Long(42));
+/// assert_eq!(DataPointValue::from(1.5), DataPointValue::Double(1.5));
+/// assert_eq!(DataPointValue::from("up"), DataPointValue::Text("up".to_string()));
+/// assert_eq!(DataPointValue::Long(42), 42.0);
+/// ```
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum DataPointValue {
+    Long(i64),
+    Double(f64),
+    Text(String),
+    Custom(serde_json::Value),
+}
 
 /// Struct to define everything for a datapoint
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 pub struct Datapoints {
     name: String,
-    datapoints: Vec<(i64, f64)>,
-    tags: HashMap<String, String>,
+    datapoints: Vec<(i64, DataPointValue)>,
+    tags: HashMap<Arc<str>, Arc<str>>,
     ttl: u32,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    data_type: Option<String>,
+}
+
+/// A set of common tags, e.g. the host, region and service of a
+/// collector, to add to many sets of datapoints with
+/// `Datapoints::add_tags`
+///
+/// # Example
+/// ```
+/// use kairosdb::datapoints::{Datapoints, TagSet};
+///
+/// let common = TagSet::from(&[("host", "a"), ("region", "eu")][..]);
+/// let datapoints = Datapoints::builder("cpu").tags(&common).point(1000, 0.5).build();
+/// # assert_eq!(serde_json::to_value(&datapoints).unwrap()["tags"],
+/// #            serde_json::json!({"host": "a", "region": "eu"}));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TagSet {
+    tags: Vec<(String, String)>,
+}
+
+/// A tag as pair of name and value, given by value or by reference
+pub trait TagPair {
+    /// Returns the name of the tag
+    fn name(&self) -> &str;
+
+    /// Returns the value of the tag
+    fn value(&self) -> &str;
+}
+
+impl<K: AsRef<str>, V: AsRef<str>> TagPair for (K, V) {
+    fn name(&self) -> &str {
+        self.0.as_ref()
+    }
+
+    fn value(&self) -> &str {
+        self.1.as_ref()
+    }
+}
+
+impl<K: AsRef<str>, V: AsRef<str>> TagPair for &(K, V) {
+    fn name(&self) -> &str {
+        self.0.as_ref()
+    }
+
+    fn value(&self) -> &str {
+        self.1.as_ref()
+    }
+}
+
+impl From<&[(&str, &str)]> for TagSet {
+    fn from(tags: &[(&str, &str)]) -> TagSet {
+        TagSet {
+            tags: tags.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a TagSet {
+    type Item = &'a (String, String);
+    type IntoIter = std::slice::Iter<'a, (String, String)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.tags.iter()
+    }
+}
+
+/// Builder for `Datapoints`, created with `Datapoints::builder`
+///
+/// # Example
+/// ```
+/// use kairosdb::datapoints::Datapoints;
+///
+/// let datapoints = Datapoints::builder("cpu")
+///     .tag("host", "a")
+///     .ttl_secs(3600)
+///     .point(1475513259000, 1.0)
+///     .point(1475513260000, 2)
+///     .build();
+/// assert_eq!(serde_json::to_string(&datapoints).unwrap(),
+///            r#"{"name":"cpu","datapoints":[[1475513259000,1.0],[1475513260000,2]],"tags":{"host":"a"},"ttl":3600}"#);
+/// ```
+#[derive(Debug, Clone)]
+pub struct DatapointsBuilder {
+    datapoints: Datapoints,
 }
 
 impl Datapoints {
-    /// Creates a new set of datapoints
+    /// Returns a `DatapointsBuilder` for a set of datapoints of the
+    /// metric `name` without ttl
+    pub fn builder(name: &str) -> DatapointsBuilder {
+        DatapointsBuilder {
+            datapoints: Datapoints::new(name, 0),
+        }
+    }
+
+    /// Creates a new set of datapoints with a time to live of `ttl`
+    /// seconds, 0 keeps them forever. The name is not checked, see
+    /// `validation::validate_batch`.
     pub fn new(name: &str, ttl: u32) -> Datapoints {
         Datapoints {
             name: name.to_string(),
             datapoints: Vec::new(),
             tags: HashMap::new(),
-            ttl
+            ttl,
+            data_type: None,
         }
     }
 
-    /// Adds a new datapoint to the set using 'DateTime'
+    /// Adds a new datapoint to the set using 'DateTime' with millisecond
+    /// precision
     pub fn add<Tz: TimeZone>(&mut self, datetime: DateTime<Tz>, value: f64) {
-        self.datapoints.push((datetime.timestamp() * 1000, value));
+        self.datapoints.push((datetime.timestamp_millis(), DataPointValue::Double(value)));
     }
 
     /// Adds a new datapoint to the set using the unix millisecond as
     /// time reference
     pub fn add_ms(&mut self, ms: i64, value: f64) {
-        self.datapoints.push((ms, value));
+        self.datapoints.push((ms, DataPointValue::Double(value)));
+    }
+
+    /// Adds a new datapoint with the current time
+    ///
+    /// ```
+    /// # use kairosdb::datapoints::Datapoints;
+    /// let mut datapoints = Datapoints::new("load", 0);
+    /// datapoints.add_now(0.75);
+    /// datapoints.add_now(3);
+    /// assert_eq!(datapoints.len(), 2);
+    /// ```
+    pub fn add_now<V: Into<DataPointValue>>(&mut self, value: V) {
+        self.add_value(Utc::now().timestamp_millis(), value);
+    }
+
+    /// Adds a new datapoint with a long value, e.g. of a counter, to the
+    /// set using the unix millisecond as time reference
+    ///
+    /// ```
+    /// # use kairosdb::datapoints::Datapoints;
+    /// let mut datapoints = Datapoints::new("requests", 0);
+    /// datapoints.add_long(1475513259000, 42);
+    /// assert_eq!(serde_json::to_string(&datapoints).unwrap(),
+    ///            r#"{"name":"requests","datapoints":[[1475513259000,42]],"tags":{},"ttl":0}"#);
+    /// ```
+    pub fn add_long(&mut self, ms: i64, value: i64) {
+        self.datapoints.push((ms, DataPointValue::Long(value)));
+    }
+
+    /// Adds a new datapoint with any value convertible into a
+    /// `DataPointValue` using the unix millisecond as time reference
+    ///
+    /// ```
+    /// # use kairosdb::datapoints::Datapoints;
+    /// let mut datapoints = Datapoints::new("myMetric", 0);
+    /// datapoints.add_value(1475513259000, 42u32);
+    /// datapoints.add_value(1475513259001, 0.5f32);
+    /// assert_eq!(serde_json::to_string(&datapoints).unwrap(),
+    ///            r#"{"name":"myMetric","datapoints":[[1475513259000,42],[1475513259001,0.5]],"tags":{},"ttl":0}"#);
+    /// ```
+    pub fn add_value<V: Into<DataPointValue>>(&mut self, ms: i64, value: V) {
+        self.datapoints.push((ms, value.into()));
+    }
+
+    /// Adds a new datapoint of a custom storage type, e.g. provided by a
+    /// KairosDB plugin, using the unix millisecond as time reference.
+    /// KairosDB expects all datapoints of a set to share the same type.
+    ///
+    /// ```
+    /// # use kairosdb::datapoints::Datapoints;
+    /// use serde_json::json;
+    ///
+    /// let mut datapoints = Datapoints::new("impedance", 0);
+    /// datapoints.add_custom(1475513259000, "complex-number",
+    ///                       json!({"real": 2.3, "imaginary": 3.4}));
+    /// assert_eq!(serde_json::to_string(&datapoints).unwrap(),
+    ///            r#"{"name":"impedance","datapoints":[[1475513259000,{"imaginary":3.4,"real":2.3}]],"tags":{},"ttl":0,"type":"complex-number"}"#);
+    /// ```
+    pub fn add_custom(&mut self, ms: i64, data_type: &str, value: serde_json::Value) {
+        self.set_type(data_type);
+        self.datapoints.push((ms, DataPointValue::Custom(value)));
+    }
+
+    /// Sets the storage type of the datapoints, e.g. `string` for text
+    /// values. Without a type KairosDB derives it from the values.
+    pub fn set_type(&mut self, data_type: &str) {
+        self.data_type = Some(data_type.to_string());
+    }
+
+    /// Adds a new datapoint to the set using the unix nanosecond as
+    /// time reference. KairosDB stores milliseconds, so the time is
+    /// rounded down to the millisecond before it is sent.
+    ///
+    /// ```
+    /// # use kairosdb::datapoints::Datapoints;
+    /// let mut datapoints = Datapoints::new("myMetric", 0);
+    /// datapoints.add_ns(1_475_513_259_001_999_999, 11.0);
+    /// assert_eq!(serde_json::to_string(&datapoints).unwrap(),
+    ///            r#"{"name":"myMetric","datapoints":[[1475513259001,11.0]],"tags":{},"ttl":0}"#);
+    /// ```
+    pub fn add_ns(&mut self, ns: i64, value: f64) {
+        self.datapoints.push((ns.div_euclid(1_000_000), DataPointValue::Double(value)));
+    }
+
+    /// Returns the number of datapoints in the set
+    ///
+    /// ```
+    /// # use kairosdb::datapoints::Datapoints;
+    /// let mut datapoints = Datapoints::new("myMetric", 0);
+    /// datapoints.reserve(100);
+    /// datapoints.add_ms(1000, 1.0);
+    /// datapoints.add_ms(2000, 2.0);
+    /// assert_eq!(datapoints.len(), 2);
+    ///
+    /// datapoints.clear();
+    /// assert!(datapoints.is_empty());
+    /// ```
+    pub fn len(&self) -> usize {
+        self.datapoints.len()
+    }
+
+    /// Returns true if the set contains no datapoints
+    pub fn is_empty(&self) -> bool {
+        self.datapoints.is_empty()
+    }
+
+    /// Removes all datapoints but keeps name, tags and ttl, e.g. to
+    /// reuse the set after a flush
+    pub fn clear(&mut self) {
+        self.datapoints.clear();
+    }
+
+    /// Reserves capacity for at least `additional` more datapoints
+    pub fn reserve(&mut self, additional: usize) {
+        self.datapoints.reserve(additional);
+    }
+
+    /// Sorts the datapoints by timestamp, keeping the order of datapoints
+    /// with the same timestamp
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::datapoints::Datapoints;
+    ///
+    /// let mut datapoints = Datapoints::builder("cpu").point(2000, 2).point(1000, 1).build();
+    /// assert!(!datapoints.is_sorted());
+    /// datapoints.sort();
+    /// assert!(datapoints.is_sorted());
+    /// ```
+    pub fn sort(&mut self) {
+        self.datapoints.sort_by_key(|(time, _)| *time);
+    }
+
+    /// Returns true if the datapoints are ordered by timestamp
+    pub fn is_sorted(&self) -> bool {
+        self.datapoints.windows(2).all(|w| w[0].0 <= w[1].0)
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
     }
 
-    /// Adds a tag to the datapoint set
+    pub(crate) fn datapoints(&self) -> &[(i64, DataPointValue)] {
+        &self.datapoints
+    }
+
+    pub(crate) fn tags(&self) -> &HashMap<Arc<str>, Arc<str>> {
+        &self.tags
+    }
+
+    /// Adds a tag to the datapoint set. Name and value are only checked
+    /// in debug builds, which panic on names the server rejects; use
+    /// `try_add_tag` for tags from untrusted input.
     pub fn add_tag(&mut self, name: &str, value: &str) {
-        self.tags.insert(name.to_string(), value.to_string());
+        debug_assert!(check_tag(name, value).is_ok(),
+                      "invalid tag '{}={}', use try_add_tag to check it", name, value);
+        self.tags.insert(name.into(), value.into());
+    }
+
+    /// Adds a tag sharing the strings of `name` and `value` instead of
+    /// copying them, e.g. strings of an `intern::Interner`
+    ///
+    /// ```
+    /// # use kairosdb::datapoints::Datapoints;
+    /// use kairosdb::intern::Interner;
+    ///
+    /// let interner = Interner::new();
+    /// let (host, a) = (interner.intern("host"), interner.intern("a"));
+    /// for metric in &["cpu", "memory"] {
+    ///     let mut datapoints = Datapoints::new(metric, 0);
+    ///     datapoints.add_shared_tag(&host, &a);
+    /// }
+    /// assert_eq!(interner.len(), 2);
+    /// ```
+    pub fn add_shared_tag(&mut self, name: &Arc<str>, value: &Arc<str>) {
+        self.tags.insert(name.clone(), value.clone());
+    }
+
+    /// Sets the time to live of the datapoints, `Duration::ZERO` keeps
+    /// them forever. KairosDB counts in seconds, so fractions are rounded
+    /// up.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use kairosdb::datapoints::Datapoints;
+    /// let mut datapoints = Datapoints::new("myMetric", 0);
+    /// datapoints.set_ttl(Duration::from_millis(1500));
+    /// assert_eq!(datapoints.ttl(), Duration::from_secs(2));
+    /// ```
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        let secs = ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0);
+        self.ttl = u32::try_from(secs).unwrap_or(u32::MAX);
+    }
+
+    /// Returns the time to live of the datapoints, zero if they are kept
+    /// forever
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(u64::from(self.ttl))
+    }
+
+    /// Adds several tags to the datapoint set, e.g. a common set of tags
+    /// from a `HashMap`, a `TagSet` or an array or slice of pairs
+    ///
+    /// ```
+    /// # use std::collections::HashMap;
+    /// # use kairosdb::datapoints::Datapoints;
+    /// let mut common = HashMap::new();
+    /// common.insert("region".to_string(), "eu".to_string());
+    /// let service: &[(&str, &str)] = &[("service", "api")];
+    ///
+    /// let mut datapoints = Datapoints::new("myMetric", 0);
+    /// datapoints.add_tags(&common);
+    /// datapoints.add_tags(service);
+    /// datapoints.add_tags([("host", "a")]);
+    /// # assert_eq!(serde_json::to_value(&datapoints).unwrap()["tags"],
+    /// #            serde_json::json!({"region": "eu", "host": "a", "service": "api"}));
+    /// ```
+    pub fn add_tags<I>(&mut self, tags: I)
+        where I: IntoIterator, I::Item: TagPair
+    {
+        for tag in tags {
+            self.add_tag(tag.name(), tag.value());
+        }
+    }
+
+    /// Adds a tag to the datapoint set if KairosDB accepts its name and
+    /// value, see `validation::is_valid_name`
+    ///
+    /// ```
+    /// # use kairosdb::datapoints::Datapoints;
+    /// let mut datapoints = Datapoints::new("myMetric", 0);
+    /// assert!(datapoints.try_add_tag("host", "server-1").is_ok());
+    /// assert!(datapoints.try_add_tag("host name", "server 1").is_err());
+    /// ```
+    pub fn try_add_tag(&mut self, name: &str, value: &str) -> Result<(), ValidationError> {
+        check_tag(name, value)?;
+        self.tags.insert(name.into(), value.into());
+        Ok(())
+    }
+}
+
+impl DatapointsBuilder {
+    /// Adds a tag to the datapoint set, checked in debug builds only like
+    /// `Datapoints::add_tag`
+    pub fn tag(mut self, name: &str, value: &str) -> DatapointsBuilder {
+        self.datapoints.add_tag(name, value);
+        self
+    }
+
+    /// Adds a tag sharing the strings of `name` and `value`
+    pub fn shared_tag(mut self, name: &Arc<str>, value: &Arc<str>) -> DatapointsBuilder {
+        self.datapoints.add_shared_tag(name, value);
+        self
+    }
+
+    /// Adds several tags to the datapoint set, see `Datapoints::add_tags`
+    pub fn tags<I>(mut self, tags: I) -> DatapointsBuilder
+        where I: IntoIterator, I::Item: TagPair
+    {
+        self.datapoints.add_tags(tags);
+        self
+    }
+
+    /// Sets the time to live of the datapoints in seconds, 0 keeps them
+    /// forever
+    pub fn ttl_secs(mut self, ttl: u32) -> DatapointsBuilder {
+        self.datapoints.ttl = ttl;
+        self
+    }
+
+    /// Sets the time to live of the datapoints
+    pub fn ttl(mut self, ttl: Duration) -> DatapointsBuilder {
+        self.datapoints.set_ttl(ttl);
+        self
+    }
+
+    /// Sets the storage type of the datapoints
+    pub fn data_type(mut self, data_type: &str) -> DatapointsBuilder {
+        self.datapoints.set_type(data_type);
+        self
+    }
+
+    /// Adds a datapoint using the unix millisecond as time reference
+    pub fn point<V: Into<DataPointValue>>(mut self, ms: i64, value: V) -> DatapointsBuilder {
+        self.datapoints.add_value(ms, value);
+        self
+    }
+
+    /// Adds a datapoint using 'DateTime' with millisecond precision
+    pub fn point_at<Tz: TimeZone, V: Into<DataPointValue>>(mut self, datetime: DateTime<Tz>,
+                                                          value: V) -> DatapointsBuilder {
+        self.datapoints.add_value(datetime.timestamp_millis(), value);
+        self
+    }
+
+    /// Returns the built `Datapoints`
+    pub fn build(self) -> Datapoints {
+        self.datapoints
+    }
+}
+
+/// Types whose fields map to datapoints, e.g. the statistics an exporter
+/// collects. With the `derive` feature it is derived with
+/// `#[derive(KairosMetrics)]`, see `kairosdb::KairosMetrics`.
+///
+/// # Example
+/// ```
+/// use kairosdb::datapoints::{Datapoints, ToDatapoints};
+///
+/// struct Load {
+///     host: String,
+///     load: f64,
+/// }
+///
+/// impl ToDatapoints for Load {
+///     fn to_datapoints(&self, ms: i64) -> Vec<Datapoints> {
+///         vec![Datapoints::builder("load").tag("host", &self.host).point(ms, self.load).build()]
+///     }
+/// }
+///
+/// let load = Load { host: "a".to_string(), load: 0.5 };
+/// assert_eq!(load.to_datapoints(1000)[0].len(), 1);
+/// ```
+pub trait ToDatapoints {
+    /// Returns the datapoints of the value at the time `ms`
+    fn to_datapoints(&self, ms: i64) -> Vec<Datapoints>;
+}
+
+impl DataPointValue {
+    /// Returns numeric values as double, longs beyond 2^53 lose precision
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            DataPointValue::Long(value) => Some(value as f64),
+            DataPointValue::Double(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+// Deserialized by the JSON type of the value instead of trying every
+// variant like an untagged enum, which buffers each value first
+impl<'de> Deserialize<'de> for DataPointValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<DataPointValue, D::Error> {
+        deserializer.deserialize_any(DataPointValueVisitor)
+    }
+}
+
+struct DataPointValueVisitor;
+
+impl<'de> Visitor<'de> for DataPointValueVisitor {
+    type Value = DataPointValue;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a datapoint value")
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<DataPointValue, E> {
+        Ok(DataPointValue::Long(value))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<DataPointValue, E> {
+        Ok(i64::try_from(value).map_or(DataPointValue::Double(value as f64), DataPointValue::Long))
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<DataPointValue, E> {
+        Ok(DataPointValue::Double(value))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<DataPointValue, E> {
+        Ok(DataPointValue::Text(value.to_string()))
+    }
+
+    fn visit_string<E: de::Error>(self, value: String) -> Result<DataPointValue, E> {
+        Ok(DataPointValue::Text(value))
+    }
+
+    fn visit_bool<E: de::Error>(self, value: bool) -> Result<DataPointValue, E> {
+        Ok(DataPointValue::Custom(value.into()))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<DataPointValue, E> {
+        Ok(DataPointValue::Custom(serde_json::Value::Null))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<DataPointValue, A::Error> {
+        serde_json::Value::deserialize(SeqAccessDeserializer::new(seq)).map(DataPointValue::Custom)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<DataPointValue, A::Error> {
+        serde_json::Value::deserialize(MapAccessDeserializer::new(map)).map(DataPointValue::Custom)
+    }
+}
+
+impl fmt::Display for DataPointValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DataPointValue::Long(value) => write!(f, "{}", value),
+            DataPointValue::Double(value) => write!(f, "{}", value),
+            DataPointValue::Text(ref value) => f.write_str(value),
+            DataPointValue::Custom(ref value) => write!(f, "{}", value),
+        }
+    }
+}
+
+impl Default for DataPointValue {
+    fn default() -> DataPointValue {
+        DataPointValue::Double(0.0)
+    }
+}
+
+impl PartialEq<f64> for DataPointValue {
+    fn eq(&self, other: &f64) -> bool {
+        self.as_f64() == Some(*other)
+    }
+}
+
+macro_rules! impl_from_long {
+    ($($t:ty),*) => {
+        $(
+            impl From<$t> for DataPointValue {
+                fn from(value: $t) -> DataPointValue {
+                    DataPointValue::Long(i64::from(value))
+                }
+            }
+        )*
+    };
+}
+
+impl_from_long!(i8, i16, i32, i64, u8, u16, u32);
+
+impl From<f32> for DataPointValue {
+    fn from(value: f32) -> DataPointValue {
+        DataPointValue::Double(f64::from(value))
+    }
+}
+
+impl From<f64> for DataPointValue {
+    fn from(value: f64) -> DataPointValue {
+        DataPointValue::Double(value)
+    }
+}
+
+impl From<String> for DataPointValue {
+    fn from(value: String) -> DataPointValue {
+        DataPointValue::Text(value)
+    }
+}
+
+impl<'a> From<&'a str> for DataPointValue {
+    fn from(value: &'a str) -> DataPointValue {
+        DataPointValue::Text(value.to_string())
+    }
+}
+
+impl From<serde_json::Value> for DataPointValue {
+    fn from(value: serde_json::Value) -> DataPointValue {
+        DataPointValue::Custom(value)
     }
 }
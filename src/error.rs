@@ -13,27 +13,479 @@
 // limitations under the License.
 //
 
-use reqwest;
-use serde_json;
-use std;
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
 
+use http::{Method, StatusCode};
+
+use crate::datapoints::Datapoints;
+use crate::validation::ValidationError;
+
+/// Bytes of a response body kept in an error at most
+pub(crate) const MAX_ERROR_BODY: usize = 4096;
+
+/// Bytes of a request body kept in a `RequestContext` at most
+const MAX_CONTEXT_BODY: usize = 256;
+
+/// The request a failed request belongs to, to trace an error in the
+/// logs back to the query or batch which caused it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestContext {
+    pub method: Method,
+    pub url: String,
+    /// The start of the request body, at most 256 bytes. Empty for
+    /// bodies which are streamed.
+    pub body: String,
+}
+
+impl RequestContext {
+    pub(crate) fn new(method: &Method, url: &str, body: &[u8]) -> RequestContext {
+        let body = String::from_utf8_lossy(&body[..body.len().min(MAX_CONTEXT_BODY)]);
+        RequestContext { method: method.clone(), url: url.to_string(), body: body.into_owned() }
+    }
+}
+
+/// A datapoint write the server rejected in part. KairosDB stores the
+/// valid sets of datapoints of a batch and reports every invalid value
+/// with a message like `metric[1](name=cpu).datapoints[0].value may not
+/// be empty`.
+///
+/// # Example
+/// ```
+/// use kairosdb::{Client, KairoError};
+/// use kairosdb::datapoints::Datapoints;
+///
+/// let server = kairosdb::testing::StubServer::start();
+/// let client = Client::new("localhost", server.port());
+/// # server.respond(kairosdb::transport::Method::POST, "/api/v1/datapoints", 400,
+/// #     r#"{"errors":["metric[1](name=cpu).datapoints[0].value may not be empty"]}"#);
+/// let batch = vec![
+///     Datapoints::builder("cpu").tag("host", "a").point(1000, 0.5).build(),
+///     Datapoints::builder("cpu").tag("host", "b").point(1000, String::new()).build(),
+/// ];
+/// match client.add_batch(&batch) {
+///     Err(KairoError::PartialWrite(err)) => {
+///         assert_eq!(err.rejected[0].metric, 1);
+///         assert_eq!(err.failed(&batch), vec![batch[1].clone()]);
+///     }
+///     other => panic!("expected partial write, got {:?}", other),
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchError {
+    pub status: StatusCode,
+    /// The rejections in the order of the messages of the server
+    pub rejected: Vec<Rejection>,
+    /// All error messages of the server, including those without an
+    /// index of a set of datapoints
+    pub errors: Vec<String>,
+    pub request: Option<Box<RequestContext>>,
+}
+
+/// A rejected set of datapoints of a batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    /// Index of the set in the batch
+    pub metric: usize,
+    /// Index of the rejected datapoint in the set, if the message names
+    /// one. Counts the datapoints as sent, i.e. sorted if the client
+    /// sorts datapoints.
+    pub datapoint: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for RequestContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.method, self.url)?;
+        if !self.body.is_empty() {
+            write!(f, " with body {}", self.body)?;
+        }
+        Ok(())
+    }
+}
+
+impl BatchError {
+    /// Returns the rejected sets of datapoints of `batch`, which must be
+    /// the batch of the failed write, e.g. to retry them after fixing
+    /// them
+    pub fn failed(&self, batch: &[Datapoints]) -> Vec<Datapoints> {
+        let mut indices: Vec<usize> = self.rejected.iter().map(|r| r.metric).collect();
+        indices.sort_unstable();
+        indices.dedup();
+        indices.into_iter().filter_map(|index| batch.get(index).cloned()).collect()
+    }
+
+    // Creates the error if any message names a set of datapoints
+    fn parse(status: StatusCode, errors: Vec<String>) -> Result<BatchError, Vec<String>> {
+        let rejected: Vec<Rejection> = errors.iter()
+            .filter_map(|message| {
+                Some(Rejection {
+                    metric: index_after(message, "metric[")?,
+                    datapoint: index_after(message, ".datapoints["),
+                    message: message.clone(),
+                })
+            })
+            .collect();
+        if rejected.is_empty() {
+            return Err(errors);
+        }
+        Ok(BatchError { status, rejected, errors, request: None })
+    }
+}
+
+// Parses the number following `prefix` up to the closing bracket
+fn index_after(message: &str, prefix: &str) -> Option<usize> {
+    let start = message.find(prefix)? + prefix.len();
+    let end = start + message[start..].find(']')?;
+    message[start..end].parse().ok()
+}
+
+/// Errors of the client. Failed requests are told apart by where they
+/// failed: `InvalidRequest` for requests the server rejected,
+/// `ServerError` for failures of the server, `Transport` for requests
+/// which never got a complete response and `Decode` for responses which
+/// could not be read. New variants may be added, so matches need a
+/// wildcard arm.
+///
+/// # Example
+/// ```
+/// use std::error::Error;
+/// use kairosdb::Client;
+///
+/// let client = Client::new("localhost", 1);
+/// let err = client.version().unwrap_err();
+/// assert!(err.to_string().starts_with("transport failed: "));
+/// assert!(err.to_string().ends_with(" (GET http://localhost:1/api/v1/version)"));
+/// assert!(err.source().is_some());
+/// ```
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum KairoError {
     Kairo(String),
-    Http(reqwest::Error),
-    Json(serde_json::error::Error),
+    /// The server rejected the request with a 4xx status code. `errors`
+    /// holds the messages of the `{"errors": [...]}` body KairosDB sends
+    /// with failures, or the body itself if it is no such JSON.
+    InvalidRequest {
+        status: StatusCode,
+        errors: Vec<String>,
+        request: Option<Box<RequestContext>>,
+    },
+    /// The server answered with a 5xx or another unexpected status
+    /// code, `errors` as for `InvalidRequest`
+    ServerError {
+        status: StatusCode,
+        errors: Vec<String>,
+        request: Option<Box<RequestContext>>,
+    },
+    /// The server rejected some sets of datapoints of a write
+    PartialWrite(BatchError),
+    /// Connecting to the server, sending the request or receiving the
+    /// response failed
+    Transport {
+        source: Box<dyn Error + Send + Sync>,
+        request: Option<Box<RequestContext>>,
+    },
+    /// A response or a file could not be decoded, or a request could not
+    /// be encoded as JSON
+    Decode(serde_json::error::Error),
+    /// Reading or writing a local file or stream failed
     IO(std::io::Error),
+    /// A query or batch failed the client-side validation
+    Validation(Vec<ValidationError>),
+    /// The request to `endpoint`, e.g. `/api/v1/datapoints`, took longer
+    /// than its timeout or the timeout of the transport. `source` is the
+    /// error of the transport, none for the timeout of `RequestOpts`.
+    Timeout {
+        elapsed: Duration,
+        endpoint: String,
+        source: Option<Box<dyn Error + Send + Sync>>,
+        request: Option<Box<RequestContext>>,
+    },
+    CircuitOpen,
+    Cancelled,
+    DeadlineExceeded,
+    /// A buffered writer is full and its overflow policy is `Error`
+    BufferFull,
+}
+
+impl KairoError {
+    /// Creates the error for a response with an unexpected status code
+    pub(crate) fn from_response(status: StatusCode, body: &str) -> KairoError {
+        #[derive(Deserialize)]
+        struct ErrorBody {
+            errors: Vec<String>,
+        }
+
+        let errors = match serde_json::from_str::<ErrorBody>(body) {
+            Ok(body) => body.errors,
+            Err(_) if body.trim().is_empty() => vec![],
+            Err(_) => {
+                let mut body = body.trim();
+                if body.len() > MAX_ERROR_BODY {
+                    let mut end = MAX_ERROR_BODY;
+                    while !body.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    body = &body[..end];
+                }
+                vec![body.to_string()]
+            }
+        };
+        if status.is_client_error() {
+            KairoError::InvalidRequest { status, errors, request: None }
+        } else {
+            KairoError::ServerError { status, errors, request: None }
+        }
+    }
+
+    /// Creates the error for a response to a datapoint write with an
+    /// unexpected status code
+    pub(crate) fn from_write_response(status: StatusCode, body: &str) -> KairoError {
+        match KairoError::from_response(status, body) {
+            KairoError::InvalidRequest { status, errors, request } => {
+                match BatchError::parse(status, errors) {
+                    Ok(err) => KairoError::PartialWrite(err),
+                    Err(errors) => KairoError::InvalidRequest { status, errors, request },
+                }
+            }
+            err => err,
+        }
+    }
+
+    /// Turns transport errors caused by a timeout into a `Timeout`
+    pub(crate) fn timed_out(self, elapsed: Duration, endpoint: &str) -> KairoError {
+        if !self.is_timeout() {
+            return self;
+        }
+        match self {
+            KairoError::Transport { source, request } => {
+                let endpoint = endpoint.to_string();
+                KairoError::Timeout { elapsed, endpoint, source: Some(source), request }
+            }
+            err => err,
+        }
+    }
+
+    /// Returns the request the error belongs to, if it is known
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// use kairosdb::transport::Method;
+    ///
+    /// let client = Client::new("localhost", 1);
+    /// let err = client.version().unwrap_err();
+    /// let request = err.request().unwrap();
+    /// assert_eq!(request.method, Method::GET);
+    /// assert_eq!(request.url, "http://localhost:1/api/v1/version");
+    /// ```
+    pub fn request(&self) -> Option<&RequestContext> {
+        match *self {
+            KairoError::InvalidRequest { ref request, .. }
+            | KairoError::ServerError { ref request, .. }
+            | KairoError::Transport { ref request, .. }
+            | KairoError::Timeout { ref request, .. } => request.as_deref(),
+            KairoError::PartialWrite(ref err) => err.request.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Returns the status code of a response with an unexpected status
+    pub fn status(&self) -> Option<StatusCode> {
+        match *self {
+            KairoError::InvalidRequest { status, .. } | KairoError::ServerError { status, .. } => {
+                Some(status)
+            }
+            KairoError::PartialWrite(ref err) => Some(err.status),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the request timed out, either on the way to the
+    /// server, in the server or by its deadline
+    pub fn is_timeout(&self) -> bool {
+        match *self {
+            KairoError::DeadlineExceeded | KairoError::Timeout { .. } => true,
+            KairoError::InvalidRequest { status, .. } | KairoError::ServerError { status, .. } => {
+                status == StatusCode::REQUEST_TIMEOUT || status == StatusCode::GATEWAY_TIMEOUT
+            }
+            KairoError::Transport { ref source, .. } => {
+                let mut cause: Option<&(dyn Error + 'static)> = Some(source.as_ref());
+                while let Some(err) = cause {
+                    #[cfg(feature = "reqwest")]
+                    {
+                        if err.downcast_ref::<reqwest::Error>().is_some_and(reqwest::Error::is_timeout) {
+                            return true;
+                        }
+                    }
+                    if err.downcast_ref::<std::io::Error>()
+                        .is_some_and(|err| err.kind() == std::io::ErrorKind::TimedOut) {
+                        return true;
+                    }
+                    cause = err.source();
+                }
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns true if sending the same request again may succeed:
+    /// for transport failures and timeouts, for 5xx status codes other than
+    /// `501 Not Implemented` and `505 HTTP Version Not Supported`, and
+    /// for `408 Request Timeout` and `429 Too Many Requests`. Rejected
+    /// requests, decode errors and the errors of the client itself, e.g.
+    /// an open circuit breaker or an exceeded deadline, are not retryable.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    ///
+    /// let client = Client::new("localhost", 1);
+    /// let err = client.version().unwrap_err();
+    /// assert!(err.is_retryable());
+    /// assert_eq!(err.status(), None);
+    /// ```
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            KairoError::Transport { .. } | KairoError::Timeout { .. } => true,
+            KairoError::ServerError { status, .. } => {
+                status.is_server_error()
+                    && status != StatusCode::NOT_IMPLEMENTED
+                    && status != StatusCode::HTTP_VERSION_NOT_SUPPORTED
+            }
+            KairoError::InvalidRequest { status, .. } => {
+                status == StatusCode::REQUEST_TIMEOUT || status == StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => false,
+        }
+    }
+
+    /// Attaches the request to errors of a request which carry none yet
+    pub(crate) fn with_request(mut self, context: &RequestContext) -> KairoError {
+        match self {
+            KairoError::InvalidRequest { ref mut request, .. }
+            | KairoError::ServerError { ref mut request, .. }
+            | KairoError::Transport { ref mut request, .. }
+            | KairoError::Timeout { ref mut request, .. } if request.is_none() => {
+                *request = Some(Box::new(context.clone()));
+            }
+            KairoError::PartialWrite(ref mut err) if err.request.is_none() => {
+                err.request = Some(Box::new(context.clone()));
+            }
+            _ => (),
+        }
+        self
+    }
+
+    /// Creates a `Transport` error
+    pub(crate) fn transport<E>(err: E) -> KairoError
+        where E: Into<Box<dyn Error + Send + Sync>>
+    {
+        KairoError::Transport { source: err.into(), request: None }
+    }
 }
 
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "server rejected datapoints with {}: {}", self.status, self.errors.join("; "))?;
+        if let Some(ref request) = self.request {
+            write!(f, " ({})", request)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for BatchError {}
+
+impl fmt::Display for KairoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let request = match *self {
+            KairoError::Kairo(ref message) => return write!(f, "{}", message),
+            KairoError::InvalidRequest { status, ref errors, ref request } => {
+                write!(f, "server rejected request with {}", status)?;
+                if !errors.is_empty() {
+                    write!(f, ": {}", errors.join("; "))?;
+                }
+                request
+            }
+            KairoError::ServerError { status, ref errors, ref request } => {
+                write!(f, "server failed with {}", status)?;
+                if !errors.is_empty() {
+                    write!(f, ": {}", errors.join("; "))?;
+                }
+                request
+            }
+            KairoError::PartialWrite(ref err) => return err.fmt(f),
+            KairoError::Transport { ref source, ref request } => {
+                write!(f, "transport failed: {}", source)?;
+                request
+            }
+            KairoError::Decode(ref err) => return write!(f, "JSON error: {}", err),
+            KairoError::IO(ref err) => return write!(f, "I/O error: {}", err),
+            KairoError::Validation(ref errors) => {
+                let errors: Vec<_> = errors.iter().map(ToString::to_string).collect();
+                return write!(f, "validation failed: {}", errors.join("; "));
+            }
+            KairoError::Timeout { elapsed, ref endpoint, ref request, .. } => {
+                write!(f, "request to {} timed out after {:?}", endpoint, elapsed)?;
+                request
+            }
+            KairoError::CircuitOpen => return write!(f, "circuit breaker is open"),
+            KairoError::Cancelled => return write!(f, "request was cancelled"),
+            KairoError::DeadlineExceeded => return write!(f, "deadline exceeded"),
+            KairoError::BufferFull => return write!(f, "buffer is full"),
+        };
+        match *request {
+            Some(ref request) => write!(f, " ({})", request),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Error for KairoError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            KairoError::Transport { ref source, .. }
+            | KairoError::Timeout { source: Some(ref source), .. } => Some(source.as_ref()),
+            KairoError::Decode(ref err) => Some(err),
+            KairoError::IO(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "reqwest")]
 impl From<reqwest::Error> for KairoError {
     fn from(err: reqwest::Error) -> KairoError {
-        KairoError::Http(err)
+        KairoError::transport(err)
     }
 }
 
 impl From<serde_json::error::Error> for KairoError {
     fn from(err: serde_json::error::Error) -> KairoError {
-        KairoError::Json(err)
+        KairoError::Decode(err)
+    }
+}
+
+#[cfg(feature = "csv")]
+impl From<csv::Error> for KairoError {
+    fn from(err: csv::Error) -> KairoError {
+        KairoError::IO(err.into())
+    }
+}
+
+#[cfg(feature = "simd-json")]
+impl From<simd_json::Error> for KairoError {
+    fn from(err: simd_json::Error) -> KairoError {
+        KairoError::Decode(serde::de::Error::custom(err))
+    }
+}
+
+impl From<Vec<ValidationError>> for KairoError {
+    fn from(errors: Vec<ValidationError>) -> KairoError {
+        KairoError::Validation(errors)
     }
 }
 
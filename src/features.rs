@@ -0,0 +1,146 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Aggregators and group-bys a server supports, as returned by
+//! `/api/v1/features`
+
+use crate::query::Query;
+use crate::validation::ValidationError;
+
+/// The features of a server, returned by `Client::features`
+///
+/// # Example
+/// ```
+/// use kairosdb::features::Features;
+/// use kairosdb::query::{Query, TimeUnit};
+/// use kairosdb::validation::ValidationError;
+///
+/// let features: Features = serde_json::from_str(r#"[
+///     {"name":"aggregators","label":"Aggregator","properties":[
+///         {"name":"avg","label":"AVG","description":"Averages the data points","properties":[
+///             {"name":"sampling","label":"Sampling","type":"object","properties":[
+///                 {"name":"value","label":"Value","type":"long","defaultValue":"1"}]}]}]},
+///     {"name":"group_by","label":"Group By","properties":[
+///         {"name":"tag","label":"Tag","properties":[
+///             {"name":"tags","label":"Tags","type":"array","optional":false}]}]}]"#).unwrap();
+/// assert_eq!(features.aggregators()[0].label, "AVG");
+/// assert_eq!(features.aggregators()[0].properties[0].properties[0].default_value, "1");
+///
+/// let query = Query::builder()
+///     .metric(|m| m.name("cpu").avg(1, TimeUnit::MINUTES).max(1, TimeUnit::MINUTES))
+///     .build();
+/// assert_eq!(features.check(&query), Err(vec![
+///     ValidationError::UnsupportedAggregator { metric: 0, aggregator: 1 },
+/// ]));
+/// ```
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct Features {
+    features: Vec<Feature>,
+}
+
+/// A kind of query component, e.g. the aggregators, with all components
+/// of the kind
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Feature {
+    pub name: String,
+    pub label: String,
+    #[serde(rename = "properties", default)]
+    pub components: Vec<Component>,
+}
+
+/// A query component, e.g. the `avg` aggregator
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Component {
+    pub name: String,
+    pub label: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub properties: Vec<Property>,
+}
+
+/// A property of a component, e.g. the sampling of an aggregator.
+/// Properties of type `object` have properties of their own.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Property {
+    pub name: String,
+    pub label: String,
+    /// The type, e.g. `long`, `double`, `enum`, `array` or `object`
+    #[serde(rename = "type", default)]
+    pub kind: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub optional: bool,
+    #[serde(rename = "defaultValue", default)]
+    pub default_value: String,
+    /// The allowed values of an `enum`
+    #[serde(default)]
+    pub options: Vec<String>,
+    #[serde(default)]
+    pub properties: Vec<Property>,
+}
+
+impl Features {
+    /// Returns all features of the server
+    pub fn features(&self) -> &[Feature] {
+        &self.features
+    }
+
+    /// Returns the aggregators of the server
+    pub fn aggregators(&self) -> &[Component] {
+        self.components("aggregators")
+    }
+
+    /// Returns the group-bys of the server
+    pub fn group_bys(&self) -> &[Component] {
+        self.components("group_by")
+    }
+
+    /// Checks that the server supports all aggregators and group-bys of
+    /// the query. All unsupported ones are returned.
+    pub fn check(&self, query: &Query) -> Result<(), Vec<ValidationError>> {
+        let supports = |components: &[Component], name: &str| {
+            components.iter().any(|component| component.name == name)
+        };
+        let mut errors = Vec::new();
+        for (m, metric) in query.metrics().iter().enumerate() {
+            for (a, aggregator) in metric.aggregators().iter().enumerate() {
+                let name = serde_json::to_value(aggregator.name()).unwrap_or_default();
+                if !supports(self.aggregators(), name.as_str().unwrap_or_default()) {
+                    errors.push(ValidationError::UnsupportedAggregator { metric: m, aggregator: a });
+                }
+            }
+            for (g, group_by) in metric.group_by().iter().enumerate() {
+                if !supports(self.group_bys(), group_by.name()) {
+                    errors.push(ValidationError::UnsupportedGroupBy { metric: m, group_by: g });
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn components(&self, name: &str) -> &[Component] {
+        self.features.iter()
+            .find(|feature| feature.name == name)
+            .map_or(&[], |feature| &feature.components)
+    }
+}
@@ -0,0 +1,126 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Filling gaps of result series on a fixed interval grid
+
+use std::collections::BTreeMap;
+
+use crate::datapoints::DataPointValue;
+use crate::error::KairoError;
+use crate::query::RelativeTime;
+use crate::result::{Series, Value};
+
+/// Number of intervals `Series::fill` fills at most
+pub const MAX_FILL_SLOTS: u64 = 1_000_000;
+
+/// How `Series::fill` fills intervals without a numeric value
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FillPolicy {
+    /// Fills with null, like the `gaps` aggregator of KairosDB
+    #[default]
+    None,
+    /// Fills with 0
+    Zero,
+    /// Repeats the previous value, null before the first value
+    Previous,
+    /// Interpolates linearly between the values around the gap, null
+    /// before the first and after the last value
+    Linear,
+}
+
+impl Series {
+    /// Returns the series with one value per `interval` from its first
+    /// to its last numeric value. Each value goes to the interval it
+    /// falls into, the last value of an interval wins. Intervals without
+    /// a numeric value, e.g. the nulls of the `gaps` aggregator, are
+    /// filled according to `policy`. Fails if the series spans more
+    /// than `MAX_FILL_SLOTS` intervals, e.g. for an interval far too
+    /// small for the time range.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::fill::FillPolicy;
+    /// use kairosdb::query::{RelativeTime, TimeUnit};
+    /// use kairosdb::result::{Series, Value};
+    ///
+    /// let series = Series {
+    ///     values: vec![
+    ///         Value { time: 0, value: 1.0.into() },
+    ///         Value { time: 3000, value: 4.0.into() },
+    ///     ],
+    ///     ..Default::default()
+    /// };
+    /// let second = RelativeTime::new(1, TimeUnit::SECONDS);
+    /// let values = |policy| -> Vec<Option<f64>> {
+    ///     series.fill(&second, policy).unwrap().values.iter().map(|v| v.value.as_f64()).collect()
+    /// };
+    /// assert_eq!(values(FillPolicy::None), vec![Some(1.0), None, None, Some(4.0)]);
+    /// assert_eq!(values(FillPolicy::Zero), vec![Some(1.0), Some(0.0), Some(0.0), Some(4.0)]);
+    /// assert_eq!(values(FillPolicy::Previous), vec![Some(1.0), Some(1.0), Some(1.0), Some(4.0)]);
+    /// assert_eq!(values(FillPolicy::Linear), vec![Some(1.0), Some(2.0), Some(3.0), Some(4.0)]);
+    ///
+    /// let millisecond = RelativeTime::new(1, TimeUnit::MILLISECONDS);
+    /// assert!(series.fill(&millisecond, FillPolicy::None).is_ok());
+    /// let mut year = series.clone();
+    /// year.values[1].time = 365 * 24 * 3_600_000;
+    /// assert!(year.fill(&millisecond, FillPolicy::None).is_err());
+    /// ```
+    pub fn fill(&self, interval: &RelativeTime, policy: FillPolicy)
+                -> Result<Series, KairoError> {
+        let width = interval.as_millis().max(1) as u64;
+        let numbers: Vec<(u64, f64)> = self.values.iter()
+            .filter_map(|value| value.value.as_f64().map(|number| (value.time, number)))
+            .collect();
+        let mut filled = Series { tags: self.tags.clone(), group: self.group.clone(), values: vec![] };
+        let start = match numbers.iter().map(|(time, _)| *time).min() {
+            Some(start) => start,
+            None => return Ok(filled),
+        };
+
+        let slots: BTreeMap<u64, f64> = numbers.into_iter()
+            .map(|(time, number)| ((time - start) / width, number))
+            .collect();
+        let last = *slots.keys().next_back().unwrap_or(&0);
+        if last >= MAX_FILL_SLOTS {
+            return Err(KairoError::Kairo(format!(
+                "Filling {} intervals exceeds the limit of {}", last + 1, MAX_FILL_SLOTS)));
+        }
+
+        filled.values.reserve(last as usize + 1);
+        for slot in 0..=last {
+            let number = match slots.get(&slot) {
+                Some(number) => Some(*number),
+                None => match policy {
+                    FillPolicy::None => None,
+                    FillPolicy::Zero => Some(0.0),
+                    FillPolicy::Previous => slots.range(..slot).next_back().map(|(_, n)| *n),
+                    FillPolicy::Linear => {
+                        let before = slots.range(..slot).next_back();
+                        let after = slots.range(slot..).next();
+                        before.zip(after).map(|((&s0, &n0), (&s1, &n1))| {
+                            n0 + (n1 - n0) * (slot - s0) as f64 / (s1 - s0) as f64
+                        })
+                    }
+                },
+            };
+            filled.values.push(Value {
+                time: start + slot * width,
+                value: number.map_or(DataPointValue::Custom(serde_json::Value::Null),
+                                     DataPointValue::Double),
+            });
+        }
+        Ok(filled)
+    }
+}
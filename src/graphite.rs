@@ -0,0 +1,194 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Bridge accepting the Graphite plaintext protocol
+//!
+//! Graphite emitters send lines of `path value timestamp` with the
+//! timestamp in seconds. Templates map the dot separated path segments to
+//! the metric name and tags. In a template each segment is either
+//! `metric` for a part of the metric name, `_` to skip the segment, or
+//! the name of a tag. Path segments beyond the template are appended to
+//! the metric name.
+//!
+//! ```
+//! use kairosdb::graphite::GraphiteBridge;
+//!
+//! let bridge = GraphiteBridge::new()
+//!     .template("servers.", "_.dc.host.metric").unwrap()
+//!     .tag("source", "graphite");
+//! assert!(GraphiteBridge::new().template("servers.", "_.dc.host").is_err());
+//!
+//! let datapoints = bridge.parse_line("servers.eu.web1.cpu.load 0.5 1475513259").unwrap();
+//! let json = serde_json::to_value(&datapoints).unwrap();
+//! assert_eq!(json["name"], "cpu.load");
+//! assert_eq!(json["tags"]["dc"], "eu");
+//! assert_eq!(json["tags"]["host"], "web1");
+//! assert_eq!(json["datapoints"][0][0], 1475513259000i64);
+//! ```
+
+use std::io::{BufRead, BufReader};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+
+use chrono::Utc;
+
+use crate::datapoints::Datapoints;
+use crate::error::KairoError;
+use crate::intern::Interner;
+use crate::writer::BufferedWriter;
+
+type Tag = (Arc<str>, Arc<str>);
+
+/// Number of distinct tag values shared between the lines of a bridge.
+/// Paths of short-lived hosts or containers would otherwise grow the
+/// table forever.
+const MAX_INTERNED_VALUES: usize = 100_000;
+
+/// Maps Graphite plaintext lines to datapoints and forwards them to a
+/// `BufferedWriter`
+#[derive(Debug, Clone)]
+pub struct GraphiteBridge {
+    templates: Vec<(String, Vec<Arc<str>>)>,
+    tags: Vec<Tag>,
+    // tag values taken from paths repeat with almost every line
+    values: Arc<Interner>,
+}
+
+impl Default for GraphiteBridge {
+    fn default() -> GraphiteBridge {
+        GraphiteBridge {
+            templates: Vec::new(),
+            tags: Vec::new(),
+            values: Arc::new(Interner::with_limit(MAX_INTERNED_VALUES)),
+        }
+    }
+}
+
+impl GraphiteBridge {
+    /// Creates a new `GraphiteBridge` using the whole path as metric name
+    pub fn new() -> GraphiteBridge {
+        GraphiteBridge::default()
+    }
+
+    /// Adds a template for paths starting with `prefix`. The first
+    /// template with a matching prefix is used. Fails if the template has
+    /// no `metric` segment, as paths of its length would have no name.
+    pub fn template(mut self, prefix: &str, template: &str)
+                    -> Result<GraphiteBridge, KairoError> {
+        let segments: Vec<Arc<str>> = template.split('.').map(Arc::from).collect();
+        if !segments.iter().any(|segment| &**segment == "metric") {
+            return Err(KairoError::Kairo(
+                format!("Graphite template '{}' has no metric segment", template)));
+        }
+        self.templates.push((prefix.to_string(), segments));
+        Ok(self)
+    }
+
+    /// Adds a tag to all datapoints. KairosDB needs at least one tag per
+    /// datapoint, so paths without tag segments need a static tag.
+    pub fn tag(mut self, name: &str, value: &str) -> GraphiteBridge {
+        self.tags.push((name.into(), value.into()));
+        self
+    }
+
+    /// Parses a single line of `path value timestamp`. A negative
+    /// timestamp stands for the current time.
+    pub fn parse_line(&self, line: &str) -> Result<Datapoints, KairoError> {
+        let invalid = || KairoError::Kairo(format!("Invalid graphite line '{}'", line));
+        let mut fields = line.split_whitespace();
+        let (path, value, timestamp) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(path), Some(value), Some(timestamp)) => (path, value, timestamp),
+            _ => return Err(invalid()),
+        };
+        let value: f64 = value.parse().map_err(|_| invalid())?;
+        let seconds: f64 = timestamp.parse().map_err(|_| invalid())?;
+        let ms = if seconds < 0.0 {
+            Utc::now().timestamp_millis()
+        } else {
+            (seconds * 1000.0) as i64
+        };
+
+        let (metric, tags) = self.map_path(path);
+        let mut datapoints = Datapoints::new(&metric, 0);
+        for (name, value) in self.tags.iter().chain(&tags) {
+            datapoints.add_shared_tag(name, value);
+        }
+        datapoints.add_value(ms, value);
+        Ok(datapoints)
+    }
+
+    /// Reads lines from `reader` and pushes them to `writer` until the end
+    /// of the input. Invalid lines are logged and skipped. Returns the
+    /// number of forwarded datapoints.
+    pub fn forward<R: BufRead>(&self, reader: R, writer: &BufferedWriter)
+                               -> Result<u64, KairoError> {
+        let mut forwarded = 0;
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match self.parse_line(&line) {
+                Ok(datapoints) => {
+                    writer.push(datapoints)?;
+                    forwarded += 1;
+                }
+                Err(err) => warn!("skipping graphite line: {:?}", err),
+            }
+        }
+        Ok(forwarded)
+    }
+
+    /// Accepts Graphite connections on `listener`, e.g. bound to port
+    /// 2003, and forwards their lines to `writer` with a thread per
+    /// connection. Blocks as long as the listener accepts connections.
+    pub fn serve(&self, listener: TcpListener, writer: Arc<BufferedWriter>)
+                 -> Result<(), KairoError> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let bridge = self.clone();
+            let writer = writer.clone();
+            thread::spawn(move || {
+                if let Err(err) = bridge.forward(BufReader::new(stream), &writer) {
+                    warn!("graphite connection failed: {:?}", err);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn map_path(&self, path: &str) -> (String, Vec<Tag>) {
+        let template = self.templates.iter()
+            .find(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .map(|(_, template)| template);
+        let template = match template {
+            Some(template) => template,
+            None => return (path.to_string(), vec![]),
+        };
+
+        let mut metric = Vec::new();
+        let mut tags = Vec::new();
+        for (i, segment) in path.split('.').enumerate() {
+            match template.get(i) {
+                Some(tag) if &**tag == "metric" => metric.push(segment),
+                None => metric.push(segment),
+                Some(tag) if &**tag == "_" => (),
+                Some(tag) => tags.push((tag.clone(), self.values.intern(segment))),
+            }
+        }
+        (metric.join("."), tags)
+    }
+}
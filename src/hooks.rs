@@ -0,0 +1,147 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Callbacks for events of background writers, whose errors never reach
+//! a return value, and for slow queries
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::KairoError;
+
+type ErrorHook = Arc<dyn Fn(&KairoError) + Send + Sync>;
+type RetryHook = Arc<dyn Fn(&KairoError, u32) + Send + Sync>;
+type FlushHook = Arc<dyn Fn(usize, usize) + Send + Sync>;
+type SlowQueryHook = Arc<dyn Fn(&SlowQuery) + Send + Sync>;
+
+/// A query which took longer than the slow query threshold of the
+/// client, see `Client::set_slow_query_threshold`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowQuery {
+    /// Serialized JSON body of the query
+    pub body: String,
+    /// Time until the response was received, or read for queries
+    /// streamed with `Client::query_each`
+    pub duration: Duration,
+    /// Number of datapoints the server read for the query, `None` if the
+    /// query failed or the response has no sample size
+    pub sample_size: Option<i64>,
+}
+
+/// Callbacks set on a client with `Client::set_hooks` and called by the
+/// `BufferedWriter`s using the client, e.g. to count failures in the
+/// telemetry of the application, and by the client for slow queries
+///
+/// # Example
+/// ```
+/// use std::sync::Arc;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use kairosdb::Client;
+/// use kairosdb::hooks::Hooks;
+///
+/// let errors = Arc::new(AtomicUsize::new(0));
+/// let counter = errors.clone();
+/// let mut client = Client::new("localhost", 8080);
+/// client.set_hooks(Hooks::new()
+///     .on_error(move |_err| { counter.fetch_add(1, Ordering::Relaxed); })
+///     .on_retry(|err, attempt| eprintln!("retry {} after {}", attempt, err))
+///     .on_flush(|written, failed| println!("{} written, {} failed", written, failed)));
+/// ```
+#[derive(Clone, Default)]
+pub struct Hooks {
+    error: Option<ErrorHook>,
+    retry: Option<RetryHook>,
+    flush: Option<FlushHook>,
+    slow_query: Option<SlowQueryHook>,
+}
+
+impl Hooks {
+    /// Creates `Hooks` without any callbacks
+    pub fn new() -> Hooks {
+        Hooks::default()
+    }
+
+    /// Calls `callback` for every failed write of a batch, including
+    /// writes which are retried
+    pub fn on_error<F>(mut self, callback: F) -> Hooks
+        where F: Fn(&KairoError) + Send + Sync + 'static
+    {
+        self.error = Some(Arc::new(callback));
+        self
+    }
+
+    /// Calls `callback` before a failed batch is written again, with the
+    /// error of the last write and the number of the retry, starting at 1
+    pub fn on_retry<F>(mut self, callback: F) -> Hooks
+        where F: Fn(&KairoError, u32) + Send + Sync + 'static
+    {
+        self.retry = Some(Arc::new(callback));
+        self
+    }
+
+    /// Calls `callback` after every written batch with the number of
+    /// written datapoints and the number of datapoints which failed
+    pub fn on_flush<F>(mut self, callback: F) -> Hooks
+        where F: Fn(usize, usize) + Send + Sync + 'static
+    {
+        self.flush = Some(Arc::new(callback));
+        self
+    }
+
+    /// Calls `callback` for every query taking longer than the slow query
+    /// threshold of the client
+    pub fn on_slow_query<F>(mut self, callback: F) -> Hooks
+        where F: Fn(&SlowQuery) + Send + Sync + 'static
+    {
+        self.slow_query = Some(Arc::new(callback));
+        self
+    }
+
+    pub(crate) fn error(&self, err: &KairoError) {
+        if let Some(ref hook) = self.error {
+            hook(err);
+        }
+    }
+
+    pub(crate) fn retry(&self, err: &KairoError, attempt: u32) {
+        if let Some(ref hook) = self.retry {
+            hook(err, attempt);
+        }
+    }
+
+    pub(crate) fn flush(&self, written: usize, failed: usize) {
+        if let Some(ref hook) = self.flush {
+            hook(written, failed);
+        }
+    }
+
+    pub(crate) fn slow_query(&self, query: &SlowQuery) {
+        if let Some(ref hook) = self.slow_query {
+            hook(query);
+        }
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_error", &self.error.is_some())
+            .field("on_retry", &self.retry.is_some())
+            .field("on_flush", &self.flush.is_some())
+            .field("on_slow_query", &self.slow_query.is_some())
+            .finish()
+    }
+}
@@ -0,0 +1,406 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Import and export of datapoints in batches
+//!
+//! Newline-delimited JSON holds one datapoint per line with its metric
+//! name, timestamp in milliseconds, value and tags:
+//!
+//! ```text
+//! {"metric":"cpu","timestamp":1475513259000,"value":0.5,"tags":{"host":"a"}}
+//! ```
+//!
+//! CSV files are imported with the `CsvImporter` of the `csv` feature.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Write};
+#[cfg(feature = "csv")]
+use std::io::Read;
+
+#[cfg(feature = "csv")]
+use chrono::DateTime;
+
+use crate::KairosClient;
+use crate::datapoints::{DataPointValue, Datapoints};
+use crate::error::KairoError;
+use crate::query::Query;
+use crate::result::Series;
+
+// A single datapoint of newline-delimited JSON
+#[derive(Serialize, Deserialize, Debug)]
+struct JsonLine {
+    metric: String,
+    timestamp: i64,
+    value: DataPointValue,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    tags: HashMap<String, String>,
+}
+
+/// Reads newline-delimited JSON datapoints line by line from `reader`
+/// and writes them with `client` in batches of `batch_size` lines.
+/// Empty lines are skipped. Returns the number of imported datapoints.
+/// Batches written before an error stay written.
+///
+/// # Example
+/// ```
+/// use kairosdb::KairosClient;
+/// use kairosdb::import::import_json_lines;
+/// use kairosdb::mock::MockClient;
+///
+/// let lines = r#"
+/// {"metric":"cpu","timestamp":1475513259000,"value":0.5,"tags":{"host":"a"}}
+/// {"metric":"cpu","timestamp":1475513260000,"value":0.7,"tags":{"host":"a"}}
+/// "#;
+///
+/// let client = MockClient::new();
+/// assert_eq!(import_json_lines(&client, lines.as_bytes(), 1000).unwrap(), 2);
+/// assert_eq!(client.list_metrics().unwrap(), vec!["cpu"]);
+/// ```
+pub fn import_json_lines<R: BufRead>(client: &dyn KairosClient, reader: R, batch_size: usize)
+                                     -> Result<u64, KairoError> {
+    let mut batch = Batch::default();
+    let mut imported = 0;
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let point: JsonLine = serde_json::from_str(&line).map_err(|err| {
+            KairoError::Kairo(format!("line {}: {}", number + 1, err))
+        })?;
+
+        batch.add(&point.metric, point.tags.into_iter().collect(), point.timestamp, point.value);
+        imported += 1;
+        if batch.rows >= batch_size.max(1) {
+            client.add_batch(&batch.take())?;
+        }
+    }
+    if batch.rows > 0 {
+        client.add_batch(&batch.take())?;
+    }
+    Ok(imported)
+}
+
+/// Runs `query` and writes every returned datapoint as a line of JSON to
+/// `writer`. The series are written while the response is read, so only
+/// one of them is held in memory with a `Client`. Each line gets the tags
+/// having exactly one value in its series, so series mixing several hosts
+/// lose their host tag. Returns the number of exported datapoints. Lines
+/// written before an error stay written.
+///
+/// # Example
+/// ```
+/// use kairosdb::KairosClient;
+/// use kairosdb::datapoints::Datapoints;
+/// use kairosdb::import::export_json_lines;
+/// use kairosdb::mock::MockClient;
+/// use kairosdb::query::{Metric, Query, Time};
+/// use kairosdb::tags;
+///
+/// let client = MockClient::new();
+/// client.add(&Datapoints::builder("cpu").tag("host", "a").point(1000, 0.5).build()).unwrap();
+///
+/// let mut query = Query::new(Time::Nanoseconds(0), Time::Nanoseconds(2000));
+/// query.add(Metric::new("cpu", tags!{"host" => ["a"]}, vec![]));
+///
+/// let mut dump = Vec::new();
+/// assert_eq!(export_json_lines(&client, &query, &mut dump).unwrap(), 1);
+/// assert_eq!(String::from_utf8(dump).unwrap(),
+///            "{\"metric\":\"cpu\",\"timestamp\":1000,\"value\":0.5,\"tags\":{\"host\":\"a\"}}\n");
+/// ```
+pub fn export_json_lines<W: Write>(client: &dyn KairosClient, query: &Query, mut writer: W)
+                                   -> Result<u64, KairoError> {
+    let mut exported = 0;
+    let mut failed = None;
+    client.query_each(query, &mut |metric, series| {
+        if failed.is_some() {
+            return;
+        }
+        match write_series(&mut writer, metric, &series) {
+            Ok(count) => exported += count,
+            Err(err) => failed = Some(err),
+        }
+    })?;
+    if let Some(err) = failed {
+        return Err(err);
+    }
+    writer.flush()?;
+    Ok(exported)
+}
+
+// Writes the datapoints of a series as JSON lines and returns their number
+fn write_series<W: Write>(writer: &mut W, metric: String, series: &Series)
+                          -> Result<u64, KairoError> {
+    let tags: HashMap<String, String> = series.tags.iter()
+        .filter(|(_, values)| values.len() == 1)
+        .map(|(name, values)| (name.clone(), values[0].clone()))
+        .collect();
+    let mut line = JsonLine { metric, timestamp: 0, value: DataPointValue::Long(0), tags };
+    for value in &series.values {
+        line.timestamp = value.time as i64;
+        line.value = value.value.clone();
+        serde_json::to_writer(&mut *writer, &line)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(series.values.len() as u64)
+}
+
+/// Column of a CSV file, selected by its header name or its index
+#[cfg(feature = "csv")]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Column {
+    Name(String),
+    Index(usize),
+}
+
+#[cfg(feature = "csv")]
+impl From<&str> for Column {
+    fn from(name: &str) -> Column {
+        Column::Name(name.to_string())
+    }
+}
+
+#[cfg(feature = "csv")]
+impl From<usize> for Column {
+    fn from(index: usize) -> Column {
+        Column::Index(index)
+    }
+}
+
+/// Imports CSV rows as datapoints. Every row holds one datapoint with
+/// its timestamp, value and optionally the metric name and tags.
+/// Timestamps are milliseconds since the epoch or RFC 3339 dates, values
+/// are parsed as long or double. Rows are written in batches of
+/// `batch_size` rows.
+///
+/// # Example
+/// ```
+/// use kairosdb::KairosClient;
+/// use kairosdb::import::CsvImporter;
+/// use kairosdb::mock::MockClient;
+///
+/// let csv = "\
+/// time,host,load
+/// 1475513259000,a,0.5
+/// 2016-10-03T16:47:40Z,b,1
+/// ";
+///
+/// let client = MockClient::new();
+/// let importer = CsvImporter::new("load")
+///     .time_column("time")
+///     .value_column("load")
+///     .tag_column("host", "host");
+/// assert_eq!(importer.import(&client, csv.as_bytes()).unwrap(), 2);
+/// assert_eq!(client.tagvalues().unwrap(), vec!["a", "b"]);
+/// ```
+#[cfg(feature = "csv")]
+#[derive(Debug, Clone)]
+pub struct CsvImporter {
+    metric: String,
+    metric_column: Option<Column>,
+    time_column: Column,
+    value_column: Column,
+    tag_columns: Vec<(String, Column)>,
+    delimiter: u8,
+    has_headers: bool,
+    batch_size: usize,
+}
+
+#[cfg(feature = "csv")]
+// Column indexes resolved against the header of a file
+struct Columns {
+    metric: Option<usize>,
+    time: usize,
+    value: usize,
+    tags: Vec<(String, usize)>,
+}
+
+#[cfg(feature = "csv")]
+impl CsvImporter {
+    /// Creates a new `CsvImporter` writing to the metric `metric`, reading
+    /// timestamps from the first and values from the second column of
+    /// comma separated files with a header row
+    pub fn new(metric: &str) -> CsvImporter {
+        CsvImporter {
+            metric: metric.to_string(),
+            metric_column: None,
+            time_column: Column::Index(0),
+            value_column: Column::Index(1),
+            tag_columns: vec![],
+            delimiter: b',',
+            has_headers: true,
+            batch_size: 1000,
+        }
+    }
+
+    /// Reads the metric name of each row from `column`
+    pub fn metric_column<C: Into<Column>>(mut self, column: C) -> CsvImporter {
+        self.metric_column = Some(column.into());
+        self
+    }
+
+    /// Reads the timestamps from `column`
+    pub fn time_column<C: Into<Column>>(mut self, column: C) -> CsvImporter {
+        self.time_column = column.into();
+        self
+    }
+
+    /// Reads the values from `column`
+    pub fn value_column<C: Into<Column>>(mut self, column: C) -> CsvImporter {
+        self.value_column = column.into();
+        self
+    }
+
+    /// Reads the values of the tag `name` from `column`
+    pub fn tag_column<C: Into<Column>>(mut self, name: &str, column: C) -> CsvImporter {
+        self.tag_columns.push((name.to_string(), column.into()));
+        self
+    }
+
+    /// Sets the field delimiter, a comma by default
+    pub fn delimiter(mut self, delimiter: u8) -> CsvImporter {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Sets whether the first row is a header, true by default. Columns
+    /// can only be selected by name in files with a header.
+    pub fn has_headers(mut self, has_headers: bool) -> CsvImporter {
+        self.has_headers = has_headers;
+        self
+    }
+
+    /// Sets the number of rows written in a single request
+    pub fn batch_size(mut self, batch_size: usize) -> CsvImporter {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Reads all rows of `reader` and writes them with `client`. Returns
+    /// the number of imported datapoints. Batches written before an
+    /// error stay written.
+    pub fn import<R: Read>(&self, client: &dyn KairosClient, reader: R)
+                           -> Result<u64, KairoError> {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(self.delimiter)
+            .has_headers(self.has_headers)
+            .from_reader(reader);
+        let headers = if self.has_headers { Some(reader.headers()?.clone()) } else { None };
+        let columns = self.resolve(headers.as_ref())?;
+
+        let mut batch = Batch::default();
+        let mut imported = 0;
+        for record in reader.records() {
+            let record = record?;
+            let line = record.position().map_or(0, |position| position.line());
+            let field = |index: usize| {
+                record.get(index).ok_or_else(|| {
+                    KairoError::Kairo(format!("line {}: missing column {}", line, index))
+                })
+            };
+
+            let metric = match columns.metric {
+                Some(index) => field(index)?,
+                None => &self.metric,
+            };
+            let time = parse_time(field(columns.time)?)
+                .map_err(|msg| KairoError::Kairo(format!("line {}: {}", line, msg)))?;
+            let value = parse_value(field(columns.value)?)
+                .map_err(|msg| KairoError::Kairo(format!("line {}: {}", line, msg)))?;
+            let mut tags = Vec::with_capacity(columns.tags.len());
+            for (name, index) in &columns.tags {
+                tags.push((name.clone(), field(*index)?.to_string()));
+            }
+
+            batch.add(metric, tags, time, value);
+            imported += 1;
+            if batch.rows >= self.batch_size {
+                client.add_batch(&batch.take())?;
+            }
+        }
+        if batch.rows > 0 {
+            client.add_batch(&batch.take())?;
+        }
+        Ok(imported)
+    }
+
+    fn resolve(&self, headers: Option<&csv::StringRecord>) -> Result<Columns, KairoError> {
+        let index = |column: &Column| match *column {
+            Column::Index(index) => Ok(index),
+            Column::Name(ref name) => headers
+                .and_then(|headers| headers.iter().position(|header| header == name))
+                .ok_or_else(|| KairoError::Kairo(format!("Unknown column {}", name))),
+        };
+
+        let mut tags = Vec::with_capacity(self.tag_columns.len());
+        for (name, column) in &self.tag_columns {
+            tags.push((name.clone(), index(column)?));
+        }
+        Ok(Columns {
+            metric: self.metric_column.as_ref().map(index).transpose()?,
+            time: index(&self.time_column)?,
+            value: index(&self.value_column)?,
+            tags,
+        })
+    }
+}
+
+// Datapoints of a batch grouped by metric name and tags
+#[derive(Default)]
+struct Batch {
+    sets: BTreeMap<(String, Vec<(String, String)>), Datapoints>,
+    rows: usize,
+}
+
+impl Batch {
+    fn add(&mut self, metric: &str, mut tags: Vec<(String, String)>, time: i64,
+           value: DataPointValue) {
+        tags.sort();
+        let datapoints = self.sets.entry((metric.to_string(), tags)).or_insert_with_key(|key| {
+            let mut datapoints = Datapoints::new(&key.0, 0);
+            datapoints.add_tags(key.1.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+            datapoints
+        });
+        datapoints.add_value(time, value);
+        self.rows += 1;
+    }
+
+    fn take(&mut self) -> Vec<Datapoints> {
+        self.rows = 0;
+        std::mem::take(&mut self.sets).into_values().collect()
+    }
+}
+
+#[cfg(feature = "csv")]
+fn parse_time(field: &str) -> Result<i64, String> {
+    let field = field.trim();
+    if let Ok(ms) = field.parse() {
+        return Ok(ms);
+    }
+    DateTime::parse_from_rfc3339(field)
+        .map(|datetime| datetime.timestamp_millis())
+        .map_err(|_| format!("invalid timestamp '{}'", field))
+}
+
+#[cfg(feature = "csv")]
+fn parse_value(field: &str) -> Result<DataPointValue, String> {
+    let field = field.trim();
+    if let Ok(value) = field.parse::<i64>() {
+        return Ok(value.into());
+    }
+    field.parse::<f64>()
+        .map(DataPointValue::from)
+        .map_err(|_| format!("invalid value '{}'", field))
+}
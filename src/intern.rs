@@ -0,0 +1,102 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Interning of tag names and values
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+/// Table of shared strings. Writers repeating the same tags for every
+/// set of datapoints intern them once and add them with
+/// `Datapoints::add_shared_tag`, so each tag is allocated only once.
+///
+/// # Example
+/// ```
+/// use std::sync::Arc;
+/// use kairosdb::intern::Interner;
+///
+/// let interner = Interner::new();
+/// let first = interner.intern("host");
+/// let second = interner.intern("host");
+/// assert!(Arc::ptr_eq(&first, &second));
+/// ```
+#[derive(Debug, Default)]
+pub struct Interner {
+    strings: Mutex<HashSet<Arc<str>>>,
+    limit: Option<usize>,
+}
+
+impl Interner {
+    /// Creates a new empty `Interner`
+    pub fn new() -> Interner {
+        Interner::default()
+    }
+
+    /// Creates a new empty `Interner` holding at most `limit` strings,
+    /// e.g. for values taken from untrusted input. A full table drops
+    /// the strings which are not used anymore, or all of them if that
+    /// frees less than half of it. Dropped strings stay valid where they
+    /// are used, they are just not shared with new ones.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::intern::Interner;
+    ///
+    /// let interner = Interner::with_limit(2);
+    /// let host = interner.intern("a");
+    /// interner.intern("b");
+    /// interner.intern("c");
+    /// assert_eq!(interner.len(), 2);
+    /// assert_eq!(&*host, "a");
+    /// ```
+    pub fn with_limit(limit: usize) -> Interner {
+        Interner { strings: Mutex::default(), limit: Some(limit.max(1)) }
+    }
+
+    /// Returns the shared copy of `string`, adding it to the table if
+    /// it is not known yet
+    pub fn intern(&self, string: &str) -> Arc<str> {
+        let mut strings = self.strings.lock().unwrap();
+        if let Some(interned) = strings.get(string) {
+            return interned.clone();
+        }
+        if let Some(limit) = self.limit {
+            if strings.len() >= limit {
+                strings.retain(|string| Arc::strong_count(string) > 1);
+                if strings.len() > limit / 2 {
+                    strings.clear();
+                }
+            }
+        }
+        let interned: Arc<str> = Arc::from(string);
+        strings.insert(interned.clone());
+        interned
+    }
+
+    /// Returns the number of interned strings
+    pub fn len(&self) -> usize {
+        self.strings.lock().unwrap().len()
+    }
+
+    /// Returns true if no strings are interned
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes all strings which are not used outside the table anymore
+    pub fn shrink(&self) {
+        self.strings.lock().unwrap().retain(|string| Arc::strong_count(string) > 1);
+    }
+}
@@ -34,7 +34,8 @@
 //! ```
 //! # use kairosdb::Client;
 //! use kairosdb::datapoints::Datapoints;
-//! # let client = Client::new("localhost", 8080);
+//! # let server = kairosdb::testing::StubServer::start();
+//! # let client = Client::new("localhost", server.port());
 //!
 //! let mut datapoints = Datapoints::new("myMetric", 0);
 //! datapoints.add_ms(1000, 11.0);
@@ -51,7 +52,8 @@
 //!
 //! ```
 //! # use kairosdb::Client;
-//! # let client = Client::new("localhost", 8080);
+//! # let server = kairosdb::testing::StubServer::start();
+//! # let client = Client::new("localhost", server.port());
 //! use std::collections::HashMap;
 //! use kairosdb::query::{Query, Time, Metric, Tags};
 //! # use kairosdb::datapoints::Datapoints;
@@ -73,11 +75,12 @@
 //! let result = client.query(&query).unwrap();
 //!
 //! assert!(result.contains_key("myMetric"));
-//! assert_eq!(result["myMetric"].len(), 2);
-//! assert_eq!(result["myMetric"][0].time, 1000);
-//! assert_eq!(result["myMetric"][0].value, 11.0);
-//! assert_eq!(result["myMetric"][1].time, 2000);
-//! assert_eq!(result["myMetric"][1].value, 12.0);
+//! let values = &result["myMetric"][0].values;
+//! assert_eq!(values.len(), 2);
+//! assert_eq!(values[0].time, 1000);
+//! assert_eq!(values[0].value, 11.0);
+//! assert_eq!(values[1].time, 2000);
+//! assert_eq!(values[1].value, 12.0);
 //! ```
 //!
 //! Optionally you can specify aggregators. Aggregators perform an operation on data
@@ -90,7 +93,8 @@
 //!
 //! ```
 //! # use kairosdb::Client;
-//! # let client = Client::new("localhost", 8080);
+//! # let server = kairosdb::testing::StubServer::start();
+//! # let client = Client::new("localhost", server.port());
 //! use kairosdb::query::*;
 //! use kairosdb::datapoints::Datapoints;
 //! # let result = client.delete_metric(&"myMetric");
@@ -115,24 +119,26 @@
 //!
 //! let result = client.query(&query).unwrap();
 //! assert!(result.contains_key("myMetric"));
-//! assert_eq!(result["myMetric"].len(), 5);
-//! assert_eq!(result["myMetric"][0].time, 0);
-//! assert_eq!(result["myMetric"][0].value, 0.5);
-//! # assert_eq!(result["myMetric"][1].time, 1000);
-//! # assert_eq!(result["myMetric"][1].value, 2.5);
-//! # assert_eq!(result["myMetric"][2].time, 2000);
-//! # assert_eq!(result["myMetric"][2].value, 4.5);
-//! # assert_eq!(result["myMetric"][3].time, 3000);
-//! # assert_eq!(result["myMetric"][3].value, 6.5);
-//! # assert_eq!(result["myMetric"][4].time, 4000);
-//! # assert_eq!(result["myMetric"][4].value, 8.5);
+//! let values = &result["myMetric"][0].values;
+//! assert_eq!(values.len(), 5);
+//! assert_eq!(values[0].time, 0);
+//! assert_eq!(values[0].value, 0.5);
+//! # assert_eq!(values[1].time, 1000);
+//! # assert_eq!(values[1].value, 2.5);
+//! # assert_eq!(values[2].time, 2000);
+//! # assert_eq!(values[2].value, 4.5);
+//! # assert_eq!(values[3].time, 3000);
+//! # assert_eq!(values[3].value, 6.5);
+//! # assert_eq!(values[4].time, 4000);
+//! # assert_eq!(values[4].value, 8.5);
 //! ```
 //!
 //! Deleting data is like querying data.
 //!
 //! ```
 //! # use kairosdb::Client;
-//! # let client = Client::new("localhost", 8080);
+//! # let server = kairosdb::testing::StubServer::start();
+//! # let client = Client::new("localhost", server.port());
 //! use kairosdb::query::{Query, Time, Metric, Tags};
 //!
 //! let mut query = Query::new(
@@ -153,7 +159,8 @@
 //!
 //! ```
 //! # use kairosdb::Client;
-//! # let client = Client::new("localhost", 8080);
+//! # let server = kairosdb::testing::StubServer::start();
+//! # let client = Client::new("localhost", server.port());
 //! # use kairosdb::datapoints::Datapoints;
 //! # let mut datapoints = Datapoints::new("myMetric", 0);
 //! # datapoints.add_ms(1000, 11.0);
@@ -172,9 +179,11 @@
 //!
 //! ```
 //! # use kairosdb::Client;
-//! # let client = Client::new("localhost", 8080);
+//! # let server = kairosdb::testing::StubServer::start();
+//! # let client = Client::new("localhost", server.port());
 //! # use kairosdb::datapoints::Datapoints;
 //! # let mut datapoints = Datapoints::new("myMetric", 0);
+//! # datapoints.add_ms(1000, 11.0);
 //! # datapoints.add_tag("test", "first");
 //! # let result = client.add(&datapoints);
 //! # assert!(result.is_ok());
@@ -188,7 +197,8 @@
 //!
 //! ```
 //! # use kairosdb::Client;
-//! # let client = Client::new("localhost", 8080);
+//! # let server = kairosdb::testing::StubServer::start();
+//! # let client = Client::new("localhost", server.port());
 //!
 //! let result = client.delete_metric(&"myMetric");
 //! assert!(result.is_ok());
@@ -200,7 +210,8 @@
 //!
 //! ```
 //! # use kairosdb::Client;
-//! # let client = Client::new("localhost", 8080);
+//! # let server = kairosdb::testing::StubServer::start();
+//! # let client = Client::new("localhost", server.port());
 //! let response = client.health();
 //! let result = response.unwrap();
 //! assert_eq!(result[0], "JVM-Thread-Deadlock: OK");
@@ -208,11 +219,34 @@
 //! ```
 //!
 //! Get the version of the KairosDB Server
-/// ```
-/// # use kairosdb::Client;
-/// let client = Client::new("localhost", 8080);
-/// assert!(client.version().unwrap().starts_with("KairosDB"));
-/// ```
+//! ```
+//! # use kairosdb::Client;
+//! let server = kairosdb::testing::StubServer::start();
+//! let client = Client::new("localhost", server.port());
+//! assert!(client.version().unwrap().starts_with("KairosDB"));
+//! ```
+//!
+//! ## Testing
+//!
+//! Code using the client can be tested without a running KairosDB server.
+//! The `mock::MockClient` implements the `KairosClient` trait in memory and
+//! the `testing::StubServer` answers the REST API on a local port, so the
+//! real `Client` including its transport can be used in tests.
+//!
+//! ## Tracing
+//!
+//! With the `tracing` feature enabled every HTTP request is wrapped in a
+//! `kairosdb.request` span carrying the method, endpoint, number of metrics,
+//! payload size, status and latency. Without the feature a summary of each
+//! request is written to the `log` crate.
+//!
+//! ## Faster result parsing
+//!
+//! With the `simd-json` feature query responses are parsed with the SIMD
+//! accelerated `simd-json` crate instead of `serde_json`, which is much
+//! faster for results of many megabytes. The client parses the response
+//! bodies in place. Responses parsed while reading them, see
+//! `Client::query_each`, still use `serde_json`.
 
 extern crate serde;
 extern crate serde_json;
@@ -221,33 +255,137 @@ extern crate serde_derive;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+#[cfg(feature = "reqwest")]
 extern crate reqwest;
+#[cfg(feature = "ureq")]
+extern crate ureq;
+#[cfg(feature = "csv")]
+extern crate csv;
+#[cfg(feature = "simd-json")]
+extern crate simd_json;
+#[cfg(feature = "arrow")]
+extern crate arrow_array;
+#[cfg(feature = "arrow")]
+extern crate arrow_schema;
+#[cfg(feature = "metrics")]
+extern crate metrics;
+#[cfg(feature = "derive")]
+extern crate kairosdb_derive;
+extern crate http;
 extern crate chrono;
 
 pub mod datapoints;
 pub mod query;
 pub mod result;
+pub mod fill;
+pub mod features;
+pub mod rolling;
+pub mod rollup;
+pub mod serverstats;
+pub mod ratelimit;
+pub mod circuitbreaker;
+pub mod concurrency;
+pub mod stats;
+pub mod options;
+pub mod transport;
+pub mod mock;
+pub mod testing;
+pub mod replay;
+pub mod validation;
+pub mod writer;
+pub mod spool;
+pub mod import;
+pub mod intern;
+pub mod telnet;
+pub mod transfer;
+pub mod bench;
+pub mod graphite;
+pub mod hooks;
+pub mod version;
+#[cfg(feature = "statsd")]
+pub mod statsd;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "arrow")]
+pub mod arrow;
+#[cfg(feature = "metrics")]
+pub mod recorder;
 mod error;
 mod helper;
+mod request;
 use std::io::Read;
-
-use reqwest::StatusCode;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 use datapoints::Datapoints;
-use query::Query;
-use result::{QueryResult, ResultMap};
-use error::KairoError;
+use chrono::Utc;
+
+use query::{Aggregator, AggregatorType, Metric, Query, RelativeTime, Tags, Time, TimeUnit};
+use result::{ResultMap, Series, TagsMap, TagsResult, UtcResultMap};
+use features::Features;
+use ratelimit::RateLimiter;
+use rollup::RollupTask;
+use serverstats::ServerStats;
+use circuitbreaker::CircuitBreaker;
+use concurrency::{ConcurrencyLimit, Permit};
 use helper::parse_metricnames_result;
+use request::{AbortableReader, BodyBuffer, CountingReader, JsonArrayReader, Request, Response};
+use transport::{HttpRequest, HttpResponse, Method, StatusCode, Transport};
+use options::{MetricFilter, MetricPages, RequestOpts};
+use stats::{ClientStats, Stats};
+use hooks::{Hooks, SlowQuery};
+use version::ServerVersion;
+
+pub use error::{BatchError, KairoError, Rejection, RequestContext};
+#[cfg(feature = "derive")]
+pub use kairosdb_derive::KairosMetrics;
+use error::MAX_ERROR_BODY;
+
+/// Timeout of `Client::ping`
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Version {
     version: String,
 }
 
+#[derive(Deserialize, Debug)]
+struct CreatedRollup {
+    id: String,
+}
+
+// A request as seen by the middleware of the client
+struct Call {
+    method: Method,
+    endpoint: String,
+    metrics: usize,
+    datapoints: usize,
+}
+
+// Status and sizes of a finished request, without a status if no
+// response was received
+struct Exchange {
+    status: Option<StatusCode>,
+    payload_size: usize,
+    response_size: usize,
+}
+
 /// The core of the kairosdb client, owns a HTTP connection.
 #[derive(Debug)]
 pub struct Client {
-    base_url: String
+    base_url: String,
+    transport: Arc<dyn Transport>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    circuit_breaker: Option<CircuitBreaker>,
+    concurrency_limit: Option<ConcurrencyLimit>,
+    sort_datapoints: bool,
+    validate: bool,
+    // buffer the write bodies are serialized into
+    write_buffer: BodyBuffer,
+    stats: Stats,
+    hooks: Hooks,
+    slow_query_threshold: Option<Duration>,
 }
 
 impl Client {
@@ -258,57 +396,371 @@ impl Client {
     /// use kairosdb::Client;
     /// let client = Client::new("localhost", 8080);
     /// ```
+    #[cfg(feature = "reqwest")]
     pub fn new(host: &str, port: u32) -> Client {
+        Client::with_transport(host, port, transport::ReqwestTransport::new())
+    }
+
+    /// Constructs a new KairosDB Client
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// let client = Client::new("localhost", 8080);
+    /// ```
+    #[cfg(all(feature = "ureq", not(feature = "reqwest")))]
+    pub fn new(host: &str, port: u32) -> Client {
+        Client::with_transport(host, port, transport::UreqTransport::new())
+    }
+
+    /// Constructs a new KairosDB Client sending its requests through
+    /// the given transport
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// use kairosdb::transport::ReqwestTransport;
+    ///
+    /// let client = Client::with_transport("localhost", 8080, ReqwestTransport::new());
+    /// ```
+    pub fn with_transport<T: Transport + 'static>(host: &str, port: u32, transport: T) -> Client {
         info!("create new client host: {} port: {}", host, port);
         Client {
-            base_url: format!("http://{}:{}", host, port)
+            base_url: format!("http://{}:{}", host, port),
+            transport: Arc::new(transport),
+            rate_limiter: None,
+            circuit_breaker: None,
+            concurrency_limit: None,
+            sort_datapoints: false,
+            validate: false,
+            write_buffer: BodyBuffer::default(),
+            stats: Stats::default(),
+            hooks: Hooks::default(),
+            slow_query_threshold: None,
         }
     }
 
+    /// Limits the requests and datapoints per second sent by this client.
+    /// The limit applies to every request including batched writes.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// use kairosdb::ratelimit::RateLimiter;
+    ///
+    /// let mut client = Client::new("localhost", 8080);
+    /// client.set_rate_limit(RateLimiter::new(0, 50_000));
+    /// ```
+    pub fn set_rate_limit(&mut self, limiter: RateLimiter) {
+        self.rate_limiter = Some(Arc::new(limiter));
+    }
+
+    /// Protects the client with a circuit breaker. While the circuit is
+    /// open all requests fail immediately with `KairoError::CircuitOpen`.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use kairosdb::Client;
+    /// use kairosdb::circuitbreaker::CircuitBreaker;
+    ///
+    /// let mut client = Client::new("localhost", 8080);
+    /// client.set_circuit_breaker(CircuitBreaker::new(3, Duration::from_secs(10)));
+    /// ```
+    pub fn set_circuit_breaker(&mut self, breaker: CircuitBreaker) {
+        self.circuit_breaker = Some(breaker);
+    }
+
+    /// Limits the number of requests in flight when the client is shared
+    /// between threads. Further requests block until one finished.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    ///
+    /// let mut client = Client::new("localhost", 8080);
+    /// client.set_concurrency_limit(8);
+    /// ```
+    pub fn set_concurrency_limit(&mut self, max: usize) {
+        self.concurrency_limit = Some(ConcurrencyLimit::new(max));
+    }
+
+    /// Sorts the datapoints of each set by timestamp before writing.
+    /// Out-of-order writes make the Cassandra backend of KairosDB re-read
+    /// rows, sorting on the client is much cheaper.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    ///
+    /// let mut client = Client::new("localhost", 8080);
+    /// client.set_sort_datapoints(true);
+    /// ```
+    pub fn set_sort_datapoints(&mut self, sort: bool) {
+        self.sort_datapoints = sort;
+    }
+
+    /// Checks batches with `validation::validate_batch` and queries with
+    /// `Query::validate` before sending them, so requests the server
+    /// would reject fail with `KairoError::Validation` instead. Streamed
+    /// writes are not checked.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::{Client, KairoError};
+    /// use kairosdb::datapoints::Datapoints;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let mut client = Client::new("localhost", server.port());
+    /// client.set_validation(true);
+    /// let datapoints = Datapoints::builder("cpu load").point(1000, 0.5).build();
+    /// match client.add(&datapoints) {
+    ///     Err(KairoError::Validation(errors)) => assert_eq!(errors.len(), 2),
+    ///     other => panic!("expected validation errors, got {:?}", other),
+    /// }
+    /// assert!(server.requests().is_empty());
+    /// ```
+    pub fn set_validation(&mut self, validate: bool) {
+        self.validate = validate;
+    }
+
+    /// Sets the callbacks the `BufferedWriter`s using this client call
+    /// for failed writes, retries and flushes, see `hooks::Hooks`
+    pub fn set_hooks(&mut self, hooks: Hooks) {
+        self.hooks = hooks;
+    }
+
+    /// Logs queries taking longer than `threshold` as warnings with their
+    /// body, duration and sample size and passes them to the
+    /// `on_slow_query` hook, e.g. to find the dashboards straining the
+    /// cluster. Failed queries are checked as well, `None` turns the
+    /// check off.
+    ///
+    /// # Example
+    /// ```
+    /// use std::sync::{Arc, Mutex};
+    /// use std::time::Duration;
+    /// use kairosdb::Client;
+    /// use kairosdb::hooks::Hooks;
+    /// use kairosdb::query::{Metric, Query, Tags, Time};
+    ///
+    /// let slow = Arc::new(Mutex::new(Vec::new()));
+    /// let queries = slow.clone();
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let mut client = Client::new("localhost", server.port());
+    /// # let mut datapoints = kairosdb::datapoints::Datapoints::new("myMetric", 0);
+    /// # datapoints.add_ms(500, 1.0);
+    /// # client.add(&datapoints).unwrap();
+    /// client.set_slow_query_threshold(Some(Duration::from_secs(0)));
+    /// client.set_hooks(Hooks::new().on_slow_query(move |query| {
+    ///     queries.lock().unwrap().push(query.clone());
+    /// }));
+    ///
+    /// let mut query = Query::new(Time::Nanoseconds(0), Time::Nanoseconds(1000));
+    /// query.add(Metric::new("myMetric", Tags::new(), vec![]));
+    /// client.query(&query).unwrap();
+    /// let slow = slow.lock().unwrap();
+    /// assert_eq!(slow[0].body, serde_json::to_string(&query).unwrap());
+    /// assert_eq!(slow[0].sample_size, Some(1));
+    /// ```
+    pub fn set_slow_query_threshold(&mut self, threshold: Option<Duration>) {
+        self.slow_query_threshold = threshold;
+    }
+
+    /// Returns a snapshot of the request statistics collected by this
+    /// client: request and error counts, transferred bytes and latencies.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// let _ = client.version();
+    /// let stats = client.stats();
+    /// assert_eq!(stats.requests, 1);
+    /// ```
+    pub fn stats(&self) -> ClientStats {
+        self.stats.snapshot()
+    }
+
     /// Returns the version string of the KairosDB Server
     ///
     /// # Example
     /// ```
     /// use kairosdb::Client;
-    /// let client = Client::new("localhost", 8080);
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
     /// assert!(client.version().unwrap().starts_with("KairosDB"));
     /// ```
     pub fn version(&self) -> Result<String, KairoError> {
-        let mut response = reqwest::get(&format!("{}/api/v1/version", self.base_url))?;
-        let mut body = String::new();
-        response.read_to_string(&mut body)?;
-        let version: Version = serde_json::from_str(&body)?;
+        let response = self.send(Request::get("/api/v1/version"))?;
+        if response.status != StatusCode::OK {
+            return Err(response.error());
+        }
+        let version: Version = serde_json::from_str(&response.body)?;
 
         info!("get server version {:?}", version.version);
         Ok(version.version)
     }
 
+    /// Returns the version of the KairosDB Server as comparable numbers
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// use kairosdb::version::ServerVersion;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// # server.respond(kairosdb::transport::Method::GET, "/api/v1/version", 200,
+    /// #     r#"{"version":"KairosDB 1.2.2-1.20181109185326"}"#);
+    /// if client.server_version().unwrap() >= ServerVersion::new(1, 2, 0) {
+    ///     // use the aggregators of KairosDB 1.2
+    /// }
+    /// ```
+    pub fn server_version(&self) -> Result<ServerVersion, KairoError> {
+        self.version()?.parse()
+    }
+
     /// Returns the health status of the KairosDB Server
     ///
     /// # Example
     /// ```
     /// use kairosdb::Client;
-    /// let client = Client::new("localhost", 8080);
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
     /// let response = client.health();
     /// ```
     pub fn health(&self) -> Result<Vec<String>, KairoError> {
-        let mut response = reqwest::get(&format!("{}/api/v1/health/status", self.base_url))?;
-        match response.status() {
+        let response = self.send(Request::get("/api/v1/health/status"))?;
+        match response.status {
             StatusCode::OK => {
-                let mut body = String::new();
-                response.read_to_string(&mut body)?;
-                let health: Vec<String> = serde_json::from_str(&body)?;
+                let health: Vec<String> = serde_json::from_str(&response.body)?;
                 info!("get server health {:?}", health);
                 Ok(health)
             }
-            _ => {
-                let msg = format!("Health endpoint returns with wrong status code: {:?}",
-                                  response.status());
-                Err(KairoError::Kairo(msg))
-            }
+            _ => Err(response.error()),
+        }
+    }
+
+    /// Checks if the server is healthy with the cheap
+    /// `/api/v1/health/check` endpoint, e.g. for liveness probes. Returns
+    /// false if the server reports itself unhealthy and an error if it
+    /// can't be reached.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// assert!(client.health_check().unwrap());
+    /// # server.respond(kairosdb::transport::Method::GET, "/api/v1/health/check", 500, "");
+    /// # assert!(!client.health_check().unwrap());
+    /// ```
+    pub fn health_check(&self) -> Result<bool, KairoError> {
+        let response = self.send(Request::get("/api/v1/health/check"))?;
+        match response.status {
+            StatusCode::NO_CONTENT => Ok(true),
+            StatusCode::INTERNAL_SERVER_ERROR => Ok(false),
+            _ => Err(response.error()),
+        }
+    }
+
+    /// Sends the cheap health check with a timeout of two seconds and
+    /// returns how long the server took to answer, e.g. to check
+    /// pooled connections or to diagnose the connection at startup. The
+    /// check bypasses the circuit breaker and the rate and concurrency
+    /// limits, so it reaches the server while they hold back requests.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use kairosdb::Client;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// let latency = client.ping().unwrap();
+    /// assert!(latency < Duration::from_secs(2));
+    /// ```
+    pub fn ping(&self) -> Result<Duration, KairoError> {
+        let endpoint = "/api/v1/health/check";
+        let request = Request::get(endpoint).into_http(&self.base_url);
+        let context = RequestContext::new(&request.method, &request.url, &request.body);
+        let started = Instant::now();
+        let response = self.transport.send_timeout(&request, PING_TIMEOUT)
+            .map_err(|err| err.timed_out(started.elapsed(), endpoint).with_request(&context))?;
+        match response.status {
+            StatusCode::NO_CONTENT | StatusCode::INTERNAL_SERVER_ERROR => Ok(started.elapsed()),
+            _ => Err(Response::new(response, context).error()),
+        }
+    }
+
+    /// Opens up to `connections` pooled connections to the server ahead
+    /// of time with concurrent health checks, so the first requests after
+    /// startup don't wait for connection setup. Fails if the server can't
+    /// be reached.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// client.warm_up(4).unwrap();
+    /// # assert_eq!(client.stats().requests, 4);
+    /// ```
+    pub fn warm_up(&self, connections: usize) -> Result<(), KairoError> {
+        let results: Vec<Result<Response, KairoError>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..connections.max(1))
+                .map(|_| scope.spawn(move || self.send(Request::get("/api/v1/health/check"))))
+                .collect();
+            handles.into_iter()
+                .map(|handle| handle.join().unwrap_or_else(|_| {
+                    Err(KairoError::Kairo("Warm-up thread panicked".to_string()))
+                }))
+                .collect()
+        });
+        results.into_iter().try_for_each(|result| result.map(|_| ()))
+    }
+
+    /// Returns the aggregators and group-bys the server supports with
+    /// their properties, e.g. to offer them in a user interface or to
+    /// check queries with `Features::check`
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// # server.respond(kairosdb::transport::Method::GET, "/api/v1/features", 200,
+    /// #     r#"[{"name":"aggregators","label":"Aggregator","properties":[
+    /// #         {"name":"avg","label":"AVG"}]}]"#);
+    /// let features = client.features().unwrap();
+    /// assert!(features.aggregators().iter().any(|aggregator| aggregator.name == "avg"));
+    /// ```
+    pub fn features(&self) -> Result<Features, KairoError> {
+        let response = self.send(Request::get("/api/v1/features"))?;
+        match response.status {
+            StatusCode::OK => Ok(serde_json::from_str(&response.body)?),
+            _ => Err(response.error()),
         }
     }
 
+    /// Returns the statistics the server reported about itself in the
+    /// time range, like its ingest rate and query times. See
+    /// `serverstats::ServerStats` for an example.
+    pub fn server_stats(&self, start: Time, end: Time) -> Result<ServerStats, KairoError> {
+        let mut query = Query::new(start, end);
+        for name in &ServerStats::metrics() {
+            query.add(Metric::new(name, Tags::new(), vec![]));
+        }
+        let (start, end) = query.range_ms(Utc::now().timestamp_millis());
+        Ok(ServerStats::from_result(&self.query(&query)?, end - start))
+    }
+
     /// Method to add datapoints to the time series database
     ///
     /// # Example
@@ -316,7 +768,8 @@ impl Client {
     /// use kairosdb::Client;
     /// use kairosdb::datapoints::Datapoints;
     ///
-    /// let client = Client::new("localhost", 8080);
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
     /// let mut datapoints = Datapoints::new("first", 0);
     /// datapoints.add_ms(1475513259000, 11.0);
     /// datapoints.add_ms(1475513259001, 12.0);
@@ -325,19 +778,163 @@ impl Client {
     /// assert!(result.is_ok())
     /// ```
     pub fn add(&self, datapoints: &Datapoints) -> Result<(), KairoError> {
-        info!("Add datapoints {:?}", datapoints);
-        let response = reqwest::Client::new()
-            .post(&format!("{}/api/v1/datapoints", self.base_url))
-            .json(&vec![datapoints])
-            .send()?;
+        self.add_with_opts(datapoints, &RequestOpts::default())
+    }
+
+    /// Adds datapoints to the time series database with a deadline,
+    /// timeout or `CancelHandle`, e.g. a generous timeout for large
+    /// imports.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use kairosdb::Client;
+    /// use kairosdb::datapoints::Datapoints;
+    /// use kairosdb::options::RequestOpts;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// let mut datapoints = Datapoints::new("first", 0);
+    /// datapoints.add_ms(1475513259000, 11.0);
+    /// let opts = RequestOpts {
+    ///     timeout: Some(Duration::from_secs(120)),
+    ///     ..Default::default()
+    /// };
+    /// assert!(client.add_with_opts(&datapoints, &opts).is_ok());
+    /// ```
+    pub fn add_with_opts(&self, datapoints: &Datapoints, opts: &RequestOpts)
+                         -> Result<(), KairoError> {
+        self.add_batch_with_opts(std::slice::from_ref(datapoints), opts)
+    }
 
-        match response.status() {
+    /// Adds several sets of datapoints, e.g. of different metrics, to the
+    /// time series database in a single request
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// use kairosdb::datapoints::Datapoints;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// let batch = vec![
+    ///     Datapoints::builder("cpu").tag("host", "a").point(1475513259000, 0.5).build(),
+    ///     Datapoints::builder("memory").tag("host", "a").point(1475513259000, 512).build(),
+    /// ];
+    /// assert!(client.add_batch(&batch).is_ok());
+    /// # assert_eq!(server.requests().len(), 1);
+    /// # assert_eq!(client.list_metrics().unwrap(), vec!["cpu", "memory"]);
+    /// ```
+    pub fn add_batch(&self, batch: &[Datapoints]) -> Result<(), KairoError> {
+        self.add_batch_with_opts(batch, &RequestOpts::default())
+    }
+
+    /// Adds several sets of datapoints in a single request with a
+    /// deadline, timeout or `CancelHandle`
+    pub fn add_batch_with_opts(&self, batch: &[Datapoints], opts: &RequestOpts)
+                               -> Result<(), KairoError> {
+        if self.validate {
+            validation::validate_batch(batch).map_err(KairoError::Validation)?;
+        }
+        let datapoints = batch.iter().map(|d| d.len()).sum();
+        // writes of a client usually have similar sizes, so the body is
+        // serialized into a buffer already grown by the earlier ones
+        let buffer = &self.write_buffer;
+        let request = if self.sort_datapoints && !batch.iter().all(Datapoints::is_sorted) {
+            let sorted: Vec<Datapoints> = batch.iter().cloned().map(sorted).collect();
+            Request::post_buffered("/api/v1/datapoints", &sorted, buffer)?
+        } else {
+            Request::post_buffered("/api/v1/datapoints", batch, buffer)?
+        };
+        let request = request.carrying(batch.len(), datapoints);
+        let response = self.send_with_opts(request, opts)?;
+
+        match response.status {
             StatusCode::NO_CONTENT => Ok(()),
-            _ => {
-                let msg = format!("Add datapoints returns with bad response code: {:?}",
-                                  response.status());
-                Err(KairoError::Kairo(msg))
+            _ => Err(response.write_error()),
+        }
+    }
+
+    /// Adds the sets of datapoints of an iterator in a single request
+    /// with a chunked body. Each set is serialized only when the transport
+    /// sends it, so large uploads never hold the whole JSON body in
+    /// memory.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// use kairosdb::datapoints::Datapoints;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// let hours = (0..24).map(|hour| {
+    ///     Datapoints::builder("temperature")
+    ///         .tag("room", "kitchen")
+    ///         .point(1475513259000 + hour * 3_600_000, 20.5)
+    ///         .build()
+    /// });
+    /// assert!(client.add_stream(hours).is_ok());
+    /// # assert_eq!(server.requests().len(), 1);
+    /// # assert_eq!(client.list_metrics().unwrap(), vec!["temperature"]);
+    /// ```
+    pub fn add_stream<I>(&self, batch: I) -> Result<(), KairoError>
+        where I: IntoIterator<Item = Datapoints>, I::IntoIter: Send + 'static
+    {
+        self.add_stream_with_opts(batch, &RequestOpts::default())
+    }
+
+    /// Adds the sets of datapoints of an iterator in a single streamed
+    /// request with a deadline, timeout or `CancelHandle`. An aborted
+    /// upload stops before the next chunk of the body is sent.
+    pub fn add_stream_with_opts<I>(&self, batch: I, opts: &RequestOpts)
+                                   -> Result<(), KairoError>
+        where I: IntoIterator<Item = Datapoints>, I::IntoIter: Send + 'static
+    {
+        let call = Call {
+            method: Method::POST,
+            endpoint: "/api/v1/datapoints".to_string(),
+            metrics: 0,
+            datapoints: 0,
+        };
+        let response = self.dispatch(call, |_permit| {
+            let start = Instant::now();
+            if let Some(err) = request::aborted(opts, start, "/api/v1/datapoints") {
+                return (Err(err), Exchange { status: None, payload_size: 0, response_size: 0 });
             }
+
+            // the request token is taken by the middleware, the datapoints
+            // while the sets are serialized
+            let limiter = self.rate_limiter.clone();
+            let sort = self.sort_datapoints;
+            let items = batch.into_iter().map(move |datapoints| {
+                if let Some(ref limiter) = limiter {
+                    limiter.acquire_datapoints(datapoints.len());
+                }
+                if sort { sorted(datapoints) } else { datapoints }
+            });
+            let sent = Arc::new(AtomicUsize::new(0));
+            let body = AbortableReader::new(JsonArrayReader::new(items, sent.clone()), opts);
+
+            let url = format!("{}/api/v1/datapoints", self.base_url);
+            let context = RequestContext::new(&Method::POST, &url, &[]);
+            let response = self.transport.send_streaming(Method::POST, url, Box::new(body))
+                .map(|response| Response::new(response, context.clone()))
+                .map_err(|err| {
+                    request::aborted(opts, start, "/api/v1/datapoints")
+                        .unwrap_or_else(|| err.timed_out(start.elapsed(), "/api/v1/datapoints"))
+                        .with_request(&context)
+                });
+            let exchange = Exchange {
+                status: response.as_ref().ok().map(|r| r.status),
+                payload_size: sent.load(Ordering::Relaxed),
+                response_size: response.as_ref().map_or(0, |r| r.body.len()),
+            };
+            (response, exchange)
+        })?;
+
+        match response.status {
+            StatusCode::NO_CONTENT => Ok(()),
+            _ => Err(response.write_error()),
         }
     }
 
@@ -348,7 +945,8 @@ impl Client {
     /// use kairosdb::Client;
     /// use kairosdb::query::{Query, Time, TimeUnit};
     ///
-    /// let client = Client::new("localhost", 8080);
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
     /// let query = Query::new(
     ///    Time::Nanoseconds(1),
     ///    Time::Relative{value: 1, unit: TimeUnit::WEEKS});
@@ -356,10 +954,414 @@ impl Client {
     /// assert!(result.is_ok())
     /// ```
     pub fn query(&self, query: &Query) -> Result<ResultMap, KairoError> {
-        match self.run_query(query, "query") {
-            Ok(body) => self.parse_query_result(&body),
-            Err(err) => Err(err),
+        self.query_with_opts(query, &RequestOpts::default())
+    }
+
+    /// Runs a query on the database which can be aborted by a deadline,
+    /// a timeout or a `CancelHandle`.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::{Duration, Instant};
+    /// use kairosdb::{Client, KairoError};
+    /// use kairosdb::options::{RequestOpts, CancelHandle};
+    /// use kairosdb::query::{Query, Time, TimeUnit};
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// let query = Query::new(
+    ///    Time::Nanoseconds(1),
+    ///    Time::Relative{value: 1, unit: TimeUnit::WEEKS});
+    /// let cancel = CancelHandle::new();
+    /// let opts = RequestOpts {
+    ///     deadline: Some(Instant::now() + Duration::from_secs(60)),
+    ///     cancel: Some(cancel.clone()),
+    ///     ..Default::default()
+    /// };
+    /// cancel.cancel();
+    /// match client.query_with_opts(&query, &opts) {
+    ///     Err(KairoError::Cancelled) => (),
+    ///     _ => panic!("query should be cancelled"),
+    /// }
+    /// ```
+    pub fn query_with_opts(&self, query: &Query, opts: &RequestOpts)
+                           -> Result<ResultMap, KairoError> {
+        self.run_query(query, "query", opts, |body| {
+            let (result, sample_size) = result::parse_sized(body)?;
+            Ok((result, Some(sample_size)))
+        })
+    }
+
+    /// Runs a query on the database and passes every series with its
+    /// metric name to `callback` while the response is read, so huge
+    /// results never have to fit into memory. Returns the number of
+    /// series. If the response turns out to be malformed, the error is
+    /// returned after `callback` already received the series before it.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// use kairosdb::query::{Query, Time, Metric, Tags};
+    /// # use kairosdb::datapoints::Datapoints;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// # let mut datapoints = Datapoints::new("myMetric", 0);
+    /// # datapoints.add_ms(1000, 11.0);
+    /// # datapoints.add_ms(2000, 12.0);
+    /// # client.add(&datapoints).unwrap();
+    /// let mut query = Query::new(Time::Nanoseconds(0), Time::Nanoseconds(3000));
+    /// query.add(Metric::new("myMetric", Tags::new(), vec![]));
+    /// let mut sum = 0.0;
+    /// client.query_each(&query, |_name, series| {
+    ///     sum += series.values.iter().filter_map(|v| v.value.as_f64()).sum::<f64>();
+    /// }).unwrap();
+    /// assert_eq!(sum, 23.0);
+    /// ```
+    pub fn query_each<F>(&self, query: &Query, mut callback: F) -> Result<usize, KairoError>
+        where F: FnMut(String, Series)
+    {
+        let request = self.query_request(query, "query")?;
+        let call = Call {
+            method: request.method.clone(),
+            endpoint: request.endpoint.clone(),
+            metrics: request.metrics,
+            datapoints: request.datapoints,
+        };
+        let request = request.into_http(&self.base_url);
+        let context = RequestContext::new(&request.method, &request.url, &request.body);
+        let payload_size = request.body.len();
+        let endpoint = request::endpoint(&request.url);
+        let started = Instant::now();
+        let result = self.dispatch(call, |_permit| {
+            let start = Instant::now();
+            let timed_out = |err: KairoError| {
+                err.with_request(&context).timed_out(start.elapsed(), &endpoint)
+            };
+            let mut exchange = Exchange { status: None, payload_size, response_size: 0 };
+            let response = match self.transport.send_reading(&request) {
+                Ok(response) => response,
+                Err(err) => return (Err(timed_out(err)), exchange),
+            };
+            exchange.status = Some(response.status);
+
+            if response.status != StatusCode::OK {
+                let mut body = String::new();
+                let read = response.body.take(MAX_ERROR_BODY as u64).read_to_string(&mut body);
+                exchange.response_size = body.len();
+                return match read {
+                    Ok(_) => {
+                        let err = KairoError::from_response(response.status, &body);
+                        (Err(err.with_request(&context)), exchange)
+                    }
+                    Err(err) => (Err(timed_out(KairoError::transport(err))), exchange),
+                };
+            }
+
+            let mut body = CountingReader::new(response.body);
+            let mut series = 0;
+            let parsed = result::parse_reader_sized(&mut body, |name, s| {
+                series += 1;
+                callback(name, s)
+            });
+            exchange.response_size = body.count();
+            (parsed.map(|sample_size| (series, sample_size)), exchange)
+        });
+        let sample_size = result.as_ref().ok().map(|&(_, sample_size)| sample_size);
+        self.check_slow_query(query, started.elapsed(), sample_size);
+        result.map(|(series, _)| series)
+    }
+
+    /// Runs a query on the database and returns the times of the
+    /// datapoints as `DateTime`
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// use kairosdb::query::{Query, Time, Metric, Tags};
+    /// # use kairosdb::datapoints::Datapoints;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// # let mut datapoints = Datapoints::new("myMetric", 0);
+    /// # datapoints.add_ms(1475513259000, 11.0);
+    /// # datapoints.add_tag("host", "a");
+    /// # client.add(&datapoints).unwrap();
+    /// let mut query = Query::new(Time::Nanoseconds(0), Time::Nanoseconds(1475513260000));
+    /// query.add(Metric::new("myMetric", Tags::new(), vec![]));
+    /// let result = client.query_utc(&query).unwrap();
+    /// assert_eq!(result["myMetric"][0].values[0].time.to_rfc3339(),
+    ///            "2016-10-03T16:47:39+00:00");
+    /// ```
+    pub fn query_utc(&self, query: &Query) -> Result<UtcResultMap, KairoError> {
+        let result = self.run_query(query, "query", &RequestOpts::default(), |body| {
+            let (result, sample_size) = result::parse_sized(body)?;
+            Ok((result, Some(sample_size)))
+        })?;
+        result::to_utc(result)
+    }
+
+    /// Runs a query on the database and returns its series in the order of
+    /// the response with their metric names, e.g. to tell which host a
+    /// series belongs to.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// use kairosdb::query::{Query, Time, Metric, Tags, TimeUnit};
+    /// # use kairosdb::datapoints::Datapoints;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// # let mut datapoints = Datapoints::new("myMetric", 0);
+    /// # datapoints.add_ms(1475513259000, 11.0);
+    /// # datapoints.add_tag("host", "a");
+    /// # client.add(&datapoints).unwrap();
+    /// let mut query = Query::new(
+    ///    Time::Nanoseconds(1),
+    ///    Time::Relative{value: 0, unit: TimeUnit::WEEKS});
+    /// query.add(Metric::new("myMetric", Tags::new(), vec![]));
+    /// let series = client.query_series(&query).unwrap();
+    /// let (name, series) = &series[0];
+    /// assert_eq!(name, "myMetric");
+    /// assert_eq!(series.tags["host"], vec!["a"]);
+    /// assert_eq!(series.values[0].value, 11.0);
+    /// ```
+    pub fn query_series(&self, query: &Query) -> Result<Vec<(String, Series)>, KairoError> {
+        self.run_query(query, "query", &RequestOpts::default(), |body| {
+            let (series, sample_size) = result::parse_series_sized(body)?;
+            Ok((series, Some(sample_size)))
+        })
+    }
+
+    /// Runs a query over a long time range as a sequence of queries over
+    /// windows of `chunk` and merges their results. This keeps single
+    /// requests short and responses small. Aggregator ranges do not span
+    /// the window boundaries, so windows should be a multiple of the
+    /// sampling.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// use kairosdb::query::{Query, Time, Metric, Tags, RelativeTime, TimeUnit};
+    /// # use kairosdb::datapoints::Datapoints;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// # let mut datapoints = Datapoints::new("myMetric", 0);
+    /// # datapoints.add_ms(1000, 11.0);
+    /// # datapoints.add_ms(2000, 12.0);
+    /// # datapoints.add_ms(3000, 13.0);
+    /// # client.add(&datapoints).unwrap();
+    /// let mut query = Query::new(Time::Nanoseconds(0), Time::Nanoseconds(3000));
+    /// query.add(Metric::new("myMetric", Tags::new(), vec![]));
+    /// let chunk = RelativeTime::new(1, TimeUnit::SECONDS);
+    /// let result = client.query_chunked(&query, &chunk).unwrap();
+    /// assert_eq!(result["myMetric"][0].values.len(), 3);
+    /// assert_eq!(result["myMetric"][0].values[2].value, 13.0);
+    /// # assert_eq!(server.requests().len(), 5);
+    /// ```
+    pub fn query_chunked(&self, query: &Query, chunk: &RelativeTime)
+                         -> Result<ResultMap, KairoError> {
+        if !query.has_start() {
+            return Err(KairoError::Kairo("Query has no start time".to_string()));
+        }
+        let (start, end) = query.range_ms(Utc::now().timestamp_millis());
+        let width = chunk.as_millis().max(1);
+
+        let mut result = ResultMap::new();
+        let mut chunk_start = start;
+        while chunk_start <= end {
+            let chunk_end = chunk_start.saturating_add(width - 1).min(end);
+            let mut chunk_query = query.clone();
+            chunk_query.set_start(Time::Nanoseconds(chunk_start));
+            chunk_query.set_end(Time::Nanoseconds(chunk_end));
+            for (name, chunk_series) in self.query(&chunk_query)? {
+                let merged: &mut Vec<Series> = result.entry(name).or_default();
+                for series in chunk_series {
+                    match merged.iter_mut().find(|m| m.group == series.group) {
+                        Some(existing) => existing.merge(series),
+                        None => merged.push(series),
+                    }
+                }
+            }
+            if chunk_end == end {
+                break;
+            }
+            chunk_start = chunk_end + 1;
+        }
+        Ok(result)
+    }
+
+    /// Runs the metrics of a query in up to `parallelism` concurrent
+    /// requests and merges their results. KairosDB processes the metrics
+    /// of one request one after another, so queries with many metrics
+    /// return faster. A concurrency limit of the client still applies.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// use kairosdb::query::{Query, Time, Metric, Tags};
+    /// # use kairosdb::datapoints::Datapoints;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// # for name in &["first", "second", "third"] {
+    /// #     let mut datapoints = Datapoints::new(name, 0);
+    /// #     datapoints.add_ms(1000, 11.0);
+    /// #     client.add(&datapoints).unwrap();
+    /// # }
+    /// let mut query = Query::new(Time::Nanoseconds(0), Time::Nanoseconds(3000));
+    /// query.add(Metric::new("first", Tags::new(), vec![]));
+    /// query.add(Metric::new("second", Tags::new(), vec![]));
+    /// query.add(Metric::new("third", Tags::new(), vec![]));
+    /// let result = client.query_parallel(&query, 2).unwrap();
+    /// assert_eq!(result.len(), 3);
+    /// assert_eq!(result["third"][0].values[0].value, 11.0);
+    /// ```
+    pub fn query_parallel(&self, query: &Query, parallelism: usize)
+                          -> Result<ResultMap, KairoError> {
+        let parallelism = parallelism.max(1).min(query.metrics().len().max(1));
+        let mut groups = vec![Vec::new(); parallelism];
+        for (i, metric) in query.metrics().iter().enumerate() {
+            groups[i % parallelism].push(metric.clone());
         }
+
+        let results: Vec<Result<ResultMap, KairoError>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = groups.into_iter()
+                .map(|metrics| {
+                    let part = query.with_metrics(metrics);
+                    scope.spawn(move || self.query(&part))
+                })
+                .collect();
+            handles.into_iter()
+                .map(|handle| handle.join()
+                     .unwrap_or_else(|_| Err(KairoError::Kairo("Query thread panicked".to_string()))))
+                .collect()
+        });
+
+        let mut merged = ResultMap::new();
+        for result in results {
+            merged.extend(result?);
+        }
+        Ok(merged)
+    }
+
+    /// Returns the tag names and values per metric of the datapoints
+    /// matching the query, e.g. to fill the variable dropdowns of a
+    /// dashboard. Aggregators of the query are ignored.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// use kairosdb::query::{Query, Time, Metric, Tags, TimeUnit};
+    /// # use kairosdb::datapoints::Datapoints;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// # let mut datapoints = Datapoints::new("myMetric", 0);
+    /// # datapoints.add_ms(1475513259000, 11.0);
+    /// # datapoints.add_tag("host", "a");
+    /// # client.add(&datapoints).unwrap();
+    /// let mut query = Query::new(
+    ///    Time::Nanoseconds(1),
+    ///    Time::Relative{value: 0, unit: TimeUnit::WEEKS});
+    /// query.add(Metric::new("myMetric", Tags::new(), vec![]));
+    /// let result = client.query_tags(&query).unwrap();
+    /// assert_eq!(result["myMetric"]["host"], vec!["a"]);
+    /// ```
+    pub fn query_tags(&self, query: &Query) -> Result<TagsMap, KairoError> {
+        self.run_query(query, "query/tags", &RequestOpts::default(), |body| {
+            Ok((TagsResult::new().parse_result(&body)?, None))
+        })
+    }
+
+    /// Returns the tag names with their values of a single metric in the
+    /// time range. Unlike `tagnames` and `tagvalues`, which return the
+    /// tags of all metrics ever written, this only reads the rows of the
+    /// metric.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// use kairosdb::query::{Time, TimeUnit};
+    /// # use kairosdb::datapoints::Datapoints;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// # let mut datapoints = Datapoints::new("myMetric", 0);
+    /// # datapoints.add_ms(1475513259000, 11.0);
+    /// # datapoints.add_tag("host", "a");
+    /// # client.add(&datapoints).unwrap();
+    /// let tags = client.tags_for_metric(
+    ///     "myMetric",
+    ///     Time::Nanoseconds(1),
+    ///     Time::Relative{value: 0, unit: TimeUnit::WEEKS}).unwrap();
+    /// assert_eq!(tags["host"], vec!["a"]);
+    /// ```
+    pub fn tags_for_metric(&self, name: &str, start: Time, end: Time)
+                           -> Result<Tags, KairoError> {
+        let mut query = Query::new(start, end);
+        query.add(Metric::new(name, Tags::new(), vec![]));
+        Ok(self.query_tags(&query)?.remove(name).unwrap_or_default())
+    }
+
+    /// Returns the number of datapoints of a metric with the tags in the
+    /// time range, counted by the server with the `count` aggregator.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// use kairosdb::query::{Tags, Time};
+    /// # use kairosdb::datapoints::Datapoints;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// # for host in &["a", "b"] {
+    /// #     let mut datapoints = Datapoints::new("myMetric", 0);
+    /// #     datapoints.add_ms(1000, 1.0);
+    /// #     datapoints.add_ms(2000, 2.0);
+    /// #     datapoints.add_tag("host", host);
+    /// #     client.add(&datapoints).unwrap();
+    /// # }
+    /// let mut tags = Tags::new();
+    /// tags.insert("host".to_string(), vec!["a".to_string()]);
+    /// let start = Time::Nanoseconds(0);
+    /// let end = Time::Nanoseconds(10_000);
+    /// assert_eq!(client.count("myMetric", tags, start.clone(), end.clone()).unwrap(), 2);
+    /// assert_eq!(client.count("myMetric", Tags::new(), start, end).unwrap(), 4);
+    /// ```
+    pub fn count(&self, metric: &str, tags: Tags, start: Time, end: Time)
+                 -> Result<u64, KairoError> {
+        let mut query = Query::new(start, end);
+        let (start, end) = query.range_ms(Utc::now().timestamp_millis());
+        let sampling = RelativeTime::new((end - start).max(1), TimeUnit::MILLISECONDS);
+        let count = Aggregator::new(AggregatorType::COUNT, sampling);
+        query.add(Metric::new(metric, tags, vec![count]));
+
+        let result = self.query(&query)?;
+        let total: f64 = result.values().flatten().map(Series::sum).sum();
+        Ok(total as u64)
+    }
+
+    /// Returns the HTTP request `query` would send without sending it,
+    /// e.g. to debug why the server rejects a complex query.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// use kairosdb::query::{Query, Time};
+    /// use kairosdb::transport::Method;
+    ///
+    /// let client = Client::new("localhost", 8080);
+    /// let query = Query::new(Time::Nanoseconds(1000), Time::Nanoseconds(2000));
+    /// let request = client.explain(&query).unwrap();
+    /// assert_eq!(request.method, Method::POST);
+    /// assert_eq!(request.url, "http://localhost:8080/api/v1/datapoints/query");
+    /// assert_eq!(request.body, br#"{"start_absolute":1000,"end_absolute":2000,"metrics":[]}"#);
+    /// ```
+    pub fn explain(&self, query: &Query) -> Result<HttpRequest, KairoError> {
+        Ok(query_request(query, "query")?.into_http(&self.base_url))
     }
 
     /// Runs a delete query on the database. View the query structure
@@ -370,7 +1372,8 @@ impl Client {
     /// use kairosdb::Client;
     /// use kairosdb::query::{Query, Time, TimeUnit};
     ///
-    /// let client = Client::new("localhost", 8080);
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
     /// let query = Query::new(
     ///    Time::Nanoseconds(1),
     ///    Time::Relative{value: 1, unit: TimeUnit::WEEKS});
@@ -378,10 +1381,7 @@ impl Client {
     /// assert!(result.is_ok())
     /// ```
     pub fn delete(&self, query: &Query) -> Result<(), KairoError> {
-        match self.run_query(query, "delete") {
-            Ok(_) => Ok(()),
-            Err(err) => Err(err),
-        }
+        self.run_query(query, "delete", &RequestOpts::default(), |_| Ok(((), None)))
     }
 
     /// Returns a list with all metric names
@@ -390,7 +1390,8 @@ impl Client {
     /// ```
     /// use kairosdb::Client;
     /// # use kairosdb::datapoints::Datapoints;
-    /// let client = Client::new("localhost", 8080);
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
     /// # let mut datapoints = Datapoints::new("first", 0);
     /// # datapoints.add_ms(1475513259000, 11.0);
     /// # datapoints.add_tag("test", "first");
@@ -401,26 +1402,89 @@ impl Client {
     /// assert!(result.unwrap().contains(&"first".to_string()));
     /// ```
     pub fn list_metrics(&self) -> Result<Vec<String>, KairoError> {
-        info!("Get metricnames");
-        let mut response = reqwest::get(&format!("{}/api/v1/metricnames", self.base_url))?;
+        let response = self.send(Request::get("/api/v1/metricnames"))?;
+
+        match response.status {
+            StatusCode::OK => {
+                Ok(parse_metricnames_result(&response.body)?)
+            }
+            _ => Err(response.error()),
+        }
+    }
 
-        match response.status() {
+    /// Returns the metric names passing the filter. The prefix and the
+    /// text are sent as `prefix` and `containing` parameters, so servers
+    /// supporting them send only the matching names. The names are
+    /// filtered on the client as well for servers which ignore them.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// use kairosdb::options::MetricFilter;
+    /// # use kairosdb::datapoints::Datapoints;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// # for name in &["cpu.user", "cpu.system", "memory.used"] {
+    /// #     client.add(&Datapoints::builder(name).tag("host", "a").point(1000, 1).build())
+    /// #         .unwrap();
+    /// # }
+    /// let filter = MetricFilter { prefix: Some("cpu.".to_string()), ..Default::default() };
+    /// assert_eq!(client.list_metrics_filtered(&filter).unwrap(), vec!["cpu.system", "cpu.user"]);
+    /// # assert_eq!(server.requests().last().unwrap().path, "/api/v1/metricnames?prefix=cpu.");
+    /// ```
+    pub fn list_metrics_filtered(&self, filter: &MetricFilter) -> Result<Vec<String>, KairoError> {
+        let endpoint = format!("/api/v1/metricnames{}", filter.query_string());
+        let response = self.send(Request::get(&endpoint))?;
+
+        match response.status {
             StatusCode::OK => {
-                let mut result_body = String::new();
-                response.read_to_string(&mut result_body)?;
-                Ok(parse_metricnames_result(&result_body)?)
+                let mut names = parse_metricnames_result(&response.body)?;
+                names.retain(|name| filter.matches(name));
+                Ok(names)
             }
-            _ => Err(KairoError::Kairo(format!("Bad response code: {:?}", response.status()))),
+            _ => Err(response.error()),
         }
     }
 
+    /// Returns the sorted metric names passing the filter in pages of
+    /// `page_size` names. KairosDB can't page metric names, so they are
+    /// fetched once and paged on the client; a `prefix` still keeps the
+    /// response small.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// use kairosdb::options::MetricFilter;
+    /// # use kairosdb::datapoints::Datapoints;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// # for name in &["c", "a", "b"] {
+    /// #     client.add(&Datapoints::builder(name).tag("host", "a").point(1000, 1).build())
+    /// #         .unwrap();
+    /// # }
+    /// let mut pages = client.list_metrics_pages(&MetricFilter::default(), 2).unwrap();
+    /// assert_eq!(pages.next().unwrap(), vec!["a", "b"]);
+    /// assert_eq!(pages.remaining(), 1);
+    /// assert_eq!(pages.next().unwrap(), vec!["c"]);
+    /// assert!(pages.next().is_none());
+    /// # assert_eq!(server.requests().iter()
+    /// #     .filter(|request| request.path.starts_with("/api/v1/metricnames")).count(), 1);
+    /// ```
+    pub fn list_metrics_pages(&self, filter: &MetricFilter, page_size: usize)
+                              -> Result<MetricPages, KairoError> {
+        Ok(MetricPages::new(self.list_metrics_filtered(filter)?, page_size))
+    }
+
     /// Deleting a metric
     ///
     /// # Example
     /// ```
     /// use kairosdb::Client;
     /// # use kairosdb::datapoints::Datapoints;
-    /// let client = Client::new("localhost", 8080);
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
     /// # let mut datapoints = Datapoints::new("first", 0);
     /// # datapoints.add_ms(1475513259000, 11.0);
     /// # let result = client.add(&datapoints);
@@ -432,23 +1496,183 @@ impl Client {
     /// # assert!(!result.unwrap().contains(&"first".to_string()));
     /// ```
     pub fn delete_metric(&self, metric: &str) -> Result<(), KairoError> {
-        let response = reqwest::Client::new()
-            .delete(&format!("{}/api/v1/metric/{}", self.base_url, metric))
-            .send()?;
+        let response = self.send(Request::delete(&format!("/api/v1/metric/{}", metric)))?;
 
-        match response.status() {
+        match response.status {
             StatusCode::NO_CONTENT => Ok(()),
-            _ => Err(KairoError::Kairo(format!("Bad response code: {:?}", response.status()))),
+            _ => Err(response.error()),
         }
     }
 
-    /// Returns a list of all tagnames
+    /// Creates a roll-up task and returns the id the server assigned to it
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// use kairosdb::query::{Query, RelativeTime, Time, TimeUnit};
+    /// use kairosdb::rollup::RollupTask;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// # server.respond(kairosdb::transport::Method::POST, "/api/v1/rollups", 200,
+    /// #     r#"{"id":"1b2c","name":"cpu daily","attributes":{"url":"/api/v1/rollups/1b2c"}}"#);
+    /// let query = Query::builder()
+    ///     .start(Time::Relative { value: 1, unit: TimeUnit::DAYS })
+    ///     .metric(|m| m.name("cpu").avg(1, TimeUnit::DAYS))
+    ///     .build();
+    /// let mut task = RollupTask::new("cpu daily", RelativeTime::new(1, TimeUnit::DAYS));
+    /// task.add("cpu.daily", query);
+    /// assert_eq!(client.create_rollup(&task).unwrap(), "1b2c");
+    /// ```
+    pub fn create_rollup(&self, task: &RollupTask) -> Result<String, KairoError> {
+        let response = self.send(Request::post("/api/v1/rollups", task)?)?;
+
+        match response.status {
+            StatusCode::OK => {
+                let created: CreatedRollup = serde_json::from_str(&response.body)?;
+                Ok(created.id)
+            }
+            _ => Err(response.error()),
+        }
+    }
+
+    /// Returns all roll-up tasks of the server
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// # server.respond(kairosdb::transport::Method::GET, "/api/v1/rollups", 200,
+    /// #     r#"[{"id":"1b2c","name":"cpu daily","execution_interval":{"value":1,"unit":"DAYS"},
+    /// #         "rollups":[{"save_as":"cpu.daily","query":{"start_relative":{"value":1,"unit":"DAYS"},
+    /// #         "metrics":[{"name":"cpu","tags":{},"aggregators":[]}]}}]}]"#);
+    /// let tasks = client.rollups().unwrap();
+    /// assert_eq!(tasks[0].id(), Some("1b2c"));
+    /// assert_eq!(tasks[0].rollups()[0].save_as(), "cpu.daily");
+    /// ```
+    pub fn rollups(&self) -> Result<Vec<RollupTask>, KairoError> {
+        let response = self.send(Request::get("/api/v1/rollups"))?;
+
+        match response.status {
+            StatusCode::OK => Ok(serde_json::from_str(&response.body)?),
+            _ => Err(response.error()),
+        }
+    }
+
+    /// Replaces the roll-up task with the given id
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// use kairosdb::query::{Query, RelativeTime, Time, TimeUnit};
+    /// use kairosdb::rollup::RollupTask;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// # server.respond(kairosdb::transport::Method::PUT, "/api/v1/rollups/1b2c", 200,
+    /// #     r#"{"id":"1b2c","name":"cpu daily"}"#);
+    /// let query = Query::builder()
+    ///     .start(Time::Relative { value: 1, unit: TimeUnit::DAYS })
+    ///     .metric(|m| m.name("cpu").avg(1, TimeUnit::DAYS))
+    ///     .build();
+    /// let mut task = RollupTask::new("cpu daily", RelativeTime::new(1, TimeUnit::DAYS));
+    /// task.add("cpu.daily", query);
+    /// assert!(client.update_rollup("1b2c", &task).is_ok());
+    /// ```
+    pub fn update_rollup(&self, id: &str, task: &RollupTask) -> Result<(), KairoError> {
+        let endpoint = format!("/api/v1/rollups/{}", id);
+        let response = self.send(Request::raw(Method::PUT, &endpoint, serde_json::to_vec(task)?))?;
+
+        match response.status {
+            StatusCode::OK => Ok(()),
+            _ => Err(response.error()),
+        }
+    }
+
+    /// Deletes the roll-up task with the given id
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// # server.respond(kairosdb::transport::Method::DELETE, "/api/v1/rollups/1b2c", 204, "");
+    /// assert!(client.delete_rollup("1b2c").is_ok());
+    /// ```
+    pub fn delete_rollup(&self, id: &str) -> Result<(), KairoError> {
+        let response = self.send(Request::delete(&format!("/api/v1/rollups/{}", id)))?;
+
+        match response.status {
+            StatusCode::NO_CONTENT => Ok(()),
+            _ => Err(response.error()),
+        }
+    }
+
+    /// Deletes many metrics with up to 8 concurrent requests, within the
+    /// concurrency limit of the client. Returns the result of every
+    /// metric in the given order, a failed deletion doesn't stop the
+    /// others.
     ///
     /// # Example
     /// ```
     /// use kairosdb::Client;
     /// # use kairosdb::datapoints::Datapoints;
-    /// let client = Client::new("localhost", 8080);
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// # for name in &["test.a", "test.b", "keep"] {
+    /// #     client.add(&Datapoints::builder(name).tag("host", "a").point(1000, 1).build())
+    /// #         .unwrap();
+    /// # }
+    /// let results = client.delete_metrics(&["test.a", "test.b"]);
+    /// let failed: Vec<_> = results.iter().filter(|(_, result)| result.is_err()).collect();
+    /// assert!(failed.is_empty());
+    /// assert_eq!(client.list_metrics().unwrap(), vec!["keep"]);
+    /// ```
+    pub fn delete_metrics(&self, metrics: &[&str]) -> Vec<(String, Result<(), KairoError>)> {
+        const PARALLELISM: usize = 8;
+        let parallelism = PARALLELISM.min(metrics.len()).max(1);
+        let mut groups = vec![Vec::new(); parallelism];
+        for (i, metric) in metrics.iter().enumerate() {
+            groups[i % parallelism].push((i, *metric));
+        }
+
+        let mut results: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = groups.iter()
+                .map(|group| scope.spawn(move || {
+                    group.iter()
+                        .map(|&(i, metric)| (i, metric, self.delete_metric(metric)))
+                        .collect::<Vec<_>>()
+                }))
+                .collect();
+            // a panicked thread fails all metrics of its group
+            handles.into_iter().zip(&groups)
+                .flat_map(|(handle, group)| handle.join().unwrap_or_else(|_| {
+                    group.iter()
+                        .map(|&(i, metric)| {
+                            let err = KairoError::Kairo("Delete thread panicked".to_string());
+                            (i, metric, Err(err))
+                        })
+                        .collect()
+                }))
+                .collect()
+        });
+        results.sort_by_key(|(i, _, _)| *i);
+        results.into_iter().map(|(_, metric, result)| (metric.to_string(), result)).collect()
+    }
+
+    /// Returns a list of all tagnames. On large servers prefer
+    /// `tags_for_metric`, which only reads the tags of one metric.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// # use kairosdb::datapoints::Datapoints;
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
     /// # let mut datapoints = Datapoints::new("first", 0);
     /// # datapoints.add_ms(1475513259000, 11.0);
     /// # datapoints.add_tag("test", "first");
@@ -459,26 +1683,25 @@ impl Client {
     /// assert!(result.unwrap().contains(&"test".to_string()));
     /// ```
     pub fn tagnames(&self) -> Result<Vec<String>, KairoError> {
-        info!("Get tagnames");
-        let mut response = reqwest::get(&format!("{}/api/v1/tagnames", self.base_url))?;
+        let response = self.send(Request::get("/api/v1/tagnames"))?;
 
-        match response.status() {
+        match response.status {
             StatusCode::OK => {
-                let mut result_body = String::new();
-                response.read_to_string(&mut result_body)?;
-                Ok(parse_metricnames_result(&result_body)?)
+                Ok(parse_metricnames_result(&response.body)?)
             }
-            _ => Err(KairoError::Kairo(format!("Bad response code: {:?}", response.status()))),
+            _ => Err(response.error()),
         }
     }
 
-    /// Returns a list of all tagvalues
+    /// Returns a list of all tagvalues. On large servers prefer
+    /// `tags_for_metric`, which only reads the tags of one metric.
     ///
     /// # Example
     /// ```
     /// use kairosdb::Client;
     /// # use kairosdb::datapoints::Datapoints;
-    /// let client = Client::new("localhost", 8080);
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
     /// # let mut datapoints = Datapoints::new("first", 0);
     /// # datapoints.add_ms(1475513259000, 11.0);
     /// # datapoints.add_tag("test", "first");
@@ -489,40 +1712,318 @@ impl Client {
     /// assert!(result.unwrap().contains(&"first".to_string()));
     /// ```
     pub fn tagvalues(&self) -> Result<Vec<String>, KairoError> {
-        info!("Get tagnames");
-        let mut response = reqwest::get(&format!("{}/api/v1/tagvalues", self.base_url))?;
+        let response = self.send(Request::get("/api/v1/tagvalues"))?;
 
-        match response.status() {
+        match response.status {
             StatusCode::OK => {
-                let mut result_body = String::new();
-                response.read_to_string(&mut result_body)?;
-                Ok(parse_metricnames_result(&result_body)?)
+                Ok(parse_metricnames_result(&response.body)?)
             }
-            _ => Err(KairoError::Kairo(format!("Bad response code: {:?}", response.status()))),
+            _ => Err(response.error()),
         }
     }
 
-    fn run_query(&self, query: &Query, endpoint: &str) -> Result<String, KairoError> {
-        info!("Run query {}", serde_json::to_string(query)?);
-        let mut response = reqwest::Client::new()
-            .post(&format!("{}/api/v1/datapoints/{}", self.base_url, endpoint))
-            .json(query)
-            .send()?;
+    /// Sends a request to an endpoint not wrapped by the client, e.g. of a
+    /// plugin. The request passes the configured rate limit, circuit breaker
+    /// and transport like every other request. Responses with error status
+    /// codes are returned as well.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::Client;
+    /// use kairosdb::transport::{Method, StatusCode};
+    ///
+    /// let server = kairosdb::testing::StubServer::start();
+    /// let client = Client::new("localhost", server.port());
+    /// let response = client.send_raw(Method::GET, "/api/v1/health/check", None).unwrap();
+    /// assert_eq!(response.status, StatusCode::NO_CONTENT);
+    /// ```
+    pub fn send_raw(&self, method: Method, path: &str, body: Option<&str>)
+                    -> Result<HttpResponse, KairoError> {
+        let body = body.map(|b| b.as_bytes().to_vec()).unwrap_or_default();
+        let response = self.send(Request::raw(method, path, body))?;
+        Ok(HttpResponse { status: response.status, body: response.body })
+    }
 
-        match response.status() {
-            StatusCode::OK => {
-                let mut result_body = String::new();
-                response.read_to_string(&mut result_body)?;
-                Ok(result_body)
+    // Sends a query and parses the body of a successful response with
+    // `parse`, which returns the sample size if the response has one.
+    // Queries are checked against the slow query threshold whether they
+    // succeed or not.
+    fn run_query<T, F>(&self, query: &Query, endpoint: &str, opts: &RequestOpts, parse: F)
+                       -> Result<T, KairoError>
+        where F: FnOnce(String) -> Result<(T, Option<i64>), KairoError>
+    {
+        let request = self.query_request(query, endpoint)?;
+        let started = Instant::now();
+        let response = self.send_with_opts(request, opts);
+        let duration = started.elapsed();
+
+        let result = response.and_then(|response| match response.status {
+            StatusCode::OK => parse(response.body),
+            StatusCode::NO_CONTENT => parse(String::new()),
+            _ => Err(response.error()),
+        });
+        let sample_size = result.as_ref().ok().and_then(|&(_, sample_size)| sample_size);
+        self.check_slow_query(query, duration, sample_size);
+        result.map(|(value, _)| value)
+    }
+
+    fn check_slow_query(&self, query: &Query, duration: Duration, sample_size: Option<i64>) {
+        match self.slow_query_threshold {
+            Some(threshold) if duration >= threshold => (),
+            _ => return,
+        }
+        let slow = SlowQuery {
+            body: serde_json::to_string(query).unwrap_or_default(),
+            duration,
+            sample_size,
+        };
+        match slow.sample_size {
+            Some(sample_size) => warn!("slow query took {:?} reading {} datapoints: {}",
+                                       slow.duration, sample_size, slow.body),
+            None => warn!("slow query took {:?}: {}", slow.duration, slow.body),
+        }
+        self.hooks.slow_query(&slow);
+    }
+
+    // Creates the request of a query, checking the query first if the
+    // client validates requests
+    fn query_request(&self, query: &Query, endpoint: &str) -> Result<Request, KairoError> {
+        if self.validate {
+            query.validate().map_err(KairoError::Validation)?;
+        }
+        query_request(query, endpoint)
+    }
+
+    fn send(&self, request: Request) -> Result<Response, KairoError> {
+        self.send_with_opts(request, &RequestOpts::default())
+    }
+
+    fn send_with_opts(&self, mut request: Request, opts: &RequestOpts)
+                      -> Result<Response, KairoError> {
+        let call = Call {
+            method: request.method.clone(),
+            endpoint: request.endpoint.clone(),
+            metrics: request.metrics,
+            datapoints: request.datapoints,
+        };
+        let payload_size = request.body.len();
+        let buffer = request.buffer.take();
+        let request = request.into_http(&self.base_url);
+        let context = RequestContext::new(&request.method, &request.url, &request.body);
+        self.dispatch(call, |permit| {
+            let response = request::execute(&self.transport, request, buffer, opts, permit)
+                .map(|response| Response::new(response, context.clone()))
+                .map_err(|err| err.with_request(&context));
+            let exchange = Exchange {
+                status: response.as_ref().ok().map(|r| r.status),
+                payload_size,
+                response_size: response.as_ref().map_or(0, |r| r.body.len()),
+            };
+            (response, exchange)
+        })
+    }
+
+    // Runs `send` behind the middleware shared by all requests: circuit
+    // breaker, rate limit, concurrency limit, tracing span or log, and
+    // statistics. `send` gets the concurrency permit and describes the
+    // exchange once it is done.
+    fn dispatch<T, F>(&self, call: Call, send: F) -> Result<T, KairoError>
+        where F: FnOnce(Option<Permit>) -> (Result<T, KairoError>, Exchange)
+    {
+        if let Some(ref breaker) = self.circuit_breaker {
+            if !breaker.allow() {
+                self.stats.record_rejected();
+                return Err(KairoError::CircuitOpen);
+            }
+        }
+        if let Some(ref limiter) = self.rate_limiter {
+            limiter.acquire(call.datapoints);
+        }
+        let permit = self.concurrency_limit.as_ref().map(|limit| limit.acquire());
+
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "kairosdb.request",
+            method = %call.method,
+            endpoint = %call.endpoint,
+            metrics = call.metrics as u64,
+            payload_size = tracing::field::Empty,
+            status = tracing::field::Empty,
+            latency_ms = tracing::field::Empty,
+        );
+        #[cfg(feature = "tracing")]
+        let _enter = span.enter();
+
+        let start = Instant::now();
+        let (result, exchange) = send(permit);
+        let latency = start.elapsed();
+
+        #[cfg(feature = "tracing")]
+        {
+            span.record("payload_size", exchange.payload_size as u64);
+            span.record("latency_ms", latency.as_millis() as u64);
+            if let Some(status) = exchange.status {
+                span.record("status", status.as_u16());
+            }
+        }
+        #[cfg(not(feature = "tracing"))]
+        info!("{} {} metrics: {} payload: {} bytes took {:?}",
+              call.method, call.endpoint, call.metrics, exchange.payload_size, latency);
+
+        match exchange.status {
+            Some(status) => self.stats.record_response(status.as_u16(), exchange.payload_size,
+                                                       exchange.response_size, latency),
+            None => self.stats.record_transport_error(exchange.payload_size),
+        }
+        if let Some(ref breaker) = self.circuit_breaker {
+            match (&result, exchange.status) {
+                // aborted by the caller, which says nothing about the server
+                (Err(KairoError::Cancelled), _) | (Err(KairoError::DeadlineExceeded), _) => (),
+                (_, Some(status)) if !status.is_server_error() => breaker.record_success(),
+                _ => breaker.record_failure(),
+            }
+        }
+        result
+    }
+}
+
+fn sorted(mut datapoints: Datapoints) -> Datapoints {
+    datapoints.sort();
+    datapoints
+}
+
+fn query_request(query: &Query, endpoint: &str) -> Result<Request, KairoError> {
+    let request = Request::post(&format!("/api/v1/datapoints/{}", endpoint), query)?;
+    Ok(request.carrying(query.metrics().len(), 0))
+}
+
+/// The operations of a KairosDB client. Application code can accept
+/// `impl KairosClient` so tests can replace the `Client` with a fake.
+///
+/// # Example
+/// ```
+/// use kairosdb::{Client, KairosClient, KairoError};
+///
+/// fn metric_count(client: &impl KairosClient) -> Result<usize, KairoError> {
+///     Ok(client.list_metrics()?.len())
+/// }
+///
+/// let server = kairosdb::testing::StubServer::start();
+/// let client = Client::new("localhost", server.port());
+/// assert!(metric_count(&client).is_ok());
+/// ```
+pub trait KairosClient {
+    /// Returns the version string of the KairosDB Server
+    fn version(&self) -> Result<String, KairoError>;
+
+    /// Returns the health status of the KairosDB Server
+    fn health(&self) -> Result<Vec<String>, KairoError>;
+
+    /// Adds datapoints to the time series database
+    fn add(&self, datapoints: &Datapoints) -> Result<(), KairoError>;
+
+    /// Adds several sets of datapoints to the time series database. The
+    /// default implementation adds them one after another.
+    fn add_batch(&self, batch: &[Datapoints]) -> Result<(), KairoError> {
+        batch.iter().try_for_each(|datapoints| self.add(datapoints))
+    }
+
+    /// Runs a query on the database
+    fn query(&self, query: &Query) -> Result<ResultMap, KairoError>;
+
+    /// Runs a query on the database and passes every series with its
+    /// metric name to `callback`, returning the number of series. The
+    /// default implementation runs `query` and passes its result.
+    fn query_each(&self, query: &Query, callback: &mut dyn FnMut(String, Series))
+                  -> Result<usize, KairoError> {
+        let mut result = self.query(query)?;
+        let mut count = 0;
+        for metric in query.metrics() {
+            for series in result.remove(metric.name()).into_iter().flatten() {
+                callback(metric.name().to_string(), series);
+                count += 1;
             }
-            StatusCode::NO_CONTENT => Ok("".to_string()),
-            _ => Err(KairoError::Kairo(format!("Bad response code: {:?}", response.status()))),
         }
+        Ok(count)
     }
 
+    /// Returns the tag names and values per metric of the datapoints
+    /// matching the query
+    fn query_tags(&self, query: &Query) -> Result<TagsMap, KairoError>;
+
+    /// Deletes the datapoints matching the query
+    fn delete(&self, query: &Query) -> Result<(), KairoError>;
+
+    /// Returns a list with all metric names
+    fn list_metrics(&self) -> Result<Vec<String>, KairoError>;
+
+    /// Deletes a metric with all its datapoints
+    fn delete_metric(&self, metric: &str) -> Result<(), KairoError>;
+
+    /// Returns a list of all tagnames
+    fn tagnames(&self) -> Result<Vec<String>, KairoError>;
+
+    /// Returns a list of all tagvalues
+    fn tagvalues(&self) -> Result<Vec<String>, KairoError>;
+
+    /// Returns the callbacks for background writers using the client.
+    /// The default implementation has none.
+    fn hooks(&self) -> Hooks {
+        Hooks::default()
+    }
+}
+
+impl KairosClient for Client {
+    fn version(&self) -> Result<String, KairoError> {
+        Client::version(self)
+    }
+
+    fn health(&self) -> Result<Vec<String>, KairoError> {
+        Client::health(self)
+    }
+
+    fn add(&self, datapoints: &Datapoints) -> Result<(), KairoError> {
+        Client::add(self, datapoints)
+    }
+
+    fn add_batch(&self, batch: &[Datapoints]) -> Result<(), KairoError> {
+        Client::add_batch(self, batch)
+    }
+
+    fn query(&self, query: &Query) -> Result<ResultMap, KairoError> {
+        Client::query(self, query)
+    }
+
+    fn query_each(&self, query: &Query, callback: &mut dyn FnMut(String, Series))
+                  -> Result<usize, KairoError> {
+        Client::query_each(self, query, callback)
+    }
+
+    fn query_tags(&self, query: &Query) -> Result<TagsMap, KairoError> {
+        Client::query_tags(self, query)
+    }
+
+    fn delete(&self, query: &Query) -> Result<(), KairoError> {
+        Client::delete(self, query)
+    }
+
+    fn list_metrics(&self) -> Result<Vec<String>, KairoError> {
+        Client::list_metrics(self)
+    }
+
+    fn delete_metric(&self, metric: &str) -> Result<(), KairoError> {
+        Client::delete_metric(self, metric)
+    }
+
+    fn tagnames(&self) -> Result<Vec<String>, KairoError> {
+        Client::tagnames(self)
+    }
+
+    fn tagvalues(&self) -> Result<Vec<String>, KairoError> {
+        Client::tagvalues(self)
+    }
 
-    fn parse_query_result(&self, body: &str) -> Result<ResultMap, KairoError> {
-        let result = QueryResult::new();
-        result.parse_result(body)
+    fn hooks(&self) -> Hooks {
+        self.hooks.clone()
     }
 }
@@ -0,0 +1,356 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! In-memory client to test code using a `KairosClient` without a
+//! running KairosDB server
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Mutex;
+
+use chrono::Utc;
+
+use crate::KairosClient;
+use crate::datapoints::Datapoints;
+use crate::error::KairoError;
+use crate::query::{Aggregator, AggregatorType, FilterOp, Metric, Order, Query, TrimMode};
+use crate::result::{ResultMap, Series, TagsMap, Value};
+
+/// Implements the client operations against an in-memory store.
+///
+/// Queries support tag filters and the `avg`, `dev`, `count`, `first`,
+/// `last`, `sum`, `min`, `max`, `diff`, `rate`, `scale`, `div`, `filter`,
+/// `trim` and `save_as` aggregators. Other aggregators leave the
+/// datapoints unchanged. Only grouping by tags is supported. Only
+/// numeric values are stored.
+///
+/// # Example
+/// ```
+/// use kairosdb::KairosClient;
+/// use kairosdb::mock::MockClient;
+/// use kairosdb::datapoints::Datapoints;
+/// use kairosdb::query::*;
+///
+/// let client = MockClient::new();
+/// let mut datapoints = Datapoints::new("myMetric", 0);
+/// datapoints.add_ms(0, 1.0);
+/// datapoints.add_ms(500, 2.0);
+/// datapoints.add_ms(1000, 3.0);
+/// datapoints.add_tag("host", "a");
+/// client.add(&datapoints).unwrap();
+///
+/// let mut query = Query::new(Time::Nanoseconds(0), Time::Nanoseconds(1000));
+/// let aggregator = Aggregator::new(
+///     AggregatorType::AVG,
+///     RelativeTime::new(1, TimeUnit::SECONDS));
+/// query.add(Metric::new("myMetric", Tags::new(), vec![aggregator]));
+///
+/// let result = client.query(&query).unwrap();
+/// let values = &result["myMetric"][0].values;
+/// assert_eq!(values.len(), 2);
+/// assert_eq!(values[0].value, 1.5);
+/// assert_eq!(values[1].time, 1000);
+/// ```
+#[derive(Debug, Default)]
+pub struct MockClient {
+    points: Mutex<Vec<StoredPoint>>,
+}
+
+#[derive(Debug, Default)]
+struct MatchingSeries {
+    tags: BTreeMap<String, BTreeSet<String>>,
+    group: HashMap<String, String>,
+    points: Vec<(i64, f64)>,
+}
+
+#[derive(Debug, Clone)]
+struct StoredPoint {
+    name: String,
+    tags: HashMap<String, String>,
+    time: i64,
+    value: f64,
+}
+
+impl MockClient {
+    /// Creates a new empty `MockClient`
+    pub fn new() -> MockClient {
+        MockClient::default()
+    }
+
+    // Returns the matching points per group of the tags grouped by. An
+    // ungrouped metric always returns one series, even without points.
+    fn matching(&self, query: &Query, metric: &Metric) -> Vec<MatchingSeries> {
+        let (start, end) = query.range_ms(Utc::now().timestamp_millis());
+        let grouped: Vec<&String> = metric.group_by().iter()
+            .filter(|group_by| group_by.name() == "tag")
+            .flat_map(|group_by| group_by.tag_names())
+            .collect();
+
+        let mut groups: BTreeMap<Vec<(String, String)>, MatchingSeries> = BTreeMap::new();
+        if grouped.is_empty() {
+            groups.insert(vec![], MatchingSeries::default());
+        }
+        for point in self.points.lock().unwrap().iter() {
+            if !matches(point, metric, start, end) {
+                continue;
+            }
+            let group: Option<Vec<_>> = grouped.iter()
+                .map(|name| point.tags.get(*name).map(|value| ((*name).clone(), value.clone())))
+                .collect();
+            let group = match group {
+                Some(group) => group,
+                None => continue,
+            };
+            let series = groups.entry(group).or_default();
+            for (name, value) in &point.tags {
+                series.tags.entry(name.clone()).or_default().insert(value.clone());
+            }
+            series.points.push((point.time, point.value));
+        }
+
+        groups.into_iter()
+            .map(|(group, mut series)| {
+                series.group = group.into_iter().collect();
+                series.points.sort_by_key(|point| point.0);
+                if let Some(Order::DESC) = metric.order() {
+                    series.points.reverse();
+                }
+                if let Some(limit) = metric.limit() {
+                    series.points.truncate(limit);
+                }
+                series
+            })
+            .collect()
+    }
+
+    fn save_as(&self, name: &str, tags: Option<&HashMap<String, String>>, points: &[(i64, f64)]) {
+        let tags = tags.cloned().unwrap_or_default();
+        let mut stored = self.points.lock().unwrap();
+        for &(time, value) in points {
+            stored.push(StoredPoint { name: name.to_string(), tags: tags.clone(), time, value });
+        }
+    }
+}
+
+impl KairosClient for MockClient {
+    fn version(&self) -> Result<String, KairoError> {
+        Ok("KairosDB mock".to_string())
+    }
+
+    fn health(&self) -> Result<Vec<String>, KairoError> {
+        Ok(vec!["JVM-Thread-Deadlock: OK".to_string(),
+                "Datastore-Query: OK".to_string()])
+    }
+
+    fn add(&self, datapoints: &Datapoints) -> Result<(), KairoError> {
+        let mut points = self.points.lock().unwrap();
+        let numeric = datapoints.datapoints().iter()
+            .filter_map(|(time, value)| value.as_f64().map(|value| (*time, value)));
+        for (time, value) in numeric {
+            points.push(StoredPoint {
+                name: datapoints.name().to_string(),
+                tags: datapoints.tags().iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+                time,
+                value,
+            });
+        }
+        Ok(())
+    }
+
+    fn query(&self, query: &Query) -> Result<ResultMap, KairoError> {
+        let mut result = ResultMap::new();
+        for metric in query.metrics() {
+            let mut series = Vec::new();
+            for matching in self.matching(query, metric) {
+                let mut points = matching.points;
+                for aggregator in metric.aggregators() {
+                    match aggregator.save_as_target() {
+                        Some((name, tags)) => self.save_as(name, tags, &points),
+                        None => points = aggregate(&points, aggregator),
+                    }
+                }
+                series.push(Series {
+                    tags: matching.tags.into_iter()
+                        .map(|(name, values)| (name, values.into_iter().collect()))
+                        .collect(),
+                    group: matching.group,
+                    values: points.into_iter()
+                        .map(|(time, value)| Value { time: time as u64, value: value.into() })
+                        .collect(),
+                });
+            }
+            result.insert(metric.name().to_string(), series);
+        }
+        Ok(result)
+    }
+
+    fn query_tags(&self, query: &Query) -> Result<TagsMap, KairoError> {
+        let (start, end) = query.range_ms(Utc::now().timestamp_millis());
+        let points = self.points.lock().unwrap();
+        let mut result = TagsMap::new();
+        for metric in query.metrics() {
+            let mut tags: HashMap<String, BTreeSet<String>> = HashMap::new();
+            for point in points.iter().filter(|point| matches(point, metric, start, end)) {
+                for (name, value) in &point.tags {
+                    tags.entry(name.clone()).or_default().insert(value.clone());
+                }
+            }
+            let tags = tags.into_iter()
+                .map(|(name, values)| (name, values.into_iter().collect()))
+                .collect();
+            result.insert(metric.name().to_string(), tags);
+        }
+        Ok(result)
+    }
+
+    fn delete(&self, query: &Query) -> Result<(), KairoError> {
+        let (start, end) = query.range_ms(Utc::now().timestamp_millis());
+        let mut points = self.points.lock().unwrap();
+        for metric in query.metrics() {
+            points.retain(|point| !matches(point, metric, start, end));
+        }
+        Ok(())
+    }
+
+    fn list_metrics(&self) -> Result<Vec<String>, KairoError> {
+        let points = self.points.lock().unwrap();
+        let names: BTreeSet<&String> = points.iter().map(|point| &point.name).collect();
+        Ok(names.into_iter().cloned().collect())
+    }
+
+    fn delete_metric(&self, metric: &str) -> Result<(), KairoError> {
+        self.points.lock().unwrap().retain(|point| point.name != metric);
+        Ok(())
+    }
+
+    fn tagnames(&self) -> Result<Vec<String>, KairoError> {
+        let points = self.points.lock().unwrap();
+        let names: BTreeSet<&String> = points.iter().flat_map(|point| point.tags.keys()).collect();
+        Ok(names.into_iter().cloned().collect())
+    }
+
+    fn tagvalues(&self) -> Result<Vec<String>, KairoError> {
+        let points = self.points.lock().unwrap();
+        let values: BTreeSet<&String> =
+            points.iter().flat_map(|point| point.tags.values()).collect();
+        Ok(values.into_iter().cloned().collect())
+    }
+}
+
+fn matches(point: &StoredPoint, metric: &Metric, start: i64, end: i64) -> bool {
+    point.name == metric.name()
+        && point.time >= start
+        && point.time <= end
+        && metric.tags().iter().all(|(name, values)| {
+            values.is_empty() || point.tags.get(name).is_some_and(|v| values.contains(v))
+        })
+}
+
+// Groups the sorted points into ranges of the sampling size starting at the
+// first point, like KairosDB does without aligned sampling
+fn aggregate(points: &[(i64, f64)], aggregator: &Aggregator) -> Vec<(i64, f64)> {
+    let reduce: fn(&[f64]) -> f64 = match *aggregator.name() {
+        AggregatorType::AVG => |v| v.iter().sum::<f64>() / v.len() as f64,
+        AggregatorType::DEV => deviation,
+        AggregatorType::COUNT => |v| v.len() as f64,
+        AggregatorType::FIRST => |v| v[0],
+        AggregatorType::LAST => |v| v[v.len() - 1],
+        AggregatorType::SUM => |v| v.iter().sum(),
+        AggregatorType::MIN => |v| v.iter().cloned().fold(f64::INFINITY, f64::min),
+        AggregatorType::MAX => |v| v.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+        AggregatorType::DIFF => {
+            return points.windows(2).map(|w| (w[1].0, w[1].1 - w[0].1)).collect()
+        }
+        AggregatorType::RATE => {
+            let unit = aggregator.unit().map_or(1, |unit| unit.as_millis()) as f64;
+            return points.windows(2)
+                .filter(|w| w[1].0 != w[0].0)
+                .map(|w| (w[1].0, (w[1].1 - w[0].1) / (w[1].0 - w[0].0) as f64 * unit))
+                .collect()
+        }
+        AggregatorType::SCALE => {
+            let factor = aggregator.factor().unwrap_or(1.0);
+            return points.iter().map(|&(t, v)| (t, v * factor)).collect()
+        }
+        AggregatorType::DIV => {
+            let divisor = aggregator.divisor().unwrap_or(1.0);
+            return points.iter().map(|&(t, v)| (t, v / divisor)).collect()
+        }
+        AggregatorType::FILTER => return filter(points, aggregator.filter_condition()),
+        AggregatorType::TRIM => return trim(points, aggregator.trim_mode()),
+        _ => return points.to_vec(),
+    };
+
+    let first = match points.first() {
+        Some(point) => point.0,
+        None => return vec![],
+    };
+    let width = aggregator.sampling().map_or(1, |s| s.as_millis()).max(1);
+
+    let mut result = Vec::new();
+    let mut range_start = first;
+    let mut values = Vec::new();
+    for &(time, value) in points {
+        let start = first + (time - first) / width * width;
+        if start != range_start {
+            result.push((range_start, reduce(&values)));
+            values.clear();
+            range_start = start;
+        }
+        values.push(value);
+    }
+    result.push((range_start, reduce(&values)));
+    result
+}
+
+fn filter(points: &[(i64, f64)], filter: Option<(&FilterOp, f64)>) -> Vec<(i64, f64)> {
+    let (op, threshold) = match filter {
+        Some(filter) => filter,
+        None => return points.to_vec(),
+    };
+    points.iter()
+        .filter(|&&(_, v)| !match *op {
+            FilterOp::LT => v < threshold,
+            FilterOp::LTE => v <= threshold,
+            FilterOp::GT => v > threshold,
+            FilterOp::GTE => v >= threshold,
+            FilterOp::EQUAL => (v - threshold).abs() < f64::EPSILON,
+        })
+        .cloned()
+        .collect()
+}
+
+fn trim(points: &[(i64, f64)], mode: Option<&TrimMode>) -> Vec<(i64, f64)> {
+    let (first, last) = match mode {
+        Some(TrimMode::FIRST) => (1, 0),
+        Some(TrimMode::LAST) => (0, 1),
+        Some(TrimMode::BOTH) | None => (1, 1),
+    };
+    if points.len() <= first + last {
+        return vec![];
+    }
+    points[first..points.len() - last].to_vec()
+}
+
+fn deviation(values: &[f64]) -> f64 {
+    if values.len() < 2 {
+        return 0.0;
+    }
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>()
+        / (values.len() - 1) as f64;
+    variance.sqrt()
+}
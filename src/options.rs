@@ -0,0 +1,195 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Per call options like deadlines and cancellation
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Options for a single call of the client
+///
+/// # Example
+/// ```
+/// use std::time::{Duration, Instant};
+/// use kairosdb::options::{RequestOpts, CancelHandle};
+///
+/// let cancel = CancelHandle::new();
+/// let opts = RequestOpts {
+///     deadline: Some(Instant::now() + Duration::from_secs(30)),
+///     cancel: Some(cancel.clone()),
+///     ..Default::default()
+/// };
+///
+/// // fail fast for interactive queries
+/// let interactive = RequestOpts {
+///     timeout: Some(Duration::from_secs(5)),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct RequestOpts {
+    /// Point in time after which the call fails with
+    /// `KairoError::DeadlineExceeded`
+    pub deadline: Option<Instant>,
+    /// Handle to abort the call from another thread
+    pub cancel: Option<CancelHandle>,
+    /// Maximum duration of the HTTP request, after which the call fails
+    /// with `KairoError::Timeout`. Longer requests also need a
+    /// transport without a shorter timeout of its own.
+    pub timeout: Option<Duration>,
+}
+
+/// Handle to cancel an in-flight call from another thread. Clones share
+/// the same state, so cancelling one of them cancels all calls using it.
+///
+/// # Example
+/// ```
+/// use kairosdb::options::CancelHandle;
+///
+/// let cancel = CancelHandle::new();
+/// let other = cancel.clone();
+/// other.cancel();
+/// assert!(cancel.is_cancelled());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+/// Filter of the metric names listed by `Client::list_metrics_filtered`
+///
+/// # Example
+/// ```
+/// use kairosdb::options::MetricFilter;
+///
+/// let filter = MetricFilter {
+///     prefix: Some("kairosdb.".to_string()),
+///     containing: Some("jvm".to_string()),
+/// };
+/// assert!(filter.matches("kairosdb.jvm.free_memory"));
+/// assert!(!filter.matches("kairosdb.http.request_time"));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetricFilter {
+    /// Only names starting with the prefix
+    pub prefix: Option<String>,
+    /// Only names containing the text
+    pub containing: Option<String>,
+}
+
+impl MetricFilter {
+    /// Returns true if `name` passes the filter
+    pub fn matches(&self, name: &str) -> bool {
+        self.prefix.as_ref().is_none_or(|prefix| name.starts_with(prefix.as_str()))
+            && self.containing.as_ref().is_none_or(|text| name.contains(text.as_str()))
+    }
+
+    /// Returns the filter as query string, empty without any filter
+    pub(crate) fn query_string(&self) -> String {
+        let params: Vec<String> = [("prefix", &self.prefix), ("containing", &self.containing)]
+            .iter()
+            .filter_map(|(name, value)| {
+                value.as_ref().map(|value| format!("{}={}", name, encode_query_value(value)))
+            })
+            .collect();
+        if params.is_empty() {
+            String::new()
+        } else {
+            format!("?{}", params.join("&"))
+        }
+    }
+}
+
+/// Pages of the sorted metric names returned by
+/// `Client::list_metrics_pages`. The names are fetched once, iterating
+/// the pages doesn't send further requests.
+#[derive(Debug, Clone)]
+pub struct MetricPages {
+    names: std::vec::IntoIter<String>,
+    page_size: usize,
+}
+
+impl MetricPages {
+    pub(crate) fn new(mut names: Vec<String>, page_size: usize) -> MetricPages {
+        names.sort();
+        MetricPages { names: names.into_iter(), page_size: page_size.max(1) }
+    }
+
+    /// Returns the number of names not yet returned in a page
+    pub fn remaining(&self) -> usize {
+        self.names.len()
+    }
+}
+
+impl Iterator for MetricPages {
+    type Item = Vec<String>;
+
+    fn next(&mut self) -> Option<Vec<String>> {
+        let page: Vec<String> = self.names.by_ref().take(self.page_size).collect();
+        if page.is_empty() {
+            None
+        } else {
+            Some(page)
+        }
+    }
+}
+
+// Percent-encodes all characters but the unreserved ones of RFC 3986
+fn encode_query_value(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+impl RequestOpts {
+    /// Returns the earlier of the deadline and the timeout counted
+    /// from `start`
+    pub(crate) fn deadline_from(&self, start: Instant) -> Option<Instant> {
+        let timeout = self.timeout.map(|timeout| start + timeout);
+        match (self.deadline, timeout) {
+            (Some(deadline), Some(timeout)) => Some(deadline.min(timeout)),
+            (deadline, timeout) => deadline.or(timeout),
+        }
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|c| c.is_cancelled())
+    }
+}
+
+impl CancelHandle {
+    /// Creates a new `CancelHandle`
+    pub fn new() -> CancelHandle {
+        CancelHandle::default()
+    }
+
+    /// Cancels all calls using this handle
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true if the handle was cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
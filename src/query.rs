@@ -16,13 +16,72 @@
 //! Several structs to create and parse queries
 
 use std::collections::HashMap;
+use std::str::FromStr;
 use chrono::{DateTime, Local, Utc};
 
+use crate::error::KairoError;
+use crate::validation::{check_name, tag_field, ValidationError};
+
 /// Internal tag type
 pub type Tags = HashMap<String, Vec<String>>;
 
+/// Creates `Tags` from tag names and their lists of values
+///
+/// ```
+/// # #[macro_use] extern crate kairosdb;
+/// # fn main() {
+/// let tags = tags!{"host" => ["a", "b"], "dc" => ["eu"]};
+/// assert_eq!(tags["host"], vec!["a", "b"]);
+/// assert_eq!(tags["dc"], vec!["eu"]);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! tags {
+    ($($name:expr => [$($value:expr),* $(,)?]),* $(,)?) => {{
+        let mut tags = $crate::query::Tags::new();
+        $(
+            tags.insert($name.to_string(), vec![$($value.to_string()),*]);
+        )*
+        tags
+    }};
+}
+
+/// Helpers to build `Tags` without handling the value lists
+///
+/// ```
+/// use kairosdb::query::{Tags, TagsExt};
+///
+/// let mut tags = Tags::from_pairs(&[("host", "a"), ("host", "b")]);
+/// tags.insert_one("dc", "eu");
+/// assert_eq!(tags["host"], vec!["a", "b"]);
+/// assert_eq!(tags["dc"], vec!["eu"]);
+/// ```
+pub trait TagsExt {
+    /// Creates `Tags` from name and value pairs. Repeated names collect
+    /// all their values.
+    fn from_pairs(pairs: &[(&str, &str)]) -> Self;
+
+    /// Adds a single value to the tag `name`
+    fn insert_one(&mut self, name: &str, value: &str) -> &mut Self;
+}
+
+impl TagsExt for Tags {
+    fn from_pairs(pairs: &[(&str, &str)]) -> Tags {
+        let mut tags = Tags::new();
+        for (name, value) in pairs {
+            tags.insert_one(name, value);
+        }
+        tags
+    }
+
+    fn insert_one(&mut self, name: &str, value: &str) -> &mut Tags {
+        self.entry(name.to_string()).or_default().push(value.to_string());
+        self
+    }
+}
+
 /// Enum for different time units
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum TimeUnit {
     MILLISECONDS,
     SECONDS,
@@ -35,7 +94,7 @@ pub enum TimeUnit {
 }
 
 /// Aggregator methods
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum AggregatorType {
     #[serde(rename = "avg")]
     AVG,
@@ -45,14 +104,62 @@ pub enum AggregatorType {
     COUNT,
     #[serde(rename = "first")]
     FIRST,
+    #[serde(rename = "last")]
+    LAST,
+    #[serde(rename = "sum")]
+    SUM,
+    #[serde(rename = "min")]
+    MIN,
+    #[serde(rename = "max")]
+    MAX,
     #[serde(rename = "gaps")]
     GAPS,
     #[serde(rename = "histogram")]
     HISTOGRAM,
+    #[serde(rename = "diff")]
+    DIFF,
+    #[serde(rename = "trim")]
+    TRIM,
+    #[serde(rename = "save_as")]
+    SAVEAS,
+    #[serde(rename = "rate")]
+    RATE,
+    #[serde(rename = "scale")]
+    SCALE,
+    #[serde(rename = "div")]
+    DIV,
+    #[serde(rename = "filter")]
+    FILTER,
+}
+
+/// Datapoints removed by the trim aggregator
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum TrimMode {
+    #[serde(rename = "first")]
+    FIRST,
+    #[serde(rename = "last")]
+    LAST,
+    #[serde(rename = "both")]
+    BOTH,
+}
+
+/// Comparison of the filter aggregator, matching datapoints are removed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum FilterOp {
+    #[serde(rename = "lt")]
+    LT,
+    #[serde(rename = "lte")]
+    LTE,
+    #[serde(rename = "gt")]
+    GT,
+    #[serde(rename = "gte")]
+    GTE,
+    #[serde(rename = "equal")]
+    EQUAL,
 }
 
 /// JSON representation of a kairosdb query
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 pub struct Query {
     #[serde(skip_serializing_if = "Option::is_none")]
     start_absolute: Option<i64>,
@@ -62,42 +169,139 @@ pub struct Query {
     end_relative: Option<RelativeTime>,
     #[serde(skip_serializing_if = "Option::is_none")]
     start_relative: Option<RelativeTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_time: Option<u32>,
 
     metrics: Vec<Metric>,
 }
 
+/// Builder for a `Query`, created with `Query::builder()`
+#[derive(Debug, Clone, Default)]
+pub struct QueryBuilder {
+    query: Query,
+}
+
+/// Builder for a `Metric`, created with `Metric::builder()`. Aggregators
+/// are appended in the order they are executed.
+///
+/// ```
+/// # use kairosdb::query::{Metric, TimeUnit};
+/// let metric = Metric::builder()
+///     .name("cpu")
+///     .avg(5, TimeUnit::MINUTES)
+///     .sum(1, TimeUnit::HOURS)
+///     .group_by_tag("host")
+///     .build();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MetricBuilder {
+    metric: Metric,
+}
+
 /// JSON representation of the a relative time object
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct RelativeTime {
     value: i64,
     unit: TimeUnit,
 }
 
 /// Type to support the several time definitions for the client
+#[derive(Debug, Clone, PartialEq)]
 pub enum Time {
     UTC(DateTime<Utc>),
     Local(DateTime<Local>),
     Nanoseconds(i64),
     Relative { value: i64, unit: TimeUnit },
+    /// The current time of the KairosDB server when running the query
+    Now,
 }
 
 /// JSON representation of the metric object
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 pub struct Metric {
     tags: Tags,
     name: String,
     aggregators: Vec<Aggregator>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    order: Option<Order>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exclude_tags: Option<bool>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    group_by: Vec<GroupBy>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    plugins: Vec<Plugin>,
+}
+
+/// JSON representation of a query plugin invoked for a metric. Besides
+/// the name a plugin takes arbitrary properties.
+///
+/// ```
+/// # use kairosdb::query::{Metric, Plugin, Tags};
+/// let mut plugin = Plugin::new("my_plugin");
+/// plugin.set("threshold", 10.into());
+/// let mut metric = Metric::new("myMetric", Tags::new(), vec![]);
+/// metric.add_plugin(plugin);
+/// assert_eq!(serde_json::to_string(&metric).unwrap(),
+///            r#"{"tags":{},"name":"myMetric","aggregators":[],"plugins":[{"name":"my_plugin","threshold":10}]}"#);
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Plugin {
+    name: String,
+    #[serde(flatten)]
+    properties: serde_json::Map<String, serde_json::Value>,
+}
+
+/// JSON representation of a group by object
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GroupBy {
+    name: String,
+    tags: Vec<String>,
+}
+
+/// Order of the datapoints returned for a metric
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Order {
+    #[serde(rename = "asc")]
+    ASC,
+    #[serde(rename = "desc")]
+    DESC,
 }
 
 /// JSON representation of the aggregator object
-#[derive(Serialize, Deserialize, Debug)]
+///
+/// Range aggregators like `avg` or `count` are created with `Aggregator::new`
+/// and a sampling. All other aggregators take their own set of parameters
+/// and are created with their own constructor, e.g. `Aggregator::rate`.
+/// Unused parameters are not serialized.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Aggregator {
     name: AggregatorType,
-    sampling: RelativeTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sampling: Option<RelativeTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit: Option<TimeUnit>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    factor: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    divisor: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filter_op: Option<FilterOp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    threshold: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trim: Option<TrimMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metric_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl: Option<u32>,
 }
 
 /// JSON representation of the sampling object
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Sampling {
     value: i64,
     unit: TimeUnit,
@@ -116,63 +320,792 @@ impl Query {
     ///    Time::Relative{value: 1, unit: TimeUnit::WEEKS});
     /// ```
     pub fn new(start: Time, end: Time) -> Query {
+        let (start_absolute, start_relative) = start.split();
+        let (end_absolute, end_relative) = end.split();
         Query {
-            start_absolute: match start {
-                Time::Nanoseconds(n) => Some(n),
-                Time::Local(n) => Some(n.timestamp() * 1000),
-                Time::UTC(n) => Some(n.timestamp() * 1000),
-                _ => None,
-            },
-            end_absolute: match end {
-                Time::Nanoseconds(n) => Some(n),
-                Time::Local(n) => Some(n.timestamp() * 1000),
-                Time::UTC(n) => Some(n.timestamp() * 1000),
-                _ => None,
-            },
-            start_relative: match start {
-                Time::Relative { value, unit } => {
-                    Some(RelativeTime {
-                        value,
-                        unit
-                    })
-                }
-                _ => None,
-            },
-            end_relative: match end {
-                Time::Relative { value, unit } => {
-                    Some(RelativeTime {
-                        value,
-                        unit
-                    })
-                }
-                _ => None,
-            },
+            start_absolute,
+            end_absolute,
+            end_relative,
+            start_relative,
+            cache_time: None,
             metrics: vec![],
         }
     }
 
+    /// Returns a `QueryBuilder` to create a query step by step
+    ///
+    /// ```
+    /// # use kairosdb::query::{Query, Time, TimeUnit};
+    /// let query = Query::builder()
+    ///     .start(Time::Relative{value: 1, unit: TimeUnit::HOURS})
+    ///     .metric(|m| m.name("cpu").tag("host", "a").avg(1, TimeUnit::MINUTES))
+    ///     .metric(|m| m.name("memory").tag("host", "a").tag("host", "b"))
+    ///     .build();
+    ///
+    /// let query = Query::builder()
+    ///     .start(Time::Nanoseconds(1000))
+    ///     .metric(|m| m.name("cpu").avg(1, TimeUnit::MINUTES))
+    ///     .build();
+    /// assert_eq!(serde_json::to_string(&query).unwrap(),
+    ///            r#"{"start_absolute":1000,"metrics":[{"tags":{},"name":"cpu","aggregators":[{"name":"avg","sampling":{"value":1,"unit":"MINUTES"}}]}]}"#);
+    /// ```
+    pub fn builder() -> QueryBuilder {
+        QueryBuilder::default()
+    }
+
     pub fn add(&mut self, metric: Metric) {
         self.metrics.push(metric);
     }
+
+    /// Lets KairosDB cache the query results for `seconds`, so repeated
+    /// queries with the same time range are answered from the cache.
+    ///
+    /// ```
+    /// # use kairosdb::query::{Query, Time};
+    /// let mut query = Query::new(Time::Nanoseconds(1000), Time::Nanoseconds(2000));
+    /// query.set_cache_time(60);
+    /// assert_eq!(serde_json::to_string(&query).unwrap(),
+    ///            r#"{"start_absolute":1000,"end_absolute":2000,"cache_time":60,"metrics":[]}"#);
+    /// ```
+    pub fn set_cache_time(&mut self, seconds: u32) {
+        self.cache_time = Some(seconds);
+    }
+
+    /// Returns the query as indented JSON, as it is sent to the server
+    ///
+    /// ```
+    /// # use kairosdb::query::{Query, Time};
+    /// let query = Query::new(Time::Nanoseconds(1000), Time::Nanoseconds(2000));
+    /// println!("{}", query.to_json_pretty().unwrap());
+    /// ```
+    pub fn to_json_pretty(&self) -> Result<String, KairoError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Sets the absolute or relative start of the query
+    pub fn set_start(&mut self, start: Time) {
+        let (absolute, relative) = start.split();
+        self.start_absolute = absolute;
+        self.start_relative = relative;
+    }
+
+    /// Sets the absolute or relative end of the query
+    pub fn set_end(&mut self, end: Time) {
+        let (absolute, relative) = end.split();
+        self.end_absolute = absolute;
+        self.end_relative = relative;
+    }
+
+    /// Returns the absolute start in milliseconds, if set
+    pub fn start_absolute(&self) -> Option<i64> {
+        self.start_absolute
+    }
+
+    /// Returns the relative start, if set
+    pub fn start_relative(&self) -> Option<&RelativeTime> {
+        self.start_relative.as_ref()
+    }
+
+    /// Returns the absolute end in milliseconds, if set
+    pub fn end_absolute(&self) -> Option<i64> {
+        self.end_absolute
+    }
+
+    /// Returns the relative end, if set
+    pub fn end_relative(&self) -> Option<&RelativeTime> {
+        self.end_relative.as_ref()
+    }
+
+    /// Returns the cache time in seconds, if set
+    pub fn cache_time(&self) -> Option<u32> {
+        self.cache_time
+    }
+
+    /// Returns the metrics of the query
+    ///
+    /// ```
+    /// # use kairosdb::query::{Query, Time, TimeUnit};
+    /// let query = Query::builder()
+    ///     .start(Time::Nanoseconds(1000))
+    ///     .metric(|m| m.name("cpu").tag("host", "a").avg(1, TimeUnit::MINUTES))
+    ///     .build();
+    /// let metric = &query.metrics()[0];
+    /// assert_eq!(metric.name(), "cpu");
+    /// assert_eq!(metric.tags()["host"], vec!["a"]);
+    /// assert_eq!(metric.aggregators()[0].sampling().unwrap().value(), 1);
+    /// ```
+    pub fn metrics(&self) -> &[Metric] {
+        &self.metrics
+    }
+
+    /// Returns the metrics of the query for modification
+    pub fn metrics_mut(&mut self) -> &mut [Metric] {
+        &mut self.metrics
+    }
+
+    /// Returns a copy of the query with other metrics
+    pub(crate) fn with_metrics(&self, metrics: Vec<Metric>) -> Query {
+        Query {
+            metrics,
+            start_relative: self.start_relative.clone(),
+            end_relative: self.end_relative.clone(),
+            ..*self
+        }
+    }
+
+    pub(crate) fn has_start(&self) -> bool {
+        self.start_absolute.is_some() || self.start_relative.is_some()
+    }
+
+    /// Returns the absolute start and end of the query in milliseconds
+    /// resolving relative times against `now`. A missing end is `now`.
+    pub(crate) fn range_ms(&self, now: i64) -> (i64, i64) {
+        let start = match (self.start_absolute, &self.start_relative) {
+            (Some(start), _) => start,
+            (None, Some(relative)) => now.saturating_sub(relative.as_millis()),
+            (None, None) => 0,
+        };
+        let end = match (self.end_absolute, &self.end_relative) {
+            (Some(end), _) => end,
+            (None, Some(relative)) => now.saturating_sub(relative.as_millis()),
+            (None, None) => now,
+        };
+        (start, end)
+    }
+}
+
+impl QueryBuilder {
+    /// Sets the absolute or relative start of the query
+    pub fn start(mut self, start: Time) -> QueryBuilder {
+        self.query.set_start(start);
+        self
+    }
+
+    /// Sets the absolute or relative end of the query, which is now
+    /// when not set
+    pub fn end(mut self, end: Time) -> QueryBuilder {
+        self.query.set_end(end);
+        self
+    }
+
+    /// Lets KairosDB cache the query results for `seconds`
+    pub fn cache_time(mut self, seconds: u32) -> QueryBuilder {
+        self.query.set_cache_time(seconds);
+        self
+    }
+
+    /// Adds a metric configured by the closure on a new `MetricBuilder`
+    pub fn metric<F>(mut self, build: F) -> QueryBuilder
+        where F: FnOnce(MetricBuilder) -> MetricBuilder
+    {
+        self.query.add(build(MetricBuilder::default()).build());
+        self
+    }
+
+    /// Returns the built `Query`
+    pub fn build(self) -> Query {
+        self.query
+    }
+}
+
+impl MetricBuilder {
+    /// Sets the metric name
+    pub fn name(mut self, name: &str) -> MetricBuilder {
+        self.metric.name = name.to_string();
+        self
+    }
+
+    /// Adds a tag value the datapoints must match. Several values of the
+    /// same tag match any of them.
+    pub fn tag(mut self, name: &str, value: &str) -> MetricBuilder {
+        self.metric.tags.insert_one(name, value);
+        self
+    }
+
+    /// Appends an aggregator
+    pub fn aggregator(mut self, aggregator: Aggregator) -> MetricBuilder {
+        self.metric.aggregators.push(aggregator);
+        self
+    }
+
+    /// Appends a range aggregator with a sampling of `value` `unit`
+    pub fn sampled(self, name: AggregatorType, value: i64, unit: TimeUnit) -> MetricBuilder {
+        self.aggregator(Aggregator::new(name, RelativeTime::new(value, unit)))
+    }
+
+    /// Appends an `avg` aggregator with a sampling of `value` `unit`
+    pub fn avg(self, value: i64, unit: TimeUnit) -> MetricBuilder {
+        self.sampled(AggregatorType::AVG, value, unit)
+    }
+
+    /// Appends a `sum` aggregator with a sampling of `value` `unit`
+    pub fn sum(self, value: i64, unit: TimeUnit) -> MetricBuilder {
+        self.sampled(AggregatorType::SUM, value, unit)
+    }
+
+    /// Appends a `min` aggregator with a sampling of `value` `unit`
+    pub fn min(self, value: i64, unit: TimeUnit) -> MetricBuilder {
+        self.sampled(AggregatorType::MIN, value, unit)
+    }
+
+    /// Appends a `max` aggregator with a sampling of `value` `unit`
+    pub fn max(self, value: i64, unit: TimeUnit) -> MetricBuilder {
+        self.sampled(AggregatorType::MAX, value, unit)
+    }
+
+    /// Appends a `count` aggregator with a sampling of `value` `unit`
+    pub fn count(self, value: i64, unit: TimeUnit) -> MetricBuilder {
+        self.sampled(AggregatorType::COUNT, value, unit)
+    }
+
+    /// Appends a `dev` aggregator with a sampling of `value` `unit`
+    pub fn dev(self, value: i64, unit: TimeUnit) -> MetricBuilder {
+        self.sampled(AggregatorType::DEV, value, unit)
+    }
+
+    /// Appends a `first` aggregator with a sampling of `value` `unit`
+    pub fn first(self, value: i64, unit: TimeUnit) -> MetricBuilder {
+        self.sampled(AggregatorType::FIRST, value, unit)
+    }
+
+    /// Appends a `last` aggregator with a sampling of `value` `unit`
+    pub fn last(self, value: i64, unit: TimeUnit) -> MetricBuilder {
+        self.sampled(AggregatorType::LAST, value, unit)
+    }
+
+    /// Appends a `diff` aggregator
+    pub fn diff(self) -> MetricBuilder {
+        self.aggregator(Aggregator::diff())
+    }
+
+    /// Appends a `rate` aggregator per `unit`
+    pub fn rate(self, unit: TimeUnit) -> MetricBuilder {
+        self.aggregator(Aggregator::rate(unit))
+    }
+
+    /// Appends a `scale` aggregator multiplying with `factor`
+    pub fn scale(self, factor: f64) -> MetricBuilder {
+        self.aggregator(Aggregator::scale(factor))
+    }
+
+    /// Appends a `div` aggregator dividing by `divisor`
+    pub fn divide(self, divisor: f64) -> MetricBuilder {
+        self.aggregator(Aggregator::div(divisor))
+    }
+
+    /// Appends a `filter` aggregator removing the matching datapoints
+    pub fn filter(self, op: FilterOp, threshold: f64) -> MetricBuilder {
+        self.aggregator(Aggregator::filter(op, threshold))
+    }
+
+    /// Appends a `trim` aggregator
+    pub fn trim(self, mode: TrimMode) -> MetricBuilder {
+        self.aggregator(Aggregator::trim(mode))
+    }
+
+    /// Groups the datapoints by the values of the tag `name`
+    pub fn group_by_tag(mut self, name: &str) -> MetricBuilder {
+        self.metric.add_group_by(GroupBy::tags(&[name]));
+        self
+    }
+
+    /// Adds a query plugin
+    pub fn plugin(mut self, plugin: Plugin) -> MetricBuilder {
+        self.metric.add_plugin(plugin);
+        self
+    }
+
+    /// Limits the number of returned datapoints
+    pub fn limit(mut self, limit: usize) -> MetricBuilder {
+        self.metric.set_limit(limit);
+        self
+    }
+
+    /// Sets the order of the returned datapoints
+    pub fn order(mut self, order: Order) -> MetricBuilder {
+        self.metric.set_order(order);
+        self
+    }
+
+    /// Omits the tags block of the metric in the response
+    pub fn exclude_tags(mut self, exclude_tags: bool) -> MetricBuilder {
+        self.metric.set_exclude_tags(exclude_tags);
+        self
+    }
+
+    /// Returns the built `Metric`
+    pub fn build(self) -> Metric {
+        self.metric
+    }
 }
 
 impl Metric {
-    /// Creates  a new `Metric` object.
+    /// Returns a `MetricBuilder` to create a metric step by step
+    pub fn builder() -> MetricBuilder {
+        MetricBuilder::default()
+    }
+
+    /// Creates a new `Metric` object if KairosDB accepts the metric name
+    /// and all tag names and values, see `validation::is_valid_name`
+    ///
+    /// ```
+    /// # use kairosdb::query::{Metric, Tags};
+    /// assert!(Metric::try_new("cpu.load", Tags::new(), vec![]).is_ok());
+    /// assert!(Metric::try_new("cpu load", Tags::new(), vec![]).is_err());
+    /// ```
+    pub fn try_new(name: &str, tags: Tags, aggregators: Vec<Aggregator>)
+                   -> Result<Metric, ValidationError> {
+        Metric::check(name, &tags)?;
+        Ok(Metric::unchecked(name, tags, aggregators))
+    }
+
+    /// Creates a new `Metric` object. The name and tags are only checked
+    /// in debug builds, which panic on names the server rejects; use
+    /// `try_new` for names from untrusted input.
     pub fn new(name: &str, tags: Tags, aggregators: Vec<Aggregator>) -> Metric {
+        debug_assert!(Metric::check(name, &tags).is_ok(),
+                      "invalid metric '{}', use Metric::try_new to check it", name);
+        Metric::unchecked(name, tags, aggregators)
+    }
+
+    fn check(name: &str, tags: &Tags) -> Result<(), ValidationError> {
+        check_name("name", name)?;
+        for (tag, values) in tags {
+            let field = tag_field(tag);
+            check_name(&field, tag)?;
+            values.iter().try_for_each(|value| check_name(&field, value))?;
+        }
+        Ok(())
+    }
+
+    fn unchecked(name: &str, tags: Tags, aggregators: Vec<Aggregator>) -> Metric {
         Metric {
             tags,
             name: name.to_string(),
-            aggregators
+            aggregators,
+            limit: None,
+            order: None,
+            exclude_tags: None,
+            group_by: vec![],
+            plugins: vec![],
         }
     }
+
+    /// Limits the number of datapoints returned for the metric. Combined
+    /// with `Order::DESC` only the newest datapoints are returned.
+    ///
+    /// ```
+    /// # use kairosdb::query::{Metric, Order, Tags};
+    /// let mut metric = Metric::new("myMetric", Tags::new(), vec![]);
+    /// metric.set_limit(10);
+    /// metric.set_order(Order::DESC);
+    /// assert_eq!(serde_json::to_string(&metric).unwrap(),
+    ///            r#"{"tags":{},"name":"myMetric","aggregators":[],"limit":10,"order":"desc"}"#);
+    /// ```
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = Some(limit);
+    }
+
+    /// Sets the order of the returned datapoints, ascending by default
+    pub fn set_order(&mut self, order: Order) {
+        self.order = Some(order);
+    }
+
+    /// Omits the tags block of the metric in the response, which shrinks
+    /// the payload of metrics with many tag values.
+    ///
+    /// ```
+    /// # use kairosdb::query::{Metric, Tags};
+    /// let mut metric = Metric::new("myMetric", Tags::new(), vec![]);
+    /// metric.set_exclude_tags(true);
+    /// assert_eq!(serde_json::to_string(&metric).unwrap(),
+    ///            r#"{"tags":{},"name":"myMetric","aggregators":[],"exclude_tags":true}"#);
+    /// ```
+    pub fn set_exclude_tags(&mut self, exclude_tags: bool) {
+        self.exclude_tags = Some(exclude_tags);
+    }
+
+    /// Returns the metric name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the tags the datapoints must match
+    pub fn tags(&self) -> &Tags {
+        &self.tags
+    }
+
+    /// Returns the tags for modification
+    pub fn tags_mut(&mut self) -> &mut Tags {
+        &mut self.tags
+    }
+
+    /// Returns the aggregators in execution order
+    pub fn aggregators(&self) -> &[Aggregator] {
+        &self.aggregators
+    }
+
+    /// Appends an aggregator
+    pub fn add_aggregator(&mut self, aggregator: Aggregator) {
+        self.aggregators.push(aggregator);
+    }
+
+    /// Groups the datapoints of the metric, e.g. by tag
+    ///
+    /// ```
+    /// # use kairosdb::query::{GroupBy, Metric, Tags};
+    /// let mut metric = Metric::new("myMetric", Tags::new(), vec![]);
+    /// metric.add_group_by(GroupBy::tags(&["host"]));
+    /// assert_eq!(serde_json::to_string(&metric).unwrap(),
+    ///            r#"{"tags":{},"name":"myMetric","aggregators":[],"group_by":[{"name":"tag","tags":["host"]}]}"#);
+    /// ```
+    pub fn add_group_by(&mut self, group_by: GroupBy) {
+        self.group_by.push(group_by);
+    }
+
+    /// Adds a query plugin invoked for the metric
+    pub fn add_plugin(&mut self, plugin: Plugin) {
+        self.plugins.push(plugin);
+    }
+
+    /// Returns the query plugins of the metric
+    pub fn plugins(&self) -> &[Plugin] {
+        &self.plugins
+    }
+
+    /// Returns the group bys of the metric
+    pub fn group_by(&self) -> &[GroupBy] {
+        &self.group_by
+    }
+
+    /// Returns the limit of returned datapoints, if set
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Returns the order of returned datapoints, if set
+    pub fn order(&self) -> Option<&Order> {
+        self.order.as_ref()
+    }
+
+    /// Returns whether the tags are omitted in the response, if set
+    pub fn exclude_tags(&self) -> Option<bool> {
+        self.exclude_tags
+    }
 }
 
 impl Aggregator {
     /// Creates a new `Aggregator` object
     pub fn new(name: AggregatorType, sampling: RelativeTime) -> Aggregator {
+        Aggregator {
+            sampling: Some(sampling),
+            ..Aggregator::named(name)
+        }
+    }
+
+    /// Creates a new `diff` aggregator, which computes the difference
+    /// between successive datapoints and takes no sampling.
+    ///
+    /// ```
+    /// # use kairosdb::query::Aggregator;
+    /// let aggregator = Aggregator::diff();
+    /// assert_eq!(serde_json::to_string(&aggregator).unwrap(), r#"{"name":"diff"}"#);
+    /// ```
+    pub fn diff() -> Aggregator {
+        Aggregator::named(AggregatorType::DIFF)
+    }
+
+    /// Creates a new `trim` aggregator, which removes the first, last or
+    /// both outer datapoints, e.g. partial ranges of a previous aggregator.
+    ///
+    /// ```
+    /// # use kairosdb::query::{Aggregator, TrimMode};
+    /// let aggregator = Aggregator::trim(TrimMode::BOTH);
+    /// assert_eq!(serde_json::to_string(&aggregator).unwrap(),
+    ///            r#"{"name":"trim","trim":"both"}"#);
+    /// ```
+    pub fn trim(mode: TrimMode) -> Aggregator {
+        Aggregator {
+            trim: Some(mode),
+            ..Aggregator::named(AggregatorType::TRIM)
+        }
+    }
+
+    /// Creates a new `save_as` aggregator, which writes the aggregated
+    /// datapoints to the metric `metric_name` with the additional `tags`.
+    /// A `ttl` of 0 keeps the datapoints forever.
+    ///
+    /// ```
+    /// # use std::collections::HashMap;
+    /// # use kairosdb::query::Aggregator;
+    /// let mut tags = HashMap::new();
+    /// tags.insert("source".to_string(), "rollup".to_string());
+    /// let aggregator = Aggregator::save_as("cpu.hourly", tags, 0);
+    /// assert_eq!(serde_json::to_string(&aggregator).unwrap(),
+    ///            r#"{"name":"save_as","metric_name":"cpu.hourly","tags":{"source":"rollup"},"ttl":0}"#);
+    /// ```
+    pub fn save_as(metric_name: &str, tags: HashMap<String, String>, ttl: u32) -> Aggregator {
+        Aggregator {
+            metric_name: Some(metric_name.to_string()),
+            tags: Some(tags),
+            ttl: Some(ttl),
+            ..Aggregator::named(AggregatorType::SAVEAS)
+        }
+    }
+
+    /// Creates a new `rate` aggregator, which computes the rate of change
+    /// between successive datapoints per `unit`.
+    ///
+    /// ```
+    /// # use kairosdb::query::{Aggregator, TimeUnit};
+    /// let aggregator = Aggregator::rate(TimeUnit::SECONDS);
+    /// assert_eq!(serde_json::to_string(&aggregator).unwrap(),
+    ///            r#"{"name":"rate","unit":"SECONDS"}"#);
+    /// ```
+    pub fn rate(unit: TimeUnit) -> Aggregator {
+        Aggregator {
+            unit: Some(unit),
+            ..Aggregator::named(AggregatorType::RATE)
+        }
+    }
+
+    /// Creates a new `scale` aggregator, which multiplies each datapoint
+    /// with `factor`.
+    ///
+    /// ```
+    /// # use kairosdb::query::Aggregator;
+    /// let aggregator = Aggregator::scale(0.5);
+    /// assert_eq!(serde_json::to_string(&aggregator).unwrap(),
+    ///            r#"{"name":"scale","factor":0.5}"#);
+    /// ```
+    pub fn scale(factor: f64) -> Aggregator {
+        Aggregator {
+            factor: Some(factor),
+            ..Aggregator::named(AggregatorType::SCALE)
+        }
+    }
+
+    /// Creates a new `div` aggregator, which divides each datapoint by
+    /// `divisor`.
+    pub fn div(divisor: f64) -> Aggregator {
+        Aggregator {
+            divisor: Some(divisor),
+            ..Aggregator::named(AggregatorType::DIV)
+        }
+    }
+
+    /// Creates a new `filter` aggregator, which removes all datapoints
+    /// matching the comparison with `threshold`.
+    ///
+    /// ```
+    /// # use kairosdb::query::{Aggregator, FilterOp};
+    /// let aggregator = Aggregator::filter(FilterOp::LT, 10.0);
+    /// assert_eq!(serde_json::to_string(&aggregator).unwrap(),
+    ///            r#"{"name":"filter","filter_op":"lt","threshold":10.0}"#);
+    /// ```
+    pub fn filter(op: FilterOp, threshold: f64) -> Aggregator {
+        Aggregator {
+            filter_op: Some(op),
+            threshold: Some(threshold),
+            ..Aggregator::named(AggregatorType::FILTER)
+        }
+    }
+
+    fn named(name: AggregatorType) -> Aggregator {
         Aggregator {
             name,
-            sampling
+            sampling: None,
+            unit: None,
+            factor: None,
+            divisor: None,
+            filter_op: None,
+            threshold: None,
+            trim: None,
+            metric_name: None,
+            tags: None,
+            ttl: None
+        }
+    }
+
+    /// Returns the aggregator type
+    pub fn name(&self) -> &AggregatorType {
+        &self.name
+    }
+
+    /// Returns the sampling of range aggregators
+    pub fn sampling(&self) -> Option<&RelativeTime> {
+        self.sampling.as_ref()
+    }
+
+    /// Returns the unit of the `rate` aggregator
+    pub fn unit(&self) -> Option<&TimeUnit> {
+        self.unit.as_ref()
+    }
+
+    /// Returns the factor of the `scale` aggregator
+    pub fn factor(&self) -> Option<f64> {
+        self.factor
+    }
+
+    /// Returns the divisor of the `div` aggregator
+    pub fn divisor(&self) -> Option<f64> {
+        self.divisor
+    }
+
+    /// Returns the comparison of the `filter` aggregator
+    pub fn filter_op(&self) -> Option<&FilterOp> {
+        self.filter_op.as_ref()
+    }
+
+    /// Returns the threshold of the `filter` aggregator
+    pub fn threshold(&self) -> Option<f64> {
+        self.threshold
+    }
+
+    /// Returns the removed datapoints of the `trim` aggregator
+    pub fn trim_mode(&self) -> Option<&TrimMode> {
+        self.trim.as_ref()
+    }
+
+    /// Returns the target metric of the `save_as` aggregator
+    pub fn metric_name(&self) -> Option<&str> {
+        self.metric_name.as_deref()
+    }
+
+    /// Returns the additional tags of the `save_as` aggregator
+    pub fn tags(&self) -> Option<&HashMap<String, String>> {
+        self.tags.as_ref()
+    }
+
+    /// Returns the ttl of the `save_as` aggregator
+    pub fn ttl(&self) -> Option<u32> {
+        self.ttl
+    }
+
+    pub(crate) fn filter_condition(&self) -> Option<(&FilterOp, f64)> {
+        match (&self.filter_op, self.threshold) {
+            (Some(op), Some(threshold)) => Some((op, threshold)),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn save_as_target(&self) -> Option<(&str, Option<&HashMap<String, String>>)> {
+        self.metric_name.as_ref().map(|name| (name.as_str(), self.tags.as_ref()))
+    }
+}
+
+impl Plugin {
+    /// Creates a new `Plugin` without properties
+    pub fn new(name: &str) -> Plugin {
+        Plugin {
+            name: name.to_string(),
+            properties: serde_json::Map::new(),
+        }
+    }
+
+    /// Sets the property `name` of the plugin
+    pub fn set(&mut self, name: &str, value: serde_json::Value) {
+        self.properties.insert(name.to_string(), value);
+    }
+
+    /// Returns the plugin name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the properties of the plugin
+    pub fn properties(&self) -> &serde_json::Map<String, serde_json::Value> {
+        &self.properties
+    }
+}
+
+impl FromStr for Time {
+    type Err = KairoError;
+
+    fn from_str(time: &str) -> Result<Time, KairoError> {
+        Time::parse(time)
+    }
+}
+
+impl GroupBy {
+    /// Creates a new `GroupBy` grouping the datapoints by the values of
+    /// the given tags
+    pub fn tags(tags: &[&str]) -> GroupBy {
+        GroupBy {
+            name: "tag".to_string(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        }
+    }
+
+    /// Returns the kind of grouping, e.g. `tag`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the tag names grouped by
+    pub fn tag_names(&self) -> &[String] {
+        &self.tags
+    }
+}
+
+impl Time {
+    /// Parses an ISO-8601 timestamp like `2020-01-31T12:00:00Z`, `now` or
+    /// a relative time like `1h-ago` or `30d-ago`. Relative times accept
+    /// the units `ms`, `s`, `m`, `h`, `d`, `w`, `mon` and `y`.
+    ///
+    /// ```
+    /// # use kairosdb::query::{Query, Time};
+    /// let start = Time::parse("2h-ago").unwrap();
+    /// let end: Time = "2020-01-31T12:00:00Z".parse().unwrap();
+    /// let query = Query::new(start, end);
+    /// assert_eq!(serde_json::to_string(&query).unwrap(),
+    ///            r#"{"end_absolute":1580472000000,"start_relative":{"value":2,"unit":"HOURS"},"metrics":[]}"#);
+    /// assert!(Time::parse("yesterday").is_err());
+    /// assert!(Time::parse("9999999999999y-ago").is_err());
+    ///
+    /// let query = Query::new(Time::parse("2h-ago").unwrap(), Time::parse("now").unwrap());
+    /// assert_eq!(serde_json::to_string(&query).unwrap(),
+    ///            r#"{"end_relative":{"value":0,"unit":"MILLISECONDS"},"start_relative":{"value":2,"unit":"HOURS"},"metrics":[]}"#);
+    /// ```
+    pub fn parse(time: &str) -> Result<Time, KairoError> {
+        let time = time.trim();
+        if time == "now" {
+            return Ok(Time::Now);
+        }
+        if let Some(relative) = time.strip_suffix("-ago") {
+            let split = relative.find(|c: char| !c.is_ascii_digit()).unwrap_or(relative.len());
+            let (value, unit) = relative.split_at(split);
+            let value: i64 = value.parse()
+                .map_err(|_| KairoError::Kairo(format!("Invalid relative time: {}", time)))?;
+            let unit = match unit.trim() {
+                "ms" => TimeUnit::MILLISECONDS,
+                "s" => TimeUnit::SECONDS,
+                "m" | "min" => TimeUnit::MINUTES,
+                "h" => TimeUnit::HOURS,
+                "d" => TimeUnit::DAYS,
+                "w" => TimeUnit::WEEKS,
+                "mon" => TimeUnit::MONTHS,
+                "y" => TimeUnit::YEARS,
+                _ => return Err(KairoError::Kairo(format!("Invalid time unit: {}", time))),
+            };
+            if value.checked_mul(unit.as_millis()).is_none() {
+                return Err(KairoError::Kairo(format!("Relative time out of range: {}", time)));
+            }
+            return Ok(Time::Relative { value, unit });
+        }
+        DateTime::parse_from_rfc3339(time)
+            .map(|time| Time::UTC(time.with_timezone(&Utc)))
+            .map_err(|err| KairoError::Kairo(format!("Invalid time {}: {}", time, err)))
+    }
+
+    // Returns the absolute time in milliseconds or the relative time
+    fn split(self) -> (Option<i64>, Option<RelativeTime>) {
+        match self {
+            Time::Nanoseconds(n) => (Some(n), None),
+            Time::Local(n) => (Some(n.timestamp_millis()), None),
+            Time::UTC(n) => (Some(n.timestamp_millis()), None),
+            Time::Relative { value, unit } => (None, Some(RelativeTime { value, unit })),
+            Time::Now => (None, Some(RelativeTime { value: 0, unit: TimeUnit::MILLISECONDS })),
         }
     }
 }
@@ -185,4 +1118,44 @@ impl RelativeTime {
             unit
         }
     }
+
+    /// Returns the value in `unit`
+    pub fn value(&self) -> i64 {
+        self.value
+    }
+
+    /// Returns the time unit
+    pub fn unit(&self) -> &TimeUnit {
+        &self.unit
+    }
+
+    /// Returns the relative time in milliseconds, saturating at the
+    /// bounds of `i64`. Months and years are approximated with 30 and
+    /// 365 days.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::query::{RelativeTime, TimeUnit};
+    ///
+    /// assert_eq!(RelativeTime::new(2, TimeUnit::MINUTES).as_millis(), 120_000);
+    /// assert_eq!(RelativeTime::new(i64::MAX, TimeUnit::YEARS).as_millis(), i64::MAX);
+    /// ```
+    pub fn as_millis(&self) -> i64 {
+        self.value.saturating_mul(self.unit.as_millis())
+    }
+}
+
+impl TimeUnit {
+    pub(crate) fn as_millis(&self) -> i64 {
+        match *self {
+            TimeUnit::MILLISECONDS => 1,
+            TimeUnit::SECONDS => 1000,
+            TimeUnit::MINUTES => 60 * 1000,
+            TimeUnit::HOURS => 60 * 60 * 1000,
+            TimeUnit::DAYS => 24 * 60 * 60 * 1000,
+            TimeUnit::WEEKS => 7 * 24 * 60 * 60 * 1000,
+            TimeUnit::MONTHS => 30 * 24 * 60 * 60 * 1000,
+            TimeUnit::YEARS => 365 * 24 * 60 * 60 * 1000,
+        }
+    }
 }
@@ -0,0 +1,122 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Client side rate limiting of requests and datapoints
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Limits the number of requests and datapoints per second the client
+/// sends to the KairosDB server.
+///
+/// A limit of `0` means unlimited. When a limit is reached the calling
+/// thread blocks until enough capacity is available again.
+///
+/// # Example
+/// ```
+/// use kairosdb::Client;
+/// use kairosdb::ratelimit::RateLimiter;
+///
+/// let mut client = Client::new("localhost", 8080);
+/// client.set_rate_limit(RateLimiter::new(100, 10_000));
+/// ```
+#[derive(Debug)]
+pub struct RateLimiter {
+    requests: Option<Mutex<Bucket>>,
+    datapoints: Option<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a new `RateLimiter` allowing `requests_per_sec` requests
+    /// and `datapoints_per_sec` datapoints per second.
+    pub fn new(requests_per_sec: u32, datapoints_per_sec: u32) -> RateLimiter {
+        RateLimiter {
+            requests: Bucket::new(requests_per_sec),
+            datapoints: Bucket::new(datapoints_per_sec),
+        }
+    }
+
+    /// Blocks until one request carrying `datapoints` datapoints
+    /// is allowed to be sent.
+    pub fn acquire(&self, datapoints: usize) {
+        let mut wait = Duration::from_secs(0);
+        if let Some(ref bucket) = self.requests {
+            wait = wait.max(bucket.lock().unwrap().take(1.0));
+        }
+        if let Some(ref bucket) = self.datapoints {
+            if datapoints > 0 {
+                wait = wait.max(bucket.lock().unwrap().take(datapoints as f64));
+            }
+        }
+
+        wait_for(wait);
+    }
+
+    /// Blocks until `datapoints` more datapoints of an already admitted
+    /// request, e.g. a streamed one, are allowed to be sent.
+    pub fn acquire_datapoints(&self, datapoints: usize) {
+        if let Some(ref bucket) = self.datapoints {
+            if datapoints > 0 {
+                let wait = bucket.lock().unwrap().take(datapoints as f64);
+                wait_for(wait);
+            }
+        }
+    }
+}
+
+fn wait_for(wait: Duration) {
+    if wait > Duration::from_secs(0) {
+        debug!("rate limit reached, waiting {:?}", wait);
+        thread::sleep(wait);
+    }
+}
+
+impl Bucket {
+    fn new(rate: u32) -> Option<Mutex<Bucket>> {
+        match rate {
+            0 => None,
+            _ => Some(Mutex::new(Bucket {
+                rate: f64::from(rate),
+                tokens: f64::from(rate),
+                last: Instant::now(),
+            })),
+        }
+    }
+
+    // Takes the given amount of tokens and returns how long the caller has
+    // to wait until they are covered. The bucket may go into debt so large
+    // batches are delayed instead of rejected.
+    fn take(&mut self, amount: f64) -> Duration {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate);
+        self.last = now;
+        self.tokens -= amount;
+
+        if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+}
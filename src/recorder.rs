@@ -0,0 +1,241 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Recorder of the `metrics` crate writing to KairosDB
+//!
+//! Enabled with the `metrics` feature. Labels of the metrics become
+//! tags. Every flush writes
+//!
+//! * the total of every counter,
+//! * the last value of every gauge,
+//! * `count`, `min`, `max`, `mean` and `p90` of the values recorded by
+//!   each histogram since the last flush as metrics with these suffixes.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use metrics::atomics::AtomicU64;
+use metrics::{Counter, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+              SharedString, Unit};
+
+use crate::KairosClient;
+use crate::datapoints::Datapoints;
+use crate::error::KairoError;
+
+/// Recorder buffering the metrics of an application until they are
+/// flushed to KairosDB. Clones share the buffered metrics, so one clone
+/// can be installed as global recorder while another one flushes.
+///
+/// # Example
+/// ```
+/// use kairosdb::KairosClient;
+/// use kairosdb::mock::MockClient;
+/// use kairosdb::recorder::KairosRecorder;
+///
+/// let recorder = KairosRecorder::new().tag("host", "a");
+/// metrics::with_local_recorder(&recorder, || {
+///     metrics::counter!("requests", "status" => "200").increment(3);
+///     metrics::gauge!("connections").set(12.0);
+///     metrics::histogram!("latency").record(20.0);
+///     metrics::histogram!("latency").record(40.0);
+/// });
+///
+/// let client = MockClient::new();
+/// assert_eq!(recorder.flush(&client).unwrap(), 7);
+/// assert_eq!(client.list_metrics().unwrap(), vec![
+///     "connections", "latency.count", "latency.max", "latency.mean", "latency.min",
+///     "latency.p90", "requests",
+/// ]);
+/// assert_eq!(client.tagvalues().unwrap(), vec!["200", "a"]);
+/// ```
+///
+/// Installed as global recorder and flushed every ten seconds:
+/// ```no_run
+/// use std::thread;
+/// use std::time::Duration;
+/// use kairosdb::Client;
+/// use kairosdb::recorder::KairosRecorder;
+///
+/// let recorder = KairosRecorder::new().tag("service", "billing");
+/// metrics::set_global_recorder(recorder.clone()).unwrap();
+/// let client = Client::new("localhost", 8080);
+/// thread::spawn(move || recorder.run(&client, Duration::from_secs(10)));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct KairosRecorder {
+    tags: Vec<(Arc<str>, Arc<str>)>,
+    registry: Arc<Registry>,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    counters: Mutex<HashMap<Key, Arc<AtomicU64>>>,
+    gauges: Mutex<HashMap<Key, Arc<AtomicU64>>>,
+    histograms: Mutex<HashMap<Key, Arc<Samples>>>,
+    // keys skipped because they have no tags, warned about once
+    untagged: Mutex<HashSet<Key>>,
+}
+
+// Values recorded by a histogram since the last flush
+#[derive(Debug, Default)]
+struct Samples(Mutex<Vec<f64>>);
+
+impl HistogramFn for Samples {
+    fn record(&self, value: f64) {
+        if value.is_finite() {
+            self.0.lock().unwrap().push(value);
+        }
+    }
+}
+
+impl KairosRecorder {
+    /// Creates a new `KairosRecorder` without metrics
+    pub fn new() -> KairosRecorder {
+        KairosRecorder::default()
+    }
+
+    /// Adds a tag to all written datapoints. KairosDB needs at least one
+    /// tag per datapoint, so metrics without labels need one.
+    pub fn tag(mut self, name: &str, value: &str) -> KairosRecorder {
+        self.tags.push((name.into(), value.into()));
+        self
+    }
+
+    /// Returns the metrics as datapoints at the time `ms` and resets the
+    /// histograms. KairosDB rejects datapoints without tags, so metrics
+    /// without labels are skipped with a warning if the recorder has no
+    /// tags either.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::recorder::KairosRecorder;
+    ///
+    /// let recorder = KairosRecorder::new();
+    /// metrics::with_local_recorder(&recorder, || {
+    ///     metrics::counter!("requests", "status" => "200").increment(3);
+    ///     metrics::gauge!("connections").set(12.0);
+    /// });
+    /// // the gauge without labels is skipped
+    /// assert_eq!(recorder.drain(1000).len(), 1);
+    /// assert_eq!(recorder.clone().tag("host", "a").drain(1000).len(), 2);
+    /// ```
+    pub fn drain(&self, ms: i64) -> Vec<Datapoints> {
+        let mut values = Vec::new();
+        for (key, counter) in self.registry.counters.lock().unwrap().iter() {
+            let value = counter.load(Ordering::Acquire) as f64;
+            values.push((key.clone(), key.name().to_string(), value));
+        }
+        for (key, gauge) in self.registry.gauges.lock().unwrap().iter() {
+            let value = f64::from_bits(gauge.load(Ordering::Acquire));
+            values.push((key.clone(), key.name().to_string(), value));
+        }
+        for (key, samples) in self.registry.histograms.lock().unwrap().iter() {
+            let mut samples = std::mem::take(&mut *samples.0.lock().unwrap());
+            if samples.is_empty() {
+                continue;
+            }
+            samples.sort_by(f64::total_cmp);
+            let count = samples.len();
+            let p90 = samples[((count as f64 * 0.9).ceil() as usize).max(1) - 1];
+            let name = key.name();
+            values.push((key.clone(), format!("{}.count", name), count as f64));
+            values.push((key.clone(), format!("{}.min", name), samples[0]));
+            values.push((key.clone(), format!("{}.max", name), samples[count - 1]));
+            values.push((key.clone(), format!("{}.mean", name),
+                         samples.iter().sum::<f64>() / count as f64));
+            values.push((key.clone(), format!("{}.p90", name), p90));
+        }
+
+        values.into_iter()
+            .filter(|(key, _, _)| self.is_tagged(key))
+            .map(|(key, name, value)| {
+                let mut datapoints = Datapoints::new(&name, 0);
+                for (name, value) in &self.tags {
+                    datapoints.add_shared_tag(name, value);
+                }
+                for label in key.labels() {
+                    if let Err(err) = datapoints.try_add_tag(label.key(), label.value()) {
+                        warn!("skipping label of metric '{}': {}", key.name(), err);
+                    }
+                }
+                datapoints.add_value(ms, value);
+                datapoints
+            })
+            .collect()
+    }
+
+    // Returns true if datapoints of the key get at least one tag
+    fn is_tagged(&self, key: &Key) -> bool {
+        if !self.tags.is_empty() || key.labels().len() > 0 {
+            return true;
+        }
+        if self.registry.untagged.lock().unwrap().insert(key.clone()) {
+            warn!("skipping metric '{}' without labels, the recorder has no tags", key.name());
+        }
+        false
+    }
+
+    /// Writes the metrics with `client` and returns the number of written
+    /// datapoints
+    pub fn flush(&self, client: &dyn KairosClient) -> Result<usize, KairoError> {
+        let batch = self.drain(Utc::now().timestamp_millis());
+        if !batch.is_empty() {
+            client.add_batch(&batch)?;
+        }
+        Ok(batch.len())
+    }
+
+    /// Flushes the metrics every `flush_interval` and never returns.
+    /// Failed flushes are logged, reported to the error hook of `client`
+    /// and their histogram values dropped.
+    pub fn run(&self, client: &dyn KairosClient, flush_interval: Duration) {
+        let mut next_flush = Instant::now() + flush_interval;
+        loop {
+            thread::sleep(next_flush.saturating_duration_since(Instant::now()));
+            if let Err(err) = self.flush(client) {
+                error!("writing metrics failed: {:?}", err);
+                client.hooks().error(&err);
+            }
+            next_flush += flush_interval;
+        }
+    }
+}
+
+impl Recorder for KairosRecorder {
+    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
+        let mut counters = self.registry.counters.lock().unwrap();
+        Counter::from_arc(counters.entry(key.clone()).or_default().clone())
+    }
+
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        let mut gauges = self.registry.gauges.lock().unwrap();
+        Gauge::from_arc(gauges.entry(key.clone()).or_default().clone())
+    }
+
+    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        let mut histograms = self.registry.histograms.lock().unwrap();
+        Histogram::from_arc(histograms.entry(key.clone()).or_default().clone())
+    }
+}
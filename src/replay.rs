@@ -0,0 +1,177 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Transports to record and replay HTTP fixtures
+//!
+//! The `RecordingTransport` wraps a real transport and writes every request
+//! and response to a JSON fixture file. The `ReplayTransport` answers
+//! requests from such a file, so tests with complex queries run
+//! deterministically without a server.
+//!
+//! ```
+//! use kairosdb::Client;
+//! use kairosdb::replay::{RecordingTransport, ReplayTransport};
+//! use kairosdb::testing::StubServer;
+//! use kairosdb::transport::ReqwestTransport;
+//!
+//! let fixture = std::env::temp_dir().join("kairosdb-version-fixture.json");
+//! let server = StubServer::start();
+//! let recording = RecordingTransport::new(ReqwestTransport::new(), &fixture);
+//! let client = Client::with_transport("localhost", server.port(), recording);
+//! let version = client.version().unwrap();
+//!
+//! let replay = ReplayTransport::load(&fixture).unwrap();
+//! let client = Client::with_transport("localhost", 8080, replay);
+//! assert_eq!(client.version().unwrap(), version);
+//! ```
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde_json::Value;
+
+use crate::error::KairoError;
+use crate::transport::{HttpRequest, HttpResponse, StatusCode, Transport};
+
+/// A recorded request and response pair
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Exchange {
+    method: String,
+    path: String,
+    request: Value,
+    status: u16,
+    response: Value,
+}
+
+/// Transport recording all exchanges of the inner transport to a fixture
+/// file. The file is rewritten after every exchange.
+#[derive(Debug)]
+pub struct RecordingTransport<T: Transport> {
+    inner: T,
+    path: PathBuf,
+    exchanges: Mutex<Vec<Exchange>>,
+}
+
+/// Transport answering requests from a fixture file. Requests are matched
+/// by method, path and JSON body; repeated requests get the recorded
+/// responses in order.
+#[derive(Debug)]
+pub struct ReplayTransport {
+    exchanges: Vec<Exchange>,
+    used: Mutex<Vec<bool>>,
+}
+
+impl<T: Transport> RecordingTransport<T> {
+    /// Creates a new `RecordingTransport` writing to the file at `path`
+    pub fn new<P: AsRef<Path>>(inner: T, path: P) -> RecordingTransport<T> {
+        RecordingTransport {
+            inner,
+            path: path.as_ref().to_path_buf(),
+            exchanges: Mutex::new(vec![]),
+        }
+    }
+
+    /// Returns the exchanges recorded so far
+    pub fn exchanges(&self) -> Vec<Exchange> {
+        self.exchanges.lock().unwrap().clone()
+    }
+}
+
+impl<T: Transport> Transport for RecordingTransport<T> {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, KairoError> {
+        let method = request.method.to_string();
+        let path = path_of(&request.url);
+        let body = json_or_string(&String::from_utf8_lossy(&request.body));
+
+        let response = self.inner.send(request)?;
+
+        let mut exchanges = self.exchanges.lock().unwrap();
+        exchanges.push(Exchange {
+            method,
+            path,
+            request: body,
+            status: response.status.as_u16(),
+            response: json_or_string(&response.body),
+        });
+        serde_json::to_writer_pretty(File::create(&self.path)?, &*exchanges)?;
+        Ok(response)
+    }
+}
+
+impl ReplayTransport {
+    /// Loads the fixture file written by a `RecordingTransport`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ReplayTransport, KairoError> {
+        let exchanges: Vec<Exchange> = serde_json::from_reader(File::open(path)?)?;
+        Ok(ReplayTransport::new(exchanges))
+    }
+
+    /// Creates a new `ReplayTransport` from the given exchanges
+    pub fn new(exchanges: Vec<Exchange>) -> ReplayTransport {
+        let used = vec![false; exchanges.len()];
+        ReplayTransport {
+            exchanges,
+            used: Mutex::new(used),
+        }
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, KairoError> {
+        let method = request.method.to_string();
+        let path = path_of(&request.url);
+        let body = json_or_string(&String::from_utf8_lossy(&request.body));
+
+        let matching: Vec<usize> = self.exchanges.iter().enumerate()
+            .filter(|(_, e)| e.method == method && e.path == path && e.request == body)
+            .map(|(i, _)| i)
+            .collect();
+
+        let mut used = self.used.lock().unwrap();
+        let index = match matching.iter().find(|i| !used[**i]).or_else(|| matching.last()) {
+            Some(index) => *index,
+            None => {
+                return Err(KairoError::Kairo(
+                    format!("No recorded response for {} {}", method, path)))
+            }
+        };
+        used[index] = true;
+
+        let exchange = &self.exchanges[index];
+        let status = StatusCode::from_u16(exchange.status)
+            .map_err(|err| KairoError::Kairo(err.to_string()))?;
+        let body = match exchange.response {
+            Value::Null => String::new(),
+            Value::String(ref body) => body.clone(),
+            ref body => body.to_string(),
+        };
+        Ok(HttpResponse { status, body })
+    }
+}
+
+// Strips scheme and host so fixtures can be replayed against any server
+fn path_of(url: &str) -> String {
+    match url.splitn(4, '/').nth(3) {
+        Some(path) => format!("/{}", path),
+        None => "/".to_string(),
+    }
+}
+
+fn json_or_string(body: &str) -> Value {
+    if body.is_empty() {
+        return Value::Null;
+    }
+    serde_json::from_str(body).unwrap_or_else(|_| Value::String(body.to_string()))
+}
@@ -0,0 +1,361 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+use crate::concurrency::Permit;
+use crate::error::{KairoError, RequestContext};
+use crate::options::RequestOpts;
+use crate::transport::{HttpRequest, HttpResponse, Method, StatusCode, Transport};
+
+/// Largest buffer kept by `BodyBuffer`
+const MAX_BODY_BUFFER: usize = 8 * 1024 * 1024;
+
+/// Interval in which deadlines and cancellation are checked
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A single HTTP request against the KairosDB REST API
+pub struct Request {
+    pub method: Method,
+    pub endpoint: String,
+    pub body: Vec<u8>,
+    pub metrics: usize,
+    pub datapoints: usize,
+    /// Buffer the body is given back to once it was sent
+    pub buffer: Option<BodyBuffer>,
+}
+
+impl Request {
+    pub fn get(endpoint: &str) -> Request {
+        Request::new(Method::GET, endpoint)
+    }
+
+    pub fn delete(endpoint: &str) -> Request {
+        Request::new(Method::DELETE, endpoint)
+    }
+
+    pub fn post<T: Serialize + ?Sized>(endpoint: &str, body: &T) -> Result<Request, KairoError> {
+        Ok(Request::raw(Method::POST, endpoint, serde_json::to_vec(body)?))
+    }
+
+    /// Creates a POST request serializing `body` with the reused `buffer`
+    pub fn post_buffered<T: Serialize + ?Sized>(endpoint: &str, body: &T, buffer: &BodyBuffer)
+                                                -> Result<Request, KairoError> {
+        let mut request = Request::raw(Method::POST, endpoint, buffer.serialize(body)?);
+        request.buffer = Some(buffer.clone());
+        Ok(request)
+    }
+
+    pub fn raw(method: Method, endpoint: &str, body: Vec<u8>) -> Request {
+        let mut request = Request::new(method, endpoint);
+        request.body = body;
+        request
+    }
+
+    /// Sets the number of metrics and datapoints carried by the body
+    pub fn carrying(mut self, metrics: usize, datapoints: usize) -> Request {
+        self.metrics = metrics;
+        self.datapoints = datapoints;
+        self
+    }
+
+    /// Turns the request into a HTTP request against the given server
+    pub fn into_http(self, base_url: &str) -> HttpRequest {
+        HttpRequest {
+            method: self.method,
+            url: format!("{}{}", base_url, self.endpoint),
+            body: self.body,
+        }
+    }
+
+    fn new(method: Method, endpoint: &str) -> Request {
+        Request {
+            method,
+            endpoint: endpoint.to_string(),
+            body: Vec::new(),
+            metrics: 0,
+            datapoints: 0,
+            buffer: None,
+        }
+    }
+}
+
+/// Buffer reused to serialize request bodies, so writes of similar sizes
+/// don't allocate a new body each. Clones share the buffer.
+#[derive(Clone, Default)]
+pub struct BodyBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl fmt::Debug for BodyBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let capacity = self.0.try_lock().map(|buffer| buffer.capacity()).ok();
+        f.debug_struct("BodyBuffer").field("capacity", &capacity).finish()
+    }
+}
+
+impl BodyBuffer {
+    /// Takes the buffer out and serializes `body` into it, so the body is
+    /// sent without a copy. `restore` gives the buffer back once the
+    /// transport is done with it. While another request holds the
+    /// buffer, `body` is serialized into a new one.
+    pub fn serialize<T: Serialize + ?Sized>(&self, body: &T) -> Result<Vec<u8>, KairoError> {
+        let mut buffer = std::mem::take(&mut *self.0.lock().unwrap());
+        buffer.clear();
+        match serde_json::to_writer(&mut buffer, body) {
+            Ok(()) => Ok(buffer),
+            Err(err) => {
+                self.restore(buffer);
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Keeps `body` for the next request, unless it grew beyond
+    /// `MAX_BODY_BUFFER` or the buffer already holds a larger one
+    pub fn restore(&self, body: Vec<u8>) {
+        if body.capacity() > MAX_BODY_BUFFER {
+            return;
+        }
+        let mut buffer = self.0.lock().unwrap();
+        if body.capacity() > buffer.capacity() {
+            *buffer = body;
+        }
+    }
+}
+
+/// A response of the server together with the request it answers
+pub struct Response {
+    pub status: StatusCode,
+    pub body: String,
+    pub request: RequestContext,
+}
+
+impl Response {
+    pub fn new(response: HttpResponse, request: RequestContext) -> Response {
+        Response { status: response.status, body: response.body, request }
+    }
+
+    /// Returns the error for an unexpected status code of the response
+    pub fn error(&self) -> KairoError {
+        KairoError::from_response(self.status, &self.body).with_request(&self.request)
+    }
+
+    /// Returns the error for an unexpected status code of the response
+    /// to a datapoint write
+    pub fn write_error(&self) -> KairoError {
+        KairoError::from_write_response(self.status, &self.body).with_request(&self.request)
+    }
+}
+
+/// Request body serializing the items of an iterator as JSON array one
+/// item at a time while the transport reads it
+pub struct JsonArrayReader<I> {
+    items: I,
+    buffer: Vec<u8>,
+    position: usize,
+    started: bool,
+    finished: bool,
+    sent: Arc<AtomicUsize>,
+}
+
+impl<I> JsonArrayReader<I> {
+    /// Creates a new reader; `sent` counts the bytes read so far
+    pub fn new(items: I, sent: Arc<AtomicUsize>) -> JsonArrayReader<I> {
+        JsonArrayReader {
+            items,
+            buffer: Vec::new(),
+            position: 0,
+            started: false,
+            finished: false,
+            sent,
+        }
+    }
+}
+
+impl<I, T> Read for JsonArrayReader<I>
+    where I: Iterator<Item = T>, T: Serialize
+{
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.buffer.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.buffer.clear();
+            self.position = 0;
+            match self.items.next() {
+                Some(item) => {
+                    self.buffer.push(if self.started { b',' } else { b'[' });
+                    self.started = true;
+                    serde_json::to_writer(&mut self.buffer, &item)?;
+                }
+                None => {
+                    if !self.started {
+                        self.buffer.push(b'[');
+                    }
+                    self.buffer.push(b']');
+                    self.finished = true;
+                }
+            }
+        }
+
+        let count = out.len().min(self.buffer.len() - self.position);
+        out[..count].copy_from_slice(&self.buffer[self.position..self.position + count]);
+        self.position += count;
+        self.sent.fetch_add(count, Ordering::Relaxed);
+        Ok(count)
+    }
+}
+
+/// Reader counting the bytes read from the inner reader
+pub struct CountingReader<R> {
+    inner: R,
+    count: usize,
+}
+
+impl<R> CountingReader<R> {
+    pub fn new(inner: R) -> CountingReader<R> {
+        CountingReader { inner, count: 0 }
+    }
+
+    /// Returns the number of bytes read so far
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(out)?;
+        self.count += count;
+        Ok(count)
+    }
+}
+
+/// Sends the request using the transport. When a deadline, timeout or
+/// cancel handle is given the request runs in its own thread, so the caller can
+/// return as soon as the call is aborted. The thread keeps `permit` until
+/// the transport returns, so aborted requests still count against the
+/// concurrency limit. Requests which are already cancelled or past their
+/// deadline are not sent at all. The body is given back to `buffer` once
+/// the transport returns.
+pub fn execute(transport: &Arc<dyn Transport>, request: HttpRequest, buffer: Option<BodyBuffer>,
+               opts: &RequestOpts, permit: Option<Permit>) -> Result<HttpResponse, KairoError> {
+    let start = Instant::now();
+    let endpoint = endpoint(&request.url);
+    if let Some(err) = aborted(opts, start, &endpoint) {
+        return Err(err);
+    }
+    let deadline = opts.deadline_from(start);
+    if deadline.is_none() && opts.cancel.is_none() {
+        let response = send(&**transport, request, buffer);
+        drop(permit);
+        return response.map_err(|err| err.timed_out(start.elapsed(), &endpoint));
+    }
+
+    let transport = transport.clone();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let response = send(&*transport, request, buffer);
+        drop(permit);
+        let _ = sender.send(response);
+    });
+
+    loop {
+        if let Some(err) = aborted(opts, start, &endpoint) {
+            return Err(err);
+        }
+        let mut wait = POLL_INTERVAL;
+        if let Some(deadline) = deadline {
+            wait = wait.min(deadline.saturating_duration_since(Instant::now()));
+        }
+
+        match receiver.recv_timeout(wait) {
+            Ok(response) => {
+                return response.map_err(|err| err.timed_out(start.elapsed(), &endpoint))
+            }
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(KairoError::Kairo("Request thread terminated".to_string()))
+            }
+        }
+    }
+}
+
+fn send(transport: &dyn Transport, request: HttpRequest, buffer: Option<BodyBuffer>)
+        -> Result<HttpResponse, KairoError> {
+    let response = transport.send(&request);
+    if let Some(buffer) = buffer {
+        buffer.restore(request.body);
+    }
+    response
+}
+
+/// Returns the error of a request started at `start` which is cancelled
+/// or past its deadline or timeout
+pub fn aborted(opts: &RequestOpts, start: Instant, endpoint: &str) -> Option<KairoError> {
+    let now = Instant::now();
+    if opts.is_cancelled() {
+        Some(KairoError::Cancelled)
+    } else if opts.deadline.is_some_and(|deadline| now >= deadline) {
+        Some(KairoError::DeadlineExceeded)
+    } else if opts.deadline_from(start).is_some_and(|deadline| now >= deadline) {
+        Some(KairoError::Timeout {
+            elapsed: now - start,
+            endpoint: endpoint.to_string(),
+            source: None,
+            request: None,
+        })
+    } else {
+        None
+    }
+}
+
+/// Request body failing once the request is aborted by its options, so
+/// streamed uploads stop between two reads
+pub struct AbortableReader<R> {
+    inner: R,
+    opts: RequestOpts,
+    start: Instant,
+}
+
+impl<R> AbortableReader<R> {
+    pub fn new(inner: R, opts: &RequestOpts) -> AbortableReader<R> {
+        AbortableReader { inner, opts: opts.clone(), start: Instant::now() }
+    }
+}
+
+impl<R: Read> Read for AbortableReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if aborted(&self.opts, self.start, "").is_some() {
+            return Err(io::Error::other("request aborted"));
+        }
+        self.inner.read(out)
+    }
+}
+
+/// Returns the path of a request URL, e.g. `/api/v1/datapoints`
+pub fn endpoint(url: &str) -> String {
+    url.parse::<http::Uri>()
+        .map(|uri| uri.path().to_string())
+        .unwrap_or_else(|_| url.to_string())
+}
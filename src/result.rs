@@ -16,57 +16,669 @@
 extern crate serde_json;
 
 use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
 
+use chrono::{DateTime, TimeZone, Utc};
+use serde::de::{self, Deserialize, Deserializer, DeserializeSeed, IgnoredAny, MapAccess,
+                SeqAccess, Visitor};
+
+use crate::datapoints::DataPointValue;
 use crate::error::KairoError;
+use crate::query::Tags;
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 pub struct QueryResult {
     queries: Vec<Query>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Query {
     sample_size: i64,
     results: Vec<ResultValues>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ResultValues {
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct ResultValues {
     name: String,
-    values: Vec<Vec<f64>>,
+    values: Vec<(u64, DataPointValue)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct ResultGroup {
+    name: String,
+    #[serde(default)]
+    group: HashMap<String, serde_json::Value>,
+}
+
+/// A datapoint of a query result with its time in milliseconds or, for
+/// results parsed with `QueryResult::parse_result_utc`, as `DateTime`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Value<T = u64> {
+    pub time: T,
+    pub value: DataPointValue,
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+        let (time, value) = <(u64, DataPointValue)>::deserialize(deserializer)?;
+        Ok(Value { time, value })
+    }
+}
+
+impl Value {
+    /// Returns the time of the datapoint, `None` if it is out of the
+    /// range of `DateTime`
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::result::Value;
+    ///
+    /// let value = Value { time: 1475513259000, value: 1.0.into() };
+    /// assert_eq!(value.datetime().unwrap().to_rfc3339(), "2016-10-03T16:47:39+00:00");
+    /// ```
+    pub fn datetime(&self) -> Option<DateTime<Utc>> {
+        Utc.timestamp_millis_opt(self.time as i64).single()
+    }
+}
+
+/// A series of datapoints returned for a metric. A metric grouped by
+/// tags returns a series per group.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Series<T = u64> {
+    /// Tag names with all their values in the series
+    pub tags: Tags,
+    /// Tag values of the group, empty without group by
+    pub group: HashMap<String, String>,
+    /// Datapoints of the series in the order returned by the server
+    pub values: Vec<Value<T>>,
+}
+
+impl<T> Series<T> {
+    /// Adds the tags and values of `other` to the series
+    pub fn merge(&mut self, other: Series<T>) {
+        for (name, values) in other.tags {
+            let merged = self.tags.entry(name).or_default();
+            for value in values {
+                if !merged.contains(&value) {
+                    merged.push(value);
+                }
+            }
+        }
+        self.values.extend(other.values);
+    }
+}
+
+impl<T: Copy> Series<T> {
+    /// Returns an iterator over the tags, times and values of the series
+    pub fn iter_points(&self) -> impl Iterator<Item = (&Tags, T, &DataPointValue)> {
+        self.values.iter().map(move |value| (&self.tags, value.time, &value.value))
+    }
+}
+
+/// Summary statistics over the numeric values of a series. Values which
+/// are not numeric are skipped.
+///
+/// # Example
+/// ```
+/// use kairosdb::result::{Series, Value};
+///
+/// let series = Series {
+///     values: (1..=10).map(|i| Value { time: i * 1000, value: i.into() }).collect(),
+///     ..Default::default()
+/// };
+/// assert_eq!(series.count(), 10);
+/// assert_eq!(series.sum(), 55.0);
+/// assert_eq!(series.min(), Some(1.0));
+/// assert_eq!(series.max(), Some(10.0));
+/// assert_eq!(series.mean(), Some(5.5));
+/// assert_eq!(series.percentile(0.9), Some(9.0));
+/// assert_eq!(Series::<u64>::default().mean(), None);
+/// ```
+impl<T> Series<T> {
+    fn numbers(&self) -> impl Iterator<Item = f64> + '_ {
+        self.values.iter().filter_map(|value| value.value.as_f64())
+    }
+
+    /// Returns the number of numeric values
+    pub fn count(&self) -> usize {
+        self.numbers().count()
+    }
+
+    /// Returns the sum of the values, 0 for a series without values
+    pub fn sum(&self) -> f64 {
+        self.numbers().sum()
+    }
+
+    /// Returns the smallest value
+    pub fn min(&self) -> Option<f64> {
+        self.numbers().min_by(f64::total_cmp)
+    }
+
+    /// Returns the largest value
+    pub fn max(&self) -> Option<f64> {
+        self.numbers().max_by(f64::total_cmp)
+    }
+
+    /// Returns the arithmetic mean of the values
+    pub fn mean(&self) -> Option<f64> {
+        match self.count() {
+            0 => None,
+            count => Some(self.sum() / count as f64),
+        }
+    }
+
+    /// Returns the value below which the fraction `p` of the values
+    /// falls, like the `percentile` aggregator of KairosDB with `p`
+    /// between 0 and 1, using the nearest rank
+    pub fn percentile(&self, p: f64) -> Option<f64> {
+        let mut numbers: Vec<f64> = self.numbers().collect();
+        if numbers.is_empty() {
+            return None;
+        }
+        numbers.sort_by(f64::total_cmp);
+        let rank = (numbers.len() as f64 * p.clamp(0.0, 1.0)).ceil() as usize;
+        Some(numbers[rank.max(1) - 1])
+    }
 }
 
-#[derive(Debug)]
-pub struct Value {
-    pub time: u64,
-    pub value: f64,
+/// Iteration over the series of a `ResultMap` or `UtcResultMap` without
+/// indexing into the nested vectors. The metrics come in no particular
+/// order.
+///
+/// # Example
+/// ```
+/// use kairosdb::result::{QueryResult, ResultIter};
+///
+/// let body = r#"{"queries":[{"sample_size":2,"results":[
+///     {"name":"load","tags":{"host":["a"]},"values":[[1000,0.5]]},
+///     {"name":"load","tags":{"host":["b"]},"values":[[1000,0.7]]}]}]}"#;
+/// let result = QueryResult::new().parse_result(body).unwrap();
+/// assert_eq!(result.iter_series().count(), 2);
+/// for (metric, tags, time, value) in result.iter_points() {
+///     println!("{} {:?} {} {:?}", metric, tags["host"], time, value);
+/// }
+/// assert_eq!(result.iter_points().map(|(_, _, time, _)| time).sum::<u64>(), 2000);
+/// ```
+pub trait ResultIter<T> {
+    /// Returns an iterator over the metric names with their series
+    fn iter_series<'a>(&'a self) -> impl Iterator<Item = (&'a str, &'a Series<T>)>
+        where T: 'a;
+
+    /// Returns an iterator over the metric name, tags, time and value of
+    /// every datapoint
+    fn iter_points<'a>(&'a self) -> impl Iterator<Item = (&'a str, &'a Tags, T, &'a DataPointValue)>
+        where T: 'a;
+}
+
+impl<T: Copy> ResultIter<T> for HashMap<String, Vec<Series<T>>> {
+    fn iter_series<'a>(&'a self) -> impl Iterator<Item = (&'a str, &'a Series<T>)>
+        where T: 'a {
+        self.iter().flat_map(|(name, series)| series.iter().map(move |s| (name.as_str(), s)))
+    }
+
+    fn iter_points<'a>(&'a self) -> impl Iterator<Item = (&'a str, &'a Tags, T, &'a DataPointValue)>
+        where T: 'a {
+        self.iter_series().flat_map(|(name, series)| {
+            series.iter_points().map(move |(tags, time, value)| (name, tags, time, value))
+        })
+    }
+}
+
+/// Points shown at the start and at the end of each series by `to_table`
+const TABLE_POINTS: usize = 3;
+
+/// Renders a result as text table with the metric, tags and the first
+/// and last points of each series, e.g. to look at results in examples
+/// and tests
+///
+/// # Example
+/// ```
+/// use kairosdb::result::{to_table, QueryResult};
+///
+/// let body = r#"{"queries":[{"sample_size":2,"results":[{"name":"load",
+///     "tags":{"host":["a"]},"values":[[1475513259000,0.5],[1475513260000,0.7]]}]}]}"#;
+/// let result = QueryResult::new().parse_result(body).unwrap();
+/// assert_eq!(to_table(&result), "\
+/// metric  tags    time                      value
+/// load    host=a  2016-10-03T16:47:39.000Z  0.5
+///                 2016-10-03T16:47:40.000Z  0.7
+/// ");
+/// ```
+pub fn to_table(result: &ResultMap) -> String {
+    let mut rows = vec![["metric".to_string(), "tags".to_string(),
+                         "time".to_string(), "value".to_string()]];
+    let mut metrics: Vec<_> = result.iter().collect();
+    metrics.sort_by(|a, b| a.0.cmp(b.0));
+    for (name, series) in metrics {
+        for series in series {
+            let mut tags: Vec<_> = series.tags.iter()
+                .map(|(tag, values)| format!("{}={}", tag, values.join(",")))
+                .collect();
+            tags.sort();
+            let mut first = [name.clone(), tags.join(" ")];
+
+            let count = series.values.len();
+            for (i, value) in series.values.iter().enumerate() {
+                if count > 2 * TABLE_POINTS && i == TABLE_POINTS {
+                    let skipped = count - 2 * TABLE_POINTS;
+                    rows.push([String::new(), String::new(), "...".to_string(),
+                               format!("({} more)", skipped)]);
+                }
+                if count > 2 * TABLE_POINTS && i >= TABLE_POINTS && i < count - TABLE_POINTS {
+                    continue;
+                }
+                let time = value.datetime()
+                    .map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+                    .unwrap_or_else(|| value.time.to_string());
+                let [metric, tags] = std::mem::take(&mut first);
+                rows.push([metric, tags, time, value.value.to_string()]);
+            }
+            if count == 0 {
+                let [metric, tags] = first;
+                rows.push([metric, tags, String::new(), String::new()]);
+            }
+        }
+    }
+
+    let mut widths = [0; 4];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut table = String::new();
+    for row in rows {
+        let line: Vec<_> = row.iter().zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        table.push_str(line.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
 }
 
-pub type ResultMap = HashMap<String, ResultVector>;
-type ResultVector = Vec<Value>;
+/// All series returned per metric name
+pub type ResultMap = HashMap<String, Vec<Series>>;
+
+/// All series returned per metric name with `DateTime` timestamps
+pub type UtcResultMap = HashMap<String, Vec<Series<DateTime<Utc>>>>;
+
+/// Tag names with their values per metric name
+pub type TagsMap = HashMap<String, Tags>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct TagsResult {
+    queries: Vec<TagsQuery>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TagsQuery {
+    results: Vec<ResultTags>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ResultTags {
+    name: String,
+    tags: Tags,
+}
 
 impl QueryResult {
     pub fn new() -> QueryResult {
         QueryResult { queries: vec![] }
     }
 
+    /// Parses a query response. Values keep the type they have in the
+    /// response, so longs, doubles and strings can be told apart.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::datapoints::DataPointValue;
+    /// use kairosdb::result::QueryResult;
+    ///
+    /// let body = r#"{"queries":[{"sample_size":3,"results":[{"name":"status",
+    ///     "tags":{"host":["a"]},"values":[[1000,42],[2000,1.5],[3000,"up"]]}]}]}"#;
+    /// let result = QueryResult::new().parse_result(body).unwrap();
+    /// let values = &result["status"][0].values;
+    /// assert_eq!(values[0].value, DataPointValue::Long(42));
+    /// assert_eq!(values[1].value, DataPointValue::Double(1.5));
+    /// assert_eq!(values[2].value, DataPointValue::Text("up".to_string()));
+    /// ```
     pub fn parse_result(&self, body: &str) -> Result<ResultMap, KairoError> {
-        let mut result: ResultMap = HashMap::new();
-        let deserialized: QueryResult = serde_json::from_str(body)?;
+        #[cfg(feature = "simd-json")]
+        return self.parse_result_owned(body.to_string());
+        #[cfg(not(feature = "simd-json"))]
+        {
+            let mut result: ResultMap = HashMap::new();
+            let callback = |name, series| result.entry(name).or_default().push(series);
+            parse_response(serde_json::Deserializer::from_str(body), callback)?;
+            Ok(result)
+        }
+    }
+
+    /// Parses a query response like `parse_result`, taking ownership of
+    /// the body. With the `simd-json` feature the body is parsed in place
+    /// instead of being copied first. Data after the response is an
+    /// error.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::result::QueryResult;
+    ///
+    /// let body = r#"{"queries":[{"sample_size":1,"results":[{"name":"load",
+    ///     "values":[[1000,0.5]]}]}]}"#.to_string();
+    /// let result = QueryResult::new().parse_result_owned(body.clone()).unwrap();
+    /// assert_eq!(result["load"][0].values[0].time, 1000);
+    /// assert!(QueryResult::new().parse_result_owned(body + "{}").is_err());
+    /// ```
+    pub fn parse_result_owned(&self, body: String) -> Result<ResultMap, KairoError> {
+        parse_sized(body).map(|(result, _)| result)
+    }
+
+    /// Parses a query response like `parse_result`, but with the times
+    /// of the datapoints as `DateTime`
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::result::QueryResult;
+    ///
+    /// let body = r#"{"queries":[{"sample_size":1,"results":[{"name":"load",
+    ///     "values":[[1475513259000,0.5]]}]}]}"#;
+    /// let result = QueryResult::new().parse_result_utc(body).unwrap();
+    /// assert_eq!(result["load"][0].values[0].time.to_rfc3339(), "2016-10-03T16:47:39+00:00");
+    /// ```
+    pub fn parse_result_utc(&self, body: &str) -> Result<UtcResultMap, KairoError> {
+        to_utc(self.parse_result(body)?)
+    }
+
+
+    /// Parses a query response while reading it from `reader` and passes
+    /// every series with its metric name to `callback` as soon as it is
+    /// parsed, so only one series is held in memory at a time
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::result::QueryResult;
+    ///
+    /// let body = r#"{"queries":[{"sample_size":2,"results":[
+    ///     {"name":"load","tags":{"host":["a"]},"values":[[1000,0.5]]},
+    ///     {"name":"load","tags":{"host":["b"]},"values":[[1000,0.7]]}]}]}"#;
+    /// let mut hosts = Vec::new();
+    /// QueryResult::new().parse_reader(body.as_bytes(), |name, series| {
+    ///     hosts.push(format!("{} {}", name, series.tags["host"][0]));
+    /// }).unwrap();
+    /// assert_eq!(hosts, vec!["load a", "load b"]);
+    /// ```
+    pub fn parse_reader<R, F>(&self, reader: R, mut callback: F) -> Result<(), KairoError>
+        where R: Read, F: FnMut(String, Series)
+    {
+        parse_reader_sized(reader, &mut callback).map(|_| ())
+    }
+
+    /// Parses the series of a query response in the order of the
+    /// response, with the metric name of each
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::result::QueryResult;
+    ///
+    /// let body = r#"{"queries":[{"sample_size":2,"results":[
+    ///     {"name":"load","tags":{"host":["a"]},"values":[[1000,0.5]]},
+    ///     {"name":"load","tags":{"host":["b"]},"values":[[1000,0.7]]}]}]}"#;
+    /// let series = QueryResult::new().parse_series(body).unwrap();
+    /// assert_eq!(series[1].0, "load");
+    /// assert_eq!(series[1].1.tags["host"], vec!["b"]);
+    /// ```
+    pub fn parse_series(&self, body: &str) -> Result<Vec<(String, Series)>, KairoError> {
+        let mut series = Vec::new();
+        parse_response(serde_json::Deserializer::from_str(body), |name, s| series.push((name, s)))?;
+        Ok(series)
+    }
+}
+
+impl TagsResult {
+    pub fn new() -> TagsResult {
+        TagsResult { queries: vec![] }
+    }
+
+    pub fn parse_result(&self, body: &str) -> Result<TagsMap, KairoError> {
+        let mut result: TagsMap = HashMap::new();
+        let deserialized: TagsResult = serde_json::from_str(body)?;
 
         for query in deserialized.queries {
             for r in query.results {
-                let mut values: ResultVector = Vec::new();
-                for v in r.values {
-                    values.push(Value {
-                        time: v[0] as u64,
-                        value: v[1] as f64,
-                    });
-                }
-                result.insert(r.name, values);
+                result.insert(r.name, r.tags);
             }
         }
 
         Ok(result)
     }
 }
+
+/// Parses a query response and returns its series per metric name with
+/// the number of datapoints the server read for it. With the `simd-json`
+/// feature the body is parsed in place.
+pub(crate) fn parse_sized(body: String) -> Result<(ResultMap, i64), KairoError> {
+    let mut result: ResultMap = HashMap::new();
+    let callback = |name, series| result.entry(name).or_default().push(series);
+    let sample_size = parse_owned(body, callback)?;
+    Ok((result, sample_size))
+}
+
+/// Parses the series of a query response like `QueryResult::parse_series`
+/// and returns the number of datapoints the server read for it
+pub(crate) fn parse_series_sized(body: String)
+                                 -> Result<(Vec<(String, Series)>, i64), KairoError> {
+    let mut series = Vec::new();
+    let sample_size = parse_owned(body, |name, s| series.push((name, s)))?;
+    Ok((series, sample_size))
+}
+
+// Parses a query response, in place with the `simd-json` feature, and
+// returns the summed sample sizes of its queries
+fn parse_owned<F: FnMut(String, Series)>(body: String, callback: F) -> Result<i64, KairoError> {
+    #[cfg(feature = "simd-json")]
+    {
+        // the whole document is validated up front, so trailing data
+        // is rejected before any series is parsed
+        let mut bytes = body.into_bytes();
+        let mut deserializer = simd_json::Deserializer::from_slice(&mut bytes)?;
+        Ok(parse_with(&mut deserializer, callback)?)
+    }
+    #[cfg(not(feature = "simd-json"))]
+    parse_response(serde_json::Deserializer::from_str(&body), callback)
+}
+
+/// Parses a query response while reading it like
+/// `QueryResult::parse_reader` and returns the number of datapoints the
+/// server read for it
+pub(crate) fn parse_reader_sized<R, F>(reader: R, callback: F) -> Result<i64, KairoError>
+    where R: Read, F: FnMut(String, Series)
+{
+    parse_response(serde_json::Deserializer::from_reader(reader), callback)
+}
+
+/// Converts the times of a result to `DateTime`
+pub(crate) fn to_utc(parsed: ResultMap) -> Result<UtcResultMap, KairoError> {
+    let mut result: UtcResultMap = HashMap::new();
+    for (name, series) in parsed {
+        let mut converted = Vec::with_capacity(series.len());
+        for series in series {
+            let values = series.values.into_iter()
+                .map(|value| match value.datetime() {
+                    Some(time) => Ok(Value { time, value: value.value }),
+                    None => Err(KairoError::Kairo(
+                        format!("Invalid timestamp {} in result", value.time))),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            converted.push(Series { tags: series.tags, group: series.group, values });
+        }
+        result.insert(name, converted);
+    }
+    Ok(result)
+}
+
+fn tag_group(group_by: &[ResultGroup]) -> HashMap<String, String> {
+    group_by.iter()
+        .filter(|group_by| group_by.name == "tag")
+        .flat_map(|group_by| &group_by.group)
+        .filter_map(|(name, value)| value.as_str().map(|v| (name.clone(), v.to_string())))
+        .collect()
+}
+
+// Parses the response and returns the summed sample sizes of its queries
+fn parse_response<'de, R, F>(mut deserializer: serde_json::Deserializer<R>, callback: F)
+                             -> Result<i64, KairoError>
+    where R: serde_json::de::Read<'de>, F: FnMut(String, Series)
+{
+    let sample_size = parse_with(&mut deserializer, callback)?;
+    deserializer.end()?;
+    Ok(sample_size)
+}
+
+fn parse_with<'de, D, F>(deserializer: D, mut callback: F) -> Result<i64, D::Error>
+    where D: Deserializer<'de>, F: FnMut(String, Series)
+{
+    deserializer.deserialize_map(ResponseVisitor(&mut callback))
+}
+
+// Visitors walking `{"queries":[{"sample_size":1,"results":[...]}]}` of
+// a query response and passing each series to the callback instead of
+// collecting them. The visitors of the response and its queries return
+// the sample sizes.
+struct ResponseVisitor<'a, F>(&'a mut F);
+struct QueriesSeed<'a, F>(&'a mut F);
+struct QueryVisitor<'a, F>(&'a mut F);
+struct ResultsSeed<'a, F>(&'a mut F);
+struct SeriesSeed<'a, F>(&'a mut F);
+
+impl<'de, 'a, F: FnMut(String, Series)> Visitor<'de> for ResponseVisitor<'a, F> {
+    type Value = i64;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a query response")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<i64, A::Error> {
+        let mut sample_size = 0;
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "queries" {
+                sample_size += map.next_value_seed(QueriesSeed(&mut *self.0))?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(sample_size)
+    }
+}
+
+impl<'de, 'a, F: FnMut(String, Series)> DeserializeSeed<'de> for QueriesSeed<'a, F> {
+    type Value = i64;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<i64, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a, F: FnMut(String, Series)> Visitor<'de> for QueriesSeed<'a, F> {
+    type Value = i64;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a list of queries")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<i64, A::Error> {
+        let mut sample_size = 0;
+        while let Some(size) = seq.next_element_seed(QueryVisitor(&mut *self.0))? {
+            sample_size += size;
+        }
+        Ok(sample_size)
+    }
+}
+
+impl<'de, 'a, F: FnMut(String, Series)> DeserializeSeed<'de> for QueryVisitor<'a, F> {
+    type Value = i64;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<i64, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, 'a, F: FnMut(String, Series)> Visitor<'de> for QueryVisitor<'a, F> {
+    type Value = i64;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a query")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<i64, A::Error> {
+        let mut sample_size = 0;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "sample_size" => sample_size = map.next_value()?,
+                "results" => map.next_value_seed(ResultsSeed(&mut *self.0))?,
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(sample_size)
+    }
+}
+
+impl<'de, 'a, F: FnMut(String, Series)> DeserializeSeed<'de> for ResultsSeed<'a, F> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a, F: FnMut(String, Series)> Visitor<'de> for ResultsSeed<'a, F> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a list of results")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while seq.next_element_seed(SeriesSeed(&mut *self.0))?.is_some() {}
+        Ok(())
+    }
+}
+
+impl<'de, 'a, F: FnMut(String, Series)> DeserializeSeed<'de> for SeriesSeed<'a, F> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, 'a, F: FnMut(String, Series)> Visitor<'de> for SeriesSeed<'a, F> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a result")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let mut name = None;
+        let mut series = Series::default();
+        let mut values = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "name" => name = Some(map.next_value()?),
+                "tags" => series.tags = map.next_value()?,
+                "group_by" => series.group = tag_group(&map.next_value::<Vec<ResultGroup>>()?),
+                "values" => values = Some(map.next_value()?),
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        series.values = values.ok_or_else(|| de::Error::missing_field("values"))?;
+        (self.0)(name.ok_or_else(|| de::Error::missing_field("name"))?, series);
+        Ok(())
+    }
+}
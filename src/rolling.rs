@@ -0,0 +1,82 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Rolling window functions over result series
+//!
+//! Each function slides a window of `window` numeric values over the
+//! series and returns one value per window at the time of its last
+//! value, so the first `window - 1` values of a series produce no value
+//! of their own. Values which are not numeric are skipped.
+//!
+//! ```
+//! use kairosdb::result::{Series, Value};
+//!
+//! let series = Series {
+//!     values: [1.0, 3.0, 2.0, 6.0].iter()
+//!         .enumerate()
+//!         .map(|(i, v)| Value { time: i as u64 * 1000, value: (*v).into() })
+//!         .collect(),
+//!     ..Default::default()
+//! };
+//! let smoothed = series.rolling_mean(2);
+//! assert_eq!(smoothed.values.len(), 3);
+//! assert_eq!(smoothed.values[0].time, 1000);
+//! assert_eq!(smoothed.values[0].value, 2.0);
+//! assert_eq!(series.rolling_max(3).values[1].value, 6.0);
+//! ```
+
+use crate::result::{Series, Value};
+
+impl<T: Copy> Series<T> {
+    /// Returns the mean of each window
+    pub fn rolling_mean(&self, window: usize) -> Series<T> {
+        self.rolling(window, |values| values.iter().sum::<f64>() / values.len() as f64)
+    }
+
+    /// Returns the smallest value of each window
+    pub fn rolling_min(&self, window: usize) -> Series<T> {
+        self.rolling(window, |values| values.iter().copied().fold(f64::INFINITY, f64::min))
+    }
+
+    /// Returns the largest value of each window
+    pub fn rolling_max(&self, window: usize) -> Series<T> {
+        self.rolling(window, |values| values.iter().copied().fold(f64::NEG_INFINITY, f64::max))
+    }
+
+    /// Returns the sample standard deviation of each window, 0 for
+    /// windows of a single value
+    pub fn rolling_stddev(&self, window: usize) -> Series<T> {
+        self.rolling(window, |values| {
+            if values.len() < 2 {
+                return 0.0;
+            }
+            let mean = values.iter().sum::<f64>() / values.len() as f64;
+            let squares: f64 = values.iter().map(|v| (v - mean) * (v - mean)).sum();
+            (squares / (values.len() - 1) as f64).sqrt()
+        })
+    }
+
+    fn rolling(&self, window: usize, function: fn(&[f64]) -> f64) -> Series<T> {
+        let (times, numbers): (Vec<T>, Vec<f64>) = self.values.iter()
+            .filter_map(|value| value.value.as_f64().map(|number| (value.time, number)))
+            .unzip();
+        let window = window.max(1);
+        let values = numbers.windows(window)
+            .zip(times.iter().skip(window - 1))
+            .map(|(values, time)| Value { time: *time, value: function(values).into() })
+            .collect();
+        Series { tags: self.tags.clone(), group: self.group.clone(), values }
+    }
+}
@@ -0,0 +1,105 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Roll-up tasks, queries KairosDB runs periodically to store their
+//! aggregated results as new metrics
+
+use crate::query::{Query, RelativeTime};
+
+/// JSON representation of a roll-up task. Each roll-up of the task runs
+/// its query every `execution_interval` and saves the result under the
+/// metric name `save_as`. Tasks returned by the server carry the id
+/// it assigned to them.
+///
+/// # Example
+/// ```
+/// use kairosdb::query::{Query, RelativeTime, Time, TimeUnit};
+/// use kairosdb::rollup::RollupTask;
+///
+/// let query = Query::builder()
+///     .start(Time::Relative { value: 1, unit: TimeUnit::HOURS })
+///     .metric(|m| m.name("cpu").avg(1, TimeUnit::HOURS))
+///     .build();
+/// let mut task = RollupTask::new("cpu hourly", RelativeTime::new(1, TimeUnit::HOURS));
+/// task.add("cpu.hourly", query);
+/// assert!(serde_json::to_string(&task).unwrap().starts_with(
+///     r#"{"name":"cpu hourly","execution_interval":{"value":1,"unit":"HOURS"},"rollups":[{"save_as":"cpu.hourly","query":"#));
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RollupTask {
+    #[serde(default, skip_serializing)]
+    id: Option<String>,
+    name: String,
+    execution_interval: RelativeTime,
+    rollups: Vec<Rollup>,
+}
+
+/// A single query of a `RollupTask` with the metric its result is saved
+/// as
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Rollup {
+    save_as: String,
+    query: Query,
+}
+
+impl RollupTask {
+    /// Creates a new `RollupTask` without roll-ups
+    pub fn new(name: &str, execution_interval: RelativeTime) -> RollupTask {
+        RollupTask {
+            id: None,
+            name: name.to_string(),
+            execution_interval,
+            rollups: vec![],
+        }
+    }
+
+    /// Adds a roll-up saving the result of `query` as metric `save_as`
+    pub fn add(&mut self, save_as: &str, query: Query) {
+        self.rollups.push(Rollup { save_as: save_as.to_string(), query });
+    }
+
+    /// Returns the id the server assigned to the task, `None` for tasks
+    /// not read from the server
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// Returns the name of the task
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the interval the roll-ups of the task run in
+    pub fn execution_interval(&self) -> &RelativeTime {
+        &self.execution_interval
+    }
+
+    /// Returns the roll-ups of the task
+    pub fn rollups(&self) -> &[Rollup] {
+        &self.rollups
+    }
+}
+
+impl Rollup {
+    /// Returns the name of the metric the result is saved as
+    pub fn save_as(&self) -> &str {
+        &self.save_as
+    }
+
+    /// Returns the query of the roll-up
+    pub fn query(&self) -> &Query {
+        &self.query
+    }
+}
@@ -0,0 +1,101 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Statistics of a KairosDB server read from the `kairosdb.*` metrics
+//! the server reports about itself
+
+use crate::result::{ResultMap, Series};
+
+/// Number of datapoints ingested through the HTTP API per report
+pub const INGEST_COUNT: &str = "kairosdb.http.ingest_count";
+/// Time in milliseconds the server took to answer a HTTP query
+pub const QUERY_TIME: &str = "kairosdb.http.query_time";
+/// Number of datapoints written to the datastore per write
+pub const WRITE_SIZE: &str = "kairosdb.datastore.write_size";
+
+/// Statistics of a KairosDB server over a time range, returned by
+/// `Client::server_stats`. Values are `None` when the server reported
+/// nothing in the range.
+///
+/// # Example
+/// ```
+/// use kairosdb::Client;
+/// use kairosdb::query::Time;
+/// # use kairosdb::datapoints::Datapoints;
+///
+/// let server = kairosdb::testing::StubServer::start();
+/// let client = Client::new("localhost", server.port());
+/// # for &(name, values) in &[("kairosdb.http.ingest_count", [500.0, 700.0]),
+/// #                          ("kairosdb.http.query_time", [20.0, 40.0])] {
+/// #     let mut datapoints = Datapoints::new(name, 0);
+/// #     datapoints.add_ms(30_000, values[0]);
+/// #     datapoints.add_ms(60_000, values[1]);
+/// #     client.add(&datapoints).unwrap();
+/// # }
+/// let stats = client.server_stats(Time::Nanoseconds(0), Time::Nanoseconds(60_000)).unwrap();
+/// assert_eq!(stats.datapoints_ingested, 1200.0);
+/// assert_eq!(stats.ingest_rate, 20.0);
+/// assert_eq!(stats.query_time_ms, Some(30.0));
+/// assert_eq!(stats.max_query_time_ms, Some(40.0));
+/// assert_eq!(stats.write_size, None);
+/// ```
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ServerStats {
+    /// Datapoints ingested through the HTTP API
+    pub datapoints_ingested: f64,
+    /// Ingested datapoints per second over the whole range
+    pub ingest_rate: f64,
+    /// Mean time of the queries in milliseconds
+    pub query_time_ms: Option<f64>,
+    /// Longest time of a query in milliseconds
+    pub max_query_time_ms: Option<f64>,
+    /// Mean number of datapoints per datastore write
+    pub write_size: Option<f64>,
+}
+
+impl ServerStats {
+    /// Returns the metrics queried for the statistics
+    pub(crate) fn metrics() -> [&'static str; 3] {
+        [INGEST_COUNT, QUERY_TIME, WRITE_SIZE]
+    }
+
+    /// Summarizes the series of the `metrics` over a range of `range_ms`
+    /// milliseconds
+    pub(crate) fn from_result(result: &ResultMap, range_ms: i64) -> ServerStats {
+        let merged = |name: &str| {
+            let mut merged = Series::<u64>::default();
+            for series in result.get(name).into_iter().flatten() {
+                merged.merge(series.clone());
+            }
+            merged
+        };
+
+        let ingest = merged(INGEST_COUNT);
+        let query_time = merged(QUERY_TIME);
+        let datapoints_ingested = ingest.sum();
+        let ingest_rate = if range_ms > 0 {
+            datapoints_ingested * 1000.0 / range_ms as f64
+        } else {
+            0.0
+        };
+        ServerStats {
+            datapoints_ingested,
+            ingest_rate,
+            query_time_ms: query_time.mean(),
+            max_query_time_ms: query_time.max(),
+            write_size: merged(WRITE_SIZE).mean(),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Local spool keeping batches which could not be written
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::Utc;
+
+use crate::KairosClient;
+use crate::datapoints::Datapoints;
+use crate::error::KairoError;
+
+/// Directory storing batches as JSON files, one file per batch, until
+/// they are replayed to the server in the order they were stored
+///
+/// # Example
+/// ```
+/// use kairosdb::KairosClient;
+/// use kairosdb::datapoints::Datapoints;
+/// use kairosdb::mock::MockClient;
+/// use kairosdb::spool::Spool;
+///
+/// let dir = std::env::temp_dir().join(format!("kairosdb-spool-{}", std::process::id()));
+/// let spool = Spool::new(&dir);
+/// spool.store(&[Datapoints::builder("cpu").point(1000, 0.5).build()]).unwrap();
+/// assert_eq!(spool.len(), 1);
+///
+/// let client = MockClient::new();
+/// assert_eq!(spool.replay(&client).unwrap(), 1);
+/// assert!(spool.is_empty());
+/// assert_eq!(client.list_metrics().unwrap(), vec!["cpu"]);
+/// # std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct Spool {
+    dir: PathBuf,
+    sequence: AtomicU64,
+}
+
+impl Spool {
+    /// Creates a new `Spool` in `dir`. The directory is created with the
+    /// first stored batch; batches stored by earlier runs are replayed.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Spool {
+        Spool {
+            dir: dir.as_ref().to_path_buf(),
+            sequence: AtomicU64::new(0),
+        }
+    }
+
+    /// Writes a batch to a new file of the spool
+    pub fn store(&self, batch: &[Datapoints]) -> Result<(), KairoError> {
+        fs::create_dir_all(&self.dir)?;
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let name = format!("{:020}-{:010}", Utc::now().timestamp_millis(), sequence);
+
+        // written under a temporary name, so a crash never leaves a
+        // partial batch to replay
+        let partial = self.dir.join(format!("{}.tmp", name));
+        let mut writer = BufWriter::new(File::create(&partial)?);
+        serde_json::to_writer(&mut writer, batch)?;
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&partial, self.dir.join(format!("{}.json", name)))?;
+        Ok(())
+    }
+
+    /// Sends the stored batches with `client`, oldest first, and removes
+    /// each written batch from the spool. Batches the server rejects with
+    /// an error which is not retryable, and files which can't be read,
+    /// are moved to the `quarantine` directory of the spool and the
+    /// replay goes on. Any other failure stops the replay, so the
+    /// remaining batches keep their order. Returns the number of replayed
+    /// batches.
+    pub fn replay(&self, client: &dyn KairosClient) -> Result<usize, KairoError> {
+        let mut replayed = 0;
+        for file in self.files()? {
+            let batch: Vec<Datapoints> = match read_batch(&file) {
+                Ok(batch) => batch,
+                Err(err) => {
+                    self.quarantine(&file, &err)?;
+                    continue;
+                }
+            };
+            match client.add_batch(&batch) {
+                Ok(()) => {
+                    fs::remove_file(&file)?;
+                    replayed += 1;
+                }
+                Err(ref err) if err.status().is_some() && !err.is_retryable() => {
+                    self.quarantine(&file, err)?
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(replayed)
+    }
+
+    /// Returns the number of batches moved to the quarantine
+    pub fn quarantined(&self) -> usize {
+        self.quarantine_dir().read_dir().map_or(0, |entries| entries.count())
+    }
+
+    /// Returns the directory keeping the batches which can't be replayed
+    pub fn quarantine_dir(&self) -> PathBuf {
+        self.dir.join("quarantine")
+    }
+
+    /// Returns the number of stored batches
+    pub fn len(&self) -> usize {
+        self.files().map_or(0, |files| files.len())
+    }
+
+    /// Returns true if no batches are stored
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn quarantine(&self, file: &Path, err: &KairoError) -> Result<(), KairoError> {
+        error!("moving spooled batch {} to the quarantine: {:?}", file.display(), err);
+        let quarantine = self.quarantine_dir();
+        fs::create_dir_all(&quarantine)?;
+        if let Some(name) = file.file_name() {
+            fs::rename(file, quarantine.join(name))?;
+        }
+        Ok(())
+    }
+
+    fn files(&self) -> Result<Vec<PathBuf>, KairoError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        let mut files = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "json") {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+}
+
+fn read_batch(file: &Path) -> Result<Vec<Datapoints>, KairoError> {
+    Ok(serde_json::from_reader(BufReader::new(File::open(file)?))?)
+}
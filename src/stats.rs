@@ -0,0 +1,158 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Statistics collected by the client about its requests
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram buckets in milliseconds
+const LATENCY_BOUNDS_MS: [u64; 13] =
+    [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Snapshot of the client statistics returned by `Client::stats`
+///
+/// # Example
+/// ```
+/// use kairosdb::Client;
+///
+/// let client = Client::new("localhost", 8080);
+/// let stats = client.stats();
+/// assert_eq!(stats.requests, 0);
+/// assert_eq!(stats.latency.count(), 0);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ClientStats {
+    /// Number of requests sent to the server
+    pub requests: u64,
+    /// Number of requests rejected before sending, e.g. by an open circuit
+    pub rejected: u64,
+    /// Number of requests failing without a response
+    pub transport_errors: u64,
+    /// Number of responses with an unexpected status code by status code
+    pub status_errors: HashMap<u16, u64>,
+    /// Number of request body bytes sent
+    pub bytes_sent: u64,
+    /// Number of response body bytes received
+    pub bytes_received: u64,
+    /// Latency of all requests which received a response
+    pub latency: LatencyHistogram,
+}
+
+/// Histogram of request latencies with fixed buckets from 1ms to 10s
+#[derive(Debug, Clone)]
+pub struct LatencyHistogram {
+    counts: Vec<u64>,
+    count: u64,
+    sum: Duration,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> LatencyHistogram {
+        LatencyHistogram {
+            counts: vec![0; LATENCY_BOUNDS_MS.len() + 1],
+            count: 0,
+            sum: Duration::from_secs(0),
+        }
+    }
+}
+
+impl LatencyHistogram {
+    /// Number of recorded latencies
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Average of all recorded latencies
+    pub fn mean(&self) -> Option<Duration> {
+        match self.count {
+            0 => None,
+            n => Some(self.sum / n as u32),
+        }
+    }
+
+    /// Upper bound of the bucket containing the given percentile (0.0 - 1.0).
+    /// Latencies above the largest bucket are reported as `None`.
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (p.clamp(0.0, 1.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return LATENCY_BOUNDS_MS.get(i).map(|ms| Duration::from_millis(*ms));
+            }
+        }
+        None
+    }
+
+    /// Returns the buckets as pairs of upper bound and count. The last
+    /// bucket has no upper bound.
+    pub fn buckets(&self) -> Vec<(Option<Duration>, u64)> {
+        self.counts.iter().enumerate()
+            .map(|(i, count)| {
+                (LATENCY_BOUNDS_MS.get(i).map(|ms| Duration::from_millis(*ms)), *count)
+            })
+            .collect()
+    }
+
+    pub(crate) fn record(&mut self, latency: Duration) {
+        let ms = latency.as_millis() as u64;
+        let index = LATENCY_BOUNDS_MS.iter()
+            .position(|bound| ms < *bound)
+            .unwrap_or(LATENCY_BOUNDS_MS.len());
+        self.counts[index] += 1;
+        self.count += 1;
+        self.sum += latency;
+    }
+}
+
+/// Collects the statistics of a client, shared between threads
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+    inner: Mutex<ClientStats>,
+}
+
+impl Stats {
+    pub fn snapshot(&self) -> ClientStats {
+        self.inner.lock().unwrap().clone()
+    }
+
+    pub fn record_rejected(&self) {
+        self.inner.lock().unwrap().rejected += 1;
+    }
+
+    pub fn record_transport_error(&self, bytes_sent: usize) {
+        let mut stats = self.inner.lock().unwrap();
+        stats.requests += 1;
+        stats.transport_errors += 1;
+        stats.bytes_sent += bytes_sent as u64;
+    }
+
+    pub fn record_response(&self, status: u16, bytes_sent: usize, bytes_received: usize,
+                           latency: Duration) {
+        let mut stats = self.inner.lock().unwrap();
+        stats.requests += 1;
+        stats.bytes_sent += bytes_sent as u64;
+        stats.bytes_received += bytes_received as u64;
+        stats.latency.record(latency);
+        if status >= 300 {
+            *stats.status_errors.entry(status).or_insert(0) += 1;
+        }
+    }
+}
@@ -0,0 +1,207 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! StatsD collector aggregating packets and writing them to KairosDB
+//!
+//! Enabled with the `statsd` feature. The collector understands counters
+//! (`c`), gauges (`g`, with `+`/`-` for relative changes) and timers
+//! (`ms`, `h`) including sample rates. Every flush writes
+//!
+//! * the sum of each counter updated since the last flush,
+//! * the last value of every gauge seen so far,
+//! * `count`, `min`, `max`, `mean` and `p90` of each timer as metrics
+//!   with these suffixes. Like counters the count is scaled by the sample
+//!   rates, the other values are taken from the received samples.
+
+use std::collections::HashMap;
+use std::net::UdpSocket;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+
+use crate::KairosClient;
+use crate::datapoints::Datapoints;
+use crate::error::KairoError;
+
+/// Aggregates StatsD packets and writes the aggregates with a client
+///
+/// # Example
+/// ```
+/// use kairosdb::KairosClient;
+/// use kairosdb::mock::MockClient;
+/// use kairosdb::statsd::StatsdCollector;
+///
+/// let collector = StatsdCollector::new().tag("host", "a");
+/// collector.handle_packet("requests:1|c\nrequests:2|c|@0.5\nlatency:20|ms\nlatency:40|ms");
+///
+/// let client = MockClient::new();
+/// assert_eq!(collector.flush(&client).unwrap(), 6);
+/// assert_eq!(client.list_metrics().unwrap(), vec![
+///     "latency.count", "latency.max", "latency.mean", "latency.min", "latency.p90",
+///     "requests",
+/// ]);
+/// ```
+#[derive(Debug, Default)]
+pub struct StatsdCollector {
+    tags: Vec<(Arc<str>, Arc<str>)>,
+    aggregates: Mutex<Aggregates>,
+}
+
+#[derive(Debug, Default)]
+struct Aggregates {
+    counters: HashMap<String, f64>,
+    gauges: HashMap<String, f64>,
+    timers: HashMap<String, Timings>,
+}
+
+// Timings of a timer with their count scaled by the sample rates
+#[derive(Debug, Default)]
+struct Timings {
+    values: Vec<f64>,
+    count: f64,
+}
+
+impl StatsdCollector {
+    /// Creates a new empty `StatsdCollector`
+    pub fn new() -> StatsdCollector {
+        StatsdCollector::default()
+    }
+
+    /// Adds a tag to all written datapoints. KairosDB needs at least one
+    /// tag per datapoint.
+    pub fn tag(mut self, name: &str, value: &str) -> StatsdCollector {
+        self.tags.push((name.into(), value.into()));
+        self
+    }
+
+    /// Adds the metrics of a packet with one metric per line. Invalid
+    /// lines are logged and skipped.
+    pub fn handle_packet(&self, packet: &str) {
+        let mut aggregates = self.aggregates.lock().unwrap();
+        for line in packet.lines().filter(|line| !line.trim().is_empty()) {
+            if aggregates.add(line.trim()).is_none() {
+                warn!("skipping invalid statsd line '{}'", line);
+            }
+        }
+    }
+
+    /// Returns the aggregates since the last call as datapoints at the
+    /// time `ms` and resets counters and timers
+    pub fn drain(&self, ms: i64) -> Vec<Datapoints> {
+        let mut aggregates = self.aggregates.lock().unwrap();
+        let mut values = Vec::new();
+        for (name, sum) in aggregates.counters.drain() {
+            values.push((name, sum));
+        }
+        for (name, value) in &aggregates.gauges {
+            values.push((name.clone(), *value));
+        }
+        for (name, Timings { values: mut timings, count: scaled }) in aggregates.timers.drain() {
+            timings.sort_by(f64::total_cmp);
+            let count = timings.len();
+            let p90 = timings[((count as f64 * 0.9).ceil() as usize).max(1) - 1];
+            values.push((format!("{}.count", name), scaled));
+            values.push((format!("{}.min", name), timings[0]));
+            values.push((format!("{}.max", name), timings[count - 1]));
+            values.push((format!("{}.mean", name), timings.iter().sum::<f64>() / count as f64));
+            values.push((format!("{}.p90", name), p90));
+        }
+
+        values.into_iter()
+            .map(|(name, value)| {
+                let mut datapoints = Datapoints::new(&name, 0);
+                for (name, value) in &self.tags {
+                    datapoints.add_shared_tag(name, value);
+                }
+                datapoints.add_value(ms, value);
+                datapoints
+            })
+            .collect()
+    }
+
+    /// Writes the aggregates since the last flush with `client` and
+    /// returns the number of written datapoints
+    pub fn flush(&self, client: &dyn KairosClient) -> Result<usize, KairoError> {
+        let batch = self.drain(Utc::now().timestamp_millis());
+        if !batch.is_empty() {
+            client.add_batch(&batch)?;
+        }
+        Ok(batch.len())
+    }
+
+    /// Receives packets on `socket`, e.g. bound to UDP port 8125, and
+    /// flushes the aggregates every `flush_interval`. Failed flushes are
+    /// logged, reported to the error hook of `client` and their
+    /// aggregates dropped. Blocks until receiving fails.
+    pub fn run(&self, socket: &UdpSocket, client: &dyn KairosClient, flush_interval: Duration)
+               -> Result<(), KairoError> {
+        let mut buffer = [0; 65536];
+        let mut next_flush = Instant::now() + flush_interval;
+        loop {
+            let now = Instant::now();
+            if now >= next_flush {
+                if let Err(err) = self.flush(client) {
+                    error!("writing statsd aggregates failed: {:?}", err);
+                    client.hooks().error(&err);
+                }
+                next_flush += flush_interval;
+                continue;
+            }
+
+            socket.set_read_timeout(Some(next_flush - now))?;
+            match socket.recv(&mut buffer) {
+                Ok(size) => self.handle_packet(&String::from_utf8_lossy(&buffer[..size])),
+                Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock
+                    || err.kind() == std::io::ErrorKind::TimedOut => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+}
+
+impl Aggregates {
+    fn add(&mut self, line: &str) -> Option<()> {
+        let (name, rest) = line.split_once(':')?;
+        let mut fields = rest.split('|');
+        let value = fields.next()?;
+        let kind = fields.next()?;
+        let rate = match fields.next() {
+            Some(rate) => rate.strip_prefix('@')?.parse::<f64>().ok().filter(|r| *r > 0.0)?,
+            None => 1.0,
+        };
+        let number: f64 = value.parse().ok().filter(|number: &f64| number.is_finite())?;
+        let name = name.to_string();
+
+        match kind {
+            "c" => *self.counters.entry(name).or_insert(0.0) += number / rate,
+            "g" => {
+                let gauge = self.gauges.entry(name).or_insert(0.0);
+                if value.starts_with('+') || value.starts_with('-') {
+                    *gauge += number;
+                } else {
+                    *gauge = number;
+                }
+            }
+            "ms" | "h" => {
+                let timings = self.timers.entry(name).or_default();
+                timings.values.push(number);
+                timings.count += 1.0 / rate;
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+}
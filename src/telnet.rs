@@ -0,0 +1,183 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Client for the telnet interface of KairosDB
+//!
+//! The telnet interface only accepts datapoints and sends no responses,
+//! which makes it much cheaper than the REST API for high volume
+//! ingestion. Errors of the server, e.g. for datapoints without tags, are
+//! only visible in the server log.
+
+use std::fmt::Write as _;
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::Mutex;
+
+use crate::datapoints::{DataPointValue, Datapoints};
+use crate::error::KairoError;
+
+/// Sends datapoints with `putm` commands over a persistent TCP
+/// connection. Commands are buffered and written once `batch_size`
+/// datapoints are collected or on `flush`. A broken connection is
+/// reconnected and the batch written again once. Dropping the client
+/// writes the remaining datapoints.
+///
+/// # Example
+/// ```
+/// use kairosdb::datapoints::Datapoints;
+/// use kairosdb::telnet::TelnetClient;
+/// # use std::io::Read;
+///
+/// // a local listener standing in for the telnet port of KairosDB
+/// let listener = std::net::TcpListener::bind("localhost:0").unwrap();
+/// let port = listener.local_addr().unwrap().port();
+/// let client = TelnetClient::new("localhost", port);
+/// let datapoints = Datapoints::builder("load")
+///     .tag("host", "a")
+///     .point(1475513259000, 0.5)
+///     .build();
+/// client.add(&datapoints).unwrap();
+/// client.flush().unwrap();
+/// # drop(client);
+/// # let mut received = String::new();
+/// # listener.accept().unwrap().0.read_to_string(&mut received).unwrap();
+/// # assert_eq!(received, "putm load 1475513259000 0.5 host=a\n");
+/// ```
+#[derive(Debug)]
+pub struct TelnetClient {
+    address: String,
+    batch_size: usize,
+    seconds: bool,
+    connection: Mutex<Connection>,
+}
+
+#[derive(Debug, Default)]
+struct Connection {
+    stream: Option<TcpStream>,
+    buffer: String,
+    points: usize,
+}
+
+impl TelnetClient {
+    /// Creates a new `TelnetClient` for the telnet port of the server,
+    /// 4242 by default. The connection is opened with the first write.
+    pub fn new(host: &str, port: u16) -> TelnetClient {
+        TelnetClient {
+            address: format!("{}:{}", host, port),
+            batch_size: 1000,
+            seconds: false,
+            connection: Mutex::new(Connection::default()),
+        }
+    }
+
+    /// Sets the number of datapoints written at once
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size.max(1);
+    }
+
+    /// Sends `put` commands with timestamps in seconds instead of `putm`
+    /// commands, e.g. for proxies speaking the OpenTSDB protocol
+    pub fn set_seconds(&mut self, seconds: bool) {
+        self.seconds = seconds;
+    }
+
+    /// Buffers a command for every datapoint. Only long and double values
+    /// can be sent over telnet.
+    pub fn add(&self, datapoints: &Datapoints) -> Result<(), KairoError> {
+        let values: Option<Vec<String>> = datapoints.datapoints().iter()
+            .map(|(_, value)| match *value {
+                DataPointValue::Long(value) => Some(value.to_string()),
+                DataPointValue::Double(value) => Some(value.to_string()),
+                _ => None,
+            })
+            .collect();
+        let values = values.ok_or_else(|| {
+            KairoError::Kairo("Only numeric values can be sent over telnet".to_string())
+        })?;
+        let mut tags: Vec<_> = datapoints.tags().iter().collect();
+        tags.sort();
+
+        let mut connection = self.connection.lock().unwrap();
+        for ((time, _), value) in datapoints.datapoints().iter().zip(values) {
+            let _ = if self.seconds {
+                write!(connection.buffer, "put {} {} {}", datapoints.name(), time / 1000, value)
+            } else {
+                write!(connection.buffer, "putm {} {} {}", datapoints.name(), time, value)
+            };
+            for (name, value) in &tags {
+                let _ = write!(connection.buffer, " {}={}", name, value);
+            }
+            connection.buffer.push('\n');
+            connection.points += 1;
+        }
+
+        if connection.points >= self.batch_size {
+            self.send(&mut connection)?;
+        }
+        Ok(())
+    }
+
+    /// Writes all buffered datapoints
+    pub fn flush(&self) -> Result<(), KairoError> {
+        let mut connection = self.connection.lock().unwrap();
+        self.send(&mut connection)
+    }
+
+    /// Returns the number of buffered datapoints not yet written
+    pub fn pending(&self) -> usize {
+        self.connection.lock().unwrap().points
+    }
+
+    // Writes the buffer, reconnecting once if the connection broke. The
+    // buffer is dropped if the second attempt fails as well.
+    fn send(&self, connection: &mut Connection) -> Result<(), KairoError> {
+        if connection.buffer.is_empty() {
+            return Ok(());
+        }
+        let buffer = std::mem::take(&mut connection.buffer);
+        connection.points = 0;
+
+        let mut result = Ok(());
+        for _ in 0..2 {
+            result = self.write(connection, buffer.as_bytes());
+            match result {
+                Ok(()) => return Ok(()),
+                Err(ref err) => {
+                    warn!("writing to {} failed, reconnecting: {:?}", self.address, err);
+                    connection.stream = None;
+                }
+            }
+        }
+        result
+    }
+
+    fn write(&self, connection: &mut Connection, bytes: &[u8]) -> Result<(), KairoError> {
+        if connection.stream.is_none() {
+            connection.stream = Some(TcpStream::connect(&self.address)?);
+        }
+        let stream = connection.stream.as_mut().unwrap();
+        stream.write_all(bytes)?;
+        stream.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for TelnetClient {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            error!("writing buffered datapoints to {} failed: {:?}", self.address, err);
+        }
+    }
+}
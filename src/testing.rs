@@ -0,0 +1,309 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Local HTTP stub speaking the KairosDB REST API for tests
+//!
+//! The `StubServer` listens on a random local port and answers requests
+//! from an in-memory `MockClient` store. Single endpoints can be replaced
+//! with canned responses to test error handling.
+//!
+//! ```
+//! use kairosdb::Client;
+//! use kairosdb::datapoints::Datapoints;
+//! use kairosdb::testing::StubServer;
+//! use kairosdb::transport::Method;
+//!
+//! let server = StubServer::start();
+//! let client = Client::new("localhost", server.port());
+//!
+//! let mut datapoints = Datapoints::new("myMetric", 0);
+//! datapoints.add_ms(1000, 11.0);
+//! assert!(client.add(&datapoints).is_ok());
+//! assert_eq!(client.list_metrics().unwrap(), vec!["myMetric"]);
+//!
+//! server.respond(Method::GET, "/api/v1/metricnames", 500, "");
+//! assert!(client.list_metrics().is_err());
+//! assert_eq!(server.requests().len(), 3);
+//! ```
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use serde_json::json;
+
+use crate::KairosClient;
+use crate::datapoints::Datapoints;
+use crate::mock::MockClient;
+use crate::query::Query;
+use crate::transport::{Method, StatusCode};
+
+/// A request received by the `StubServer`
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: Method,
+    /// Path including the query string
+    pub path: String,
+    pub body: String,
+}
+
+/// HTTP server answering KairosDB API requests on a local port. The server
+/// shuts down when it is dropped.
+#[derive(Debug)]
+pub struct StubServer {
+    port: u32,
+    state: Arc<StubState>,
+}
+
+#[derive(Debug, Default)]
+struct StubState {
+    store: MockClient,
+    responses: Mutex<HashMap<(Method, String), (u16, String)>>,
+    requests: Mutex<Vec<RecordedRequest>>,
+    shutdown: AtomicBool,
+}
+
+impl StubServer {
+    /// Starts a new `StubServer` on a random local port
+    ///
+    /// # Panics
+    /// When no local port can be bound
+    pub fn start() -> StubServer {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind stub server");
+        let port = u32::from(listener.local_addr().expect("stub server address").port());
+        let state = Arc::new(StubState::default());
+
+        let accept_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if accept_state.shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let state = accept_state.clone();
+                    thread::spawn(move || state.serve(stream));
+                }
+            }
+        });
+
+        debug!("stub server listening on port {}", port);
+        StubServer { port, state }
+    }
+
+    /// Returns the port the server listens on
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Returns the base URL of the server, e.g. `http://127.0.0.1:34567`
+    pub fn url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    /// Returns the in-memory store the server answers from, e.g. to
+    /// prepare datapoints or check written ones.
+    pub fn store(&self) -> &MockClient {
+        &self.state.store
+    }
+
+    /// Answers all following requests with the given method and path
+    /// (without query string) with the canned status and body.
+    pub fn respond(&self, method: Method, path: &str, status: u16, body: &str) {
+        self.state.responses.lock().unwrap()
+            .insert((method, path.to_string()), (status, body.to_string()));
+    }
+
+    /// Returns all requests received so far
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for StubServer {
+    fn drop(&mut self) {
+        self.state.shutdown.store(true, Ordering::SeqCst);
+        // wake up the accept loop so it can terminate
+        let _ = TcpStream::connect(format!("127.0.0.1:{}", self.port));
+    }
+}
+
+impl StubState {
+    fn serve(&self, stream: TcpStream) {
+        let mut reader = BufReader::new(match stream.try_clone() {
+            Ok(stream) => stream,
+            Err(_) => return,
+        });
+        let mut writer = stream;
+
+        while let Some(request) = read_request(&mut reader) {
+            let (status, body) = self.answer(&request);
+            self.requests.lock().unwrap().push(request);
+
+            let reason = StatusCode::from_u16(status).ok()
+                .and_then(|status| status.canonical_reason())
+                .unwrap_or("");
+            let response = format!(
+                "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                status, reason, body.len(), body);
+            if writer.write_all(response.as_bytes()).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn answer(&self, request: &RecordedRequest) -> (u16, String) {
+        let path = request.path.split('?').next().unwrap_or("");
+        if let Some(canned) = self.responses.lock().unwrap()
+            .get(&(request.method.clone(), path.to_string())) {
+            return canned.clone();
+        }
+
+        let store = &self.store;
+        let result = match (&request.method, path) {
+            (&Method::GET, "/api/v1/version") => Ok((200, json!({"version": "KairosDB stub"}))),
+            (&Method::GET, "/api/v1/health/status") => store.health().map(|h| (200, json!(h))),
+            (&Method::GET, "/api/v1/health/check") => Ok((204, json!(null))),
+            (&Method::GET, "/api/v1/metricnames") =>
+                store.list_metrics().map(|r| (200, json!({"results": r}))),
+            (&Method::GET, "/api/v1/tagnames") =>
+                store.tagnames().map(|r| (200, json!({"results": r}))),
+            (&Method::GET, "/api/v1/tagvalues") =>
+                store.tagvalues().map(|r| (200, json!({"results": r}))),
+            (&Method::POST, "/api/v1/datapoints") => {
+                serde_json::from_str::<Vec<Datapoints>>(&request.body)
+                    .map_err(Into::into)
+                    .and_then(|sets| sets.iter().try_for_each(|d| store.add(d)))
+                    .map(|_| (204, json!(null)))
+            }
+            (&Method::POST, "/api/v1/datapoints/query") => {
+                serde_json::from_str::<Query>(&request.body)
+                    .map_err(Into::into)
+                    .and_then(|query| store.query(&query).map(|result| (query, result)))
+                    .map(|(query, result)| {
+                        let results: Vec<_> = query.metrics().iter().flat_map(|metric| {
+                            result.get(metric.name()).into_iter().flatten().map(move |series| {
+                                let values: Vec<_> = series.values.iter()
+                                    .map(|v| json!([v.time, v.value]))
+                                    .collect();
+                                let mut group_by = vec![];
+                                if !series.group.is_empty() {
+                                    let names: Vec<_> = series.group.keys().collect();
+                                    group_by.push(json!({"name": "tag", "tags": names,
+                                                         "group": series.group}));
+                                }
+                                json!({"name": metric.name(), "tags": series.tags,
+                                       "group_by": group_by, "values": values})
+                            })
+                        }).collect();
+                        let sample_size: usize = result.values()
+                            .flatten()
+                            .map(|series| series.values.len())
+                            .sum();
+                        (200, json!({"queries": [{"sample_size": sample_size,
+                                                  "results": results}]}))
+                    })
+            }
+            (&Method::POST, "/api/v1/datapoints/query/tags") => {
+                serde_json::from_str::<Query>(&request.body)
+                    .map_err(Into::into)
+                    .and_then(|query| store.query_tags(&query))
+                    .map(|result| {
+                        let results: Vec<_> = result.iter()
+                            .map(|(name, tags)| json!({"name": name, "tags": tags}))
+                            .collect();
+                        (200, json!({"queries": [{"results": results}]}))
+                    })
+            }
+            (&Method::POST, "/api/v1/datapoints/delete") => {
+                serde_json::from_str::<Query>(&request.body)
+                    .map_err(Into::into)
+                    .and_then(|query| store.delete(&query))
+                    .map(|_| (204, json!(null)))
+            }
+            (&Method::DELETE, path) if path.starts_with("/api/v1/metric/") => {
+                store.delete_metric(&path["/api/v1/metric/".len()..])
+                    .map(|_| (204, json!(null)))
+            }
+            _ => Ok((404, json!(null))),
+        };
+
+        match result {
+            Ok((status, serde_json::Value::Null)) => (status, String::new()),
+            Ok((status, body)) => (status, body.to_string()),
+            Err(err) => (400, json!({"errors": [format!("{:?}", err)]}).to_string()),
+        }
+    }
+}
+
+fn read_request(reader: &mut BufReader<TcpStream>) -> Option<RecordedRequest> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).ok()? == 0 {
+        return None;
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.parse::<Method>().ok()?;
+    let path = parts.next()?.to_string();
+
+    let mut content_length = 0;
+    let mut chunked = false;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().ok()?;
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                chunked = value.trim().eq_ignore_ascii_case("chunked");
+            }
+        }
+    }
+
+    let body = if chunked {
+        read_chunked(reader)?
+    } else {
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).ok()?;
+        body
+    };
+    Some(RecordedRequest {
+        method,
+        path,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+fn read_chunked(reader: &mut BufReader<TcpStream>) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let size = usize::from_str_radix(line.trim().split(';').next()?, 16).ok()?;
+        let mut chunk = vec![0; size + 2];
+        reader.read_exact(&mut chunk).ok()?;
+        if size == 0 {
+            return Some(body);
+        }
+        body.extend_from_slice(&chunk[..size]);
+    }
+}
@@ -0,0 +1,227 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Copying of datapoints from one server to another, e.g. for
+//! migrations or to backfill a server after an outage
+
+use std::fmt;
+use std::sync::Arc;
+
+use chrono::Utc;
+
+use crate::KairosClient;
+use crate::datapoints::Datapoints;
+use crate::error::KairoError;
+use crate::query::{GroupBy, Metric, Query, RelativeTime, Tags, Time, TimeUnit};
+
+type ProgressHook = Arc<dyn Fn(&Progress) + Send + Sync>;
+
+/// State of a running `Transfer`, passed to its progress callback after
+/// every chunk
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    /// Start of the transferred range in milliseconds
+    pub start: i64,
+    /// End of the transferred range in milliseconds
+    pub end: i64,
+    /// End of the last copied chunk in milliseconds
+    pub copied_until: i64,
+    /// Number of datapoints copied so far
+    pub datapoints: u64,
+}
+
+impl Progress {
+    /// Returns the copied fraction of the range between 0 and 1
+    pub fn fraction(&self) -> f64 {
+        if self.end <= self.start {
+            return 1.0;
+        }
+        (self.copied_until - self.start + 1) as f64 / (self.end - self.start + 1) as f64
+    }
+}
+
+/// Copies the datapoints of metrics in a time range from a source to a
+/// target server. The range is read in chunks of `chunk`, one day by
+/// default, and written in batches of `batch_size` datapoints. Series
+/// keep their tags. Chunks copied before an error stay copied, so a
+/// failed transfer can be resumed from the last reported progress.
+///
+/// # Example
+/// ```
+/// use kairosdb::KairosClient;
+/// use kairosdb::datapoints::Datapoints;
+/// use kairosdb::mock::MockClient;
+/// use kairosdb::query::{Metric, Query, RelativeTime, Tags, Time, TimeUnit};
+/// use kairosdb::transfer::Transfer;
+///
+/// let source = MockClient::new();
+/// for host in &["a", "b"] {
+///     source.add(&Datapoints::builder("cpu").tag("host", host)
+///         .point(1000, 0.5).point(2000, 0.7).build()).unwrap();
+/// }
+///
+/// let target = MockClient::new();
+/// let copied = Transfer::new(Time::Nanoseconds(0), Time::Nanoseconds(2999))
+///     .metric("cpu")
+///     .chunk(RelativeTime::new(1, TimeUnit::SECONDS))
+///     .on_progress(|progress| println!("{:.0}%", progress.fraction() * 100.0))
+///     .run(&source, &target)
+///     .unwrap();
+/// assert_eq!(copied, 4);
+///
+/// let mut query = Query::new(Time::Nanoseconds(0), Time::Nanoseconds(2999));
+/// query.add(Metric::new("cpu", Tags::new(), vec![]));
+/// assert_eq!(target.query(&query).unwrap(), source.query(&query).unwrap());
+/// assert_eq!(target.query_tags(&query).unwrap()["cpu"]["host"], vec!["a", "b"]);
+/// ```
+#[derive(Clone)]
+pub struct Transfer {
+    start: Time,
+    end: Time,
+    metrics: Vec<String>,
+    chunk: RelativeTime,
+    batch_size: usize,
+    progress: Option<ProgressHook>,
+}
+
+impl Transfer {
+    /// Creates a new `Transfer` of the time range without metrics
+    pub fn new(start: Time, end: Time) -> Transfer {
+        Transfer {
+            start,
+            end,
+            metrics: vec![],
+            chunk: RelativeTime::new(1, TimeUnit::DAYS),
+            batch_size: 10_000,
+            progress: None,
+        }
+    }
+
+    /// Adds a metric to transfer
+    pub fn metric(mut self, name: &str) -> Transfer {
+        self.metrics.push(name.to_string());
+        self
+    }
+
+    /// Sets the time range read with a single query
+    pub fn chunk(mut self, chunk: RelativeTime) -> Transfer {
+        self.chunk = chunk;
+        self
+    }
+
+    /// Sets the number of datapoints written with a single request
+    pub fn batch_size(mut self, batch_size: usize) -> Transfer {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Calls `callback` after every copied chunk
+    pub fn on_progress<F>(mut self, callback: F) -> Transfer
+        where F: Fn(&Progress) + Send + Sync + 'static
+    {
+        self.progress = Some(Arc::new(callback));
+        self
+    }
+
+    /// Copies the datapoints from `source` to `target` and returns the
+    /// number of copied datapoints
+    pub fn run(&self, source: &dyn KairosClient, target: &dyn KairosClient)
+               -> Result<u64, KairoError> {
+        let mut query = Query::new(self.start.clone(), self.end.clone());
+        let (start, end) = query.range_ms(Utc::now().timestamp_millis());
+        for name in &self.metrics {
+            query.add(Metric::new(name, Tags::new(), vec![]));
+        }
+
+        // group by all tags, so every series of the source is copied
+        // separately with its tag values
+        let tags = source.query_tags(&query)?;
+        for metric in query.metrics_mut() {
+            let names: Vec<&str> = tags.get(metric.name()).into_iter()
+                .flat_map(|tags| tags.keys())
+                .map(String::as_str)
+                .collect();
+            if !names.is_empty() {
+                metric.add_group_by(GroupBy::tags(&names));
+            }
+        }
+
+        let width = self.chunk.as_millis().max(1);
+        let mut progress = Progress { start, end, copied_until: start, datapoints: 0 };
+        let mut chunk_start = start;
+        while chunk_start <= end {
+            let chunk_end = chunk_start.saturating_add(width - 1).min(end);
+            query.set_start(Time::Nanoseconds(chunk_start));
+            query.set_end(Time::Nanoseconds(chunk_end));
+            progress.datapoints += self.copy(&query, source, target)?;
+            progress.copied_until = chunk_end;
+            if let Some(ref callback) = self.progress {
+                callback(&progress);
+            }
+            if chunk_end == end {
+                break;
+            }
+            chunk_start = chunk_end + 1;
+        }
+        Ok(progress.datapoints)
+    }
+
+    // Copies the result of a single chunk query
+    fn copy(&self, query: &Query, source: &dyn KairosClient, target: &dyn KairosClient)
+            -> Result<u64, KairoError> {
+        let mut batch = Vec::new();
+        let mut rows = 0;
+        let mut copied = 0;
+        for (name, series) in source.query(query)? {
+            for series in series.into_iter().filter(|series| !series.values.is_empty()) {
+                let mut datapoints = Datapoints::new(&name, 0);
+                for (tag, values) in &series.tags {
+                    if let [value] = values.as_slice() {
+                        datapoints.add_tag(tag, value);
+                    }
+                }
+                for value in series.values {
+                    datapoints.add_value(value.time as i64, value.value);
+                }
+                rows += datapoints.len();
+                batch.push(datapoints);
+                if rows >= self.batch_size.max(1) {
+                    target.add_batch(&batch)?;
+                    copied += rows as u64;
+                    batch.clear();
+                    rows = 0;
+                }
+            }
+        }
+        if !batch.is_empty() {
+            target.add_batch(&batch)?;
+            copied += rows as u64;
+        }
+        Ok(copied)
+    }
+}
+
+impl fmt::Debug for Transfer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Transfer")
+            .field("start", &self.start)
+            .field("end", &self.end)
+            .field("metrics", &self.metrics)
+            .field("chunk", &self.chunk)
+            .field("batch_size", &self.batch_size)
+            .field("on_progress", &self.progress.is_some())
+            .finish()
+    }
+}
@@ -0,0 +1,319 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! HTTP backends used by the client to talk to the KairosDB server
+//!
+//! The `reqwest` feature (enabled by default) provides the
+//! `ReqwestTransport`. The `ureq` feature provides the small synchronous
+//! `UreqTransport` for binaries where size and dependency count matter,
+//! e.g. with `default-features = false, features = ["ureq"]`. Other
+//! backends can be plugged in by implementing the `Transport` trait and
+//! passing it to `Client::with_transport`.
+
+use std::fmt::{self, Debug};
+use std::io::{Cursor, Read};
+#[cfg(feature = "reqwest")]
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub use http::{Method, StatusCode};
+
+use crate::error::KairoError;
+
+/// A HTTP request sent to the KairosDB server
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub url: String,
+    /// JSON body of the request, empty for requests without a body
+    pub body: Vec<u8>,
+}
+
+/// A HTTP response of the KairosDB server
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub body: String,
+}
+
+/// A HTTP response whose body is read while it is received
+pub struct StreamingResponse {
+    pub status: StatusCode,
+    pub body: Box<dyn Read + Send>,
+}
+
+impl Debug for StreamingResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StreamingResponse").field("status", &self.status).finish()
+    }
+}
+
+/// Sends HTTP requests to the KairosDB server
+pub trait Transport: Send + Sync + Debug {
+    /// Sends the request and reads the complete response
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, KairoError>;
+
+    /// Sends a request with a JSON body which is read while sending, e.g.
+    /// with chunked transfer encoding. The default implementation reads
+    /// the whole body before sending it.
+    fn send_streaming(&self, method: Method, url: String, mut body: Box<dyn Read + Send>)
+                      -> Result<HttpResponse, KairoError> {
+        let mut bytes = Vec::new();
+        body.read_to_end(&mut bytes)?;
+        self.send(&HttpRequest { method, url, body: bytes })
+    }
+
+    /// Sends the request and gives up with a transport error once
+    /// `timeout` passed without the complete response. The default
+    /// implementation ignores `timeout` and relies on the timeouts the
+    /// transport was configured with.
+    fn send_timeout(&self, request: &HttpRequest, _timeout: Duration)
+                    -> Result<HttpResponse, KairoError> {
+        self.send(request)
+    }
+
+    /// Sends the request and returns the response as soon as its headers
+    /// are received, e.g. to parse huge responses while reading them. The
+    /// default implementation reads the complete response first.
+    fn send_reading(&self, request: &HttpRequest) -> Result<StreamingResponse, KairoError> {
+        let response = self.send(request)?;
+        Ok(StreamingResponse {
+            status: response.status,
+            body: Box::new(Cursor::new(response.body.into_bytes())),
+        })
+    }
+}
+
+/// Transport based on the blocking `reqwest` client
+///
+/// # Example
+/// ```
+/// use kairosdb::Client;
+/// use kairosdb::transport::ReqwestTransport;
+///
+/// let client = Client::with_transport("localhost", 8080, ReqwestTransport::new());
+/// ```
+#[cfg(feature = "reqwest")]
+#[derive(Debug)]
+pub struct ReqwestTransport {
+    client: reqwest::Client,
+    // the blocking client of reqwest 0.9 has no per request timeouts, so
+    // requests with a timeout use a client built with it. `None` for
+    // transports with a client configured by the user.
+    timeout_client: Option<Mutex<Option<(Duration, reqwest::Client)>>>,
+}
+
+#[cfg(feature = "reqwest")]
+impl ReqwestTransport {
+    /// Creates a new `ReqwestTransport` with a default `reqwest::Client`
+    pub fn new() -> ReqwestTransport {
+        ReqwestTransport {
+            client: reqwest::Client::new(),
+            timeout_client: Some(Mutex::new(None)),
+        }
+    }
+
+    /// Creates a new `ReqwestTransport` using the given client, e.g. to
+    /// configure proxies, TLS or timeouts. Requests with a timeout, like
+    /// `Client::ping`, are sent with the timeout of `client`.
+    pub fn with_client(client: reqwest::Client) -> ReqwestTransport {
+        ReqwestTransport { client, timeout_client: None }
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl Default for ReqwestTransport {
+    fn default() -> ReqwestTransport {
+        ReqwestTransport::new()
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl Transport for ReqwestTransport {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, KairoError> {
+        read_reqwest_response(self.request(request))
+    }
+
+    fn send_timeout(&self, request: &HttpRequest, timeout: Duration)
+                    -> Result<HttpResponse, KairoError> {
+        let timeout_client = match self.timeout_client {
+            Some(ref timeout_client) => timeout_client,
+            None => return self.send(request),
+        };
+        let client = {
+            let mut timeout_client = timeout_client.lock().unwrap();
+            match *timeout_client {
+                Some((cached, ref client)) if cached == timeout => client.clone(),
+                _ => {
+                    let client = reqwest::Client::builder().timeout(timeout).build()?;
+                    *timeout_client = Some((timeout, client.clone()));
+                    client
+                }
+            }
+        };
+        read_reqwest_response(ReqwestTransport::build(&client, request))
+    }
+
+    fn send_reading(&self, request: &HttpRequest) -> Result<StreamingResponse, KairoError> {
+        let response = self.request(request).send()?;
+        Ok(StreamingResponse { status: response.status(), body: Box::new(response) })
+    }
+
+    fn send_streaming(&self, method: Method, url: String, body: Box<dyn Read + Send>)
+                      -> Result<HttpResponse, KairoError> {
+        use reqwest::header::CONTENT_TYPE;
+
+        let builder = self.client.request(method, &url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(reqwest::Body::new(body));
+        read_reqwest_response(builder)
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl ReqwestTransport {
+    fn request(&self, request: &HttpRequest) -> reqwest::RequestBuilder {
+        ReqwestTransport::build(&self.client, request)
+    }
+
+    // reqwest takes ownership of request bodies, so the body is copied
+    fn build(client: &reqwest::Client, request: &HttpRequest) -> reqwest::RequestBuilder {
+        use reqwest::header::CONTENT_TYPE;
+
+        let builder = client.request(request.method.clone(), &request.url);
+        if request.body.is_empty() {
+            return builder;
+        }
+        builder
+            .header(CONTENT_TYPE, "application/json")
+            .body(request.body.clone())
+    }
+}
+
+#[cfg(feature = "reqwest")]
+fn read_reqwest_response(builder: reqwest::RequestBuilder) -> Result<HttpResponse, KairoError> {
+    let mut response = builder.send()?;
+    let mut body = String::new();
+    response.read_to_string(&mut body).map_err(KairoError::transport)?;
+    Ok(HttpResponse { status: response.status(), body })
+}
+
+/// Transport based on the minimal `ureq` client without any async runtime
+///
+/// # Example
+/// ```
+/// use kairosdb::Client;
+/// use kairosdb::transport::UreqTransport;
+///
+/// let client = Client::with_transport("localhost", 8080, UreqTransport::new());
+/// ```
+#[cfg(feature = "ureq")]
+#[derive(Debug)]
+pub struct UreqTransport {
+    agent: ureq::Agent,
+}
+
+#[cfg(feature = "ureq")]
+impl UreqTransport {
+    /// Creates a new `UreqTransport` with a default `ureq::Agent`
+    pub fn new() -> UreqTransport {
+        UreqTransport::with_agent(ureq::Agent::new())
+    }
+
+    /// Creates a new `UreqTransport` using the given agent, e.g. to
+    /// configure timeouts or proxies.
+    pub fn with_agent(agent: ureq::Agent) -> UreqTransport {
+        UreqTransport { agent }
+    }
+}
+
+#[cfg(feature = "ureq")]
+impl Default for UreqTransport {
+    fn default() -> UreqTransport {
+        UreqTransport::new()
+    }
+}
+
+#[cfg(feature = "ureq")]
+impl Transport for UreqTransport {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, KairoError> {
+        read_ureq_response(self.call(request)?)
+    }
+
+    fn send_timeout(&self, request: &HttpRequest, timeout: Duration)
+                    -> Result<HttpResponse, KairoError> {
+        read_ureq_response(self.call_timeout(request, Some(timeout))?)
+    }
+
+    fn send_reading(&self, request: &HttpRequest) -> Result<StreamingResponse, KairoError> {
+        let response = self.call(request)?;
+        Ok(StreamingResponse { status: ureq_status(&response)?, body: response.into_reader() })
+    }
+
+    fn send_streaming(&self, method: Method, url: String, body: Box<dyn Read + Send>)
+                      -> Result<HttpResponse, KairoError> {
+        let result = self.agent.request(method.as_str(), &url)
+            .set("Content-Type", "application/json")
+            .send(body);
+        read_ureq_response(ureq_response(result)?)
+    }
+}
+
+#[cfg(feature = "ureq")]
+impl UreqTransport {
+    fn call(&self, request: &HttpRequest) -> Result<ureq::Response, KairoError> {
+        self.call_timeout(request, None)
+    }
+
+    fn call_timeout(&self, request: &HttpRequest, timeout: Option<Duration>)
+                    -> Result<ureq::Response, KairoError> {
+        let mut builder = self.agent.request(request.method.as_str(), &request.url);
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout);
+        }
+        ureq_response(if request.body.is_empty() {
+            builder.call()
+        } else {
+            builder
+                .set("Content-Type", "application/json")
+                .send_bytes(&request.body)
+        })
+    }
+}
+
+#[cfg(feature = "ureq")]
+fn read_ureq_response(response: ureq::Response) -> Result<HttpResponse, KairoError> {
+    let status = ureq_status(&response)?;
+    let mut body = String::new();
+    response.into_reader().read_to_string(&mut body).map_err(KairoError::transport)?;
+    Ok(HttpResponse { status, body })
+}
+
+#[cfg(feature = "ureq")]
+fn ureq_response(result: Result<ureq::Response, ureq::Error>)
+                 -> Result<ureq::Response, KairoError> {
+    // ureq reports 4xx and 5xx responses as errors, the client
+    // handles them by status code like every other response
+    match result {
+        Ok(response) => Ok(response),
+        Err(ureq::Error::Status(_, response)) => Ok(response),
+        Err(ureq::Error::Transport(err)) => Err(KairoError::transport(err)),
+    }
+}
+
+#[cfg(feature = "ureq")]
+fn ureq_status(response: &ureq::Response) -> Result<StatusCode, KairoError> {
+    StatusCode::from_u16(response.status()).map_err(|err| KairoError::Kairo(err.to_string()))
+}
@@ -0,0 +1,427 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Client-side validation of queries, datapoints and names before they
+//! are sent
+//!
+//! Names are only checked where asked for: `Metric::new`,
+//! `Datapoints::new` and `Datapoints::add_tag` accept any name, while
+//! `Metric::try_new` and `Datapoints::try_add_tag` reject names KairosDB
+//! does not accept. `Query::validate` and `validate_batch` check
+//! complete queries and batches.
+
+use std::fmt;
+
+use chrono::Utc;
+
+use crate::datapoints::{DataPointValue, Datapoints};
+use crate::query::{Aggregator, AggregatorType, Query};
+
+/// A problem found by `Query::validate` or `validate_batch`. Metrics
+/// and aggregators are referenced by their index in the query, sets of
+/// datapoints by their index in the batch. `metric`, `field` and
+/// `reason` tell where the problem is and what it is, e.g. to mark it
+/// in an editor.
+///
+/// # Example
+/// ```
+/// use kairosdb::query::{Query, TimeUnit};
+///
+/// let query = Query::builder().metric(|m| m.name("cpu").rate(TimeUnit::SECONDS)).build();
+/// let errors = query.validate().unwrap_err();
+/// assert_eq!(errors[0].metric(), None);
+/// assert_eq!(errors[0].field(), "start");
+/// assert_eq!(errors[0].reason(), "is missing");
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    NoMetrics,
+    MissingStart,
+    EndBeforeStart,
+    EmptyMetricName { metric: usize },
+    /// A metric name, tag name or tag value with characters KairosDB
+    /// does not accept. `field` is `name` or the tag, e.g. `tags.host`,
+    /// `metric` is unknown for names checked on their own.
+    InvalidName { metric: Option<usize>, field: String, name: String },
+    /// A set of datapoints without tags, KairosDB needs at least one
+    NoTags { metric: usize },
+    /// A datapoint whose value is not a finite number
+    InvalidValue { metric: usize, datapoint: usize },
+    MissingSampling { metric: usize, aggregator: usize },
+    UnexpectedSampling { metric: usize, aggregator: usize },
+    InvalidSampling { metric: usize, aggregator: usize },
+    MissingParameter { metric: usize, aggregator: usize, parameter: &'static str },
+    InvalidParameter { metric: usize, aggregator: usize, parameter: &'static str },
+    /// An aggregator the server does not support, see `Features::check`
+    UnsupportedAggregator { metric: usize, aggregator: usize },
+    /// A group-by the server does not support, see `Features::check`
+    UnsupportedGroupBy { metric: usize, group_by: usize },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ValidationError::NoMetrics => write!(f, "query has no metrics"),
+            ValidationError::MissingStart => write!(f, "query has no start time"),
+            ValidationError::EndBeforeStart => write!(f, "query ends before it starts"),
+            ValidationError::EmptyMetricName { metric } =>
+                write!(f, "metric {} has no name", metric),
+            ValidationError::InvalidName { ref name, .. } =>
+                write!(f, "invalid name '{}', only letters, digits, '-', '_', '.' and '/' \
+                           are allowed", name),
+            ValidationError::NoTags { metric } =>
+                write!(f, "datapoints of metric {} have no tags", metric),
+            ValidationError::InvalidValue { metric, datapoint } =>
+                write!(f, "datapoint {} of metric {} is not a finite number", datapoint, metric),
+            ValidationError::MissingSampling { metric, aggregator } =>
+                write!(f, "aggregator {} of metric {} needs a sampling", aggregator, metric),
+            ValidationError::UnexpectedSampling { metric, aggregator } =>
+                write!(f, "aggregator {} of metric {} takes no sampling", aggregator, metric),
+            ValidationError::InvalidSampling { metric, aggregator } =>
+                write!(f, "aggregator {} of metric {} has a sampling of zero or less",
+                       aggregator, metric),
+            ValidationError::MissingParameter { metric, aggregator, parameter } =>
+                write!(f, "aggregator {} of metric {} needs the parameter {}",
+                       aggregator, metric, parameter),
+            ValidationError::InvalidParameter { metric, aggregator, parameter } =>
+                write!(f, "aggregator {} of metric {} has an invalid {}",
+                       aggregator, metric, parameter),
+            ValidationError::UnsupportedAggregator { metric, aggregator } =>
+                write!(f, "aggregator {} of metric {} is not supported by the server",
+                       aggregator, metric),
+            ValidationError::UnsupportedGroupBy { metric, group_by } =>
+                write!(f, "group-by {} of metric {} is not supported by the server",
+                       group_by, metric),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl ValidationError {
+    /// Returns the index of the metric or set of datapoints with the
+    /// problem, `None` for problems of the whole query
+    pub fn metric(&self) -> Option<usize> {
+        match *self {
+            ValidationError::NoMetrics
+            | ValidationError::MissingStart
+            | ValidationError::EndBeforeStart => None,
+            ValidationError::InvalidName { metric, .. } => metric,
+            ValidationError::EmptyMetricName { metric }
+            | ValidationError::NoTags { metric }
+            | ValidationError::InvalidValue { metric, .. }
+            | ValidationError::MissingSampling { metric, .. }
+            | ValidationError::UnexpectedSampling { metric, .. }
+            | ValidationError::InvalidSampling { metric, .. }
+            | ValidationError::MissingParameter { metric, .. }
+            | ValidationError::InvalidParameter { metric, .. }
+            | ValidationError::UnsupportedAggregator { metric, .. }
+            | ValidationError::UnsupportedGroupBy { metric, .. } => Some(metric),
+        }
+    }
+
+    /// Returns the JSON field with the problem, relative to the metric
+    /// if there is one, e.g. `aggregators[1].sampling`
+    pub fn field(&self) -> String {
+        match *self {
+            ValidationError::NoMetrics => "metrics".to_string(),
+            ValidationError::MissingStart => "start".to_string(),
+            ValidationError::EndBeforeStart => "end".to_string(),
+            ValidationError::EmptyMetricName { .. } => "name".to_string(),
+            ValidationError::InvalidName { ref field, .. } => field.clone(),
+            ValidationError::NoTags { .. } => "tags".to_string(),
+            ValidationError::InvalidValue { datapoint, .. } => format!("datapoints[{}]", datapoint),
+            ValidationError::MissingSampling { aggregator, .. }
+            | ValidationError::UnexpectedSampling { aggregator, .. }
+            | ValidationError::InvalidSampling { aggregator, .. } => {
+                format!("aggregators[{}].sampling", aggregator)
+            }
+            ValidationError::MissingParameter { aggregator, parameter, .. }
+            | ValidationError::InvalidParameter { aggregator, parameter, .. } => {
+                format!("aggregators[{}].{}", aggregator, parameter)
+            }
+            ValidationError::UnsupportedAggregator { aggregator, .. } => {
+                format!("aggregators[{}].name", aggregator)
+            }
+            ValidationError::UnsupportedGroupBy { group_by, .. } => {
+                format!("group_by[{}].name", group_by)
+            }
+        }
+    }
+
+    /// Returns what is wrong with the field
+    pub fn reason(&self) -> &'static str {
+        match *self {
+            ValidationError::NoMetrics
+            | ValidationError::EmptyMetricName { .. }
+            | ValidationError::NoTags { .. } => "is empty",
+            ValidationError::MissingStart
+            | ValidationError::MissingSampling { .. }
+            | ValidationError::MissingParameter { .. } => "is missing",
+            ValidationError::EndBeforeStart => "is before the start",
+            ValidationError::InvalidName { .. } => {
+                "may only contain letters, digits, '-', '_', '.' and '/'"
+            }
+            ValidationError::InvalidValue { .. } => "is not a finite number",
+            ValidationError::UnexpectedSampling { .. } => "is not supported by the aggregator",
+            ValidationError::InvalidSampling { .. } => "is zero or less",
+            ValidationError::InvalidParameter { .. } => "is invalid",
+            ValidationError::UnsupportedAggregator { .. }
+            | ValidationError::UnsupportedGroupBy { .. } => "is not supported by the server",
+        }
+    }
+}
+
+impl Query {
+    /// Checks the query for problems the server would reject, without
+    /// sending it. All problems found are returned.
+    ///
+    /// # Example
+    /// ```
+    /// use kairosdb::query::{Aggregator, AggregatorType, Metric, Query, RelativeTime,
+    ///                       Tags, Time, TimeUnit};
+    /// use kairosdb::validation::ValidationError;
+    ///
+    /// let mut query = Query::new(Time::Nanoseconds(2000), Time::Nanoseconds(1000));
+    /// let aggregator = Aggregator::new(AggregatorType::AVG,
+    ///                                  RelativeTime::new(0, TimeUnit::MINUTES));
+    /// query.add(Metric::new("myMetric", Tags::new(), vec![aggregator]));
+    ///
+    /// assert_eq!(query.validate(), Err(vec![
+    ///     ValidationError::EndBeforeStart,
+    ///     ValidationError::InvalidSampling { metric: 0, aggregator: 0 },
+    /// ]));
+    /// ```
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Vec::new();
+
+        if !self.has_start() {
+            errors.push(ValidationError::MissingStart);
+        } else {
+            let (start, end) = self.range_ms(Utc::now().timestamp_millis());
+            if end < start {
+                errors.push(ValidationError::EndBeforeStart);
+            }
+        }
+
+        if self.metrics().is_empty() {
+            errors.push(ValidationError::NoMetrics);
+        }
+        for (m, metric) in self.metrics().iter().enumerate() {
+            if metric.name().is_empty() {
+                errors.push(ValidationError::EmptyMetricName { metric: m });
+            } else if let Err(err) = check_name("name", metric.name()) {
+                errors.push(err.of_metric(m));
+            }
+            for (name, values) in metric.tags() {
+                let field = tag_field(name);
+                let invalid = std::iter::once(name).chain(values)
+                    .filter_map(|name| check_name(&field, name).err());
+                errors.extend(invalid.map(|err| err.of_metric(m)));
+            }
+            for (a, aggregator) in metric.aggregators().iter().enumerate() {
+                validate_aggregator(aggregator, m, a, &mut errors);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Checks a batch of datapoints for problems the server would reject,
+/// without sending it. All problems found are returned.
+///
+/// # Example
+/// ```
+/// use kairosdb::datapoints::Datapoints;
+/// use kairosdb::validation::{validate_batch, ValidationError};
+///
+/// let batch = vec![
+///     Datapoints::builder("cpu").tag("host", "a").point(1000, 0.5).build(),
+///     Datapoints::builder("cpu load").point(1000, f64::NAN).build(),
+/// ];
+/// assert_eq!(validate_batch(&batch), Err(vec![
+///     ValidationError::InvalidName {
+///         metric: Some(1), field: "name".into(), name: "cpu load".into(),
+///     },
+///     ValidationError::NoTags { metric: 1 },
+///     ValidationError::InvalidValue { metric: 1, datapoint: 0 },
+/// ]));
+/// ```
+pub fn validate_batch(batch: &[Datapoints]) -> Result<(), Vec<ValidationError>> {
+    let mut errors = Vec::new();
+    for (m, datapoints) in batch.iter().enumerate() {
+        if datapoints.name().is_empty() {
+            errors.push(ValidationError::EmptyMetricName { metric: m });
+        } else if let Err(err) = check_name("name", datapoints.name()) {
+            errors.push(err.of_metric(m));
+        }
+        if datapoints.tags().is_empty() {
+            errors.push(ValidationError::NoTags { metric: m });
+        }
+        let mut tags: Vec<_> = datapoints.tags().iter().collect();
+        tags.sort();
+        for (name, value) in tags {
+            if let Err(err) = check_tag(name, value) {
+                errors.push(err.of_metric(m));
+            }
+        }
+        for (d, (_, value)) in datapoints.datapoints().iter().enumerate() {
+            if let DataPointValue::Double(value) = *value {
+                if !value.is_finite() {
+                    errors.push(ValidationError::InvalidValue { metric: m, datapoint: d });
+                }
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Returns true if KairosDB accepts `name` as metric name, tag name or
+/// tag value. Allowed are letters, digits, `-`, `_`, `.` and `/`.
+///
+/// # Example
+/// ```
+/// use kairosdb::validation::{is_valid_name, sanitize_name};
+///
+/// assert!(is_valid_name("cpu.load/1m"));
+/// assert!(!is_valid_name("cpu load"));
+/// assert_eq!(sanitize_name("cpu load"), "cpu_load");
+/// ```
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(is_valid_char)
+}
+
+/// Replaces all characters KairosDB does not accept in names with `_`
+pub fn sanitize_name(name: &str) -> String {
+    name.chars().map(|c| if is_valid_char(c) { c } else { '_' }).collect()
+}
+
+pub(crate) fn check_name(field: &str, name: &str) -> Result<(), ValidationError> {
+    if is_valid_name(name) {
+        Ok(())
+    } else {
+        Err(ValidationError::InvalidName {
+            metric: None,
+            field: field.to_string(),
+            name: name.to_string(),
+        })
+    }
+}
+
+/// Checks the name and value of a tag
+pub(crate) fn check_tag(name: &str, value: &str) -> Result<(), ValidationError> {
+    let field = tag_field(name);
+    check_name(&field, name)?;
+    check_name(&field, value)
+}
+
+/// Returns the field of a tag in errors, e.g. `tags.host`
+pub(crate) fn tag_field(name: &str) -> String {
+    format!("tags.{}", name)
+}
+
+impl ValidationError {
+    fn of_metric(self, index: usize) -> ValidationError {
+        match self {
+            ValidationError::InvalidName { field, name, .. } => {
+                ValidationError::InvalidName { metric: Some(index), field, name }
+            }
+            err => err,
+        }
+    }
+}
+
+fn is_valid_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '-' || c == '_' || c == '.' || c == '/'
+}
+
+fn validate_aggregator(aggregator: &Aggregator, metric: usize, index: usize,
+                       errors: &mut Vec<ValidationError>) {
+    let missing = |parameter| ValidationError::MissingParameter {
+        metric, aggregator: index, parameter,
+    };
+
+    let needs_sampling = match *aggregator.name() {
+        AggregatorType::DIFF => false,
+        AggregatorType::RATE => {
+            if aggregator.unit().is_none() {
+                errors.push(missing("unit"));
+            }
+            // rate takes an optional sampling
+            if aggregator.sampling().is_none() {
+                return;
+            }
+            true
+        }
+        AggregatorType::SCALE => {
+            if aggregator.factor().is_none() {
+                errors.push(missing("factor"));
+            }
+            false
+        }
+        AggregatorType::DIV => {
+            match aggregator.divisor() {
+                None => errors.push(missing("divisor")),
+                Some(0.0) => errors.push(ValidationError::InvalidParameter {
+                    metric, aggregator: index, parameter: "divisor",
+                }),
+                Some(_) => (),
+            }
+            false
+        }
+        AggregatorType::FILTER => {
+            if aggregator.filter_condition().is_none() {
+                errors.push(missing("filter_op"));
+            }
+            false
+        }
+        AggregatorType::TRIM => {
+            if aggregator.trim_mode().is_none() {
+                errors.push(missing("trim"));
+            }
+            false
+        }
+        AggregatorType::SAVEAS => {
+            if aggregator.save_as_target().is_none() {
+                errors.push(missing("metric_name"));
+            }
+            false
+        }
+        _ => true,
+    };
+
+    match (needs_sampling, aggregator.sampling()) {
+        (true, None) => errors.push(ValidationError::MissingSampling {
+            metric, aggregator: index,
+        }),
+        (true, Some(sampling)) if sampling.as_millis() <= 0 => {
+            errors.push(ValidationError::InvalidSampling { metric, aggregator: index })
+        }
+        (false, Some(_)) => errors.push(ValidationError::UnexpectedSampling {
+            metric, aggregator: index,
+        }),
+        _ => (),
+    }
+}
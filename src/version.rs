@@ -0,0 +1,75 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Version of a KairosDB server
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::KairoError;
+
+/// Version of a KairosDB server, ordered by major, minor and patch
+/// version, e.g. to check for features of newer versions
+///
+/// # Example
+/// ```
+/// use kairosdb::version::ServerVersion;
+///
+/// let version: ServerVersion = "KairosDB 1.2.2-1.20181109185326".parse().unwrap();
+/// assert_eq!(version, ServerVersion::new(1, 2, 2));
+/// assert!(version >= ServerVersion::new(1, 2, 0));
+/// assert!(version < ServerVersion::new(1, 10, 0));
+/// assert_eq!(version.to_string(), "1.2.2");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ServerVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl ServerVersion {
+    /// Creates a new `ServerVersion`
+    pub fn new(major: u32, minor: u32, patch: u32) -> ServerVersion {
+        ServerVersion { major, minor, patch }
+    }
+}
+
+impl FromStr for ServerVersion {
+    type Err = KairoError;
+
+    /// Parses the first dotted number of a version string like
+    /// `KairosDB 1.2.2-1.20181109185326`. Missing minor or patch
+    /// versions are 0.
+    fn from_str(version: &str) -> Result<ServerVersion, KairoError> {
+        let invalid = || KairoError::Kairo(format!("Invalid server version '{}'", version));
+        let start = version.find(|c: char| c.is_ascii_digit()).ok_or_else(invalid)?;
+        let number = version[start..]
+            .split(|c: char| !c.is_ascii_digit() && c != '.')
+            .next()
+            .unwrap_or_default();
+
+        let mut parts = number.split('.').filter(|part| !part.is_empty()).map(str::parse::<u32>);
+        let mut next = || parts.next().transpose().map_err(|_| invalid());
+        let major = next()?.ok_or_else(invalid)?;
+        Ok(ServerVersion::new(major, next()?.unwrap_or(0), next()?.unwrap_or(0)))
+    }
+}
+
+impl fmt::Display for ServerVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
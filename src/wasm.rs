@@ -0,0 +1,326 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Browser client based on the fetch API
+//!
+//! Compile the crate for `wasm32-unknown-unknown` with
+//! `default-features = false, features = ["wasm"]` to query KairosDB
+//! from a Rust frontend with the same `Query` and `ResultMap` types as the
+//! blocking `Client`. The KairosDB server has to allow cross origin
+//! requests from the frontend.
+//!
+//! ```no_run
+//! # async fn run() {
+//! use kairosdb::wasm::FetchClient;
+//! use kairosdb::query::{Query, Time, Metric, Tags, TimeUnit};
+//!
+//! let client = FetchClient::new("http://localhost:8080");
+//! let mut query = Query::new(
+//!    Time::Relative{value: 1, unit: TimeUnit::HOURS},
+//!    Time::Relative{value: 0, unit: TimeUnit::HOURS});
+//! query.add(Metric::new("myMetric", Tags::new(), vec![]));
+//! let result = client.query(&query).await.unwrap();
+//! # }
+//! ```
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use web_sys::{RequestInit, RequestMode};
+
+use crate::datapoints::Datapoints;
+use crate::error::{KairoError, RequestContext};
+use crate::helper::parse_metricnames_result;
+use crate::query::Query;
+use crate::result::{QueryResult, ResultMap};
+use crate::request::Response;
+use crate::transport::{Method, StatusCode};
+use crate::writer::{OverflowPolicy, Queue, WriterConfig};
+
+/// Asynchronous client sending its requests with the browser fetch API
+#[derive(Debug, Clone)]
+pub struct FetchClient {
+    base_url: String,
+}
+
+impl FetchClient {
+    /// Creates a new `FetchClient` for the server at `base_url`,
+    /// e.g. `http://localhost:8080`
+    pub fn new(base_url: &str) -> FetchClient {
+        FetchClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Returns the version string of the KairosDB Server
+    pub async fn version(&self) -> Result<String, KairoError> {
+        let response = self.fetch(Method::GET, "/api/v1/version", None).await?;
+        let version: serde_json::Value = serde_json::from_str(&response.body)?;
+        match version["version"].as_str() {
+            Some(version) => Ok(version.to_string()),
+            None => Err(KairoError::Kairo("Missing version in response".to_string())),
+        }
+    }
+
+    /// Returns a list with all metric names
+    pub async fn list_metrics(&self) -> Result<Vec<String>, KairoError> {
+        let response = self.fetch(Method::GET, "/api/v1/metricnames", None).await?;
+        match response.status {
+            StatusCode::OK => Ok(parse_metricnames_result(&response.body)?),
+            _ => Err(response.error()),
+        }
+    }
+
+    /// Adds datapoints to the time series database
+    pub async fn add(&self, datapoints: &Datapoints) -> Result<(), KairoError> {
+        self.add_batch(std::slice::from_ref(datapoints)).await
+    }
+
+    /// Adds several sets of datapoints in a single request
+    pub async fn add_batch(&self, batch: &[Datapoints]) -> Result<(), KairoError> {
+        let body = serde_json::to_string(batch)?;
+        let response = self.fetch(Method::POST, "/api/v1/datapoints", Some(body)).await?;
+        match response.status {
+            StatusCode::NO_CONTENT => Ok(()),
+            _ => Err(response.write_error()),
+        }
+    }
+
+    /// Runs a query on the database
+    pub async fn query(&self, query: &Query) -> Result<ResultMap, KairoError> {
+        let body = serde_json::to_string(query)?;
+        let response = self.fetch(Method::POST, "/api/v1/datapoints/query", Some(body)).await?;
+        match response.status {
+            StatusCode::OK => QueryResult::new().parse_result(&response.body),
+            _ => Err(response.error()),
+        }
+    }
+
+    async fn fetch(&self, method: Method, endpoint: &str, body: Option<String>)
+                   -> Result<Response, KairoError> {
+        let url = format!("{}{}", self.base_url, endpoint);
+        let context = RequestContext::new(&method, &url, body.as_deref().unwrap_or("").as_bytes());
+        self.send(&method, &url, body).await
+            .map(|(status, body)| Response { status, body, request: context.clone() })
+            .map_err(|err| err.with_request(&context))
+    }
+
+    async fn send(&self, method: &Method, url: &str, body: Option<String>)
+                  -> Result<(StatusCode, String), KairoError> {
+        let init = RequestInit::new();
+        init.set_method(method.as_str());
+        init.set_mode(RequestMode::Cors);
+        if let Some(ref body) = body {
+            init.set_body(&JsValue::from_str(body));
+        }
+
+        let request = web_sys::Request::new_with_str_and_init(url, &init).map_err(js_error)?;
+        if body.is_some() {
+            request.headers().set("Content-Type", "application/json").map_err(js_error)?;
+        }
+
+        let window = web_sys::window()
+            .ok_or_else(|| KairoError::Kairo("No browser window available".to_string()))?;
+        let response: web_sys::Response = JsFuture::from(window.fetch_with_request(&request))
+            .await
+            .map_err(js_error)?
+            .dyn_into()
+            .map_err(js_error)?;
+
+        let status = StatusCode::from_u16(response.status())
+            .map_err(|err| KairoError::Kairo(err.to_string()))?;
+        let text = JsFuture::from(response.text().map_err(js_error)?)
+            .await
+            .map_err(js_error)?;
+        Ok((status, text.as_string().unwrap_or_default()))
+    }
+}
+
+/// Buffers datapoints for a `FetchClient` and writes them in batches. A
+/// batch is written when `max_batch` datapoints are buffered or
+/// `flush_interval` elapsed since the first buffered datapoint. Failed
+/// batches are retried with an exponential backoff. At most `max_pending`
+/// datapoints are buffered; with the `Block` overflow policy a push into
+/// a full buffer writes the buffer first. Dropping the sink
+/// writes the remaining datapoints in a spawned task; `close` writes them
+/// and returns the result.
+///
+/// ```no_run
+/// # async fn run() {
+/// use kairosdb::datapoints::Datapoints;
+/// use kairosdb::wasm::{DatapointSink, FetchClient};
+/// use kairosdb::writer::WriterConfig;
+///
+/// let client = FetchClient::new("http://localhost:8080");
+/// let sink = DatapointSink::new(client, WriterConfig::default());
+///
+/// let mut datapoints = Datapoints::new("clicks", 0);
+/// datapoints.add_ms(1475513259000, 1.0);
+/// sink.push(datapoints).await.unwrap();
+/// sink.close().await.unwrap();
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct DatapointSink {
+    inner: Rc<SinkInner>,
+}
+
+#[derive(Debug)]
+struct SinkInner {
+    client: FetchClient,
+    config: WriterConfig,
+    buffer: RefCell<Buffer>,
+}
+
+#[derive(Debug, Default)]
+struct Buffer {
+    queue: Queue,
+    timer: bool,
+}
+
+impl DatapointSink {
+    /// Creates a new `DatapointSink` writing with `client`
+    pub fn new(client: FetchClient, config: WriterConfig) -> DatapointSink {
+        DatapointSink {
+            inner: Rc::new(SinkInner { client, config, buffer: RefCell::default() }),
+        }
+    }
+
+    /// Adds a set of datapoints to the buffer. Writes the buffer if it
+    /// holds `max_batch` datapoints afterwards. If the buffer is full the
+    /// `overflow` policy decides whether the buffer is written first,
+    /// datapoints are dropped or this fails with `KairoError::BufferFull`.
+    pub async fn push(&self, datapoints: Datapoints) -> Result<(), KairoError> {
+        if datapoints.is_empty() {
+            return Ok(());
+        }
+        let points = datapoints.len();
+        let config = &self.inner.config;
+        let fits = self.inner.buffer.borrow().queue.fits(points, config.max_pending);
+        if !fits {
+            match config.overflow {
+                OverflowPolicy::Block => self.inner.flush().await?,
+                OverflowPolicy::DropOldest => {
+                    self.inner.buffer.borrow_mut().queue.drop_oldest(points, config.max_pending)
+                }
+                OverflowPolicy::DropNewest => {
+                    self.inner.buffer.borrow_mut().queue.drop_points(points);
+                    return Ok(());
+                }
+                OverflowPolicy::Error => return Err(KairoError::BufferFull),
+            }
+        }
+        let (full, start_timer) = {
+            let mut buffer = self.inner.buffer.borrow_mut();
+            buffer.queue.push(datapoints);
+            let start_timer = !buffer.timer;
+            buffer.timer = true;
+            (buffer.queue.points() >= config.max_batch, start_timer)
+        };
+        if start_timer {
+            let inner = self.inner.clone();
+            spawn_local(async move {
+                let _ = sleep(inner.config.flush_interval).await;
+                inner.buffer.borrow_mut().timer = false;
+                if let Err(err) = inner.flush().await {
+                    error!("writing buffered datapoints failed: {:?}", err);
+                }
+            });
+        }
+        if full {
+            self.inner.flush().await
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Writes all buffered datapoints. The datapoints are dropped if the
+    /// write still fails after all retries.
+    pub async fn flush(&self) -> Result<(), KairoError> {
+        self.inner.flush().await
+    }
+
+    /// Returns the number of buffered datapoints not yet written
+    pub fn pending(&self) -> usize {
+        self.inner.buffer.borrow().queue.points()
+    }
+
+    /// Returns the number of datapoints dropped by the overflow policy
+    pub fn dropped(&self) -> u64 {
+        self.inner.buffer.borrow().queue.dropped()
+    }
+
+    /// Writes all buffered datapoints and closes the sink
+    pub async fn close(self) -> Result<(), KairoError> {
+        self.inner.flush().await
+    }
+}
+
+impl Drop for DatapointSink {
+    fn drop(&mut self) {
+        if self.pending() > 0 {
+            let inner = self.inner.clone();
+            spawn_local(async move {
+                if let Err(err) = inner.flush().await {
+                    error!("writing buffered datapoints failed: {:?}", err);
+                }
+            });
+        }
+    }
+}
+
+impl SinkInner {
+    async fn flush(&self) -> Result<(), KairoError> {
+        let (batch, _) = self.buffer.borrow_mut().queue.take();
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let mut backoff = self.config.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            match self.client.add_batch(&batch).await {
+                Err(err) if attempt < self.config.max_retries && err.is_retryable() => {
+                    warn!("writing {} datapoint sets failed, retrying: {:?}", batch.len(), err);
+                    sleep(backoff).await?;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+// Resolves after `duration` using the browser timer
+async fn sleep(duration: Duration) -> Result<(), KairoError> {
+    let millis = duration.as_millis().min(i32::MAX as u128) as i32;
+    let promise = js_sys::Promise::new(&mut |resolve, _| {
+        let scheduled = web_sys::window().map(|window| {
+            window.set_timeout_with_callback_and_timeout_and_arguments_0(&resolve, millis)
+        });
+        if !matches!(scheduled, Some(Ok(_))) {
+            let _ = resolve.call0(&JsValue::NULL);
+        }
+    });
+    JsFuture::from(promise).await.map(|_| ()).map_err(js_error)
+}
+
+fn js_error(err: JsValue) -> KairoError {
+    KairoError::transport(format!("Fetch failed: {:?}", err))
+}
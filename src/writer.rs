@@ -0,0 +1,633 @@
+// Copyright 2016-2020 Kai Strempel
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Buffered writer sending datapoints in batches from a background thread
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::KairosClient;
+use crate::datapoints::Datapoints;
+use crate::error::KairoError;
+use crate::hooks::Hooks;
+use crate::spool::Spool;
+
+/// What a buffered writer does with new datapoints when it already holds
+/// `max_pending` datapoints
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OverflowPolicy {
+    /// Wait until the background write made room
+    #[default]
+    Block,
+    /// Drop the oldest buffered datapoint sets
+    DropOldest,
+    /// Drop the new datapoints
+    DropNewest,
+    /// Reject the new datapoints with `KairoError::BufferFull`
+    Error,
+}
+
+/// Callback receiving the batches a buffered writer gave up on, e.g. to
+/// log or persist them
+///
+/// # Example
+/// ```
+/// use kairosdb::writer::{DeadLetter, WriterConfig};
+///
+/// let config = WriterConfig {
+///     retry_queue: 100,
+///     dead_letter: Some(DeadLetter::new(|batch| eprintln!("lost {} sets", batch.len()))),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Clone)]
+pub struct DeadLetter(Arc<dyn Fn(Vec<Datapoints>) + Send + Sync>);
+
+impl DeadLetter {
+    /// Creates a new `DeadLetter` calling `callback`
+    pub fn new<F>(callback: F) -> DeadLetter
+        where F: Fn(Vec<Datapoints>) + Send + Sync + 'static
+    {
+        DeadLetter(Arc::new(callback))
+    }
+}
+
+impl fmt::Debug for DeadLetter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DeadLetter")
+    }
+}
+
+/// Configuration of a `BufferedWriter`
+#[derive(Debug, Clone)]
+pub struct WriterConfig {
+    /// Number of buffered datapoints which triggers a flush, at least 1
+    pub max_batch: usize,
+    /// Maximum time datapoints stay in the buffer
+    pub flush_interval: Duration,
+    /// Number of immediate retries of a failed batch before it is
+    /// spooled, queued for a later retry or given up. Errors which are
+    /// not retryable, e.g. a rejected batch, are not retried immediately.
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled for every further retry
+    pub retry_backoff: Duration,
+    /// Maximum number of buffered datapoints, including those of the
+    /// batch being written. A single set larger than the bound is
+    /// accepted into an empty buffer.
+    pub max_pending: usize,
+    /// What to do with new datapoints when `max_pending` is reached
+    pub overflow: OverflowPolicy,
+    /// Directory to store batches in which still fail after all retries.
+    /// Stored batches are written again before new ones once the server
+    /// is reachable, including batches stored by earlier runs. Stored
+    /// batches the server rejects are moved to its quarantine.
+    pub spool_dir: Option<PathBuf>,
+    /// Number of failed batches kept in memory to be written again with
+    /// the following batches. Without a retry queue or spool failed
+    /// batches are dropped.
+    pub retry_queue: usize,
+    /// Number of later attempts of a batch in the retry queue
+    pub retry_attempts: u32,
+    /// Receives the batches which are dropped after all attempts, when
+    /// the retry queue is full or when the writer is dropped, and right
+    /// away the sets of datapoints the server rejected
+    pub dead_letter: Option<DeadLetter>,
+    /// Number of concurrent requests a batch is split into
+    pub workers: usize,
+    /// Keeps all sets of a series in the same request when a batch is
+    /// split, so the datapoints of a series arrive in order. Otherwise
+    /// sets are distributed evenly by their number of datapoints.
+    pub ordered: bool,
+}
+
+impl Default for WriterConfig {
+    fn default() -> WriterConfig {
+        WriterConfig {
+            max_batch: 1000,
+            flush_interval: Duration::from_secs(1),
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(100),
+            max_pending: 100_000,
+            overflow: OverflowPolicy::Block,
+            spool_dir: None,
+            retry_queue: 0,
+            retry_attempts: 3,
+            dead_letter: None,
+            workers: 1,
+            ordered: false,
+        }
+    }
+}
+
+/// Collects datapoints pushed from any thread and writes them in batches
+/// from a background thread. A batch is written when `max_batch`
+/// datapoints are buffered or `flush_interval` elapsed. Failed batches
+/// are retried with an exponential backoff and then stored on disk with
+/// a `spool_dir` or kept in the `retry_queue`. At most
+/// `max_pending` datapoints are buffered, further ones are handled by the
+/// `overflow` policy. Dropping the writer writes the remaining datapoints.
+///
+/// # Example
+/// ```
+/// use std::sync::Arc;
+/// use kairosdb::Client;
+/// use kairosdb::datapoints::Datapoints;
+/// use kairosdb::writer::{BufferedWriter, WriterConfig};
+/// # use kairosdb::KairosClient;
+///
+/// let server = kairosdb::testing::StubServer::start();
+/// let client = Arc::new(Client::new("localhost", server.port()));
+/// let writer = BufferedWriter::new(client, WriterConfig::default());
+///
+/// let mut datapoints = Datapoints::new("load", 0);
+/// datapoints.add_now(0.75);
+/// writer.push(datapoints).unwrap();
+///
+/// writer.flush();
+/// assert_eq!(writer.written(), 1);
+/// # assert_eq!(server.store().list_metrics().unwrap(), vec!["load"]);
+/// ```
+#[derive(Debug)]
+pub struct BufferedWriter {
+    shared: Arc<Shared>,
+    worker: Option<JoinHandle<()>>,
+    spool: Option<Arc<Spool>>,
+    max_pending: usize,
+    overflow: OverflowPolicy,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    queue: Queue,
+    // datapoints of the batch being written, which count against
+    // `max_pending` until the write finished
+    in_flight: usize,
+    flush_requested: bool,
+    writing: bool,
+    closed: bool,
+    written: u64,
+    failed: u64,
+    retrying: usize,
+}
+
+// A failed batch waiting in the retry queue
+// Sets of a batch which could not be written
+#[derive(Default)]
+struct Unwritten {
+    // rejected by the server, they fail again on every attempt
+    rejected: Vec<Datapoints>,
+    // failed for other reasons, e.g. while the server was unreachable
+    failed: Vec<Datapoints>,
+}
+
+impl Unwritten {
+    fn failed(batch: Vec<Datapoints>) -> Unwritten {
+        Unwritten { rejected: vec![], failed: batch }
+    }
+
+    fn extend(&mut self, other: Unwritten) {
+        self.rejected.extend(other.rejected);
+        self.failed.extend(other.failed);
+    }
+}
+
+struct Retry {
+    batch: Vec<Datapoints>,
+    points: usize,
+    attempts: u32,
+}
+
+// Buffered datapoint sets, shared by the blocking and the async writer
+#[derive(Debug, Default)]
+pub(crate) struct Queue {
+    sets: Vec<Datapoints>,
+    points: usize,
+    dropped: u64,
+}
+
+impl Queue {
+    pub(crate) fn points(&self) -> usize {
+        self.points
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.sets.is_empty()
+    }
+
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub(crate) fn fits(&self, points: usize, max_pending: usize) -> bool {
+        self.sets.is_empty() || self.points + points <= max_pending
+    }
+
+    pub(crate) fn push(&mut self, datapoints: Datapoints) {
+        self.points += datapoints.len();
+        self.sets.push(datapoints);
+    }
+
+    pub(crate) fn drop_points(&mut self, points: usize) {
+        self.dropped += points as u64;
+    }
+
+    // Drops the oldest sets until `points` more datapoints fit
+    pub(crate) fn drop_oldest(&mut self, points: usize, max_pending: usize) {
+        let mut count = 0;
+        let mut removed = 0;
+        for set in &self.sets {
+            if self.points - removed + points <= max_pending {
+                break;
+            }
+            removed += set.len();
+            count += 1;
+        }
+        self.sets.drain(..count);
+        self.points -= removed;
+        self.dropped += removed as u64;
+    }
+
+    pub(crate) fn take(&mut self) -> (Vec<Datapoints>, usize) {
+        (mem::take(&mut self.sets), mem::replace(&mut self.points, 0))
+    }
+}
+
+impl BufferedWriter {
+    /// Creates a new `BufferedWriter` and starts its background thread
+    pub fn new<C>(client: Arc<C>, mut config: WriterConfig) -> BufferedWriter
+        where C: KairosClient + Send + Sync + 'static
+    {
+        // an empty buffer would count as full and keep the worker spinning
+        config.max_batch = config.max_batch.max(1);
+        let shared = Arc::new(Shared::default());
+        let spool = config.spool_dir.as_ref().map(|dir| Arc::new(Spool::new(dir)));
+        let worker_shared = shared.clone();
+        let worker_spool = spool.clone();
+        let max_pending = config.max_pending;
+        let overflow = config.overflow;
+        let worker = thread::spawn(move || {
+            worker_shared.run(&*client, &config, worker_spool.as_deref())
+        });
+        BufferedWriter { shared, worker: Some(worker), spool, max_pending, overflow }
+    }
+
+    /// Adds a set of datapoints to the buffer. If the buffer is full the
+    /// `overflow` policy decides whether this blocks, drops datapoints or
+    /// fails with `KairoError::BufferFull`.
+    pub fn push(&self, datapoints: Datapoints) -> Result<(), KairoError> {
+        if datapoints.is_empty() {
+            return Ok(());
+        }
+        let points = datapoints.len();
+        let max_pending = self.max_pending;
+        let mut state = self.shared.lock();
+        if !state.fits(points, max_pending) {
+            match self.overflow {
+                OverflowPolicy::Block => {
+                    state.flush_requested = true;
+                    self.shared.changed.notify_all();
+                    while !state.fits(points, max_pending) {
+                        state = self.shared.changed.wait(state).unwrap();
+                    }
+                }
+                OverflowPolicy::DropOldest => {
+                    let available = max_pending.saturating_sub(state.in_flight);
+                    state.queue.drop_oldest(points, available)
+                }
+                OverflowPolicy::DropNewest => {
+                    state.queue.drop_points(points);
+                    return Ok(());
+                }
+                OverflowPolicy::Error => return Err(KairoError::BufferFull),
+            }
+        }
+        state.queue.push(datapoints);
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    /// Writes all buffered datapoints and blocks until they are written,
+    /// spooled, queued for a later retry or given up
+    pub fn flush(&self) {
+        let mut state = self.shared.lock();
+        state.flush_requested = true;
+        self.shared.changed.notify_all();
+        while !state.queue.is_empty() || state.writing {
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Returns the number of buffered datapoints not yet written
+    pub fn pending(&self) -> usize {
+        self.shared.lock().queue.points()
+    }
+
+    /// Returns the number of datapoints written so far
+    pub fn written(&self) -> u64 {
+        self.shared.lock().written
+    }
+
+    /// Returns the number of batches waiting in the spool
+    pub fn spooled(&self) -> usize {
+        self.spool.as_ref().map_or(0, |spool| spool.len())
+    }
+
+    /// Returns the number of datapoints in the retry queue
+    pub fn retrying(&self) -> usize {
+        self.shared.lock().retrying
+    }
+
+    /// Returns the number of datapoints given up after failed retries and
+    /// handed to the dead letter callback
+    pub fn failed(&self) -> u64 {
+        self.shared.lock().failed
+    }
+
+    /// Returns the number of datapoints dropped by the overflow policy
+    pub fn dropped(&self) -> u64 {
+        self.shared.lock().queue.dropped()
+    }
+}
+
+impl Drop for BufferedWriter {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.changed.notify_all();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl State {
+    // Returns true if `points` more datapoints fit next to the buffered
+    // and the in-flight ones
+    fn fits(&self, points: usize, max_pending: usize) -> bool {
+        if self.in_flight == 0 {
+            self.queue.fits(points, max_pending)
+        } else {
+            self.queue.points() + self.in_flight + points <= max_pending
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn run(&self, client: &(dyn KairosClient + Sync), config: &WriterConfig,
+           spool: Option<&Spool>) {
+        let hooks = client.hooks();
+        let mut retries = VecDeque::new();
+        loop {
+            let next = {
+                let mut state = self.lock();
+                let deadline = Instant::now() + config.flush_interval;
+                while state.queue.points() < config.max_batch && !state.flush_requested && !state.closed {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+                }
+                state.flush_requested = false;
+                if state.queue.is_empty() {
+                    if state.closed {
+                        break;
+                    }
+                    self.changed.notify_all();
+                    None
+                } else {
+                    state.writing = true;
+                    let (batch, points) = state.queue.take();
+                    state.in_flight = points;
+                    Some((batch, points))
+                }
+            };
+
+            // spooled batches go first, new batches are spooled behind
+            // them as long as the server is unreachable
+            let replayed = spool.is_none_or(|spool| match spool.replay(client) {
+                Ok(0) => true,
+                Ok(batches) => {
+                    info!("replayed {} spooled batches", batches);
+                    true
+                }
+                Err(err) => {
+                    warn!("replaying spooled batches failed: {:?}", err);
+                    hooks.error(&err);
+                    false
+                }
+            });
+            let retried = replayed && self.retry(client, config, &hooks, &mut retries, false);
+            let (batch, points) = match next {
+                Some(next) => next,
+                None => continue,
+            };
+            let unwritten = if retried {
+                write_batch(client, batch, config, &hooks)
+            } else {
+                Unwritten::failed(batch)
+            };
+            let rejected_points: usize = unwritten.rejected.iter().map(Datapoints::len).sum();
+            let failed_points: usize = unwritten.failed.iter().map(Datapoints::len).sum();
+            let written = points - rejected_points - failed_points;
+            self.lock().written += written as u64;
+            hooks.flush(written, rejected_points + failed_points);
+
+            // rejected sets are neither spooled nor retried
+            if !unwritten.rejected.is_empty() {
+                let batch = unwritten.rejected;
+                self.give_up(config, Retry { batch, points: rejected_points, attempts: 0 });
+            }
+            let failed = unwritten.failed;
+            let spooled = failed.is_empty() || spool.is_some_and(|spool| {
+                match spool.store(&failed) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        error!("spooling {} datapoint sets failed: {:?}", failed.len(), err);
+                        hooks.error(&err);
+                        false
+                    }
+                }
+            });
+            if !spooled {
+                let retry = Retry { batch: failed, points: failed_points, attempts: 0 };
+                if config.retry_queue > 0 {
+                    retries.push_back(retry);
+                    if retries.len() > config.retry_queue {
+                        let oldest = retries.pop_front().unwrap();
+                        self.give_up(config, oldest);
+                    }
+                } else {
+                    self.give_up(config, retry);
+                }
+            }
+
+            let mut state = self.lock();
+            state.writing = false;
+            // wakes pushes blocked on a full buffer
+            state.in_flight = 0;
+            state.retrying = retries.iter().map(|retry| retry.points).sum();
+            self.changed.notify_all();
+        }
+        self.retry(client, config, &hooks, &mut retries, true);
+        self.lock().retrying = 0;
+    }
+
+    // Writes the batches of the retry queue in order and stops at the
+    // first failure. On the last chance every batch is tried once and
+    // given up if it fails. Returns true if the queue is empty afterwards.
+    fn retry(&self, client: &dyn KairosClient, config: &WriterConfig, hooks: &Hooks,
+             retries: &mut VecDeque<Retry>, last_chance: bool) -> bool {
+        while let Some(mut retry) = retries.pop_front() {
+            match client.add_batch(&retry.batch) {
+                Ok(()) => self.lock().written += retry.points as u64,
+                Err(KairoError::PartialWrite(err)) => {
+                    // the rejected sets fail again on every attempt
+                    let failed = err.failed(&retry.batch);
+                    let points = failed.iter().map(Datapoints::len).sum();
+                    warn!("server rejected {} of {} datapoint sets: {:?}",
+                          failed.len(), retry.batch.len(), err);
+                    hooks.error(&KairoError::PartialWrite(err));
+                    self.lock().written += (retry.points - points) as u64;
+                    self.give_up(config, Retry { batch: failed, points, attempts: retry.attempts });
+                }
+                Err(err) => {
+                    retry.attempts += 1;
+                    warn!("attempt {} of writing {} datapoint sets failed: {:?}",
+                          retry.attempts, retry.batch.len(), err);
+                    hooks.error(&err);
+                    if last_chance || retry.attempts >= config.retry_attempts {
+                        self.give_up(config, retry);
+                    } else {
+                        retries.push_front(retry);
+                    }
+                    if !last_chance {
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    fn give_up(&self, config: &WriterConfig, retry: Retry) {
+        self.lock().failed += retry.points as u64;
+        if let Some(ref dead_letter) = config.dead_letter {
+            (dead_letter.0)(retry.batch);
+        }
+    }
+}
+
+// Writes the batch with up to `workers` concurrent requests and returns
+// the sets which could not be written
+fn write_batch(client: &(dyn KairosClient + Sync), batch: Vec<Datapoints>,
+               config: &WriterConfig, hooks: &Hooks) -> Unwritten {
+    if config.workers <= 1 || batch.len() < 2 {
+        return write_with_retry(client, &batch, config, hooks);
+    }
+
+    let chunks = split(batch, config.workers, config.ordered);
+    thread::scope(|scope| {
+        let requests: Vec<_> = chunks.iter()
+            .map(|chunk| scope.spawn(move || write_with_retry(client, chunk, config, hooks)))
+            .collect();
+        // a panicked request fails its whole chunk
+        let mut unwritten = Unwritten::default();
+        for (request, chunk) in requests.into_iter().zip(&chunks) {
+            unwritten.extend(request.join().unwrap_or_else(|_| {
+                let err = KairoError::Kairo("Writer thread panicked".to_string());
+                error!("writing {} datapoint sets failed: {:?}", chunk.len(), err);
+                hooks.error(&err);
+                Unwritten::failed(chunk.clone())
+            }));
+        }
+        unwritten
+    })
+}
+
+fn split(batch: Vec<Datapoints>, workers: usize, ordered: bool) -> Vec<Vec<Datapoints>> {
+    let mut chunks: Vec<Vec<Datapoints>> = (0..workers).map(|_| vec![]).collect();
+    if ordered {
+        for datapoints in batch {
+            let mut tags: Vec<_> = datapoints.tags().iter().collect();
+            tags.sort();
+            let mut hasher = DefaultHasher::new();
+            (datapoints.name(), tags).hash(&mut hasher);
+            chunks[(hasher.finish() % workers as u64) as usize].push(datapoints);
+        }
+    } else {
+        // largest sets first, each to the chunk with the fewest datapoints
+        let mut batch = batch;
+        batch.sort_by_key(|datapoints| std::cmp::Reverse(datapoints.len()));
+        let mut sizes = vec![0; workers];
+        for datapoints in batch {
+            let smallest = (0..workers).min_by_key(|&i| sizes[i]).unwrap();
+            sizes[smallest] += datapoints.len();
+            chunks[smallest].push(datapoints);
+        }
+    }
+    chunks.retain(|chunk| !chunk.is_empty());
+    chunks
+}
+
+// Writes the batch, retrying retryable failures, and returns the sets
+// which could not be written
+fn write_with_retry(client: &dyn KairosClient, batch: &[Datapoints], config: &WriterConfig,
+                    hooks: &Hooks) -> Unwritten {
+    let mut backoff = config.retry_backoff;
+    for attempt in 0..=config.max_retries {
+        match client.add_batch(batch) {
+            Ok(()) => return Unwritten::default(),
+            Err(err) if attempt < config.max_retries && err.is_retryable() => {
+                warn!("writing {} datapoint sets failed, retrying: {:?}", batch.len(), err);
+                hooks.error(&err);
+                hooks.retry(&err, attempt + 1);
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+            Err(KairoError::PartialWrite(err)) => {
+                // the server stored all sets but the rejected ones
+                let rejected = err.failed(batch);
+                error!("server rejected {} of {} datapoint sets: {:?}",
+                       rejected.len(), batch.len(), err);
+                hooks.error(&KairoError::PartialWrite(err));
+                return Unwritten { rejected, failed: vec![] };
+            }
+            Err(err) => {
+                error!("writing {} datapoint sets failed after {} attempts: {:?}",
+                       batch.len(), attempt + 1, err);
+                hooks.error(&err);
+                return Unwritten::failed(batch.to_vec());
+            }
+        }
+    }
+    Unwritten::failed(batch.to_vec())
+}
@@ -1,3 +1,6 @@
+// The tests talk to a stub server through the client's own transport
+#![cfg(any(feature = "reqwest", feature = "ureq"))]
+
 extern crate kairosdb;
 extern crate env_logger;
 extern crate chrono;
@@ -5,15 +8,42 @@ extern crate log;
 
 use std::collections::HashMap;
 use std::ops::{Add, Sub};
-use chrono::Local;
+use std::time::{Duration as StdDuration, Instant};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use chrono::{Local, TimeZone, Utc};
 use chrono::Duration;
-use kairosdb::Client;
+use kairosdb::{Client, KairosClient, KairoError};
+use kairosdb::mock::MockClient;
+use kairosdb::concurrency::ConcurrencyLimit;
+use kairosdb::circuitbreaker::{CircuitBreaker, CircuitState};
 use kairosdb::datapoints::Datapoints;
+use kairosdb::ratelimit::RateLimiter;
+use kairosdb::options::{CancelHandle, RequestOpts};
+use kairosdb::transport::{HttpRequest, HttpResponse, Method, StatusCode, Transport};
+use kairosdb::validation::ValidationError;
+use kairosdb::writer::{BufferedWriter, OverflowPolicy, WriterConfig};
 use kairosdb::query::{Query, Metric, Time, TimeUnit, RelativeTime, Aggregator, AggregatorType};
+use kairosdb::testing::StubServer;
+
+/// Returns a new temporary directory unique to the test process
+fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("kairosdb-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+/// Starts a stub server and returns it with a client using it
+fn stub_client() -> (StubServer, Client) {
+    let server = StubServer::start();
+    let client = Client::new("localhost", server.port());
+    (server, client)
+}
 
 #[test]
 fn get_version() {
-    let client = Client::new("localhost", 8080);
+    let (_server, client) = stub_client();
     assert!(client.version().unwrap().starts_with("KairosDB"));
 }
 
@@ -27,7 +57,7 @@ fn get_version_wrong_host() {
 
 #[test]
 fn add_datapoints_ns() {
-    let client = Client::new("localhost", 8080);
+    let (_server, client) = stub_client();
     let mut datapoints = Datapoints::new("first", 0);
     datapoints.add_ms(1_475_513_259_000, 11.0);
     datapoints.add_ms(1_475_513_259_001, 12.0);
@@ -38,7 +68,7 @@ fn add_datapoints_ns() {
 
 #[test]
 fn add_datapoints() {
-    let client = Client::new("localhost", 8080);
+    let (_server, client) = stub_client();
     let mut datapoints = Datapoints::new("first", 0);
     let dt = Local::now();
     datapoints.add(dt, 11.0);
@@ -51,7 +81,7 @@ fn add_datapoints() {
 
 #[test]
 fn simple_query() {
-    let client = Client::new("localhost", 8080);
+    let (_server, client) = stub_client();
 
     let mut datapoints = Datapoints::new("second", 0);
     datapoints.add_ms(1_147_724_326_001, 111.0);
@@ -70,21 +100,21 @@ fn simple_query() {
 
     let result = client.query(&query).unwrap();
     assert!(result.contains_key("second"));
-    let first = &result["second"][0];
+    let first = &result["second"][0].values[0];
     assert_eq!(first.time, 1_147_724_326_001);
-    assert!((first.value - 111.0).abs() < 0.001);
+    assert!((first.value.as_f64().unwrap() - 111.0).abs() < 0.001);
 
-    let second = &result["second"][1];
+    let second = &result["second"][0].values[1];
     assert_eq!(second.time, 1_147_724_326_040);
-    assert!((second.value - 112.0).abs() < 0.001);
+    assert!((second.value.as_f64().unwrap() - 112.0).abs() < 0.001);
 
-    let array = &result["second"];
+    let array = &result["second"][0].values;
     assert_eq!(array.len(), 2);
 }
 
 #[test]
 fn metrics_average_query() {
-    let client = Client::new("localhost", 8080);
+    let (_server, client) = stub_client();
 
     let mut datapoints = Datapoints::new("second", 0);
     datapoints.add_ms(1_147_724_326_001, 111.0);
@@ -105,17 +135,17 @@ fn metrics_average_query() {
 
     let result = client.query(&query).unwrap();
     assert!(result.contains_key("second"));
-    let first = &result["second"][0];
+    let first = &result["second"][0].values[0];
     assert_eq!(first.time, 1_147_724_326_001);
-    assert!((first.value - 111.5).abs() < 0.001);
+    assert!((first.value.as_f64().unwrap() - 111.5).abs() < 0.001);
 
-    let array = &result["second"];
+    let array = &result["second"][0].values;
     assert_eq!(array.len(), 1);
 }
 
 #[test]
 fn simple_query_with_delete() {
-    let client = Client::new("localhost", 8080);
+    let (_server, client) = stub_client();
     let mut datapoints = Datapoints::new("third", 0);
     let duration = Duration::minutes(1);
     let dt = Local::now();
@@ -135,13 +165,968 @@ fn simple_query_with_delete() {
 
     let result = client.query(&query).unwrap();
     assert!(result.contains_key("third"));
-    assert_eq!(result["third"].len(), 2);
+    assert_eq!(result["third"][0].values.len(), 2);
 
     let result = client.delete(&query);
     assert!(result.is_ok());
 
     let result = client.query(&query).unwrap();
     assert!(result.contains_key("third"));
-    assert_eq!(result["third"].len(), 0);
+    assert_eq!(result["third"][0].values.len(), 0);
+
+}
+
+#[test]
+fn rate_limiter_delays_bursts() {
+    let limiter = RateLimiter::new(0, 100);
+    let start = Instant::now();
+    limiter.acquire(100);
+    limiter.acquire(50);
+    assert!(start.elapsed() >= StdDuration::from_millis(450));
+}
+
+#[test]
+fn circuit_breaker_opens_after_failures() {
+    let mut client = Client::new("localhost", 1);
+    client.set_circuit_breaker(CircuitBreaker::new(2, StdDuration::from_secs(60)));
+    assert!(client.version().is_err());
+    assert!(client.version().is_err());
+    match client.version() {
+        Err(KairoError::CircuitOpen) => (),
+        other => panic!("expected open circuit, got {:?}", other),
+    }
+}
+
+#[test]
+fn circuit_breaker_half_opens_after_cool_down() {
+    let breaker = CircuitBreaker::new(1, StdDuration::from_millis(0));
+    assert_eq!(breaker.state(), CircuitState::Closed);
+    let mut client = Client::new("localhost", 1);
+    client.set_circuit_breaker(breaker);
+    assert!(client.version().is_err());
+    if let Err(KairoError::CircuitOpen) = client.version() {
+        panic!("circuit should half-open");
+    }
+}
+
+#[test]
+fn ping_bypasses_the_open_circuit() {
+    let (server, mut client) = stub_client();
+    server.respond(Method::GET, "/api/v1/version", 500, "");
+    client.set_circuit_breaker(CircuitBreaker::new(1, StdDuration::from_secs(60)));
+    assert!(client.version().is_err());
+    match client.version() {
+        Err(KairoError::CircuitOpen) => (),
+        other => panic!("expected open circuit, got {:?}", other),
+    }
+    assert!(client.ping().is_ok());
+}
+
+#[test]
+fn stats_count_transport_errors() {
+    let client = Client::new("localhost", 1);
+    assert!(matches!(client.version(), Err(KairoError::Transport { .. })));
+    let stats = client.stats();
+    assert_eq!(stats.requests, 1);
+    assert_eq!(stats.transport_errors, 1);
+    assert_eq!(stats.latency.count(), 0);
+}
+
+#[test]
+fn query_with_expired_deadline() {
+    let (server, mut client) = stub_client();
+    client.set_circuit_breaker(CircuitBreaker::new(1, StdDuration::from_secs(60)));
+    let query = Query::new(Time::Nanoseconds(1), Time::Nanoseconds(2));
+    let opts = RequestOpts { deadline: Some(Instant::now()), ..Default::default() };
+    match client.query_with_opts(&query, &opts) {
+        Err(KairoError::DeadlineExceeded) => (),
+        other => panic!("expected exceeded deadline, got {:?}", other),
+    }
+    assert!(server.requests().is_empty());
+    assert!(client.query(&query).is_ok());
+}
+
+#[derive(Debug)]
+struct SlowTransport;
+
+impl Transport for SlowTransport {
+    fn send(&self, _request: &HttpRequest) -> Result<HttpResponse, KairoError> {
+        thread::sleep(StdDuration::from_millis(500));
+        Ok(HttpResponse { status: StatusCode::OK, body: r#"{"queries":[]}"#.to_string() })
+    }
+}
+
+#[test]
+fn query_with_timeout() {
+    let client = Client::with_transport("localhost", 8080, SlowTransport);
+    let query = Query::new(Time::Nanoseconds(1), Time::Nanoseconds(2));
+
+    let opts = RequestOpts { timeout: Some(StdDuration::from_millis(50)), ..Default::default() };
+    let start = Instant::now();
+    match client.query_with_opts(&query, &opts) {
+        Err(KairoError::Timeout { elapsed, endpoint, source, request }) => {
+            assert!(elapsed >= StdDuration::from_millis(50));
+            assert_eq!(endpoint, "/api/v1/datapoints/query");
+            assert!(source.is_none());
+            assert_eq!(request.unwrap().url, "http://localhost:8080/api/v1/datapoints/query");
+        }
+        other => panic!("expected timeout, got {:?}", other),
+    }
+    assert!(start.elapsed() < StdDuration::from_millis(400));
+
+    let opts = RequestOpts { timeout: Some(StdDuration::from_secs(5)), ..Default::default() };
+    assert!(client.query_with_opts(&query, &opts).is_ok());
+}
+
+#[test]
+fn aborted_requests_keep_their_permit() {
+    let mut client = Client::with_transport("localhost", 8080, SlowTransport);
+    client.set_concurrency_limit(1);
+    let query = Query::new(Time::Nanoseconds(1), Time::Nanoseconds(2));
+
+    let start = Instant::now();
+    let opts = RequestOpts { timeout: Some(StdDuration::from_millis(50)), ..Default::default() };
+    assert!(client.query_with_opts(&query, &opts).is_err());
+    assert!(client.query(&query).is_ok());
+    assert!(start.elapsed() >= StdDuration::from_millis(1000));
+}
+
+#[derive(Debug)]
+struct RejectingTransport(&'static str);
+
+impl Transport for RejectingTransport {
+    fn send(&self, _request: &HttpRequest) -> Result<HttpResponse, KairoError> {
+        Ok(HttpResponse { status: StatusCode::BAD_REQUEST, body: self.0.to_string() })
+    }
+}
+
+#[test]
+fn rejected_requests_carry_error_messages() {
+    let query = Query::new(Time::Nanoseconds(1), Time::Nanoseconds(2));
+    let body = r#"{"errors":["query.metric[0].aggregate must be one of avg,sum"]}"#;
+    let client = Client::with_transport("localhost", 8080, RejectingTransport(body));
+    for result in [client.query(&query).map(|_| ()), client.query_each(&query, |_, _| ()).map(|_| ())] {
+        match result {
+            Err(KairoError::InvalidRequest { status, errors, request }) => {
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+                let request = request.unwrap();
+                assert_eq!(request.url, "http://localhost:8080/api/v1/datapoints/query");
+                assert!(request.body.starts_with(r#"{"start_absolute":1"#));
+                assert_eq!(errors, vec!["query.metric[0].aggregate must be one of avg,sum"]);
+            }
+            other => panic!("expected invalid request, got {:?}", other),
+        }
+    }
+
+    let server = Arc::new(FlakyServer::default());
+    server.down.store(true, Ordering::SeqCst);
+    let client = Client::with_transport("localhost", 8080, FlakyTransport(server));
+    match client.add(&Datapoints::builder("cpu").point(1000, 1).build()) {
+        Err(KairoError::ServerError { status, errors, .. }) => {
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+            assert!(errors.is_empty());
+        }
+        other => panic!("expected server error, got {:?}", other),
+    }
+
+    let client = Client::with_transport("localhost", 8080, RejectingTransport(" Bad Request\n"));
+    match client.add(&Datapoints::builder("cpu").point(1000, 1).build()) {
+        Err(KairoError::InvalidRequest { errors, .. }) => assert_eq!(errors, vec!["Bad Request"]),
+        other => panic!("expected invalid request, got {:?}", other),
+    }
+
+    let body = r#"{"errors":["metric[0](name=cpu).datapoints[2].value may not be empty"]}"#;
+    let client = Client::with_transport("localhost", 8080, RejectingTransport(body));
+    match client.add(&Datapoints::builder("cpu").point(1000, 1).build()) {
+        Err(KairoError::PartialWrite(err)) => {
+            assert_eq!(err.rejected[0].metric, 0);
+            assert_eq!(err.rejected[0].datapoint, Some(2));
+            assert!(err.request.is_some());
+        }
+        other => panic!("expected partial write, got {:?}", other),
+    }
+}
+
+#[test]
+fn errors_tell_if_retrying_may_succeed() {
+    let server = Arc::new(FlakyServer::default());
+    server.down.store(true, Ordering::SeqCst);
+    let client = Client::with_transport("localhost", 8080, FlakyTransport(server));
+    let err = client.list_metrics().unwrap_err();
+    assert!(err.is_retryable());
+    assert!(!err.is_timeout());
+    assert_eq!(err.status(), Some(StatusCode::SERVICE_UNAVAILABLE));
+
+    let client = Client::with_transport("localhost", 8080, RejectingTransport(""));
+    let err = client.list_metrics().unwrap_err();
+    assert!(!err.is_retryable());
+    assert_eq!(err.status(), Some(StatusCode::BAD_REQUEST));
+
+    let client = Client::with_transport("localhost", 8080, SlowTransport);
+    let query = Query::new(Time::Nanoseconds(1), Time::Nanoseconds(2));
+    let opts = RequestOpts { timeout: Some(StdDuration::from_millis(10)), ..Default::default() };
+    let err = client.query_with_opts(&query, &opts).unwrap_err();
+    assert!(err.is_timeout());
+    assert!(err.is_retryable());
+
+    let opts = RequestOpts { deadline: Some(Instant::now()), ..Default::default() };
+    let err = client.query_with_opts(&query, &opts).unwrap_err();
+    assert!(err.is_timeout());
+    assert!(!err.is_retryable());
+}
+
+#[test]
+fn mock_client_filters_tags_and_deletes() {
+    let client = MockClient::new();
+    for host in &["a", "b"] {
+        let mut datapoints = Datapoints::new("mock", 0);
+        datapoints.add_ms(1000, 1.0);
+        datapoints.add_ms(2000, 2.0);
+        datapoints.add_tag("host", host);
+        client.add(&datapoints).unwrap();
+    }
+
+    let mut query = Query::new(Time::Nanoseconds(0), Time::Nanoseconds(1500));
+    let mut tags: HashMap<String, Vec<String>> = HashMap::new();
+    tags.insert("host".to_string(), vec!["b".to_string()]);
+    query.add(Metric::new("mock", tags, vec![]));
+    assert_eq!(client.query(&query).unwrap()["mock"][0].values.len(), 1);
+
+    client.delete(&query).unwrap();
+    let mut query = Query::new(Time::Nanoseconds(0), Time::Nanoseconds(3000));
+    query.add(Metric::new("mock", HashMap::new(), vec![]));
+    assert_eq!(client.query(&query).unwrap()["mock"][0].values.len(), 3);
+    assert_eq!(client.tagvalues().unwrap(), vec!["a", "b"]);
+}
+
+#[test]
+fn concurrency_limit_bounds_parallel_requests() {
+    let limit = Arc::new(ConcurrencyLimit::new(2));
+    let running = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+
+    let workers: Vec<_> = (0..6).map(|_| {
+        let (limit, running, peak) = (limit.clone(), running.clone(), peak.clone());
+        thread::spawn(move || {
+            let _permit = limit.acquire();
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(StdDuration::from_millis(20));
+            running.fetch_sub(1, Ordering::SeqCst);
+        })
+    }).collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    assert_eq!(peak.load(Ordering::SeqCst), 2);
+    assert_eq!(limit.in_flight(), 0);
+}
+
+#[test]
+fn grouped_query_returns_every_series() {
+    let (_server, client) = stub_client();
+    for host in &["a", "b", "c"] {
+        let datapoints = Datapoints::builder("load")
+            .tag("host", host)
+            .tag("dc", "eu")
+            .point(1000, 1.0)
+            .build();
+        client.add(&datapoints).unwrap();
+    }
+
+    let mut query = Query::new(Time::Nanoseconds(0), Time::Nanoseconds(2000));
+    query.add(Metric::builder().name("load").group_by_tag("host").build());
+    let result = client.query(&query).unwrap();
+    let hosts: Vec<_> = result["load"].iter().map(|series| series.group["host"].as_str()).collect();
+    assert_eq!(hosts, vec!["a", "b", "c"]);
+    assert_eq!(result["load"][1].tags["dc"], vec!["eu"]);
+
+    let chunk = RelativeTime::new(500, TimeUnit::MILLISECONDS);
+    assert_eq!(client.query_chunked(&query, &chunk).unwrap(), result);
+}
+
+#[test]
+fn query_each_streams_every_series() {
+    let (_server, client) = stub_client();
+    for host in &["a", "b"] {
+        let datapoints = Datapoints::builder("load").tag("host", host).point(1000, 1.0).build();
+        client.add(&datapoints).unwrap();
+    }
+
+    let mut query = Query::new(Time::Nanoseconds(0), Time::Nanoseconds(2000));
+    query.add(Metric::builder().name("load").group_by_tag("host").build());
+    let mut hosts = Vec::new();
+    let count = client.query_each(&query, |name, series| {
+        assert_eq!(name, "load");
+        hosts.push(series.group["host"].clone());
+    }).unwrap();
+    assert_eq!(count, 2);
+    assert_eq!(hosts, vec!["a", "b"]);
+    assert!(client.stats().bytes_received > 0);
+}
+
+#[test]
+fn streamed_requests_take_one_request_token() {
+    let (_server, mut client) = stub_client();
+    client.set_rate_limit(RateLimiter::new(2, 0));
+    let sets: Vec<Datapoints> = (0..20)
+        .map(|i| Datapoints::builder("load").tag("host", "a").point(i * 1000, 1.0).build())
+        .collect();
+    let mut query = Query::new(Time::Nanoseconds(0), Time::Nanoseconds(20_000));
+    query.add(Metric::builder().name("load").build());
+
+    let start = Instant::now();
+    client.add_stream(sets).unwrap();
+    assert_eq!(client.query_each(&query, |_, _| ()).unwrap(), 1);
+    assert!(start.elapsed() < StdDuration::from_millis(400));
+    // both requests took a token, so the next one waits for a new one
+    client.version().unwrap();
+    assert!(start.elapsed() >= StdDuration::from_millis(400));
+    assert_eq!(client.stats().requests, 3);
+}
+
+#[test]
+fn cancelled_streams_are_not_sent() {
+    let (server, mut client) = stub_client();
+    client.set_circuit_breaker(CircuitBreaker::new(1, StdDuration::from_secs(60)));
+    let cancel = CancelHandle::new();
+    cancel.cancel();
+    let opts = RequestOpts { cancel: Some(cancel), ..Default::default() };
+    let sets = vec![Datapoints::builder("load").tag("host", "a").point(1000, 1.0).build()];
+    match client.add_stream_with_opts(sets, &opts) {
+        Err(KairoError::Cancelled) => (),
+        other => panic!("expected cancellation, got {:?}", other),
+    }
+    assert!(server.requests().is_empty());
+    assert!(client.version().is_ok());
+}
+
+#[test]
+fn datetimes_keep_milliseconds() {
+    let client = MockClient::new();
+    let time = Utc.timestamp_millis_opt(1_475_513_259_123).unwrap();
+    let mut datapoints = Datapoints::new("precise", 0);
+    datapoints.add(time, 11.0);
+    client.add(&datapoints).unwrap();
+
+    let mut query = Query::new(Time::UTC(time), Time::UTC(time));
+    query.add(Metric::new("precise", HashMap::new(), vec![]));
+    let result = client.query(&query).unwrap();
+    assert_eq!(result["precise"][0].values.len(), 1);
+    assert_eq!(result["precise"][0].values[0].time, 1_475_513_259_123);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+fn unchecked_names_panic_in_debug_builds() {
+    assert!(std::panic::catch_unwind(|| Datapoints::new("cpu", 0).add_tag("host name", "a"))
+        .is_err());
+    assert!(std::panic::catch_unwind(|| Metric::new("cpu load", HashMap::new(), vec![]))
+        .is_err());
+    assert!(Datapoints::new("cpu", 0).try_add_tag("host name", "a").is_err());
+    assert!(Metric::try_new("cpu load", HashMap::new(), vec![]).is_err());
+}
+
+#[test]
+fn query_validation() {
+    let query = Query::builder()
+        .start(Time::Relative { value: 1, unit: TimeUnit::HOURS })
+        .metric(|m| m.name("cpu").avg(1, TimeUnit::MINUTES).rate(TimeUnit::SECONDS))
+        .build();
+    assert_eq!(query.validate(), Ok(()));
+
+    let mut query = Query::builder().build();
+    query.add(Metric::builder()
+        .aggregator(Aggregator::new(AggregatorType::DIFF, RelativeTime::new(1, TimeUnit::MINUTES)))
+        .aggregator(Aggregator::new(AggregatorType::RATE, RelativeTime::new(1, TimeUnit::MINUTES)))
+        .build());
+    assert_eq!(query.validate(), Err(vec![
+        ValidationError::MissingStart,
+        ValidationError::EmptyMetricName { metric: 0 },
+        ValidationError::UnexpectedSampling { metric: 0, aggregator: 0 },
+        ValidationError::MissingParameter { metric: 0, aggregator: 1, parameter: "unit" },
+    ]));
+
+    let errors = query.validate().unwrap_err();
+    let located: Vec<_> = errors.iter().map(|err| (err.metric(), err.field())).collect();
+    assert_eq!(located, vec![
+        (None, "start".to_string()),
+        (Some(0), "name".to_string()),
+        (Some(0), "aggregators[0].sampling".to_string()),
+        (Some(0), "aggregators[1].unit".to_string()),
+    ]);
+    assert!(KairoError::from(errors).to_string().starts_with("validation failed: "));
+
+    let body = r#"{"name":"cpu","datapoints":[[1000,1]],"tags":{"host":"web 1"},"ttl":0}"#;
+    let batch = vec![serde_json::from_str::<Datapoints>(body).unwrap()];
+    let errors = kairosdb::validation::validate_batch(&batch).unwrap_err();
+    assert_eq!(errors[0].field(), "tags.host");
+}
+
+#[test]
+fn query_templates_can_be_cloned() {
+    let template = Query::builder()
+        .start(Time::Relative { value: 1, unit: TimeUnit::HOURS })
+        .metric(|m| m.name("cpu").avg(1, TimeUnit::MINUTES))
+        .build();
+    let mut query = template.clone();
+    assert_eq!(query, template);
+
+    query.metrics_mut()[0].tags_mut().insert("host".to_string(), vec!["a".to_string()]);
+    assert_ne!(query, template);
+    assert!(template.metrics()[0].tags().is_empty());
+}
+
+#[test]
+fn buffered_writer_flushes_full_batches_and_on_drop() {
+    let client = Arc::new(MockClient::new());
+    let config = WriterConfig {
+        max_batch: 2,
+        flush_interval: StdDuration::from_secs(60),
+        ..Default::default()
+    };
+    let writer = BufferedWriter::new(client.clone(), config);
+
+    writer.push(Datapoints::builder("first").point(1000, 1.0).build()).unwrap();
+    writer.push(Datapoints::builder("first").point(2000, 2.0).build()).unwrap();
+    let start = Instant::now();
+    while writer.written() < 2 && start.elapsed() < StdDuration::from_secs(5) {
+        thread::sleep(StdDuration::from_millis(10));
+    }
+    assert_eq!(writer.written(), 2);
+
+    writer.push(Datapoints::builder("second").point(1000, 1.0).build()).unwrap();
+    assert_eq!(writer.pending(), 1);
+    drop(writer);
+    assert_eq!(client.list_metrics().unwrap(), vec!["first", "second"]);
+}
+
+#[test]
+fn buffered_writer_overflow_policies() {
+    let config = |overflow| WriterConfig {
+        max_batch: 100,
+        flush_interval: StdDuration::from_secs(60),
+        max_pending: 2,
+        overflow,
+        ..Default::default()
+    };
+    let set = |name: &str| Datapoints::builder(name).point(1000, 1.0).build();
+
+    let client = Arc::new(MockClient::new());
+    let writer = BufferedWriter::new(client.clone(), config(OverflowPolicy::DropOldest));
+    for name in &["a", "b", "c"] {
+        writer.push(set(name)).unwrap();
+    }
+    assert_eq!(writer.dropped(), 1);
+    writer.flush();
+    assert_eq!(client.list_metrics().unwrap(), vec!["b", "c"]);
+
+    let client = Arc::new(MockClient::new());
+    let writer = BufferedWriter::new(client.clone(), config(OverflowPolicy::DropNewest));
+    for name in &["a", "b", "c"] {
+        writer.push(set(name)).unwrap();
+    }
+    writer.flush();
+    assert_eq!(writer.dropped(), 1);
+    assert_eq!(client.list_metrics().unwrap(), vec!["a", "b"]);
+
+    let writer = BufferedWriter::new(Arc::new(MockClient::new()), config(OverflowPolicy::Error));
+    writer.push(set("a")).unwrap();
+    writer.push(set("b")).unwrap();
+    match writer.push(set("c")) {
+        Err(KairoError::BufferFull) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    let client = Arc::new(MockClient::new());
+    let writer = BufferedWriter::new(client.clone(), config(OverflowPolicy::Block));
+    for name in &["a", "b", "c"] {
+        writer.push(set(name)).unwrap();
+    }
+    writer.flush();
+    assert_eq!(writer.written(), 3);
+}
+
+#[derive(Debug)]
+struct SlowWrites;
+
+impl Transport for SlowWrites {
+    fn send(&self, _request: &HttpRequest) -> Result<HttpResponse, KairoError> {
+        thread::sleep(StdDuration::from_millis(300));
+        Ok(HttpResponse { status: StatusCode::NO_CONTENT, body: String::new() })
+    }
+}
+
+#[test]
+fn blocking_writer_counts_the_batch_in_flight() {
+    let config = WriterConfig {
+        max_batch: 2,
+        max_pending: 2,
+        overflow: OverflowPolicy::Block,
+        ..Default::default()
+    };
+    let client = Arc::new(Client::with_transport("localhost", 8080, SlowWrites));
+    let writer = BufferedWriter::new(client, config);
+    writer.push(Datapoints::builder("a").point(1000, 1.0).build()).unwrap();
+    writer.push(Datapoints::builder("b").point(1000, 1.0).build()).unwrap();
+    thread::sleep(StdDuration::from_millis(50));
+
+    let start = Instant::now();
+    writer.push(Datapoints::builder("c").point(1000, 1.0).build()).unwrap();
+    assert!(start.elapsed() >= StdDuration::from_millis(150));
+    writer.flush();
+    assert_eq!(writer.written(), 3);
+}
+
+#[derive(Debug, Default, Clone)]
+struct BodyAddresses(Arc<std::sync::Mutex<Vec<usize>>>);
+
+impl Transport for BodyAddresses {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, KairoError> {
+        self.0.lock().unwrap().push(request.body.as_ptr() as usize);
+        Ok(HttpResponse { status: StatusCode::NO_CONTENT, body: String::new() })
+    }
+}
+
+#[test]
+fn writes_reuse_the_body_buffer() {
+    let addresses = BodyAddresses::default();
+    let client = Client::with_transport("localhost", 8080, addresses.clone());
+    client.add(&Datapoints::builder("cpu").tag("host", "a").point(1000, 1.0).build()).unwrap();
+    client.add(&Datapoints::builder("cpu").tag("host", "b").point(1000, 1.0).build()).unwrap();
+    let addresses = addresses.0.lock().unwrap();
+    assert_eq!(addresses[0], addresses[1]);
+}
+
+#[derive(Debug)]
+struct PanickingTransport;
+
+impl Transport for PanickingTransport {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, KairoError> {
+        let body = String::from_utf8_lossy(&request.body);
+        if request.url.ends_with("/boom") || body.contains("\"boom\"") {
+            panic!("transport failed");
+        }
+        Ok(HttpResponse { status: StatusCode::NO_CONTENT, body: String::new() })
+    }
+}
+
+#[test]
+fn buffered_writer_survives_panicking_workers() {
+    let config = WriterConfig { workers: 2, ..Default::default() };
+    let client = Arc::new(Client::with_transport("localhost", 8080, PanickingTransport));
+    let writer = BufferedWriter::new(client, config);
+    writer.push(Datapoints::builder("boom").point(1000, 1.0).build()).unwrap();
+    writer.push(Datapoints::builder("fine").point(1000, 1.0).point(2000, 1.0).build()).unwrap();
+    writer.flush();
+    assert_eq!(writer.written(), 2);
+    assert_eq!(writer.failed(), 1);
+
+    writer.push(Datapoints::builder("later").point(1000, 1.0).build()).unwrap();
+    writer.flush();
+    assert_eq!(writer.written(), 3);
+    assert_eq!(writer.pending(), 0);
+}
+
+#[test]
+fn delete_metrics_survives_panicking_threads() {
+    let client = Client::with_transport("localhost", 8080, PanickingTransport);
+    let results = client.delete_metrics(&["boom", "fine"]);
+    assert_eq!(results[0].0, "boom");
+    assert!(results[0].1.is_err());
+    assert!(results[1].1.is_ok());
+}
+
+#[cfg(feature = "csv")]
+#[test]
+fn csv_import_by_index_and_errors() {
+    use kairosdb::import::CsvImporter;
+
+    let client = MockClient::new();
+    let importer = CsvImporter::new("unused")
+        .has_headers(false)
+        .delimiter(b';')
+        .metric_column(0)
+        .time_column(1)
+        .value_column(2)
+        .batch_size(1);
+    let csv = "cpu;1000;1\nmemory;1000;2.5\ncpu;2000;3\n";
+    assert_eq!(importer.import(&client, csv.as_bytes()).unwrap(), 3);
+    assert_eq!(client.list_metrics().unwrap(), vec!["cpu", "memory"]);
+
+    match importer.import(&client, "cpu;3000;high\n".as_bytes()) {
+        Err(KairoError::Kairo(msg)) => assert_eq!(msg, "line 1: invalid value 'high'"),
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn json_lines_round_trip() {
+    use kairosdb::import::{export_json_lines, import_json_lines};
+
+    let (_server, source) = stub_client();
+    let lines = "{\"metric\":\"cpu\",\"timestamp\":1000,\"value\":1,\"tags\":{\"host\":\"a\"}}\n\
+                 {\"metric\":\"cpu\",\"timestamp\":2000,\"value\":2.5,\"tags\":{\"host\":\"a\"}}\n";
+    assert_eq!(import_json_lines(&source, lines.as_bytes(), 1).unwrap(), 2);
+
+    let mut query = Query::new(Time::Nanoseconds(0), Time::Nanoseconds(3000));
+    query.add(Metric::new("cpu", kairosdb::tags!{"host" => ["a"]}, vec![]));
+    let mut dump = Vec::new();
+    assert_eq!(export_json_lines(&source, &query, &mut dump).unwrap(), 2);
+
+    let target = MockClient::new();
+    assert_eq!(import_json_lines(&target, &dump[..], 1000).unwrap(), 2);
+    assert_eq!(target.query(&query).unwrap(), source.query(&query).unwrap());
+
+    match import_json_lines(&target, "{\"metric\":\"cpu\"}\n".as_bytes(), 1000) {
+        Err(KairoError::Kairo(msg)) => assert!(msg.starts_with("line 1: missing field")),
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn telnet_client_writes_batches() {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use kairosdb::telnet::TelnetClient;
+
+    let listener = TcpListener::bind("localhost:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut client = TelnetClient::new("localhost", port);
+    client.set_batch_size(2);
+    client.set_seconds(true);
+
+    let datapoints = Datapoints::builder("load")
+        .tag("host", "a")
+        .tag("dc", "eu")
+        .point(1000, 1)
+        .point(2000, 2.5)
+        .build();
+    client.add(&datapoints).unwrap();
+    assert_eq!(client.pending(), 0);
+
+    let (stream, _) = listener.accept().unwrap();
+    let mut lines = BufReader::new(stream).lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "put load 1 1 dc=eu host=a");
+    assert_eq!(lines.next().unwrap().unwrap(), "put load 2 2.5 dc=eu host=a");
+
+    let text = Datapoints::builder("state").tag("host", "a").point(1000, "up").build();
+    assert!(client.add(&text).is_err());
+    assert_eq!(client.pending(), 0);
+}
+
+#[test]
+fn graphite_bridge_forwards_lines() {
+    use kairosdb::graphite::GraphiteBridge;
+
+    let client = Arc::new(MockClient::new());
+    let writer = BufferedWriter::new(client.clone(), WriterConfig::default());
+    let bridge = GraphiteBridge::new()
+        .template("servers.", "_.host.metric").unwrap()
+        .tag("source", "graphite");
+
+    let lines = "servers.web1.cpu.user 1.5 1\n\
+                 broken line\n\
+                 servers.web2.cpu.user 2.5 1\n\
+                 other.metric 3 2\n";
+    assert_eq!(bridge.forward(lines.as_bytes(), &writer).unwrap(), 3);
+    writer.flush();
+
+    assert_eq!(client.list_metrics().unwrap(), vec!["cpu.user", "other.metric"]);
+    assert_eq!(client.tagvalues().unwrap(), vec!["graphite", "web1", "web2"]);
+}
+
+#[cfg(feature = "statsd")]
+#[test]
+fn statsd_collector_aggregates() {
+    use kairosdb::statsd::StatsdCollector;
+
+    let collector = StatsdCollector::new().tag("host", "a");
+    collector.handle_packet("hits:1|c|@0.1\nmemory:100|g\nmemory:-20|g\nbroken\nlatency:5|ms|@0.5");
+    let values = |batch: Vec<Datapoints>| {
+        let mut values: Vec<String> = batch.iter()
+            .map(|d| serde_json::to_value(d).unwrap())
+            .map(|json| format!("{}={}", json["name"].as_str().unwrap(), json["datapoints"][0][1]))
+            .collect();
+        values.sort();
+        values
+    };
+
+    assert_eq!(values(collector.drain(1000)), vec![
+        "hits=10.0", "latency.count=2.0", "latency.max=5.0", "latency.mean=5.0",
+        "latency.min=5.0", "latency.p90=5.0", "memory=80.0",
+    ]);
+    assert_eq!(values(collector.drain(2000)), vec!["memory=80.0"]);
+}
+
+#[test]
+fn client_sorts_datapoints_before_sending() {
+    let (server, mut client) = stub_client();
+    client.set_sort_datapoints(true);
+
+    let datapoints = Datapoints::builder("cpu").point(3000, 3).point(1000, 1).point(2000, 2).build();
+    client.add(&datapoints).unwrap();
+    client.add_stream(vec![datapoints]).unwrap();
+
+    for request in server.requests() {
+        assert!(request.body.contains("[[1000,1],[2000,2],[3000,3]]"), "{}", request.body);
+    }
+    assert_eq!(server.requests().len(), 2);
+}
+
+#[derive(Debug, Default)]
+struct FlakyServer {
+    down: std::sync::atomic::AtomicBool,
+    bodies: std::sync::Mutex<Vec<String>>,
+}
+
+#[derive(Debug)]
+struct FlakyTransport(Arc<FlakyServer>);
+
+impl Transport for FlakyTransport {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, KairoError> {
+        if self.0.down.load(Ordering::SeqCst) {
+            return Ok(HttpResponse { status: StatusCode::SERVICE_UNAVAILABLE, body: String::new() });
+        }
+        self.0.bodies.lock().unwrap().push(String::from_utf8_lossy(&request.body).into_owned());
+        Ok(HttpResponse { status: StatusCode::NO_CONTENT, body: String::new() })
+    }
+}
+
+#[test]
+fn buffered_writer_spools_failed_batches() {
+    let dir = temp_dir("writer-spool");
+
+    let server = Arc::new(FlakyServer::default());
+    server.down.store(true, Ordering::SeqCst);
+    let client = Arc::new(Client::with_transport("localhost", 8080, FlakyTransport(server.clone())));
+    let config = WriterConfig {
+        max_retries: 0,
+        spool_dir: Some(dir.clone()),
+        ..Default::default()
+    };
+    let writer = BufferedWriter::new(client, config);
+
+    writer.push(Datapoints::builder("first").point(1000, 1).build()).unwrap();
+    writer.flush();
+    writer.push(Datapoints::builder("second").point(1000, 2).build()).unwrap();
+    writer.flush();
+    assert_eq!(writer.spooled(), 2);
+    assert_eq!(writer.failed(), 0);
+
+    server.down.store(false, Ordering::SeqCst);
+    writer.push(Datapoints::builder("third").point(1000, 3).build()).unwrap();
+    writer.flush();
+    assert_eq!(writer.spooled(), 0);
+
+    let bodies = server.bodies.lock().unwrap();
+    let names: Vec<_> = bodies.iter()
+        .map(|body| serde_json::from_str::<serde_json::Value>(body).unwrap()[0]["name"].clone())
+        .collect();
+    assert_eq!(names, vec!["first", "second", "third"]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn spool_quarantines_batches_it_cannot_replay() {
+    use kairosdb::spool::Spool;
+
+    let dir = temp_dir("spool-quarantine");
+    let spool = Spool::new(&dir);
+    spool.store(&[Datapoints::builder("rejected").point(1000, 1).build()]).unwrap();
+    std::fs::write(dir.join("00000000000000000000-0000000000.json"), "not json").unwrap();
+    let body = r#"{"errors":["metric[0].tags cannot be empty"]}"#;
+    let client = Client::with_transport("localhost", 8080, RejectingTransport(body));
+    assert_eq!(spool.replay(&client).unwrap(), 0);
+    assert!(spool.is_empty());
+    assert_eq!(spool.quarantined(), 2);
+
+    let server = Arc::new(FlakyServer::default());
+    server.down.store(true, Ordering::SeqCst);
+    let client = Client::with_transport("localhost", 8080, FlakyTransport(server.clone()));
+    spool.store(&[Datapoints::builder("first").point(1000, 1).build()]).unwrap();
+    spool.store(&[Datapoints::builder("second").point(1000, 2).build()]).unwrap();
+    assert!(spool.replay(&client).unwrap_err().is_retryable());
+    assert_eq!(spool.len(), 2);
+    server.down.store(false, Ordering::SeqCst);
+    assert_eq!(spool.replay(&client).unwrap(), 2);
+    assert_eq!(spool.quarantined(), 2);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn delete_metrics_reports_every_metric() {
+    let server = Arc::new(FlakyServer::default());
+    server.down.store(true, Ordering::SeqCst);
+    let client = Client::with_transport("localhost", 8080, FlakyTransport(server));
+    let names: Vec<String> = (0..20).map(|i| format!("stale.{}", i)).collect();
+    let metrics: Vec<&str> = names.iter().map(String::as_str).collect();
+
+    let results = client.delete_metrics(&metrics);
+    let deleted: Vec<&str> = results.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(deleted, metrics);
+    assert!(results.iter().all(|(_, result)| result.as_ref().unwrap_err().is_retryable()));
+}
+
+#[test]
+fn buffered_writer_calls_client_hooks() {
+    use std::sync::Mutex;
+    use kairosdb::hooks::Hooks;
+
+    let server = Arc::new(FlakyServer::default());
+    server.down.store(true, Ordering::SeqCst);
+    let mut client = Client::with_transport("localhost", 8080, FlakyTransport(server.clone()));
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let (errors, retries, flushes) = (events.clone(), events.clone(), events.clone());
+    client.set_hooks(Hooks::new()
+        .on_error(move |err| errors.lock().unwrap().push(format!("error {:?}", err.status())))
+        .on_retry(move |_, attempt| retries.lock().unwrap().push(format!("retry {}", attempt)))
+        .on_flush(move |written, failed| {
+            flushes.lock().unwrap().push(format!("flush {} {}", written, failed))
+        }));
+    let config = WriterConfig {
+        max_retries: 1,
+        retry_backoff: StdDuration::from_millis(1),
+        ..Default::default()
+    };
+    let writer = BufferedWriter::new(Arc::new(client), config);
+
+    writer.push(Datapoints::builder("first").point(1000, 1).point(2000, 2).build()).unwrap();
+    writer.flush();
+    server.down.store(false, Ordering::SeqCst);
+    writer.push(Datapoints::builder("second").point(1000, 1).build()).unwrap();
+    writer.flush();
+
+    assert_eq!(*events.lock().unwrap(), vec![
+        "error Some(503)", "retry 1", "error Some(503)", "flush 0 2", "flush 1 0",
+    ]);
+}
+
+#[test]
+fn slow_queries_are_reported_when_failed_or_streamed() {
+    use std::sync::Mutex;
+    use kairosdb::hooks::Hooks;
+
+    let (server, mut client) = stub_client();
+    client.add(&Datapoints::builder("cpu").tag("host", "a").point(1000, 1).build()).unwrap();
+    let slow = Arc::new(Mutex::new(Vec::new()));
+    let queries = slow.clone();
+    client.set_slow_query_threshold(Some(StdDuration::from_secs(0)));
+    client.set_hooks(Hooks::new().on_slow_query(move |query| {
+        queries.lock().unwrap().push(query.sample_size)
+    }));
+    let mut query = Query::new(Time::Nanoseconds(0), Time::Nanoseconds(2000));
+    query.add(Metric::new("cpu", HashMap::new(), vec![]));
+
+    client.query_each(&query, |_, _| ()).unwrap();
+    server.respond(Method::POST, "/api/v1/datapoints/query", 500, "");
+    assert!(client.query(&query).is_err());
+    client.set_slow_query_threshold(None);
+    assert!(client.query(&query).is_err());
+
+    assert_eq!(*slow.lock().unwrap(), vec![Some(1), None]);
+}
+
+#[test]
+fn buffered_writer_retry_queue_and_dead_letter() {
+    use std::sync::Mutex;
+    use kairosdb::writer::DeadLetter;
+
+    let server = Arc::new(FlakyServer::default());
+    server.down.store(true, Ordering::SeqCst);
+    let client = Arc::new(Client::with_transport("localhost", 8080, FlakyTransport(server.clone())));
+    let dead = Arc::new(Mutex::new(Vec::new()));
+    let dead_letters = dead.clone();
+    let config = WriterConfig {
+        max_retries: 0,
+        retry_queue: 1,
+        retry_attempts: 2,
+        dead_letter: Some(DeadLetter::new(move |batch: Vec<Datapoints>| {
+            dead_letters.lock().unwrap().push(batch.len());
+        })),
+        ..Default::default()
+    };
+    let writer = BufferedWriter::new(client, config);
+
+    writer.push(Datapoints::builder("first").point(1000, 1).build()).unwrap();
+    writer.flush();
+    assert_eq!(writer.retrying(), 1);
+    writer.push(Datapoints::builder("second").point(1000, 2).point(2000, 2).build()).unwrap();
+    writer.flush();
+    assert_eq!(writer.retrying(), 2);
+    assert_eq!(writer.failed(), 1);
+    assert_eq!(*dead.lock().unwrap(), vec![1]);
+
+    server.down.store(false, Ordering::SeqCst);
+    writer.push(Datapoints::builder("third").point(1000, 3).build()).unwrap();
+    writer.flush();
+    assert_eq!(writer.retrying(), 0);
+    assert_eq!(writer.written(), 3);
+    assert_eq!(server.bodies.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn buffered_writer_gives_up_only_rejected_sets() {
+    use std::sync::Mutex;
+    use kairosdb::writer::DeadLetter;
+
+    let spool = temp_dir("rejected-spool");
+    let configs = vec![
+        WriterConfig::default(),
+        WriterConfig { retry_queue: 4, ..Default::default() },
+        WriterConfig { spool_dir: Some(spool.clone()), ..Default::default() },
+    ];
+    for config in configs {
+        let (server, client) = stub_client();
+        server.respond(Method::POST, "/api/v1/datapoints", 400,
+                       r#"{"errors":["metric[1](name=cpu).datapoints[0].value may not be empty"]}"#);
+        let dead = Arc::new(Mutex::new(Vec::new()));
+        let dead_letters = dead.clone();
+        let config = WriterConfig {
+            dead_letter: Some(DeadLetter::new(move |batch: Vec<Datapoints>| {
+                dead_letters.lock().unwrap().extend(batch);
+            })),
+            ..config
+        };
+        let writer = BufferedWriter::new(Arc::new(client), config);
+        let valid = Datapoints::builder("cpu").tag("host", "a").point(1000, 0.5).point(2000, 0.6)
+            .build();
+        let invalid = Datapoints::builder("cpu").tag("host", "b").point(1000, String::new())
+            .build();
+        writer.push(valid).unwrap();
+        writer.push(invalid.clone()).unwrap();
+        writer.flush();
+        writer.flush();
+
+        assert_eq!(writer.written(), 2);
+        assert_eq!(writer.failed(), 1);
+        assert_eq!(writer.retrying(), 0);
+        assert_eq!(writer.spooled(), 0);
+        assert_eq!(*dead.lock().unwrap(), vec![invalid]);
+        assert_eq!(server.requests().len(), 1);
+    }
+    let _ = std::fs::remove_dir_all(&spool);
+}
+
+#[test]
+fn buffered_writer_splits_batches_across_workers() {
+    let server = Arc::new(FlakyServer::default());
+    let client = Arc::new(Client::with_transport("localhost", 8080, FlakyTransport(server.clone())));
+    let config = WriterConfig { workers: 4, ordered: true, ..Default::default() };
+    let writer = BufferedWriter::new(client, config);
+
+    for i in 0..16 {
+        let name = format!("metric{}", i % 8);
+        writer.push(Datapoints::builder(&name).point(i, 1).build()).unwrap();
+    }
+    writer.flush();
+    assert_eq!(writer.written(), 16);
 
+    let bodies = server.bodies.lock().unwrap();
+    assert!(bodies.len() > 1 && bodies.len() <= 4);
+    for i in 0..8 {
+        let name = format!("\"metric{}\"", i);
+        assert_eq!(bodies.iter().filter(|body| body.contains(&name)).count(), 1);
+    }
 }